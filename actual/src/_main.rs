@@ -7,6 +7,7 @@ mod http_task;
 mod event_handler;
 mod router;
 mod dhcp;
+mod sntp_task;
 
 use esp_backtrace as _;
 use types::{String};
@@ -129,6 +130,15 @@ async fn main(spawner: Spawner) {
     spawner.spawn(dhcp::dhcp_server_task(ap_stack)).unwrap();
     println!("dhcp_server_task spawned...");
 
+    spawner.spawn(sntp_task::sntp_task(sta_stack)).unwrap();
+    println!("sntp_task spawned...");
+
+    server::chunks::init_all().await;
+    spawner.spawn(server::verify::task_verifier()).unwrap();
+    println!("task_verifier spawned...");
+    spawner.spawn(server::trash::task_trash_purger(file_manager::consts::TRASH_MAX_AGE_SECS)).unwrap();
+    println!("task_trash_purger spawned...");
+
     static AP_SOCKET_RESOURCES: StaticCell<([u8; 1024], [u8; 1024], [u8; 2048])> = StaticCell::new();
     static STA_SOCKET_RESOURCES: StaticCell<([u8; 1024], [u8; 1024], [u8; 2048])> = StaticCell::new();
 
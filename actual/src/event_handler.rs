@@ -29,6 +29,7 @@ pub fn get_wifi_status<'a>(
         ip_address: config.clone().map(|c| c.address.address()),
         gateway: config.and_then(|c| c.gateway),
         mac_address: stack.hardware_address().as_bytes().try_into().unwrap_or([0; 6]),
+        sntp: file_manager::sntp::status().into(),
     };
 }
 
@@ -7,7 +7,7 @@ mod types;
 
 use esp_backtrace as _;
 use esp_alloc as _;
-use embedded_sdmmc::{SdCard, TimeSource, Timestamp};
+use embedded_sdmmc::{SdCard};
 use embedded_hal::delay::DelayNs;
 use embedded_hal::spi::SpiBus;
 use embedded_sdmmc::{VolumeManager};
@@ -101,19 +101,3 @@ async fn main(spawner: Spawner) {
         tick += 1;
     }
 }
-
-#[derive(Default)]
-pub struct DummyTimesource();
-
-impl TimeSource for DummyTimesource {
-    fn get_timestamp(&self) -> Timestamp {
-        Timestamp {
-            year_since_1970: 0,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
-        }
-    }
-}
@@ -74,6 +74,17 @@ fn files_routes() -> Router<impl PathRouter> {
     Router::new()
         .route("/list", get(server::handle_files))
         .route(("/delete", parse_path_segment::<String>()), delete(server::handle_delete_file))
+        .route("/verify", get(server::verify::handle_verify_status).post(server::verify::handle_verify_all))
+        .route(("/verify", parse_path_segment::<String>()), get(server::verify::handle_verify_file))
+        .route(("/versions", parse_path_segment::<String>()), get(server::versions::handle_versions))
+        .route(
+            ("/versions", parse_path_segment::<String>(), parse_path_segment::<u32>()),
+            get(server::versions::handle_version_download)
+        )
+        .route(
+            ("/rollback", parse_path_segment::<String>(), parse_path_segment::<u32>()),
+            post(server::versions::handle_rollback)
+        )
 }
 
 fn upload_routes() -> Router<impl PathRouter> {
@@ -82,6 +93,29 @@ fn upload_routes() -> Router<impl PathRouter> {
         .route("/music", post(server::handle_music_upload))
 }
 
+fn resumable_routes() -> Router<impl PathRouter> {
+    Router::new()
+        .route("/file", post(server::handle_tus_create_file))
+        .route("/music", post(server::handle_tus_create_music))
+        .route(
+            server::UploadKey,
+            get(server::handle_tus_status)
+                .patch_service(server::TusPatch)
+                .delete(server::handle_tus_delete)
+        )
+}
+
+fn api_routes() -> Router<impl PathRouter> {
+    Router::new()
+        .route(("/fs", CatchAll), get(server::api::handle_api_fs))
+        .route("/files", get(server::api::handle_api_files))
+        .route("/music", get(server::api::handle_api_music))
+        .route("/card", get(server::api::handle_api_card))
+        .route("/volumes", get(server::api::handle_api_volumes))
+        .route("/usage", get(server::api::handle_api_usage))
+        .route("/fsck", get(server::api::handle_api_fsck).post(server::api::handle_api_fsck_repair))
+}
+
 pub fn router() -> Router<impl PathRouter> {
     Router::new()
         .route("/", get(home))
@@ -96,9 +130,16 @@ pub fn router() -> Router<impl PathRouter> {
         .route("/print-alloc", get(print_alloc))
 
         .route(("/download", CatchAll), get(server::handle_download))
-        .route(("/fs", CatchAll), get(server::handle_fs))
+        .route(("/fs", CatchAll), get(server::handle_fs).put(server::handle_fs_put).delete(server::handle_fs_delete))
         .route("/db", delete(server::handle_delete_db))
         .nest("/files", files_routes())
         .nest("/upload", upload_routes())
+        .nest("/uploads", resumable_routes())
+        .route("/trash", get(server::trash::handle_trash_list).delete(server::trash::handle_trash_purge_all))
+        .route(("/trash/restore", parse_path_segment::<String>()), post(server::trash::handle_trash_restore))
+        .route(("/trash", parse_path_segment::<String>()), delete(server::trash::handle_trash_purge))
+        .nest("/api", api_routes())
+        .route("/time", get(server::clock::handle_time).post(server::clock::handle_set_time))
+        .route_service(("/dav", CatchAll), server::webdav::WebDav)
 }
 
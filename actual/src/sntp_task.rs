@@ -0,0 +1,55 @@
+use esp_println::println;
+use embassy_net::{IpEndpoint, Stack};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use file_manager::sntp::{self, SntpConfig, SntpError, SntpSocket};
+
+const SNTP_SERVER: &str = "pool.ntp.org";
+
+/// A UDP socket of the STA interface talking to `server` only.
+struct StaSocket<'a> {
+    socket: UdpSocket<'a>,
+    server: IpEndpoint,
+}
+
+impl SntpSocket for StaSocket<'_> {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), SntpError> {
+        self.socket.send_to(packet, self.server).await.map_err(|_| SntpError::Io)
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, SntpError> {
+        loop {
+            let (len, meta) = self.socket.recv_from(buf).await.map_err(|_| SntpError::Io)?;
+            if meta.endpoint == self.server {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+/// Keeps the clock in sync once the STA interface is on the router.
+#[embassy_executor::task(pool_size = 1)]
+pub async fn sntp_task(stack: Stack<'static>) {
+    let config = SntpConfig::default();
+    let mut retry = config.min_retry_ms;
+
+    let server = loop {
+        stack.wait_config_up().await;
+        match stack.dns_query(SNTP_SERVER, DnsQueryType::A).await {
+            Ok(addrs) if !addrs.is_empty() => break IpEndpoint::new(addrs[0], sntp::PORT),
+            result => println!("cannot resolve {}: {:?}", SNTP_SERVER, result),
+        }
+        embassy_time::Timer::after_millis(retry).await;
+        retry = (retry * 2).min(config.max_retry_ms);
+    };
+    println!("syncing time with {}", server);
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0; 256];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buf = [0; 256];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    socket.bind(0).unwrap();
+
+    sntp::run(&mut StaSocket { socket, server }, config).await;
+}
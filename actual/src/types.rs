@@ -19,5 +19,32 @@ pub struct WifiStatus {
     pub ip_address: Option<embassy_net::Ipv4Address>,
     pub gateway: Option<embassy_net::Ipv4Address>,
     pub mac_address: [u8; 6],
+    pub sntp: SntpStatus,
+}
+
+/// [`file_manager::sntp::SntpStatus`] for the `/status` JSON.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SntpStatus {
+    pub syncs: u32,
+    pub failures: u32,
+    pub last_sync: Option<u32>,
+    pub offset_ms: i32,
+    pub delay_ms: u32,
+    pub drift_ppm: i32,
+    pub last_error: Option<&'static str>,
+}
+
+impl From<file_manager::sntp::SntpStatus> for SntpStatus {
+    fn from(status: file_manager::sntp::SntpStatus) -> Self {
+        Self {
+            syncs: status.syncs,
+            failures: status.failures,
+            last_sync: status.last_sync,
+            offset_ms: status.offset_ms,
+            delay_ms: status.delay_ms,
+            drift_ppm: status.drift_ppm,
+            last_error: status.last_error.map(|e| e.as_str()),
+        }
+    }
 }
 
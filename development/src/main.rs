@@ -3,15 +3,24 @@ use alpa::embedded_sdmmc_ram_device::{allocators};
 use picoserve::time::Duration;
 use picoserve::routing::{post, get, delete, parse_path_segment, Router, PathRouter};
 use picoserve::response::{Response, IntoResponse};
-use file_manager::{init_file_manager, DummyTimesource};
+use file_manager::{init_file_manager, get_file_manager, Clock};
 use server::{CatchAll, HOME_PAGE};
 use file_manager::{BlkDev, init_file_system, ExtAlloc};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     allocators::init_simulated_hardware();
+
+    // `development fsck [--repair] [image]` checks an image instead of serving it
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("fsck") {
+        let repair = args.iter().any(|arg| arg == "--repair");
+        let image = args[1..].iter().find(|arg| *arg != "--repair").map_or("test_file.db", String::as_str);
+        return fsck(image, repair).await;
+    }
+
     let sdcard = BlkDev::new("test_file.db").unwrap();
-    init_file_manager(sdcard, DummyTimesource);
+    init_file_manager(sdcard, Clock);
 
     let listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 8000)).await.unwrap();
 
@@ -38,6 +47,27 @@ async fn main() {
 
             server::chunks::init_all().await;
             tokio::task::spawn_local(server::chunks::task_file_uploader());
+            tokio::task::spawn_local(server::verify::task_verifier());
+            // e.g. TRASH_MAX_AGE=86400 to keep deleted files for a day, 0 for ever
+            let trash_max_age = std::env::var("TRASH_MAX_AGE").ok()
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(file_manager::consts::TRASH_MAX_AGE_SECS);
+            tokio::task::spawn_local(server::trash::task_trash_purger(trash_max_age));
+            tokio::task::spawn_local(get_file_manager().monitor_card(ExtAlloc::default()));
+            // e.g. SNTP_SERVER=pool.ntp.org:123, instead of the host clock
+            if let Ok(server) = std::env::var("SNTP_SERVER") {
+                tokio::task::spawn_local(async move {
+                    let mut socket = tokio::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+                    socket.connect(server).await.unwrap();
+                    file_manager::sntp::run(&mut socket, Default::default()).await;
+                });
+            }
+            tokio::task::spawn_local(async {
+                loop {
+                    let event = get_file_manager().card_events.wait().await;
+                    println!("card: {:?}", event);
+                }
+            });
 
             loop {
                 let (stream, remote_address) = listener.accept().await.unwrap();
@@ -58,6 +88,38 @@ async fn main() {
     .await
 }
 
+/// Checks `image` against its tables and prints what is wrong, repairing it
+/// if `repair` is set.
+async fn fsck(image: &str, repair: bool) {
+    init_file_manager(BlkDev::new(image).unwrap(), Clock);
+    if let Err(e) = init_file_system(ExtAlloc::default()).await {
+        println!("error: {:?}", e);
+        std::process::exit(1);
+    }
+
+    match get_file_manager().fsck(repair).await {
+        Ok(report) => {
+            for finding in report.findings.iter() {
+                println!("{}", finding);
+            }
+            println!(
+                "{} files, {} rows, {} problems{}",
+                report.files,
+                report.rows,
+                report.findings.len(),
+                if report.repaired { " repaired" } else { "" }
+            );
+            if !report.findings.is_empty() && !report.repaired {
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            println!("error: {:?}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
 async fn home() -> impl IntoResponse {
     Response::ok(HOME_PAGE)
         .with_header("Content-Type", "text/html")
@@ -67,6 +129,17 @@ fn files_routes() -> Router<impl PathRouter> {
     Router::new()
         .route("/list", get(server::handle_files))
         .route(("/delete", parse_path_segment::<String>()), delete(server::handle_delete_file))
+        .route("/verify", get(server::verify::handle_verify_status).post(server::verify::handle_verify_all))
+        .route(("/verify", parse_path_segment::<String>()), get(server::verify::handle_verify_file))
+        .route(("/versions", parse_path_segment::<String>()), get(server::versions::handle_versions))
+        .route(
+            ("/versions", parse_path_segment::<String>(), parse_path_segment::<u32>()),
+            get(server::versions::handle_version_download)
+        )
+        .route(
+            ("/rollback", parse_path_segment::<String>(), parse_path_segment::<u32>()),
+            post(server::versions::handle_rollback)
+        )
 }
 
 fn upload_routes() -> Router<impl PathRouter> {
@@ -75,13 +148,43 @@ fn upload_routes() -> Router<impl PathRouter> {
         .route("/music", post(server::handle_music_upload))
 }
 
+fn resumable_routes() -> Router<impl PathRouter> {
+    Router::new()
+        .route("/file", post(server::handle_tus_create_file))
+        .route("/music", post(server::handle_tus_create_music))
+        .route(
+            server::UploadKey,
+            get(server::handle_tus_status)
+                .patch_service(server::TusPatch)
+                .delete(server::handle_tus_delete)
+        )
+}
+
+fn api_routes() -> Router<impl PathRouter> {
+    Router::new()
+        .route(("/fs", CatchAll), get(server::api::handle_api_fs))
+        .route("/files", get(server::api::handle_api_files))
+        .route("/music", get(server::api::handle_api_music))
+        .route("/card", get(server::api::handle_api_card))
+        .route("/volumes", get(server::api::handle_api_volumes))
+        .route("/usage", get(server::api::handle_api_usage))
+        .route("/fsck", get(server::api::handle_api_fsck).post(server::api::handle_api_fsck_repair))
+}
+
 pub fn router() -> Router<impl PathRouter> {
     Router::new()
         .route("/", get(home))
         .nest("/files", files_routes())
         .nest("/upload", upload_routes())
+        .nest("/uploads", resumable_routes())
+        .route("/trash", get(server::trash::handle_trash_list).delete(server::trash::handle_trash_purge_all))
+        .route(("/trash/restore", parse_path_segment::<String>()), post(server::trash::handle_trash_restore))
+        .route(("/trash", parse_path_segment::<String>()), delete(server::trash::handle_trash_purge))
+        .nest("/api", api_routes())
+        .route("/time", get(server::clock::handle_time).post(server::clock::handle_set_time))
+        .route_service(("/dav", CatchAll), server::webdav::WebDav)
         .route("/db", delete(server::handle_delete_db))
         .route(("/download", CatchAll), get(server::handle_download))
-        .route(("/fs", CatchAll), get(server::handle_fs))
+        .route(("/fs", CatchAll), get(server::handle_fs).put(server::handle_fs_put).delete(server::handle_fs_delete))
 }
 
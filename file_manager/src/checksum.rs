@@ -0,0 +1,334 @@
+//! CRC32 and SHA-256 of uploaded files.
+//!
+//! Uploads record both in their row of `files`/`music` (see
+//! [`crate::consts::FILES_TABLE`]) as they are written, so that a file the
+//! card has since corrupted can be told apart by reading it again with
+//! [`FileManager::verify`].
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Query, QueryExecutor, Value};
+use embedded_sdmmc::{Mode, RawDirectory};
+use crate::ops::FsErr;
+use crate::{consts, DirGuard, ExtAlloc, FileManager, FsVolumeManager};
+
+/// CRC-32 as used by zip and Ethernet (reflected, polynomial 0xEDB88320).
+#[derive(Debug, Clone)]
+pub struct Crc32(u32);
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 = CRC_TABLE[((self.0 ^ b as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// SHA-256 (FIPS 180-4).
+#[derive(Debug, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    /// Bytes in `block`.
+    filled: usize,
+    /// Bytes hashed so far.
+    length: u64,
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ],
+            block: [0; 64],
+            filled: 0,
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        while !data.is_empty() {
+            let n = (64 - self.filled).min(data.len());
+            self.block[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+            if self.filled == 64 {
+                self.compress();
+                self.filled = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bits = self.length * 8;
+        self.update(&[0x80]);
+        while self.filled != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0u8; 32];
+        for (out, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (k, w) in K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(*k).wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (state, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(v);
+        }
+    }
+}
+
+/// Both checksums of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksums {
+    pub crc32: u32,
+    pub sha256: [u8; 32],
+}
+
+impl Checksums {
+    /// `crc32` as 8 hex digits, as stored and reported.
+    pub fn crc32_hex(&self) -> String {
+        alloc::format!("{:08x}", self.crc32)
+    }
+
+    /// `sha256` as 64 hex digits, as stored and reported.
+    pub fn sha256_hex(&self) -> String {
+        self.sha256.iter().map(|b| alloc::format!("{:02x}", b)).collect()
+    }
+
+    /// Reads back what [`Checksums::sha256_hex`] wrote.
+    pub fn parse_sha256(hex: &[u8]) -> Option<[u8; 32]> {
+        if hex.len() != 64 {
+            return None;
+        }
+        let mut digest = [0u8; 32];
+        for (out, pair) in digest.iter_mut().zip(hex.chunks_exact(2)) {
+            *out = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(digest)
+    }
+}
+
+/// Computes [`Checksums`] of data written in pieces.
+#[derive(Debug, Clone)]
+pub struct Checksummer {
+    crc32: Crc32,
+    sha256: Sha256,
+}
+
+impl Default for Checksummer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Checksummer {
+    pub fn new() -> Self {
+        Self { crc32: Crc32::new(), sha256: Sha256::new() }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.crc32.update(data);
+        self.sha256.update(data);
+    }
+
+    pub fn finish(self) -> Checksums {
+        Checksums { crc32: self.crc32.finish(), sha256: self.sha256.finish() }
+    }
+}
+
+/// Reads the file `name` in `dir` through, returning its size and checksums.
+pub fn checksum_file(dir: &DirGuard<'_>, name: &str) -> Result<(u32, Checksums), FsErr> {
+    let file = dir.open_file(name, Mode::ReadOnly)?;
+    let mut sums = Checksummer::new();
+    let mut buf = [0u8; 512];
+    let mut size = 0;
+    while !file.is_eof()? {
+        let n = file.read(&mut buf)?;
+        sums.update(&buf[..n]);
+        size += n as u32;
+    }
+    Ok((size, sums.finish()))
+}
+
+/// The row of the file `name` in `table`.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub upload_name: String,
+    pub size: i64,
+    pub checksums: Checksums,
+}
+
+/// Reads the row of the file `name` from `table`.
+pub(crate) fn recorded(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    table: &str,
+    name: &str,
+) -> Result<Option<Recorded>, FsErr> {
+    let key = name.to_ascii_uppercase();
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(table, ExtAlloc::default())?;
+
+    let query = Query::<_, &str>::new(table, ExtAlloc::default())
+                                 .key(Value::Chars(key.as_bytes()));
+    let Ok(mut exec) = QueryExecutor::new(
+        query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+        &db.file_handler.page_rw.as_ref().unwrap()
+    ) else {
+        return Ok(None);
+    };
+    let Ok(row) = exec.next() else {
+        return Ok(None);
+    };
+    Ok(Some(Recorded {
+        upload_name: String::from_utf8_lossy(row[1].to_chars().unwrap()).into_owned(),
+        size: row[2].to_int().unwrap(),
+        checksums: Checksums {
+            crc32: row[3].to_int().unwrap() as u32,
+            sha256: Checksums::parse_sha256(row[4].to_chars().unwrap()).unwrap_or([0; 32]),
+        },
+    }))
+}
+
+/// What re-reading an uploaded file found, see [`FileManager::verify`].
+#[derive(Debug, Clone)]
+pub struct Verification {
+    /// Short name of the file.
+    pub name: String,
+    pub recorded: Recorded,
+    /// Size and checksums of the file as it reads now, or `None` if it is
+    /// gone.
+    pub actual: Option<(u32, Checksums)>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.actual.is_some_and(|(size, sums)| {
+            size as i64 == self.recorded.size && sums == self.recorded.checksums
+        })
+    }
+}
+
+impl FileManager {
+    /// Reads the uploaded file `name` of `table` again and compares it with
+    /// the checksums recorded for it. `None` if it has no row.
+    pub async fn verify(&self, table: &str, name: &str) -> Result<Option<Verification>, FsErr> {
+        let dir = match table {
+            consts::FILES_TABLE => consts::FILES_DIR,
+            consts::MUSIC_TABLE => consts::MUSIC_DIR,
+            _ => return Ok(None),
+        };
+
+        self.with_root_dir(|vm, root_dir| {
+            let Some(recorded) = recorded(vm, root_dir, table, name)? else {
+                return Ok(None);
+            };
+            let files = DirGuard::new(vm, vm.open_dir(root_dir, dir)?);
+            let actual = match checksum_file(&files, name) {
+                Ok(actual) => Some(actual),
+                Err(crate::FManError::SdErr(embedded_sdmmc::Error::NotFound)) => None,
+                Err(e) => return Err(e),
+            };
+            Ok(Some(Verification { name: name.to_ascii_uppercase(), recorded, actual }))
+        })
+        .await
+    }
+
+    /// Short names of every file registered in `table`.
+    pub async fn registered(&self, table: &str) -> Result<Vec<String>, FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+            let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+            let table = db.get_table(table, ExtAlloc::default())?;
+            let mut names = Vec::new();
+            let query = Query::<_, &str>::new(table, ExtAlloc::default());
+            // an empty table has no pages to run a query over
+            if let Ok(mut exec) = QueryExecutor::new(
+                query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+                &db.file_handler.page_rw.as_ref().unwrap()
+            ) {
+                while let Ok(row) = exec.next() {
+                    names.push(String::from_utf8_lossy(row[0].to_chars().unwrap()).into_owned());
+                }
+            }
+            Ok(names)
+        })
+        .await
+    }
+}
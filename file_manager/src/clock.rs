@@ -0,0 +1,99 @@
+//! Wall clock for the timestamps of files.
+//!
+//! Neither board has a battery-backed clock, so the time is kept as the
+//! Unix time at boot plus [`uptime_ms`]. It is unset until someone tells
+//! it: the browser posting its `Date.now()`, a client's `Date` header, or
+//! the host clock in the tokio build. Until then files are stamped
+//! 1970-01-01 as before. Once SNTP has synced it, clients are no longer
+//! listened to.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use embedded_sdmmc::{TimeSource, Timestamp};
+use crate::runtime::uptime_ms;
+
+/// Unix time in seconds at an uptime of zero, or 0 while unset. A `u32`
+/// lasts until 2106; the milliseconds are kept apart in [`BOOT_MILLIS`]
+/// as there are no 64-bit atomics on the ESP32.
+static BOOT_TIME: AtomicU32 = AtomicU32::new(0);
+static BOOT_MILLIS: AtomicU32 = AtomicU32::new(0);
+
+/// The wall clock, as the [`TimeSource`] of the volume manager.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Clock;
+
+impl Clock {
+    /// Sets the current time to `unix_ms` milliseconds since the epoch.
+    pub fn set_unix_ms(unix_ms: u64) {
+        let boot = unix_ms.saturating_sub(uptime_ms()).max(1000);
+        BOOT_MILLIS.store((boot % 1000) as u32, Ordering::Relaxed);
+        BOOT_TIME.store((boot / 1000).min(u32::MAX as u64) as u32, Ordering::Relaxed);
+    }
+
+    /// Milliseconds since the epoch, if the clock has been set.
+    pub fn unix_ms() -> Option<u64> {
+        match BOOT_TIME.load(Ordering::Relaxed) {
+            0 => None,
+            boot => Some(boot as u64 * 1000 + BOOT_MILLIS.load(Ordering::Relaxed) as u64 + uptime_ms()),
+        }
+    }
+
+    /// Seconds since the epoch, if the clock has been set.
+    pub fn unix_secs() -> Option<u64> {
+        Self::unix_ms().map(|ms| ms / 1000)
+    }
+
+    pub fn is_set() -> bool {
+        BOOT_TIME.load(Ordering::Relaxed) != 0
+    }
+
+    /// Sets the time a client tells, like the browser's `Date.now()`,
+    /// unless [`crate::sntp`] has synced the clock: its time is the better
+    /// one, and a jump it did not make would count towards its drift.
+    /// Returns whether the clock was set.
+    pub fn set_by_client(unix_ms: u64) -> bool {
+        if crate::sntp::status().syncs > 0 {
+            return false;
+        }
+        Self::set_unix_ms(unix_ms);
+        true
+    }
+
+    /// Sets the clock from the host's, in the tokio build.
+    #[cfg(feature = "tokio")]
+    pub fn set_from_host() {
+        if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Self::set_unix_ms(now.as_millis() as u64);
+        }
+    }
+}
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        timestamp(Self::unix_secs().unwrap_or(0))
+    }
+}
+
+/// The calendar date and time `unix_secs` seconds after the epoch, in UTC.
+pub fn timestamp(unix_secs: u64) -> Timestamp {
+    let (days, secs) = (unix_secs / 86400, unix_secs % 86400);
+
+    // days to civil date, after Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5;
+    let month = if mp < 10 { mp + 2 } else { mp - 10 };
+    let year = yoe + era * 400 + (month < 2) as u64;
+
+    Timestamp {
+        year_since_1970: (year - 1970).min(u8::MAX as u64) as u8,
+        zero_indexed_month: month as u8,
+        zero_indexed_day: day as u8,
+        hours: (secs / 3600) as u8,
+        minutes: (secs / 60 % 60) as u8,
+        seconds: (secs % 60) as u8,
+    }
+}
@@ -1,7 +1,36 @@
 pub const FILES_DIR: &'static str = "FILES";
 pub const MUSIC_DIR: &'static str = "MUSIC";
+pub const TRASH_DIR: &'static str = "TRASH";
+pub const VERSIONS_DIR: &'static str = "VERSIONS";
 pub const DB_DIR: &'static str = "DB";
 
 pub const FILES_TABLE: &'static str = "files";
 pub const MUSIC_TABLE: &'static str = "music";
 pub const COUNT_TRACKER_TABLE: &'static str = "count_tracker";
+pub const UPLOADS_TABLE: &'static str = "uploads";
+pub const LONG_NAMES_TABLE: &'static str = "long_names";
+pub const SHORT_NAMES_TABLE: &'static str = "short_names";
+pub const TRASH_TABLE: &'static str = "trash";
+pub const VERSIONS_TABLE: &'static str = "versions";
+
+/// Directories the volume manager can have open at once, across volumes.
+pub const MAX_OPEN_DIRS: usize = 4;
+/// Files the volume manager can have open at once, across volumes.
+pub const MAX_OPEN_FILES: usize = 4;
+/// Volumes that can be mounted at once, see [`crate::volumes`].
+pub const MAX_VOLUMES: usize = 4;
+
+/// How long opening a handle waits for one to be closed when all of them
+/// are in use, before failing with `TooManyOpenDirs`/`TooManyOpenFiles`.
+pub const HANDLE_WAIT_MS: u64 = 1000;
+/// How often a waiting open is retried.
+pub const HANDLE_RETRY_MS: u64 = 20;
+
+/// How long deleted uploads stay in the trash unless told otherwise, see
+/// [`crate::trash`].
+pub const TRASH_MAX_AGE_SECS: u64 = 30 * 24 * 3600;
+/// How often the trash is checked for what has been there long enough.
+pub const TRASH_PURGE_EVERY_MS: u64 = 3600 * 1000;
+
+/// Old versions kept of each uploaded file, see [`crate::versions`].
+pub const MAX_VERSIONS: usize = 5;
@@ -41,7 +41,7 @@ unsafe impl Allocator for EspAlloc {
 
 pub type BlkDev<S, D> = FsBlockDevice<S, D>;
 pub type ExtAlloc = EspAlloc;
-pub type FMan<S, D> = FileManager<BlkDev<S, D>, TimeSrc, 4, 4, 1>;
+pub type FMan<S, D> = FileManager<BlkDev<S, D>, TimeSrc, { consts::MAX_OPEN_DIRS }, { consts::MAX_OPEN_FILES }, { consts::MAX_VOLUMES }>;
 pub type FsError = embedded_sdmmc::SdCardError;
 
 pub type ConcreteSpi<'a> = ExclusiveDevice<Spi<'a, Blocking>, Output<'a>, Delay>;
@@ -53,7 +53,7 @@ unsafe impl <'a> Sync for SyncFMan<'a> {}
 
 pub static FILE_MAN: OnceLock<SyncFMan> = OnceLock::new();
 
-pub fn init_file_manager(block_device: BlkDev<ConcreteSpi<'static>, ConcreteDelay>, time_src: DummyTimesource)
+pub fn init_file_manager(block_device: BlkDev<ConcreteSpi<'static>, ConcreteDelay>, time_src: Clock)
 {
     let _ = FILE_MAN.init(SyncFMan(FileManager::new(block_device, time_src)));
 }
@@ -93,131 +93,291 @@ where
     embedded_sdmmc::Error<<FsBlockDevice<ConcreteSpi<'static>, ConcreteDelay> as BlockDevice>::Error>: Into<embedded_sdmmc::Error<FsError>>
 {
     let sdcard = BlkDev::new(spi_device, delay);
-    init_file_manager(sdcard, DummyTimesource);
+    init_file_manager(sdcard, Clock);
 
     let fman = get_file_manager().await;
+    fman.with_vol_man(|vm, vol| prepare_card(vm, vol, allocator)).await?;
+    Ok(())
+}
+
+/// Whether the card still answers. A card that does not is marked for a
+/// fresh initialisation, which a reinserted one needs.
+pub(crate) fn probe_card<S: embedded_hal::spi::SpiDevice, D: embedded_hal::delay::DelayNs>(device: &BlkDev<S, D>) -> bool {
+    let present = device.num_blocks().is_ok();
+    if !present {
+        device.mark_card_uninit();
+    }
+    present
+}
+
+/// Creates the directories and tables the server relies on, keeping what
+/// a card that was prepared before already has.
+pub(crate) fn prepare_card(
+    vm: &VolumeManager<BlkDev<ConcreteSpi<'static>, ConcreteDelay>, Clock, { consts::MAX_OPEN_DIRS }, { consts::MAX_OPEN_FILES }, { consts::MAX_VOLUMES }>,
+    vol: &RawVolume,
+    allocator: ExtAlloc,
+) -> Result<(), FManError<SdCardError>> {
+    let root_dir = FileManager::<FsBlockDevice<ConcreteSpi<'static>, ConcreteDelay>, Clock, { consts::MAX_OPEN_DIRS }, { consts::MAX_OPEN_FILES }, { consts::MAX_VOLUMES }>
+                              ::root_dir(vm, vol)?
+                              .to_directory(vm);
+    let _ = root_dir.make_dir_in_dir(consts::DB_DIR).or_else(|e| {
+        if matches!(e, embedded_sdmmc::Error::DirAlreadyExists) {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })?;
+    let _ = root_dir.make_dir_in_dir(consts::FILES_DIR).or_else(|e| {
+        if matches!(e, embedded_sdmmc::Error::DirAlreadyExists) {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })?;
+    let _ = root_dir.make_dir_in_dir(consts::MUSIC_DIR).or_else(|e| {
+        if matches!(e, embedded_sdmmc::Error::DirAlreadyExists) {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })?;
+    let _ = root_dir.make_dir_in_dir(consts::TRASH_DIR).or_else(|e| {
+        if matches!(e, embedded_sdmmc::Error::DirAlreadyExists) {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })?;
+    let _ = root_dir.make_dir_in_dir(consts::VERSIONS_DIR).or_else(|e| {
+        if matches!(e, embedded_sdmmc::Error::DirAlreadyExists) {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })?;
 
-    fman.with_vol_man(|vm, vol| {
-        let root_dir = FileManager::<FsBlockDevice<ConcreteSpi<'static>, ConcreteDelay>, DummyTimesource, 4, 4, 1>
-                                  ::root_dir(vm, vol)?
-                                  .to_directory(vm);
-        let _ = root_dir.make_dir_in_dir(consts::DB_DIR).or_else(|e| {
-            if matches!(e, embedded_sdmmc::Error::DirAlreadyExists) {
-                Ok(())
-            } else {
-                Err(e)
-            }
-        })?;
-        let _ = root_dir.make_dir_in_dir(consts::FILES_DIR).or_else(|e| {
-            if matches!(e, embedded_sdmmc::Error::DirAlreadyExists) {
-                Ok(())
-            } else {
-                Err(e)
-            }
-        })?;
-        let _ = root_dir.make_dir_in_dir(consts::MUSIC_DIR).or_else(|e| {
-            if matches!(e, embedded_sdmmc::Error::DirAlreadyExists) {
-                Ok(())
-            } else {
-                Err(e)
-            }
-        })?;
-
-        println!("created all dirs");
+    println!("created all dirs");
+
+    {
+        let db_dir = root_dir.open_dir(consts::DB_DIR)?.to_raw_directory();
+        let stuff_dir = DbDirSdmmc::new(db_dir);
+        let mut db = Database::new_init(VM::new(vm), stuff_dir, allocator.clone())?;
+        println!("db init success");
 
         {
-            let db_dir = root_dir.open_dir(consts::DB_DIR)?.to_raw_directory();
-            let stuff_dir = DbDirSdmmc::new(db_dir);
-            let mut db = Database::new_init(VM::new(vm), stuff_dir, allocator.clone())?;
-            println!("db init success");
-
-            {
-                let name = Column::new("name", ColumnType::Chars).primary();
-                let count = Column::new("count", ColumnType::Int);
-                db.new_table_begin(consts::COUNT_TRACKER_TABLE);
-                db.add_column(name)?;
-                db.add_column(count)?;
-                let _ = db.create_table(allocator.clone()).or_else(|e| {
-                    if matches!(e, alpa::db::Error::DuplicateKey) {
-                        Ok(0)
-                    } else {
-                        Err(e)
-                    }
-                })?;
-            }
-
-            println!("count_tracker done");
-
-            {
-                let name = Column::new("path", ColumnType::Chars).primary();
-                let count = Column::new("name", ColumnType::Chars);
-                let size = Column::new("size", ColumnType::Int);
-                db.new_table_begin(consts::FILES_TABLE);
-                db.add_column(name)?;
-                db.add_column(count)?;
-                db.add_column(size)?;
-                let _ = db.create_table(allocator.clone()).or_else(|e| {
-                    if matches!(e, alpa::db::Error::DuplicateKey) {
-                        Ok(0)
-                    } else {
-                        Err(e)
-                    }
-                })?;
-            }
-
-            println!("files table done");
-
-            {
-                let name = Column::new("path", ColumnType::Chars).primary();
-                let count = Column::new("name", ColumnType::Chars);
-                db.new_table_begin(consts::MUSIC_TABLE);
-                db.add_column(name)?;
-                db.add_column(count)?;
-                let _ = db.create_table(allocator.clone()).or_else(|e| {
-                    if matches!(e, alpa::db::Error::DuplicateKey) {
-                        Ok(0)
-                    } else {
-                        Err(e)
-                    }
-                })?;
-            }
-            println!("music table done");
-
-            let count_tracker = db.get_table(consts::COUNT_TRACKER_TABLE, allocator.clone())?;
-
-            {
-                let mut row = Row::new_in(allocator.clone());
-                row.push(Value::Chars(consts::FILES_TABLE.as_bytes()));
-                row.push(Value::Int(1));
-                let _ = db.insert_to_table(count_tracker, row, allocator.clone()).or_else(|e| {
-                    if matches!(e, alpa::db::Error::DuplicateKey) {
-                        Ok(())
-                    } else {
-                        Err(e)
-                    }
-                })?;
-            }
-
-            println!("insert files_table to count_tracker table done");
-
-            {
-                let mut row = Row::new_in(allocator.clone());
-                row.push(Value::Chars(consts::MUSIC_TABLE.as_bytes()));
-                row.push(Value::Int(1));
-                let _ = db.insert_to_table(count_tracker, row, allocator.clone()).or_else(|e| {
-                    if matches!(e, alpa::db::Error::DuplicateKey) {
-                        Ok(())
-                    } else {
-                        Err(e)
-                    }
-                })?;
-            }
-            println!("insert music_table to count_tracker table done");
-
-            println!("closed db successfully");
+            let name = Column::new("name", ColumnType::Chars).primary();
+            let count = Column::new("count", ColumnType::Int);
+            db.new_table_begin(consts::COUNT_TRACKER_TABLE);
+            db.add_column(name)?;
+            db.add_column(count)?;
+            let _ = db.create_table(allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
 
-            Ok(())
+        println!("count_tracker done");
+
+        {
+            let name = Column::new("path", ColumnType::Chars).primary();
+            let count = Column::new("name", ColumnType::Chars);
+            let size = Column::new("size", ColumnType::Int);
+            let crc32 = Column::new("crc32", ColumnType::Int);
+            let sha256 = Column::new("sha256", ColumnType::Chars);
+            db.new_table_begin(consts::FILES_TABLE);
+            db.add_column(name)?;
+            db.add_column(count)?;
+            db.add_column(size)?;
+            db.add_column(crc32)?;
+            db.add_column(sha256)?;
+            let _ = db.create_table(allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
         }
-    }).await?;
-    Ok(())
+
+        println!("files table done");
+
+        {
+            let name = Column::new("path", ColumnType::Chars).primary();
+            let count = Column::new("name", ColumnType::Chars);
+            let size = Column::new("size", ColumnType::Int);
+            let crc32 = Column::new("crc32", ColumnType::Int);
+            let sha256 = Column::new("sha256", ColumnType::Chars);
+            db.new_table_begin(consts::MUSIC_TABLE);
+            db.add_column(name)?;
+            db.add_column(count)?;
+            db.add_column(size)?;
+            db.add_column(crc32)?;
+            db.add_column(sha256)?;
+            let _ = db.create_table(allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+        println!("music table done");
+
+        {
+            let key = Column::new("key", ColumnType::Chars).primary();
+            let name = Column::new("name", ColumnType::Chars);
+            let length = Column::new("length", ColumnType::Int);
+            let offset = Column::new("offset", ColumnType::Int);
+            db.new_table_begin(consts::UPLOADS_TABLE);
+            db.add_column(key)?;
+            db.add_column(name)?;
+            db.add_column(length)?;
+            db.add_column(offset)?;
+            let _ = db.create_table(allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+        println!("uploads table done");
+
+        {
+            let key = Column::new("key", ColumnType::Chars).primary();
+            let short = Column::new("short", ColumnType::Chars);
+            db.new_table_begin(consts::LONG_NAMES_TABLE);
+            db.add_column(key)?;
+            db.add_column(short)?;
+            let _ = db.create_table(allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+
+        {
+            let key = Column::new("key", ColumnType::Chars).primary();
+            let long = Column::new("long", ColumnType::Chars);
+            db.new_table_begin(consts::SHORT_NAMES_TABLE);
+            db.add_column(key)?;
+            db.add_column(long)?;
+            let _ = db.create_table(allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+        println!("long name tables done");
+
+        {
+            let key = Column::new("key", ColumnType::Chars).primary();
+            let name = Column::new("name", ColumnType::Chars);
+            let size = Column::new("size", ColumnType::Int);
+            let crc32 = Column::new("crc32", ColumnType::Int);
+            let sha256 = Column::new("sha256", ColumnType::Chars);
+            let category = Column::new("category", ColumnType::Chars);
+            let original = Column::new("original", ColumnType::Chars);
+            let deleted = Column::new("deleted", ColumnType::Int);
+            db.new_table_begin(consts::TRASH_TABLE);
+            db.add_column(key)?;
+            db.add_column(name)?;
+            db.add_column(size)?;
+            db.add_column(crc32)?;
+            db.add_column(sha256)?;
+            db.add_column(category)?;
+            db.add_column(original)?;
+            db.add_column(deleted)?;
+            let _ = db.create_table(allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+        println!("trash table done");
+
+        {
+            let key = Column::new("key", ColumnType::Chars).primary();
+            let name = Column::new("name", ColumnType::Chars);
+            let version = Column::new("version", ColumnType::Int);
+            let size = Column::new("size", ColumnType::Int);
+            let crc32 = Column::new("crc32", ColumnType::Int);
+            let sha256 = Column::new("sha256", ColumnType::Chars);
+            let replaced = Column::new("replaced", ColumnType::Int);
+            db.new_table_begin(consts::VERSIONS_TABLE);
+            db.add_column(key)?;
+            db.add_column(name)?;
+            db.add_column(version)?;
+            db.add_column(size)?;
+            db.add_column(crc32)?;
+            db.add_column(sha256)?;
+            db.add_column(replaced)?;
+            let _ = db.create_table(allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+        println!("versions table done");
+
+        let count_tracker = db.get_table(consts::COUNT_TRACKER_TABLE, allocator.clone())?;
+
+        {
+            let mut row = Row::new_in(allocator.clone());
+            row.push(Value::Chars(consts::FILES_TABLE.as_bytes()));
+            row.push(Value::Int(1));
+            let _ = db.insert_to_table(count_tracker, row, allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+
+        println!("insert files_table to count_tracker table done");
+
+        {
+            let mut row = Row::new_in(allocator.clone());
+            row.push(Value::Chars(consts::MUSIC_TABLE.as_bytes()));
+            row.push(Value::Int(1));
+            let _ = db.insert_to_table(count_tracker, row, allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+        println!("insert music_table to count_tracker table done");
+
+        {
+            let mut row = Row::new_in(allocator.clone());
+            row.push(Value::Chars(consts::TRASH_TABLE.as_bytes()));
+            row.push(Value::Int(1));
+            let _ = db.insert_to_table(count_tracker, row, allocator.clone()).or_else(|e| {
+                if matches!(e, alpa::db::Error::DuplicateKey) {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+        println!("insert trash to count_tracker table done");
+
+        println!("closed db successfully");
+
+        Ok(())
+    }
 }
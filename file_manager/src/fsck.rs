@@ -0,0 +1,200 @@
+//! Consistency check between the upload directories and their tables.
+//!
+//! Deleting an upload removes the file and then its row, and deleting the
+//! DB drops the rows while the files stay, so `FILES`/`MUSIC` and the
+//! `files`/`music` tables can drift apart. [`FileManager::fsck`] compares
+//! every file directly in those directories with the rows of their table,
+//! and `count_tracker` with the ids in use.
+//!
+//! Files of a tus upload still in progress have no row yet and are left
+//! alone.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Query, QueryExecutor, Row, Value};
+use embedded_sdmmc::RawDirectory;
+use crate::ops::{open_below, close_unless, register, unregister, FsErr};
+use crate::tree::{display_name, list};
+use crate::{consts, ExtAlloc, FileManager, FsVolumeManager};
+
+/// Something [`FileManager::fsck`] found wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// A file has no row; repaired by registering it under its long name,
+    /// or its short one.
+    Unregistered { name: String, size: u32 },
+    /// A row names a file that is gone; repaired by dropping the row.
+    Missing { name: String },
+    /// The row records another size than the file has; repaired by
+    /// recording the file's.
+    SizeMismatch { name: String, recorded: i64, actual: u32 },
+    /// `count_tracker` would hand out an id already in use; repaired by
+    /// moving it past the highest one.
+    StaleCounter { recorded: i64, next_free: i64 },
+}
+
+/// An [`Issue`] with the table it concerns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub table: &'static str,
+    pub issue: Issue,
+}
+
+impl core::fmt::Display for Finding {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.issue {
+            Issue::Unregistered { ref name, size } => write!(f, "{}: {} ({} B) has no row", self.table, name, size),
+            Issue::Missing { ref name } => write!(f, "{}: {} has a row but no file", self.table, name),
+            Issue::SizeMismatch { ref name, recorded, actual } => {
+                write!(f, "{}: {} is {} B, its row says {} B", self.table, name, actual, recorded)
+            },
+            Issue::StaleCounter { recorded, next_free } => {
+                write!(f, "{}: count_tracker is at {}, the next free id is {}", self.table, recorded, next_free)
+            },
+        }
+    }
+}
+
+/// What [`FileManager::fsck`] went through.
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// Files looked at.
+    pub files: u32,
+    /// Rows looked at.
+    pub rows: u32,
+    pub findings: Vec<Finding>,
+    /// Whether the findings have been repaired.
+    pub repaired: bool,
+}
+
+/// The id of an upload named `<id>.<ext>`.
+fn upload_id(name: &str) -> Option<i64> {
+    name.split('.').next()?.parse().ok()
+}
+
+/// Finds what disagrees between the directory `dir` and its `table`.
+fn check_dir(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    dir: &'static str,
+    table: &'static str,
+    report: &mut FsckReport,
+) -> Result<(), FsErr> {
+    let handle = open_below(vm, root_dir, dir)?;
+    let listing = list(vm, handle);
+    close_unless(vm, handle, root_dir);
+    let files = listing?.files;
+
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    // the first column of every row of `table`, with the integer in column `int_column`
+    let mut rows = |table: &'static str, int_column: usize| -> Result<Vec<(String, i64)>, FsErr> {
+        let table = db.get_table(table, ExtAlloc::default())?;
+        let mut rows = Vec::new();
+        let query = Query::<_, &str>::new(table, ExtAlloc::default());
+        // an empty table has no pages to run a query over
+        if let Ok(mut exec) = QueryExecutor::new(
+            query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+            &db.file_handler.page_rw.as_ref().unwrap()
+        ) {
+            while let Ok(row) = exec.next() {
+                let Ok(key) = core::str::from_utf8(row[0].to_chars().unwrap()) else {
+                    continue;
+                };
+                rows.push((String::from(key), row[int_column].to_int().unwrap()));
+            }
+        }
+        Ok(rows)
+    };
+    let registered = rows(table, 2)?;
+    let counter = rows(consts::COUNT_TRACKER_TABLE, 1)?
+        .into_iter()
+        .find(|(name, _)| name == table)
+        .map_or(0, |(_, count)| count);
+    let session_prefix = format!("{}-", table);
+    let in_progress: Vec<String> = rows(consts::UPLOADS_TABLE, 2)?
+        .into_iter()
+        .filter_map(|(key, _)| key.strip_prefix(session_prefix.as_str()).map(String::from))
+        .collect();
+    drop(db);
+
+    report.files += files.len() as u32;
+    report.rows += registered.len() as u32;
+    let mut found = |issue| report.findings.push(Finding { table, issue });
+
+    for (name, size) in files.iter() {
+        let row = registered.iter().find(|(key, _)| key.eq_ignore_ascii_case(name));
+        match row {
+            None if in_progress.iter().any(|key| key.eq_ignore_ascii_case(name)) => (),
+            None => found(Issue::Unregistered { name: name.clone(), size: *size }),
+            Some((_, recorded)) if *recorded != *size as i64 => {
+                found(Issue::SizeMismatch { name: name.clone(), recorded: *recorded, actual: *size })
+            },
+            Some(_) => (),
+        }
+    }
+    for (key, _) in registered.iter() {
+        if !files.iter().any(|(name, _)| name.eq_ignore_ascii_case(key)) {
+            found(Issue::Missing { name: key.clone() });
+        }
+    }
+
+    let highest = files.iter().map(|(name, _)| name)
+        .chain(registered.iter().map(|(key, _)| key))
+        .chain(in_progress.iter())
+        .filter_map(|name| upload_id(name))
+        .max();
+    if let Some(highest) = highest.filter(|id| *id >= counter) {
+        found(Issue::StaleCounter { recorded: counter, next_free: highest + 1 });
+    }
+    Ok(())
+}
+
+/// Repairs `finding` as its [`Issue`] describes.
+fn repair_finding(vm: &FsVolumeManager, root_dir: RawDirectory, dir: &str, finding: &Finding) -> Result<(), FsErr> {
+    match finding.issue {
+        Issue::Unregistered { ref name, size } | Issue::SizeMismatch { ref name, actual: size, .. } => {
+            let upload_name = display_name(vm, root_dir, dir, name)?;
+            register(vm, root_dir, dir, name, &upload_name, size, None)
+        },
+        Issue::Missing { ref name } => unregister(vm, root_dir, dir, name).map(|_| ()),
+        Issue::StaleCounter { next_free, .. } => {
+            let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+            let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+            let count_tracker = db.get_table(consts::COUNT_TRACKER_TABLE, ExtAlloc::default())?;
+            let mut row = Row::new_in(ExtAlloc::default());
+            row.push(Value::Chars(finding.table.as_bytes()));
+            row.push(Value::Int(next_free));
+            db.update_row(count_tracker, Value::Chars(finding.table.as_bytes()), row, ExtAlloc::default())?;
+            Ok(())
+        },
+    }
+}
+
+impl FileManager {
+    /// Checks `FILES` and `MUSIC` against the `files` and `music` tables
+    /// and `count_tracker`, then repairs what it found if `repair` is set.
+    /// Holds the state lock for its whole duration.
+    pub async fn fsck(&self, repair: bool) -> Result<FsckReport, FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            let mut report = FsckReport::default();
+            let dirs = [(consts::FILES_DIR, consts::FILES_TABLE), (consts::MUSIC_DIR, consts::MUSIC_TABLE)];
+            for (dir, table) in dirs {
+                check_dir(vm, root_dir, dir, table, &mut report)?;
+            }
+
+            if repair {
+                for finding in report.findings.iter() {
+                    let dir = if finding.table == consts::MUSIC_TABLE { consts::MUSIC_DIR } else { consts::FILES_DIR };
+                    repair_finding(vm, root_dir, dir, finding)?;
+                }
+                report.repaired = true;
+            }
+            Ok(report)
+        })
+        .await
+    }
+}
@@ -0,0 +1,287 @@
+//! Handles that close themselves.
+//!
+//! A [`RawDirectory`] or [`RawFile`] stays open until it is closed by hand,
+//! and the volume manager has room for only four of each, so a single early
+//! `?` could leak one until the next mount. [`DirGuard`] and [`FileGuard`]
+//! close their handle when dropped instead. They borrow the volume manager
+//! out of the locked [`FileManagerState`], so they are gone before the lock
+//! is. [`Opened`] holds on to [`FileManager`] instead, for a handle that has
+//! to outlive the lock, like the file of a download being streamed, and
+//! locks the state again for each use of it.
+//!
+//! There are only [`consts::MAX_OPEN_DIRS`] directories and
+//! [`consts::MAX_OPEN_FILES`] files to go around, so the entry points below
+//! wait for one to be closed rather than failing right away, see
+//! [`FileManager::lock_waiting`].
+
+use embedded_sdmmc::{BlockDevice, DirEntry, Error, Mode, RawDirectory, RawFile};
+use crate::runtime::{sleep_ms, MutexGuard};
+use crate::{consts, CardState, FManError, FileManager, FileManagerState, FileType, FsBlockDevice, FsVolumeManager};
+
+type SdErr = Error<<FsBlockDevice as BlockDevice>::Error>;
+type FsErr = FManError<<FsBlockDevice as BlockDevice>::Error>;
+
+/// Whether `e` is the volume manager running out of directory or file
+/// handles, which closing one would cure.
+pub fn is_out_of_handles(e: &FsErr) -> bool {
+    matches!(e, FManError::SdErr(Error::TooManyOpenDirs | Error::TooManyOpenFiles))
+}
+
+/// An open directory, closed on drop.
+#[derive(Debug)]
+pub struct DirGuard<'a> {
+    vm: &'a FsVolumeManager,
+    raw: RawDirectory,
+}
+
+impl<'a> DirGuard<'a> {
+    /// Takes over closing `raw`.
+    pub fn new(vm: &'a FsVolumeManager, raw: RawDirectory) -> Self {
+        Self { vm, raw }
+    }
+
+    pub fn vm(&self) -> &'a FsVolumeManager {
+        self.vm
+    }
+
+    pub fn raw(&self) -> RawDirectory {
+        self.raw
+    }
+
+    /// Gives the handle up without closing it, to an owner that closes it
+    /// itself such as `DbDirSdmmc`.
+    pub fn into_raw(self) -> RawDirectory {
+        let raw = self.raw;
+        core::mem::forget(self);
+        raw
+    }
+
+    /// Closes the directory, reporting the error dropping it would ignore.
+    pub fn close(self) -> Result<(), SdErr> {
+        let vm = self.vm;
+        vm.close_dir(self.into_raw())
+    }
+
+    pub fn open_dir(&self, name: &str) -> Result<DirGuard<'a>, SdErr> {
+        Ok(DirGuard::new(self.vm, self.vm.open_dir(self.raw, name)?))
+    }
+
+    pub fn open_file(&self, name: &str, mode: Mode) -> Result<FileGuard<'a>, SdErr> {
+        Ok(FileGuard::new(self.vm, self.vm.open_file_in_dir(self.raw, name, mode)?))
+    }
+
+    pub fn find(&self, name: &str) -> Result<DirEntry, SdErr> {
+        self.vm.find_directory_entry(self.raw, name)
+    }
+
+    pub fn make_dir(&self, name: &str) -> Result<(), SdErr> {
+        self.vm.make_dir_in_dir(self.raw, name)
+    }
+
+    pub fn delete_file(&self, name: &str) -> Result<(), SdErr> {
+        self.vm.delete_file_in_dir(self.raw, name)
+    }
+
+    pub fn iterate<F: FnMut(&DirEntry)>(&self, f: F) -> Result<(), SdErr> {
+        self.vm.iterate_dir(self.raw, f)
+    }
+}
+
+impl Drop for DirGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.vm.close_dir(self.raw);
+    }
+}
+
+/// An open file, closed on drop.
+#[derive(Debug)]
+pub struct FileGuard<'a> {
+    vm: &'a FsVolumeManager,
+    raw: RawFile,
+}
+
+impl<'a> FileGuard<'a> {
+    /// Takes over closing `raw`.
+    pub fn new(vm: &'a FsVolumeManager, raw: RawFile) -> Self {
+        Self { vm, raw }
+    }
+
+    pub fn vm(&self) -> &'a FsVolumeManager {
+        self.vm
+    }
+
+    pub fn raw(&self) -> RawFile {
+        self.raw
+    }
+
+    /// Gives the handle up without closing it.
+    pub fn into_raw(self) -> RawFile {
+        let raw = self.raw;
+        core::mem::forget(self);
+        raw
+    }
+
+    /// Closes the file, reporting the error of its final flush that dropping
+    /// it would ignore. Anything written must be closed this way.
+    pub fn close(self) -> Result<(), SdErr> {
+        let vm = self.vm;
+        vm.close_file(self.into_raw())
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, SdErr> {
+        self.vm.read(self.raw, buf)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<(), SdErr> {
+        self.vm.write(self.raw, buf)
+    }
+
+    pub fn seek_from_start(&self, offset: u32) -> Result<(), SdErr> {
+        self.vm.file_seek_from_start(self.raw, offset)
+    }
+
+    pub fn flush(&self) -> Result<(), SdErr> {
+        self.vm.flush_file(self.raw)
+    }
+
+    pub fn is_eof(&self) -> Result<bool, SdErr> {
+        self.vm.file_eof(self.raw)
+    }
+
+    pub fn length(&self) -> Result<u32, SdErr> {
+        self.vm.file_length(self.raw)
+    }
+}
+
+impl Drop for FileGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.vm.close_file(self.raw);
+    }
+}
+
+/// A file or directory opened by [`FileManager::open_path`], closed on
+/// drop. It keeps only the handle: the state is locked for each use of it,
+/// see [`Opened::with_vm`], so other requests go on between the chunks of a
+/// download.
+#[derive(Debug)]
+pub struct Opened<'a> {
+    fman: &'a FileManager,
+    entry: FileType,
+    /// [`FileManagerState::generation`] when opened; the handle is gone
+    /// once the card was mounted again.
+    generation: u32,
+}
+
+impl<'a> Opened<'a> {
+    pub fn entry(&self) -> &FileType {
+        &self.entry
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self.entry, FileType::Dir(_))
+    }
+
+    /// Locks the state and runs `f` with the volume manager the handle
+    /// belongs to. Fails with [`FManError::CardNotActive`] once the card the
+    /// handle was opened on is gone.
+    pub async fn with_vm<R>(&self, f: impl FnOnce(&FsVolumeManager) -> R) -> Result<R, FsErr> {
+        let state = self.fman.state.lock().await;
+        self.fman.close_deferred(&state);
+        match state.card_state {
+            CardState::Active { ref vm, .. } if state.generation == self.generation => Ok(f(vm)),
+            _ => Err(FManError::CardNotActive),
+        }
+    }
+}
+
+impl Drop for Opened<'_> {
+    fn drop(&mut self) {
+        match self.fman.state.try_lock() {
+            Some(state) => state.close_opened(self.generation, &self.entry),
+            // whoever holds the lock closes it, see `FileManager::close_deferred`
+            None => self.fman.closing.lock(|closing| closing.push((self.generation, self.entry.clone()))),
+        }
+    }
+}
+
+impl FileManagerState {
+    /// The volume manager of the mounted card.
+    pub fn vm(&self) -> Result<&FsVolumeManager, FsErr> {
+        match self.card_state {
+            CardState::Active { ref vm, .. } => Ok(vm),
+            _ => Err(FManError::CardNotActive),
+        }
+    }
+
+    /// Closes `entry`, opened while [`FileManagerState::generation`] was
+    /// `generation`, unless the card went away since.
+    fn close_opened(&self, generation: u32, entry: &FileType) {
+        if let CardState::Active { ref vm, .. } = self.card_state {
+            if self.generation == generation {
+                let _ = match *entry {
+                    FileType::File(_, f) => vm.close_file(f),
+                    FileType::Dir(dir) => vm.close_dir(dir),
+                };
+            }
+        }
+    }
+
+    /// The root directory of the mounted volume `index`.
+    pub fn open_root(&self, index: usize) -> Result<DirGuard<'_>, FsErr> {
+        let vm = self.vm()?;
+        let vol = self.raw_volume(index).ok_or(FManError::SdErr(Error::NoSuchVolume))?;
+        Ok(DirGuard::new(vm, vm.open_root_dir(vol)?))
+    }
+}
+
+impl FileManager {
+    /// Locks the state and runs `open` on it. While `open` fails for want of
+    /// handles, the lock is given up for [`consts::HANDLE_RETRY_MS`] so that
+    /// their holders can close them, then `open` is retried, for up to
+    /// [`consts::HANDLE_WAIT_MS`] in all. Time spent waiting for the lock
+    /// itself does not count.
+    ///
+    /// `open` may run several times, so it must not leave anything behind
+    /// when it fails. Handles it returns have to be given up with `into_raw`
+    /// and adopted again under the returned lock.
+    pub async fn lock_waiting<T>(
+        &self,
+        open: impl Fn(&FileManagerState) -> Result<T, FsErr>,
+    ) -> Result<(MutexGuard<'_, FileManagerState>, T), FsErr> {
+        let mut waited = 0;
+        loop {
+            let state = self.state.lock().await;
+            self.close_deferred(&state);
+            match open(&state) {
+                Err(e) if is_out_of_handles(&e) && waited < consts::HANDLE_WAIT_MS => (),
+                opened => return opened.map(|value| (state, value)),
+            }
+            drop(state);
+            sleep_ms(consts::HANDLE_RETRY_MS).await;
+            waited += consts::HANDLE_RETRY_MS;
+        }
+    }
+
+    /// Locks the state with the root directory of volume `index` open,
+    /// waiting for a directory handle like [`FileManager::lock_waiting`].
+    /// The root is to be adopted with [`DirGuard::new`] under the lock.
+    pub async fn lock_root(&self, index: usize) -> Result<(MutexGuard<'_, FileManagerState>, RawDirectory), FsErr> {
+        self.lock_waiting(|state| state.open_root(index).map(DirGuard::into_raw)).await
+    }
+
+    /// Closes the handles of [`Opened`]s dropped while the state was
+    /// locked, now that it is.
+    fn close_deferred(&self, state: &FileManagerState) {
+        while let Some((generation, entry)) = self.closing.lock(|closing| closing.pop()) {
+            state.close_opened(generation, &entry);
+        }
+    }
+
+    /// Opens the file or directory at the long `path` like
+    /// [`FileManager::resolve_path_iter`], closing it once the result is
+    /// dropped.
+    pub async fn open_path(&self, path: &str) -> Result<Opened<'_>, FsErr> {
+        let (state, entry) = self.lock_waiting(|state| state.resolve_path(path)).await?;
+        Ok(Opened { fman: self, entry, generation: state.generation })
+    }
+}
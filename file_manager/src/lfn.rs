@@ -0,0 +1,267 @@
+//! Long file names over the 8.3 names of `embedded_sdmmc`.
+//!
+//! A name that is not a valid 8.3 name gets a short alias such as
+//! `MYHOLI~1.JPE` the first time it is created, and the pair is kept in the
+//! DB so that the alias stays stable across reboots:
+//!
+//! - [`consts::LONG_NAMES_TABLE`]: `<parent>/<long name, lower case>` → short name
+//! - [`consts::SHORT_NAMES_TABLE`]: `<parent>/<short name>` → long name
+//!
+//! `<parent>` is the upper-case short path of the directory, empty for the
+//! root. Long names are matched case-insensitively, like FAT does.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Query, QueryExecutor, Row, Value};
+use embedded_sdmmc::RawDirectory;
+use crate::ops::{close_unless, open_below, FsErr};
+use crate::{consts, ExtAlloc, FManError, FsVolumeManager};
+
+/// Characters FAT refuses in short names, on top of controls, space and
+/// anything outside ASCII.
+const INVALID_83: &[u8] = b"\"*+,/:;<=>?[\\]|";
+
+/// Characters FAT refuses in long names, on top of controls.
+const INVALID_LONG: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+pub const MAX_LONG_NAME: usize = 255;
+
+/// Whether `name` can be stored as a FAT short name as it is.
+pub fn is_valid_83(name: &[u8]) -> bool {
+    let (base, ext) = match name.iter().position(|&b| b == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &b""[..]),
+    };
+    let valid_char = |b: &u8| b.is_ascii_graphic() && !INVALID_83.contains(b) && *b != b'.';
+
+    (1..=8).contains(&base.len())
+        && ext.len() <= 3
+        && base.iter().all(valid_char)
+        && ext.iter().all(valid_char)
+}
+
+/// Whether `name` is acceptable as a long name. Like on Windows, names may
+/// not end in a dot or a space, which also rules out `.` and `..`.
+pub fn is_valid_long(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_LONG_NAME
+        && !name.chars().any(|c| c.is_control() || INVALID_LONG.contains(&c))
+        && !name.ends_with(['.', ' '])
+}
+
+/// The characters of `s` that may appear in a short name, upper-cased.
+fn short_chars(s: &str) -> impl Iterator<Item = char> + '_ {
+    s.chars()
+        .filter(|c| c.is_ascii_graphic() && !INVALID_83.contains(&(*c as u8)) && *c != '.')
+        .map(|c| c.to_ascii_uppercase())
+}
+
+/// The `n`th short alias candidate for `long`, e.g. `MYHOLI~1.JPE` for
+/// `My holiday.jpeg`.
+fn alias(long: &str, n: u32) -> String {
+    let (stem, ext) = match long.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+        _ => (long, ""),
+    };
+    let tail = format!("~{}", n);
+
+    let mut short: String = short_chars(stem).take(8 - tail.len()).collect();
+    if short.is_empty() {
+        short.push('_');
+    }
+    short.push_str(&tail);
+
+    let ext: String = short_chars(ext).take(3).collect();
+    if !ext.is_empty() {
+        short.push('.');
+        short.push_str(&ext);
+    }
+    short
+}
+
+/// Row key of `name` in the directory at the short path `parent`, which
+/// may come with or without its leading and trailing `/`.
+fn key(parent: &str, name: &str) -> String {
+    match parent.trim_matches('/') {
+        "" => format!("/{}", name),
+        parent => format!("/{}/{}", parent, name),
+    }
+}
+
+/// The highest `n` of a short alias: `~999999` leaves room for one
+/// character of the name.
+const MAX_ALIAS: u32 = 999_999;
+
+/// The DB kept in [`consts::DB_DIR`], as opened by [`open_db`].
+type NamesDb<'a> = Database<VM<'a>, DbDirSdmmc, ExtAlloc>;
+
+/// Opens the DB holding both name tables. `root_dir` stays open.
+fn open_db(vm: &FsVolumeManager, root_dir: RawDirectory) -> Result<NamesDb<'_>, FsErr> {
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    Ok(Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?)
+}
+
+/// The name stored under `row_key` in `table`, if there is one.
+fn lookup(db: &mut NamesDb<'_>, table: &str, row_key: &str) -> Option<String> {
+    let table = db.get_table(table, ExtAlloc::default()).ok()?;
+    let query = Query::<_, &str>::new(table, ExtAlloc::default())
+                                 .key(Value::Chars(row_key.as_bytes()));
+    let mut exec = QueryExecutor::new(
+        query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+        &db.file_handler.page_rw.as_ref().unwrap()
+    ).ok()?;
+    let row = exec.next().ok()?;
+    core::str::from_utf8(row[1].to_chars().unwrap()).ok().map(String::from)
+}
+
+/// Inserts the alias `short` of `long` in the directory at the short path
+/// `parent` into both tables.
+fn insert_alias(db: &mut NamesDb<'_>, parent: &str, short: &str, long: &str) -> Result<(), FsErr> {
+    let long_table = db.get_table(consts::LONG_NAMES_TABLE, ExtAlloc::default())?;
+    let mut row = Row::new_in(ExtAlloc::default());
+    row.push(Value::Chars(key(parent, &long.to_lowercase()).as_bytes()));
+    row.push(Value::Chars(short.as_bytes()));
+    db.insert_to_table(long_table, row, ExtAlloc::default())?;
+
+    let short_table = db.get_table(consts::SHORT_NAMES_TABLE, ExtAlloc::default())?;
+    let mut row = Row::new_in(ExtAlloc::default());
+    row.push(Value::Chars(key(parent, short).as_bytes()));
+    row.push(Value::Chars(long.as_bytes()));
+    db.insert_to_table(short_table, row, ExtAlloc::default())?;
+    Ok(())
+}
+
+/// Maps the long `path` (absolute, `/` separated, see [`is_valid_long`]) to
+/// the short path stored on the card, e.g. `/FILES/MYHOLI~1.JPE`. Names
+/// without an alias resolve to `NotFound`, unless `create` is set in which
+/// case they are given one. `root_dir` stays open.
+pub fn short_path(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    path: &str,
+    create: bool,
+) -> Result<String, FsErr> {
+    let segments = path.split('/').filter(|s| !s.is_empty());
+    let mut short = String::with_capacity(path.len());
+
+    // most paths never leave 8.3 and don't need the DB at all
+    if segments.clone().all(|s| is_valid_83(s.as_bytes())) {
+        for segment in segments {
+            short.push('/');
+            short.push_str(&segment.to_ascii_uppercase());
+        }
+        if short.is_empty() {
+            short.push('/');
+        }
+        return Ok(short);
+    }
+
+    let mut db = open_db(vm, root_dir)?;
+
+    for segment in segments {
+        if is_valid_83(segment.as_bytes()) {
+            short.push('/');
+            short.push_str(&segment.to_ascii_uppercase());
+            continue;
+        }
+        if !is_valid_long(segment) {
+            return Err(FManError::SdErr(embedded_sdmmc::Error::NotFound));
+        }
+
+        let long_key = key(&short, &segment.to_lowercase());
+        let name = match lookup(&mut db, consts::LONG_NAMES_TABLE, &long_key) {
+            Some(name) => name,
+            None if create => {
+                // a directory that does not exist yet has nothing in it
+                let parent = open_below(vm, root_dir, &short).ok();
+                let found = (1..=MAX_ALIAS).map(|n| alias(segment, n)).find(|candidate| {
+                    lookup(&mut db, consts::SHORT_NAMES_TABLE, &key(&short, candidate)).is_none()
+                        && parent.is_none_or(|dir| vm.find_directory_entry(dir, candidate).is_err())
+                });
+                if let Some(dir) = parent {
+                    close_unless(vm, dir, root_dir);
+                }
+                let name = found.ok_or("no free short name left in directory")?;
+                insert_alias(&mut db, &short, &name, segment)?;
+                name
+            },
+            None => return Err(FManError::SdErr(embedded_sdmmc::Error::NotFound)),
+        };
+
+        short.push('/');
+        short.push_str(&name);
+    }
+
+    Ok(short)
+}
+
+/// The aliased entries of the directory at the short path `dir`, as
+/// `(short name, long name)` pairs. `root_dir` stays open.
+pub fn long_names(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    dir: &str,
+) -> Result<Vec<(String, String)>, FsErr> {
+    let mut db = open_db(vm, root_dir)?;
+    let table = db.get_table(consts::SHORT_NAMES_TABLE, ExtAlloc::default())?;
+
+    let prefix = key(dir, "");
+    let mut names = Vec::new();
+
+    let query = Query::<_, &str>::new(table, ExtAlloc::default());
+    // an empty table has no pages to run a query over
+    if let Ok(mut exec) = QueryExecutor::new(
+        query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+        &db.file_handler.page_rw.as_ref().unwrap()
+    ) {
+        while let Ok(row) = exec.next() {
+            let (Ok(key), Ok(long)) = (
+                core::str::from_utf8(row[0].to_chars().unwrap()),
+                core::str::from_utf8(row[1].to_chars().unwrap()),
+            ) else {
+                continue;
+            };
+            if let Some(short) = key.strip_prefix(prefix.as_str()).filter(|s| !s.contains('/')) {
+                names.push((String::from(short), String::from(long)));
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+/// Drops the alias of the entry `name` (short) in the directory at the
+/// short path `dir`, if it has one. `root_dir` stays open.
+pub fn forget(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    dir: &str,
+    name: &str,
+) -> Result<(), FsErr> {
+    let mut db = open_db(vm, root_dir)?;
+    let short_key = key(dir, &name.to_ascii_uppercase());
+    if let Some(long) = lookup(&mut db, consts::SHORT_NAMES_TABLE, &short_key) {
+        let short_table = db.get_table(consts::SHORT_NAMES_TABLE, ExtAlloc::default())?;
+        db.delete_from_table(short_table, Value::Chars(short_key.as_bytes()), ExtAlloc::default())?;
+        let long_table = db.get_table(consts::LONG_NAMES_TABLE, ExtAlloc::default())?;
+        db.delete_from_table(long_table, Value::Chars(key(dir, &long.to_lowercase()).as_bytes()), ExtAlloc::default())?;
+    }
+    Ok(())
+}
+
+/// Records `long` as the long name of the entry `name` (short) in the
+/// directory at the short path `dir`, e.g. for a copy of an aliased entry.
+/// `root_dir` stays open.
+pub fn remember(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    dir: &str,
+    name: &str,
+    long: &str,
+) -> Result<(), FsErr> {
+    let mut db = open_db(vm, root_dir)?;
+    insert_alias(&mut db, dir, &name.to_ascii_uppercase(), long)
+}
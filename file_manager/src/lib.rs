@@ -1,12 +1,39 @@
 #![no_std]
 
+extern crate alloc;
+
+pub mod checksum;
+pub mod clock;
 pub mod consts;
+pub mod fsck;
+pub mod handles;
+pub mod lfn;
+pub mod monitor;
+mod ops;
 pub mod runtime;
+pub mod sntp;
+pub mod trash;
+mod tree;
+pub mod usage;
+pub mod versions;
+pub mod volumes;
+
+pub use checksum::{Checksummer, Checksums};
+pub use clock::Clock;
+pub use fsck::{Finding, FsckReport, Issue};
+pub use handles::{DirGuard, FileGuard, Opened};
+pub use monitor::CardEvent;
+pub use ops::{check_reserved, table_for_dir};
+pub use trash::TrashEntry;
+pub use tree::TreeStats;
+pub use usage::{Capacity, DiskUsage};
+pub use versions::Version;
+pub use volumes::VolumeInfo;
 
 use alpa::embedded_sdmmc_fs::{DbDirSdmmc};
 use alpa::db::Database;
 use alpa::{Column, ColumnType, Value, Row};
-pub use runtime::{Mutex};
+pub use runtime::{Mutex, Signal};
 use embedded_sdmmc::{
     BlockDevice,
     TimeSource,
@@ -33,23 +60,17 @@ mod embassy_impl;
 #[cfg(feature = "embassy")]
 pub use embassy_impl::*;
 
-#[derive(Default, Debug)]
-pub struct DummyTimesource;
-
-impl embedded_sdmmc::TimeSource for DummyTimesource {
-    fn get_timestamp(&self) -> embedded_sdmmc::Timestamp {
-        embedded_sdmmc::Timestamp {
-            year_since_1970: 0,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
-        }
-    }
-}
+pub type TimeSrc = Clock;
 
-pub type TimeSrc = DummyTimesource;
+/// The volume manager of a mounted card, with the handle limits of
+/// [`consts`].
+pub type FsVolumeManager = VolumeManager<
+    BlkDev,
+    Clock,
+    { consts::MAX_OPEN_DIRS },
+    { consts::MAX_OPEN_FILES },
+    { consts::MAX_VOLUMES },
+>;
 
 #[derive(Debug, Clone)]
 pub enum FileType {
@@ -59,28 +80,42 @@ pub enum FileType {
 
 #[derive(Debug)]
 pub enum CardState {
-    NoCard { device: BlkDev, timer: DummyTimesource },
-    Active { vm: VolumeManager<BlkDev, DummyTimesource, 4, 4, 1>, vol: RawVolume },
+    NoCard { device: BlkDev, timer: Clock },
+    Active { vm: FsVolumeManager, vol: RawVolume },
     Processing
 }
 
+/// Spacing of the handle ids of successive mounts, so that a handle kept
+/// from before a remount cannot be mistaken for a new one.
+const HANDLE_ID_STRIDE: u32 = 0x1_0000;
+
 #[derive(Debug)]
 pub struct FileManagerState {
-    pub card_state: CardState
+    pub card_state: CardState,
+    /// Number of mounts so far. Handle ids of each mount are offset by it.
+    pub generation: u32,
+    /// Partitions of the mounted card, see [`volumes`].
+    pub volumes: alloc::vec::Vec<volumes::VolumeInfo>,
 }
 
 impl FileManagerState {
-    pub fn new(block_device: BlkDev, time_src: DummyTimesource) -> Self {
+    pub fn new(block_device: BlkDev, time_src: Clock) -> Self {
         Self {
-            card_state: CardState::NoCard{ device: block_device, timer: time_src }
+            card_state: CardState::NoCard{ device: block_device, timer: time_src },
+            generation: 0,
+            volumes: alloc::vec::Vec::new(),
         }
     }
 
     pub fn try_mount(&mut self) {
         if let CardState::NoCard { device, timer } = core::mem::replace(&mut self.card_state, CardState::Processing) {
-            let vm = VolumeManager::new(device, timer);
+            let vm = VolumeManager::new_with_limits(device, timer, self.generation.wrapping_mul(HANDLE_ID_STRIDE));
+            self.generation = self.generation.wrapping_add(1);
             self.card_state = match vm.open_raw_volume(VolumeIdx(0)) {
-                Ok(vol) => CardState::Active{ vm, vol },
+                Ok(vol) => {
+                    self.volumes = volumes::open_all(&vm, vol);
+                    CardState::Active{ vm, vol }
+                },
                 Err(_) => {
                     let (device, timer) = vm.free();
                     CardState::NoCard { device, timer }
@@ -91,15 +126,64 @@ impl FileManagerState {
 
     pub fn handle_ejection(&mut self) {
         if let CardState::Active{ vm, vol: _ } = core::mem::replace(&mut self.card_state, CardState::Processing) {
+             self.volumes.clear();
              let (device, timer) = vm.free();
              self.card_state = CardState::NoCard { device, timer };
         }
     }
+
+    /// `(short name, long name)` pairs of the aliased entries of the
+    /// directory at the long `path`. Only volume 0 has long names.
+    pub fn long_names(&self, path: &str)
+        -> Result<alloc::vec::Vec<(alloc::string::String, alloc::string::String)>, FManError<<FsBlockDevice as BlockDevice>::Error>>
+    {
+        if self.volume_path(path).0 != 0 {
+            return Ok(alloc::vec::Vec::new());
+        }
+        let root = self.open_root(0)?;
+        let short = lfn::short_path(root.vm(), root.raw(), path, false)?;
+        lfn::long_names(root.vm(), root.raw(), &short)
+    }
+
+    /// Opens the file or directory at the long `path`, which may start on
+    /// another volume, see [`volumes`]. The caller has to close it; see
+    /// [`FileManager::open_path`] for a handle that does so itself.
+    pub fn resolve_path(&self, path: &str) -> Result<FileType, FManError<<FsBlockDevice as BlockDevice>::Error>> {
+        let (index, path) = self.volume_path(path);
+        let root = self.open_root(index)?;
+        let short = lfn::short_path(root.vm(), root.raw(), path, false)?;
+
+        let path = short.trim_matches('/');
+        if path.is_empty() {
+            return Ok(FileType::Dir(root.into_raw()));
+        }
+
+        let (parents, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut dir = root;
+        for parent in parents.split('/') {
+            dir = dir.open_dir(parent).map_err(|e| match e {
+                Error::TooManyOpenDirs => FManError::SdErr(e),
+                _ => FManError::SdErr(Error::NotFound),
+            })?;
+        }
+
+        let entry = dir.find(name)?;
+        if entry.attributes.is_directory() {
+            Ok(FileType::Dir(dir.open_dir(name)?.into_raw()))
+        } else {
+            Ok(FileType::File(entry, dir.open_file(name, Mode::ReadOnly)?.into_raw()))
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct FileManager {
     pub state: Mutex<FileManagerState>,
+    /// Card changes seen by [`FileManager::monitor_card`].
+    pub card_events: Signal<CardEvent>,
+    /// Handles of [`Opened`]s dropped while the state was locked, with the
+    /// generation they belong to.
+    closing: runtime::BlockingMutex<alloc::vec::Vec<(u32, FileType)>>,
 }
 
 #[derive(Debug)]
@@ -108,7 +192,10 @@ pub enum FManError<E: core::fmt::Debug> {
     DbErr(alpa::db::Error<embedded_sdmmc::Error<E>>),
     ServerErr(&'static str),
     CardNotActive,
-    IsDir
+    IsDir,
+    DirNotEmpty,
+    /// The operation has no support in the FAT driver.
+    Unsupported(&'static str),
 }
 
 impl<E: core::fmt::Debug> From<alpa::db::Error<embedded_sdmmc::Error<E>>> for FManError<E> {
@@ -132,15 +219,19 @@ impl<E: core::fmt::Debug> From<&'static str> for FManError<E> {
 pub trait AsyncRootFn<R> {
     type Fut<'a>: core::future::Future<Output = Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>> + 'a 
     where Self: 'a;
-    fn call<'a>(self, dir: RawDirectory, vm: &'a VolumeManager<BlkDev, DummyTimesource, 4, 4, 1>) -> Self::Fut<'a>;
+    /// Runs with the root directory of the volume, which is closed once
+    /// `root` is dropped.
+    fn call<'a>(self, root: DirGuard<'a>) -> Self::Fut<'a>;
 }
 
 impl FileManager {
-    pub fn new(block_device: BlkDev, time_src: DummyTimesource) -> Self {
+    pub fn new(block_device: BlkDev, time_src: Clock) -> Self {
         let mut state = FileManagerState::new(block_device, time_src);
         state.try_mount();
         Self {
-            state: Mutex::new(state)
+            state: Mutex::new(state),
+            card_events: Signal::new(),
+            closing: runtime::BlockingMutex::new(alloc::vec::Vec::new()),
         }
     }
 
@@ -201,7 +292,7 @@ impl FileManager {
         Err(FManError::CardNotActive)
     }
 
-    pub fn root_dir(vm: &VolumeManager<BlkDev, DummyTimesource, 4, 4, 1>, vol: &RawVolume)
+    pub fn root_dir(vm: &FsVolumeManager, vol: &RawVolume)
         -> Result<RawDirectory, FManError<<FsBlockDevice as BlockDevice>::Error>>
     {
         Ok(vm.open_root_dir(*vol)?)
@@ -217,7 +308,7 @@ impl FileManager {
 
     pub async fn with_vol_man<F, R>(&self, f: F) -> Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>
     where
-        F: FnOnce(&VolumeManager<BlkDev, DummyTimesource, 4, 4, 1>, &RawVolume) -> Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>,
+        F: FnOnce(&FsVolumeManager, &RawVolume) -> Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>,
     {
         let state = self.state.lock().await;
         if let CardState::Active{ ref vm, ref vol } = state.card_state {
@@ -226,103 +317,60 @@ impl FileManager {
         Err(FManError::CardNotActive)
     }
 
+    /// Runs `f` with the root directory of volume 0 under the state lock,
+    /// closing it afterwards.
     pub async fn with_root_dir<F, R>(&self, f: F) -> Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>
     where
-        F: FnOnce(RawDirectory) -> Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>,
+        F: FnOnce(&FsVolumeManager, RawDirectory) -> Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>,
     {
-        let state = self.state.lock().await;
-        if let CardState::Active{ ref vm, ref vol } = state.card_state {
-            return f(Self::root_dir(vm, vol)?);
-        }
-        Err(FManError::CardNotActive)
+        let (state, root) = self.lock_root(0).await?;
+        let root = DirGuard::new(state.vm()?, root);
+        f(root.vm(), root.raw())
     }
 
     pub async fn with_root_dir_async<F, R>(&self, f: F) -> Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>
     where
         F: AsyncRootFn<R>,
     {
-        let state = self.state.lock().await;
-        if let CardState::Active { ref vm, ref vol } = state.card_state {
-            let root = Self::root_dir(vm, vol)?;
-            return f.call(root, vm).await;
-        }
-        Err(FManError::CardNotActive)
+        self.with_volume_root_dir_async(0, f).await
     }
 
-    pub async fn resolve_path_iter<'a>(&self, path: &'a str) -> Result<FileType, FManError<<FsBlockDevice as BlockDevice>::Error>> {
-        let state = self.state.lock().await;
-
-        if let CardState::Active{ ref vm, ref vol } = state.card_state {
-            let mut cur_dir = vm.open_root_dir(*vol)?;
-
-            let path = path.trim_matches('/');
-            let mut names = path.split("/").peekable();
-
-            if path == "" {
-                let _ = names.next();
-            }
-
-            if let None = names.peek() {
-                return Ok(FileType::Dir(cur_dir));
-            }
-
-            let mut prev_name: Option<&str> = None;
-            
-            while let Some(name) = names.next() {
-                prev_name = Some(name);
-                if let None = names.peek() {
-                    break;
-                }
+    /// Like [`FileManager::with_root_dir_async`], on the root directory of
+    /// the volume `index`, see [`volumes`].
+    pub async fn with_volume_root_dir_async<F, R>(&self, index: usize, f: F) -> Result<R, FManError<<FsBlockDevice as BlockDevice>::Error>>
+    where
+        F: AsyncRootFn<R>,
+    {
+        let (state, root) = self.lock_root(index).await?;
+        f.call(DirGuard::new(state.vm()?, root)).await
+    }
 
-                match vm.find_directory_entry(cur_dir, name) {
-                    Ok(entry) => {
-                        if entry.attributes.is_directory() {
-                            let prev_dir = cur_dir;
-                            match vm.open_dir(cur_dir, name) {
-                                Ok(dir) => cur_dir = dir,
-                                Err(_) => {
-                                    break;
-                                }
-                            }
-                            let _ = vm.close_dir(prev_dir);
-                        } else {
-                            break;
-                        }
-                    },
-                    Err(_) => {
-                        break;
-                    }
-                }
-            }
+    /// The volume the long `path` is on and the path within it, see
+    /// [`FileManagerState::volume_path`].
+    pub async fn volume_path(&self, path: &str) -> (usize, alloc::string::String) {
+        let state = self.state.lock().await;
+        let (index, path) = state.volume_path(path);
+        (index, alloc::string::String::from(path))
+    }
 
-            let last_name = prev_name.unwrap();
-
-            let mut ret: Result<FileType, FManError<<FsBlockDevice as BlockDevice>::Error>> = Err(FManError::SdErr(Error::NotFound));
-
-            if let None = names.peek() {
-                ret = match vm.find_directory_entry(cur_dir, last_name) {
-                    Ok(entry) => {
-                        if entry.attributes.is_directory() {
-                            match vm.open_dir(cur_dir, last_name) {
-                                Ok(dir) => Ok(FileType::Dir(dir)),
-                                Err(e) => Err(FManError::SdErr(e))
-                            }
-                        } else {
-                            match vm.open_file_in_dir(cur_dir, last_name, Mode::ReadOnly) {
-                                Ok(f) => Ok(FileType::File(entry, f)),
-                                Err(e) => Err(FManError::SdErr(e))
-                            }
-                        }
-                    },
-                    Err(e) => Err(FManError::SdErr(e))
-                };
-            }
+    /// Short path on the card for the long `path`, see [`lfn::short_path`].
+    pub async fn short_path(&self, path: &str, create: bool) -> Result<alloc::string::String, FManError<<FsBlockDevice as BlockDevice>::Error>> {
+        let (state, root) = self.lock_root(0).await?;
+        let root = DirGuard::new(state.vm()?, root);
+        lfn::short_path(root.vm(), root.raw(), path, create)
+    }
 
-            let _ = vm.close_dir(cur_dir);
-            return ret;
-        }
+    /// See [`FileManagerState::long_names`].
+    pub async fn long_names(&self, path: &str)
+        -> Result<alloc::vec::Vec<(alloc::string::String, alloc::string::String)>, FManError<<FsBlockDevice as BlockDevice>::Error>>
+    {
+        self.state.lock().await.long_names(path)
+    }
 
-        Err(FManError::CardNotActive)
+    /// Opens the file or directory at the long `path`, see
+    /// [`FileManagerState::resolve_path`]. The caller has to close it with
+    /// [`FileManager::close_file_type`].
+    pub async fn resolve_path_iter<'a>(&self, path: &'a str) -> Result<FileType, FManError<<FsBlockDevice as BlockDevice>::Error>> {
+        self.state.lock().await.resolve_path(path)
     }
 }
-
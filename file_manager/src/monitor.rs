@@ -0,0 +1,86 @@
+//! Card hot-plug detection.
+//!
+//! [`FileManager::monitor_card`] probes the card every
+//! [`PROBE_PERIOD_MS`] and mounts or drops it to match. Every mount starts
+//! a new generation of handle ids (see [`FileManagerState::generation`]), so
+//! a `RawFile` or `RawDirectory` kept from an earlier card fails with
+//! `BadHandle` instead of reaching whatever now has its id.
+
+use crate::runtime::sleep_ms;
+use crate::{prepare_card, probe_card, CardState, ExtAlloc, FileManager, FileManagerState};
+
+pub const PROBE_PERIOD_MS: u64 = 1000;
+
+/// A change of the card, as published on [`FileManager::card_events`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardEvent {
+    /// A card was mounted and prepared, as mount number `generation`.
+    Mounted { generation: u32 },
+    /// The card went away.
+    Removed,
+}
+
+impl FileManagerState {
+    /// Probes the card and mounts or drops it to match, returning what
+    /// changed.
+    pub fn poll_card(&mut self) -> Option<CardEvent> {
+        match self.card_state {
+            CardState::Active { ref vm, .. } => {
+                if vm.device(|device| probe_card(device)) {
+                    return None;
+                }
+                self.handle_ejection();
+                Some(CardEvent::Removed)
+            },
+            CardState::NoCard { ref device, .. } => {
+                if !probe_card(device) {
+                    return None;
+                }
+                self.try_mount();
+                match self.card_state {
+                    CardState::Active { .. } => Some(CardEvent::Mounted { generation: self.generation }),
+                    _ => None,
+                }
+            },
+            CardState::Processing => None,
+        }
+    }
+}
+
+impl FileManager {
+    /// Watches the card and never returns. Every newly mounted card is
+    /// prepared like `init_file_system` does and each change published on
+    /// [`FileManager::card_events`]. A card that cannot be prepared is
+    /// dropped again and retried on the next probe.
+    pub async fn monitor_card(&self, allocator: ExtAlloc) {
+        loop {
+            sleep_ms(PROBE_PERIOD_MS).await;
+
+            let mut state = self.state.lock().await;
+            let event = match state.poll_card() {
+                Some(CardEvent::Mounted { generation }) => {
+                    let prepared = match state.card_state {
+                        CardState::Active { ref vm, ref vol } => prepare_card(vm, vol, allocator.clone()).is_ok(),
+                        _ => false,
+                    };
+                    if !prepared {
+                        state.handle_ejection();
+                        continue;
+                    }
+                    CardEvent::Mounted { generation }
+                },
+                Some(event) => event,
+                None => continue,
+            };
+            drop(state);
+
+            self.card_events.signal(event).await;
+        }
+    }
+
+    /// Whether a card is mounted, and the number of its mount.
+    pub async fn card_status(&self) -> (bool, u32) {
+        let state = self.state.lock().await;
+        (matches!(state.card_state, CardState::Active { .. }), state.generation)
+    }
+}
@@ -0,0 +1,418 @@
+//! Write-side operations of [`FileManager`].
+//!
+//! Every method takes long paths (see [`lfn`]), holds the state lock for
+//! its whole duration and closes every handle it opened, whatever the
+//! outcome. Files written to in `FILES` or `MUSIC` get their table row
+//! updated along.
+
+use allocator_api2::vec::Vec;
+use alloc::string::String;
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Row, Value};
+use embedded_sdmmc::{BlockDevice, Error, Mode, RawDirectory, RawFile};
+use crate::checksum::{checksum_file, recorded, Checksums, Recorded};
+use crate::tree::{display_name, remove_empty_dirs};
+use crate::{consts, lfn, versions, DirGuard, ExtAlloc, FManError, FileManager, FsBlockDevice, FsVolumeManager};
+
+pub(crate) type FsErr = FManError<<FsBlockDevice as BlockDevice>::Error>;
+
+const COPY_CHUNK: usize = 1024;
+
+/// Splits a short path into its parent directories and final name.
+pub(crate) fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_matches('/');
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+pub(crate) fn close_unless(vm: &FsVolumeManager, dir: RawDirectory, base: RawDirectory) {
+    if dir != base {
+        let _ = vm.close_dir(dir);
+    }
+}
+
+/// Opens the directory at the short path `parents` below `base`. `base`
+/// stays open and is itself returned for an empty `parents`.
+pub(crate) fn open_below(
+    vm: &FsVolumeManager,
+    base: RawDirectory,
+    parents: &str,
+) -> Result<RawDirectory, FsErr> {
+    let mut dir = base;
+    for name in parents.split('/').filter(|s| !s.is_empty()) {
+        let next = vm.open_dir(dir, name);
+        close_unless(vm, dir, base);
+        dir = next?;
+    }
+    Ok(dir)
+}
+
+/// Opens `name` in `dir` with `mode` for the duration of `f`.
+pub(crate) fn with_file<R>(
+    vm: &FsVolumeManager,
+    dir: RawDirectory,
+    name: &str,
+    mode: Mode,
+    f: impl FnOnce(RawFile) -> Result<R, FsErr>,
+) -> Result<R, FsErr> {
+    let file = vm.open_file_in_dir(dir, name, mode)?;
+    let result = f(file);
+    let closed = vm.close_file(file);
+    let result = result?;
+    closed?;
+    Ok(result)
+}
+
+/// Copies what is left of `src` to `dst`.
+pub(crate) fn copy_contents(vm: &FsVolumeManager, src: RawFile, dst: RawFile) -> Result<(), FsErr> {
+    let mut buffer: Vec<u8, ExtAlloc> = Vec::with_capacity_in(COPY_CHUNK, ExtAlloc::default());
+    buffer.resize(buffer.capacity(), 0);
+
+    while !vm.file_eof(src)? {
+        let n = vm.read(src, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        vm.write(dst, &buffer[..n])?;
+    }
+    Ok(())
+}
+
+/// Copies the file `src_name` of `src_dir` to `dst_name` of `dst_dir`,
+/// opening the destination with `mode`. A partial copy is removed again.
+pub(crate) fn copy_file(
+    vm: &FsVolumeManager,
+    src_dir: RawDirectory,
+    src_name: &str,
+    dst_dir: RawDirectory,
+    dst_name: &str,
+    mode: Mode,
+) -> Result<(), FsErr> {
+    let dst = vm.open_file_in_dir(dst_dir, dst_name, mode)?;
+    let copied = with_file(vm, src_dir, src_name, Mode::ReadOnly, |src| copy_contents(vm, src, dst));
+    let closed = vm.close_file(dst).map_err(FManError::SdErr);
+
+    let result = copied.and(closed);
+    if result.is_err() {
+        let _ = vm.delete_file_in_dir(dst_dir, dst_name);
+    }
+    result
+}
+
+/// Moves the entry at the short path `from` to the short path `to`, whose
+/// parent must exist. Only directory entries are rewritten, see
+/// [`embedded_sdmmc::VolumeManager::rename_in_dir`].
+pub(crate) fn move_entry(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    from: &str,
+    to: &str,
+) -> Result<(), FsErr> {
+    let (src_parent, src_name) = split_path(from);
+    let (dst_parent, dst_name) = split_path(to);
+
+    let src_dir = open_below(vm, root_dir, src_parent)?;
+    let moved = open_below(vm, root_dir, dst_parent).and_then(|dst_dir| {
+        let moved = vm.rename_in_dir(src_dir, src_name, dst_dir, dst_name);
+        close_unless(vm, dst_dir, root_dir);
+        Ok(moved?)
+    });
+    close_unless(vm, src_dir, root_dir);
+    moved
+}
+
+/// Moves the file at the short path `from` to the short path `to`, see
+/// [`FileManager::rename`]. `to_name` is the long name it arrives under.
+pub(crate) fn rename_file(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    from: &str,
+    to: &str,
+    to_name: &str,
+) -> Result<(), FsErr> {
+    let (src_parent, src_name) = split_path(from);
+    let (dst_parent, dst_name) = split_path(to);
+    if src_name.is_empty() || dst_name.is_empty() {
+        return Err("the root directory cannot be moved".into());
+    }
+    if from == to {
+        return Ok(());
+    }
+
+    let src_dir = open_below(vm, root_dir, src_parent)?;
+    let entry = vm.find_directory_entry(src_dir, src_name);
+    close_unless(vm, src_dir, root_dir);
+    let entry = entry?;
+    if entry.attributes.is_directory() {
+        return Err(FManError::Unsupported("directories cannot be moved"));
+    }
+    move_entry(vm, root_dir, from, to)?;
+
+    let recorded = retire(vm, root_dir, src_parent, src_name)?;
+    lfn::forget(vm, root_dir, src_parent, src_name)?;
+    let (upload_name, checksums) = match recorded {
+        Some(recorded) => (recorded.upload_name, Some(recorded.checksums)),
+        None => (String::from(to_name), None),
+    };
+    register(vm, root_dir, dst_parent, dst_name, &upload_name, entry.size, checksums)
+}
+
+/// Refuses changes at or below the directories the server keeps for itself:
+/// [`consts::DB_DIR`], [`consts::TRASH_DIR`] and [`consts::VERSIONS_DIR`].
+/// `path` may be long or short, as their 8.3 names are never an alias.
+pub fn check_reserved(path: &str) -> Result<(), &'static str> {
+    let top = path.trim_matches('/').split('/').next().unwrap_or("");
+    if top.eq_ignore_ascii_case(consts::DB_DIR) {
+        Err("the DB directory cannot be changed")
+    } else if top.eq_ignore_ascii_case(consts::TRASH_DIR) {
+        Err("the trash is changed through /trash only")
+    } else if top.eq_ignore_ascii_case(consts::VERSIONS_DIR) {
+        Err("versions are changed through /files/versions only")
+    } else {
+        Ok(())
+    }
+}
+
+/// Table registering uploads stored in the top level directory `dir`, if any.
+pub fn table_for_dir(dir: &str) -> Option<&'static str> {
+    let dir = dir.trim_matches('/');
+    if dir.eq_ignore_ascii_case(consts::FILES_DIR) {
+        Some(consts::FILES_TABLE)
+    } else if dir.eq_ignore_ascii_case(consts::MUSIC_DIR) {
+        Some(consts::MUSIC_TABLE)
+    } else {
+        None
+    }
+}
+
+/// Drops the category table row of the file `name` leaving the directory
+/// at the short path `dir`, returning what it recorded.
+pub(crate) fn unregister(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    dir: &str,
+    name: &str,
+) -> Result<Option<Recorded>, FsErr> {
+    let Some(table) = table_for_dir(dir) else {
+        return Ok(None);
+    };
+    let recorded = recorded(vm, root_dir, table, name)?;
+    // files put there other than by upload were never registered
+    if recorded.is_some() {
+        let key = name.to_ascii_uppercase();
+        let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+        let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+        let table = db.get_table(table, ExtAlloc::default())?;
+        db.delete_from_table(table, Value::Chars(key.as_bytes()), ExtAlloc::default())?;
+    }
+    Ok(recorded)
+}
+
+/// Like [`unregister`], for a file whose name is gone for good: the old
+/// versions of a file of `FILES` are deleted along, see
+/// [`versions::drop_versions`].
+pub(crate) fn retire(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    dir: &str,
+    name: &str,
+) -> Result<Option<Recorded>, FsErr> {
+    let recorded = unregister(vm, root_dir, dir, name)?;
+    if table_for_dir(dir) == Some(consts::FILES_TABLE) {
+        versions::drop_versions(vm, root_dir, name)?;
+    }
+    Ok(recorded)
+}
+
+/// Registers the file `name` arriving in the directory at the short path
+/// `dir` in its category table, as uploaded under `upload_name`. Its
+/// checksums are read from the file unless they are known already.
+pub(crate) fn register(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    dir: &str,
+    name: &str,
+    upload_name: &str,
+    size: u32,
+    checksums: Option<Checksums>,
+) -> Result<(), FsErr> {
+    let Some(table) = table_for_dir(dir) else {
+        return Ok(());
+    };
+    let checksums = match checksums {
+        Some(checksums) => checksums,
+        None => {
+            let dir = DirGuard::new(vm, open_below(vm, root_dir, dir)?);
+            checksum_file(&dir, name)?.1
+        },
+    };
+    let sha256 = checksums.sha256_hex();
+
+    let key = name.to_ascii_uppercase();
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(table, ExtAlloc::default())?;
+
+    let row = || {
+        let mut row = Row::new_in(ExtAlloc::default());
+        row.push(Value::Chars(key.as_bytes()));
+        row.push(Value::Chars(upload_name.as_bytes()));
+        row.push(Value::Int(size as i64));
+        row.push(Value::Int(checksums.crc32 as i64));
+        row.push(Value::Chars(sha256.as_bytes()));
+        row
+    };
+    match db.insert_to_table(table, row(), ExtAlloc::default()) {
+        Err(alpa::db::Error::DuplicateKey) => {
+            db.update_row(table, Value::Chars(key.as_bytes()), row(), ExtAlloc::default())?;
+        },
+        other => other?,
+    }
+    Ok(())
+}
+
+/// What [`FileManager::with_target`] hands to its closure.
+struct Target<'a> {
+    root_dir: RawDirectory,
+    /// Short path of the parent directory.
+    parent: &'a str,
+    /// The parent directory itself.
+    dir: RawDirectory,
+    /// Short name of the entry.
+    name: &'a str,
+}
+
+/// Registers the target file again after its contents changed, keeping
+/// the name it was uploaded under, see [`register`].
+fn reregister(vm: &FsVolumeManager, t: &Target) -> Result<(), FsErr> {
+    let Some(table) = table_for_dir(t.parent) else {
+        return Ok(());
+    };
+    let upload_name = match recorded(vm, t.root_dir, table, t.name)? {
+        Some(recorded) => recorded.upload_name,
+        None => display_name(vm, t.root_dir, t.parent, t.name)?,
+    };
+    let size = vm.find_directory_entry(t.dir, t.name)?.size;
+    register(vm, t.root_dir, t.parent, t.name, &upload_name, size, None)
+}
+
+impl FileManager {
+    /// Runs `f` on the entry at the long `path`, giving its last segment a
+    /// short alias first when `create` is set.
+    async fn with_target<F, R>(&self, path: &str, create: bool, f: F) -> Result<R, FsErr>
+    where
+        F: FnOnce(&FsVolumeManager, &Target) -> Result<R, FsErr>,
+    {
+        self.with_root_dir(|vm, root_dir| {
+            // the parent has to exist, so only the last segment may be new
+            lfn::short_path(vm, root_dir, split_path(path).0, false)?;
+            let (short, created) = match lfn::short_path(vm, root_dir, path, false) {
+                Err(FManError::SdErr(Error::NotFound)) if create => (lfn::short_path(vm, root_dir, path, true)?, true),
+                short => (short?, false),
+            };
+            let (parent, name) = split_path(&short);
+            if name.is_empty() {
+                return Err("the root directory cannot be changed".into());
+            }
+            check_reserved(&short)?;
+
+            let dir = open_below(vm, root_dir, parent)?;
+            let result = f(vm, &Target { root_dir, parent, dir, name });
+            close_unless(vm, dir, root_dir);
+            // an alias of nothing would shadow the next try
+            if result.is_err() && created {
+                let _ = lfn::forget(vm, root_dir, parent, name);
+            }
+            result
+        }).await
+    }
+
+    /// Creates an empty file at `path`. Fails if anything is there already.
+    pub async fn create_file(&self, path: &str) -> Result<(), FsErr> {
+        self.with_target(path, true, |vm, t| {
+            with_file(vm, t.dir, t.name, Mode::ReadWriteCreate, |_| Ok(()))
+        }).await
+    }
+
+    /// Replaces the contents of the file at `path` with `data`, creating it
+    /// when needed.
+    pub async fn write_file(&self, path: &str, data: &[u8]) -> Result<(), FsErr> {
+        self.with_target(path, true, |vm, t| {
+            with_file(vm, t.dir, t.name, Mode::ReadWriteCreateOrTruncate, |f| Ok(vm.write(f, data)?))?;
+            reregister(vm, t)
+        }).await
+    }
+
+    /// Appends `data` to the file at `path`, creating it when needed.
+    pub async fn append_file(&self, path: &str, data: &[u8]) -> Result<(), FsErr> {
+        self.with_target(path, true, |vm, t| {
+            with_file(vm, t.dir, t.name, Mode::ReadWriteCreateOrAppend, |f| Ok(vm.write(f, data)?))?;
+            reregister(vm, t)
+        }).await
+    }
+
+    /// Shortens the file at `path` to `len` bytes, freeing the clusters
+    /// past it. Longer lengths leave it as it is.
+    pub async fn truncate_file(&self, path: &str, len: u32) -> Result<(), FsErr> {
+        self.with_target(path, false, |vm, t| {
+            let entry = vm.find_directory_entry(t.dir, t.name)?;
+            if entry.attributes.is_directory() {
+                return Err(FManError::IsDir);
+            }
+            if len >= entry.size {
+                return Ok(());
+            }
+            with_file(vm, t.dir, t.name, Mode::ReadWriteAppend, |f| {
+                vm.file_seek_from_start(f, len)?;
+                Ok(vm.truncate_file(f)?)
+            })?;
+            reregister(vm, t)
+        }).await
+    }
+
+    /// Removes the file at `path`.
+    pub async fn remove_file(&self, path: &str) -> Result<(), FsErr> {
+        self.with_target(path, false, |vm, t| {
+            vm.delete_file_in_dir(t.dir, t.name)?;
+            retire(vm, t.root_dir, t.parent, t.name)?;
+            lfn::forget(vm, t.root_dir, t.parent, t.name)
+        }).await
+    }
+
+    /// Moves the file at `from` to `to`, anywhere on the volume. The parent
+    /// of `to` must exist and `to` itself must not.
+    ///
+    /// Only directory entries are rewritten, no data is copied. Directories
+    /// cannot be moved here, see [`FileManager::move_tree`]. A file moved
+    /// out of or into `FILES` or `MUSIC` takes its table row along, but not
+    /// its old versions, which are deleted.
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            check_reserved(from)?;
+            check_reserved(to)?;
+            let from = lfn::short_path(vm, root_dir, from, false)?;
+            let to_short = lfn::short_path(vm, root_dir, to, true)?;
+            rename_file(vm, root_dir, &from, &to_short, split_path(to).1)
+        }).await
+    }
+
+    /// Creates the directory at `path`. Its parent must exist.
+    pub async fn make_dir(&self, path: &str) -> Result<(), FsErr> {
+        self.with_target(path, true, |vm, t| Ok(vm.make_dir_in_dir(t.dir, t.name)?)).await
+    }
+
+    /// Removes the directory at `path` if it is empty, else fails with
+    /// [`FManError::DirNotEmpty`].
+    pub async fn remove_dir(&self, path: &str) -> Result<(), FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            let short = lfn::short_path(vm, root_dir, path, false)?;
+            let (parent, name) = split_path(&short);
+            if name.is_empty() {
+                return Err("the root directory cannot be removed".into());
+            }
+            check_reserved(&short)?;
+            remove_empty_dirs(vm, root_dir, &[(parent, name)])?;
+            lfn::forget(vm, root_dir, parent, name)
+        }).await
+    }
+}
@@ -1,6 +1,8 @@
 #![allow(unused)]
 
 use core::ops::{Deref, DerefMut};
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::Mutex as EmbassyBlockingMutex;
 use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
 use embassy_sync::signal::Signal as EmbassySignal;
 use embassy_sync::channel::Channel as EmbassyChannel;
@@ -8,6 +10,20 @@ use embassy_sync::mutex::{Mutex as EmbassyMutex, MutexGuard as EmbassyMutexGuard
 use embassy_sync::channel::Receiver as EmbassyReceiver;
 use embassy_sync::channel::Sender as EmbassySender;
 
+pub async fn sleep_ms(ms: u64) {
+    embassy_time::Timer::after_millis(ms).await;
+}
+
+/// Runs `future` for at most `ms` milliseconds.
+pub async fn timeout_ms<F: core::future::Future>(ms: u64, future: F) -> Option<F::Output> {
+    embassy_time::with_timeout(embassy_time::Duration::from_millis(ms), future).await.ok()
+}
+
+/// Milliseconds since boot.
+pub fn uptime_ms() -> u64 {
+    embassy_time::Instant::now().as_millis()
+}
+
 pub struct Channel<T, const N: usize> {
     ch: EmbassyChannel<CriticalSectionRawMutex, T, N>,
 }
@@ -102,6 +118,10 @@ impl <T> Mutex<T> {
     pub async fn lock<'a>(&'a self) -> MutexGuard<'a, T> {
         MutexGuard { g: self.m.lock().await }
     }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.m.try_lock().ok().map(|g| MutexGuard { g })
+    }
 }
 
 impl<'a, T> Deref for MutexGuard<'a, T> {
@@ -117,3 +137,17 @@ impl<'a, T> DerefMut for MutexGuard<'a, T> {
         &mut self.g
     }
 }
+
+pub struct BlockingMutex<T> {
+    m: EmbassyBlockingMutex<CriticalSectionRawMutex, RefCell<T>>
+}
+
+impl<T> BlockingMutex<T> {
+    pub fn new(val: T) -> Self {
+        Self { m: EmbassyBlockingMutex::new(RefCell::new(val)) }
+    }
+
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.m.lock(|cell| f(&mut cell.borrow_mut()))
+    }
+}
@@ -7,7 +7,10 @@ mod tokio_rt;
 #[cfg(feature = "embassy")]
 mod embassy_rt;
 #[cfg(feature = "embassy")]
+pub use embassy_rt::{sleep_ms, timeout_ms, uptime_ms};
+#[cfg(feature = "embassy")]
 use embassy_rt::{
+    BlockingMutex as BlockingMutexInner,
     Channel as ChannelInner,
     Signal as SignalInner,
     Mutex as MutexInner,
@@ -16,8 +19,11 @@ use embassy_rt::{
     Receiver as ReceiverInner
 };
 
+#[cfg(feature = "tokio")]
+pub use tokio_rt::{sleep_ms, timeout_ms, uptime_ms};
 #[cfg(feature = "tokio")]
 use tokio_rt::{
+    BlockingMutex as BlockingMutexInner,
     Channel as ChannelInner,
     Signal as SignalInner,
     Mutex as MutexInner,
@@ -122,6 +128,11 @@ impl <T> Mutex<T> {
     pub async fn lock(&self) -> MutexGuard<'_, T> {
         MutexGuard { inner: self.inner.lock().await }
     }
+
+    /// The guard, unless the mutex is locked already.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.inner.try_lock().map(|inner| MutexGuard { inner })
+    }
 }
 
 impl<'a, T> Deref for MutexGuard<'a, T> {
@@ -137,3 +148,26 @@ impl<'a, T> DerefMut for MutexGuard<'a, T> {
         &mut self.inner
     }
 }
+
+/// A mutex that is only ever held for a few instructions and never across
+/// an `await`, so it can be taken where waiting is impossible, e.g. in
+/// `Drop`.
+pub struct BlockingMutex<T> {
+    inner: BlockingMutexInner<T>,
+}
+
+impl<T> BlockingMutex<T> {
+    pub fn new(val: T) -> Self {
+        Self { inner: BlockingMutexInner::new(val) }
+    }
+
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.inner.lock(f)
+    }
+}
+
+impl<T> core::fmt::Debug for BlockingMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("BlockingMutex")
+    }
+}
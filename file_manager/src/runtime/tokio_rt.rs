@@ -4,6 +4,21 @@ use tokio::sync::{Notify, Mutex as TokioMutex, MutexGuard as TokioMutexGuard};
 use tokio::sync::mpsc::{Sender as TokioSender, Receiver as TokioReceiver};
 use std::sync::Arc;
 
+pub async fn sleep_ms(ms: u64) {
+    tokio::time::sleep(tokio::time::Duration::from_millis(ms)).await;
+}
+
+/// Runs `future` for at most `ms` milliseconds.
+pub async fn timeout_ms<F: core::future::Future>(ms: u64, future: F) -> Option<F::Output> {
+    tokio::time::timeout(tokio::time::Duration::from_millis(ms), future).await.ok()
+}
+
+/// Milliseconds since the first call.
+pub fn uptime_ms() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed().as_millis() as u64
+}
+
 #[derive(Debug)]
 pub struct Channel<T, const N: usize> {
     tx: TokioSender<T>,
@@ -107,6 +122,10 @@ impl <T> Mutex<T> {
     pub async fn lock(&self) -> MutexGuard<'_, T> {
         MutexGuard { g: self.m.lock().await }
     }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.m.try_lock().ok().map(|g| MutexGuard { g })
+    }
 }
 
 impl<'a, T> Deref for MutexGuard<'a, T> {
@@ -122,3 +141,18 @@ impl<'a, T> DerefMut for MutexGuard<'a, T> {
         &mut self.g
     }
 }
+
+#[derive(Debug)]
+pub struct BlockingMutex<T> {
+    m: std::sync::Mutex<T>
+}
+
+impl<T> BlockingMutex<T> {
+    pub fn new(val: T) -> Self {
+        Self { m: std::sync::Mutex::new(val) }
+    }
+
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.m.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
@@ -0,0 +1,267 @@
+//! SNTP client (RFC 4330) keeping [`Clock`] in step with a time server.
+//!
+//! The client only needs a datagram socket talking to one server, see
+//! [`SntpSocket`]; the tokio build implements it for a connected
+//! `tokio::net::UdpSocket`, the firmware for a UDP socket of the STA
+//! interface. [`run`] syncs every [`SntpConfig::poll_ms`] and backs off
+//! from [`SntpConfig::min_retry_ms`] to [`SntpConfig::max_retry_ms`] while
+//! the server does not answer. What happened last is kept in statics so
+//! that a status page can read it with [`status`] at any time.
+
+use core::sync::atomic::{AtomicI32, AtomicU32, AtomicU8, Ordering};
+use crate::clock::Clock;
+use crate::runtime::{sleep_ms, timeout_ms, uptime_ms};
+
+pub const PACKET_LEN: usize = 48;
+pub const PORT: u16 = 123;
+
+/// Seconds from the NTP era (1900) to the Unix epoch.
+const NTP_TO_UNIX: u64 = 2_208_988_800;
+
+/// Why a sync failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SntpError {
+    /// The socket failed to send or receive.
+    Io,
+    /// No reply within [`SntpConfig::timeout_ms`].
+    Timeout,
+    /// Too short, not from a server, or not answering our request.
+    Malformed,
+    /// The server has no time to give (leap indicator 3 or stratum above 15).
+    Unsynchronized,
+    /// The server told us to go away (stratum 0).
+    KissOfDeath,
+}
+
+impl SntpError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SntpError::Io => "socket error",
+            SntpError::Timeout => "no reply",
+            SntpError::Malformed => "malformed reply",
+            SntpError::Unsynchronized => "server unsynchronized",
+            SntpError::KissOfDeath => "kiss of death",
+        }
+    }
+
+    const ALL: [SntpError; 5] = [
+        SntpError::Io,
+        SntpError::Timeout,
+        SntpError::Malformed,
+        SntpError::Unsynchronized,
+        SntpError::KissOfDeath,
+    ];
+}
+
+/// A datagram socket exchanging packets with a single time server.
+#[allow(async_fn_in_trait)]
+pub trait SntpSocket {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), SntpError>;
+    /// Receives the next datagram from the server, ignoring any other.
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, SntpError>;
+}
+
+#[cfg(feature = "tokio")]
+impl SntpSocket for tokio::net::UdpSocket {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), SntpError> {
+        tokio::net::UdpSocket::send(self, packet).await.map(|_| ()).map_err(|_| SntpError::Io)
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, SntpError> {
+        tokio::net::UdpSocket::recv(self, buf).await.map_err(|_| SntpError::Io)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SntpConfig {
+    /// Time between successful syncs.
+    pub poll_ms: u64,
+    /// Wait after the first failed attempt, doubled with every further one.
+    pub min_retry_ms: u64,
+    pub max_retry_ms: u64,
+    /// How long to wait for a reply.
+    pub timeout_ms: u64,
+}
+
+impl Default for SntpConfig {
+    fn default() -> Self {
+        Self { poll_ms: 3_600_000, min_retry_ms: 2_000, max_retry_ms: 300_000, timeout_ms: 3_000 }
+    }
+}
+
+/// One exchange with the server, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// What the server's time is ahead of ours.
+    pub offset_ms: i64,
+    /// Round trip, less the time the server held the request.
+    pub delay_ms: i64,
+}
+
+/// What the client has done so far, see [`status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SntpStatus {
+    pub syncs: u32,
+    pub failures: u32,
+    /// Unix time of the last sync, in seconds.
+    pub last_sync: Option<u32>,
+    /// Correction made by the last sync.
+    pub offset_ms: i32,
+    pub delay_ms: u32,
+    /// How fast our clock drifted between the last two syncs, in parts per
+    /// million; positive when it runs slow.
+    pub drift_ppm: i32,
+    /// Why the last attempt failed, if it did.
+    pub last_error: Option<SntpError>,
+}
+
+static SYNCS: AtomicU32 = AtomicU32::new(0);
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+static LAST_SYNC: AtomicU32 = AtomicU32::new(0);
+/// Uptime of the last sync, wrapping after 49 days.
+static LAST_SYNC_UPTIME: AtomicU32 = AtomicU32::new(0);
+static OFFSET_MS: AtomicI32 = AtomicI32::new(0);
+static DELAY_MS: AtomicU32 = AtomicU32::new(0);
+static DRIFT_PPM: AtomicI32 = AtomicI32::new(0);
+/// Index into [`SntpError::ALL`] plus one, or 0.
+static LAST_ERROR: AtomicU8 = AtomicU8::new(0);
+
+pub fn status() -> SntpStatus {
+    SntpStatus {
+        syncs: SYNCS.load(Ordering::Relaxed),
+        failures: FAILURES.load(Ordering::Relaxed),
+        last_sync: Some(LAST_SYNC.load(Ordering::Relaxed)).filter(|secs| *secs != 0),
+        offset_ms: OFFSET_MS.load(Ordering::Relaxed),
+        delay_ms: DELAY_MS.load(Ordering::Relaxed),
+        drift_ppm: DRIFT_PPM.load(Ordering::Relaxed),
+        last_error: match LAST_ERROR.load(Ordering::Relaxed) {
+            0 => None,
+            i => SntpError::ALL.get(i as usize - 1).copied(),
+        },
+    }
+}
+
+/// Our time in milliseconds: the wall clock, or the uptime until it is set.
+fn local_ms() -> u64 {
+    Clock::unix_ms().unwrap_or_else(uptime_ms)
+}
+
+fn write_timestamp(buf: &mut [u8], unix_ms: u64) {
+    let secs = (unix_ms / 1000 + NTP_TO_UNIX) as u32;
+    // rounded up, so that reading it back gives the same milliseconds
+    let frac = ((unix_ms % 1000) << 32).div_ceil(1000) as u32;
+    buf[..4].copy_from_slice(&secs.to_be_bytes());
+    buf[4..8].copy_from_slice(&frac.to_be_bytes());
+}
+
+fn read_timestamp(buf: &[u8]) -> u64 {
+    let secs = u32::from_be_bytes(buf[..4].try_into().unwrap()) as u64;
+    let frac = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as u64;
+    secs.wrapping_sub(NTP_TO_UNIX) * 1000 + ((frac * 1000) >> 32)
+}
+
+/// A client request sent at `transmit_ms`.
+pub fn request(transmit_ms: u64) -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    // no leap indicator, version 4, mode 3 (client)
+    packet[0] = 0b00_100_011;
+    write_timestamp(&mut packet[40..48], transmit_ms);
+    packet
+}
+
+/// Checks the server's `reply` to our request sent at `sent_ms`, received
+/// at `received_ms`, and works out how far off we are.
+pub fn parse_reply(reply: &[u8], sent_ms: u64, received_ms: u64) -> Result<Sample, SntpError> {
+    if reply.len() < PACKET_LEN {
+        return Err(SntpError::Malformed);
+    }
+    let (leap, version, mode) = (reply[0] >> 6, (reply[0] >> 3) & 0b111, reply[0] & 0b111);
+    // the server echoes our transmit time as its originate time
+    let mut sent = [0u8; 8];
+    write_timestamp(&mut sent, sent_ms);
+    if mode != 4 || !(3..=4).contains(&version) || reply[24..32] != sent || reply[40..48] == [0; 8] {
+        return Err(SntpError::Malformed);
+    }
+    match reply[1] {
+        0 => return Err(SntpError::KissOfDeath),
+        16.. => return Err(SntpError::Unsynchronized),
+        _ if leap == 3 => return Err(SntpError::Unsynchronized),
+        _ => (),
+    }
+
+    let (t1, t4) = (sent_ms as i64, received_ms as i64);
+    let t2 = read_timestamp(&reply[32..40]) as i64;
+    let t3 = read_timestamp(&reply[40..48]) as i64;
+    Ok(Sample {
+        offset_ms: ((t2 - t1) + (t3 - t4)) / 2,
+        delay_ms: ((t4 - t1) - (t3 - t2)).max(0),
+    })
+}
+
+/// Asks the server once for the time, without touching the clock.
+pub async fn query<S: SntpSocket>(socket: &mut S, timeout: u64) -> Result<Sample, SntpError> {
+    let sent = local_ms();
+    socket.send(&request(sent)).await?;
+
+    let mut buf = [0u8; PACKET_LEN * 2];
+    timeout_ms(timeout, async {
+        // a late reply to an earlier request does not match and is skipped
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            match parse_reply(&buf[..len], sent, local_ms()) {
+                Err(SntpError::Malformed) => continue,
+                result => return result,
+            }
+        }
+    })
+    .await
+    .unwrap_or(Err(SntpError::Timeout))
+}
+
+/// Queries the server, sets the clock from the answer and records the
+/// outcome for [`status`].
+pub async fn sync<S: SntpSocket>(socket: &mut S, timeout: u64) -> Result<Sample, SntpError> {
+    let sample = match query(socket, timeout).await {
+        Ok(sample) => sample,
+        Err(e) => {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+            let index = SntpError::ALL.iter().position(|other| *other == e).unwrap_or(0);
+            LAST_ERROR.store(index as u8 + 1, Ordering::Relaxed);
+            return Err(e);
+        },
+    };
+
+    let was_set = Clock::is_set();
+    Clock::set_unix_ms(local_ms().saturating_add_signed(sample.offset_ms));
+
+    let now = uptime_ms() as u32;
+    let elapsed = now.wrapping_sub(LAST_SYNC_UPTIME.swap(now, Ordering::Relaxed));
+    if was_set && SYNCS.load(Ordering::Relaxed) > 0 && elapsed > 0 {
+        let ppm = sample.offset_ms * 1_000_000 / elapsed as i64;
+        DRIFT_PPM.store(ppm.clamp(i32::MIN as i64, i32::MAX as i64) as i32, Ordering::Relaxed);
+    }
+    OFFSET_MS.store(sample.offset_ms.clamp(i32::MIN as i64, i32::MAX as i64) as i32, Ordering::Relaxed);
+    DELAY_MS.store(sample.delay_ms.min(u32::MAX as i64) as u32, Ordering::Relaxed);
+    LAST_SYNC.store(Clock::unix_secs().unwrap_or(0) as u32, Ordering::Relaxed);
+    LAST_ERROR.store(0, Ordering::Relaxed);
+    SYNCS.fetch_add(1, Ordering::Relaxed);
+    Ok(sample)
+}
+
+/// Keeps the clock in sync with the server behind `socket` and never
+/// returns.
+pub async fn run<S: SntpSocket>(socket: &mut S, config: SntpConfig) {
+    let mut retry = config.min_retry_ms;
+    loop {
+        match sync(socket, config.timeout_ms).await {
+            Ok(_) => {
+                retry = config.min_retry_ms;
+                sleep_ms(config.poll_ms).await;
+            },
+            Err(_) => {
+                sleep_ms(retry).await;
+                retry = (retry * 2).min(config.max_retry_ms);
+            },
+        }
+    }
+}
@@ -48,7 +48,10 @@ unsafe impl Sync for SyncFMan {}
 
 pub static FILE_MAN: OnceLock<SyncFMan> = OnceLock::new();
 
-pub fn init_file_manager(block_device: BlkDev, time_src: DummyTimesource) {
+pub fn init_file_manager(block_device: BlkDev, time_src: Clock) {
+    if !Clock::is_set() {
+        Clock::set_from_host();
+    }
     FILE_MAN.set(
         SyncFMan(FileManager::new(block_device, time_src))
     ).expect("initing twice file_manager");
@@ -89,65 +92,177 @@ where
     embedded_sdmmc::Error<<FsBlockDevice as BlockDevice>::Error>: Into<embedded_sdmmc::Error<FsError>>
 {
     let fman = get_file_manager();
-    fman.with_vol_man(|vm, vol| -> Result<(), FManError<FsBlockDeviceError>> {
-        let root_dir = FileManager::root_dir(vm, vol)?.to_directory(vm);
-        let _ = root_dir.make_dir_in_dir(consts::DB_DIR);
-        let _ = root_dir.make_dir_in_dir(consts::FILES_DIR);
-        let _ = root_dir.make_dir_in_dir(consts::MUSIC_DIR);
-
-        {
-            let db_dir = root_dir.open_dir(consts::DB_DIR)?;
-            let db_dir = db_dir.to_raw_directory();
-            let stuff_dir = DbDirSdmmc::new(db_dir);
-            let mut db = Database::new_init(VM::new(vm), stuff_dir, allocator.clone())?;
-
-            {
-                let name = Column::new("name", ColumnType::Chars).primary();
-                let count = Column::new("count", ColumnType::Int);
-                db.new_table_begin(consts::COUNT_TRACKER_TABLE);
-                db.add_column(name)?;
-                db.add_column(count)?;
-                let _ = db.create_table(allocator.clone())?;
-            }
-
-            {
-                let name = Column::new("path", ColumnType::Chars).primary();
-                let count = Column::new("name", ColumnType::Chars);
-                let size = Column::new("size", ColumnType::Int);
-                db.new_table_begin(consts::FILES_TABLE);
-                db.add_column(name)?;
-                db.add_column(count)?;
-                db.add_column(size)?;
-                let _ = db.create_table(allocator.clone())?;
-            }
-
-            {
-                let name = Column::new("path", ColumnType::Chars).primary();
-                let count = Column::new("name", ColumnType::Chars);
-                db.new_table_begin(consts::MUSIC_TABLE);
-                db.add_column(name)?;
-                db.add_column(count)?;
-                let _ = db.create_table(allocator.clone())?;
-            }
-
-            let count_tracker = db.get_table(consts::COUNT_TRACKER_TABLE, allocator.clone())?;
-
-            {
-                let mut row = Row::new_in(allocator.clone());
-                row.push(Value::Chars(consts::FILES_TABLE.as_bytes()));
-                row.push(Value::Int(1));
-                db.insert_to_table(count_tracker, row, allocator.clone())?;
-            }
-
-            {
-                let mut row = Row::new_in(allocator.clone());
-                row.push(Value::Chars(consts::MUSIC_TABLE.as_bytes()));
-                row.push(Value::Int(1));
-                db.insert_to_table(count_tracker, row, allocator.clone())?;
-            }
-
-            Ok(())
-        }
-    }).await?;
+    fman.with_vol_man(|vm, vol| prepare_card(vm, vol, allocator)).await?;
+    Ok(())
+}
+
+/// Whether the card still answers. The simulated card only goes away with
+/// its backing file.
+pub(crate) fn probe_card(device: &BlkDev) -> bool {
+    device.num_blocks().is_ok()
+}
+
+/// `Ok` for what already exists on the card.
+fn existing_ok<T>(result: Result<T, alpa::db::Error<embedded_sdmmc::Error<FsError>>>)
+    -> Result<(), alpa::db::Error<embedded_sdmmc::Error<FsError>>>
+{
+    match result {
+        Ok(_) | Err(alpa::db::Error::DuplicateKey) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Creates the directories and tables the server relies on, keeping what
+/// a card that was prepared before already has.
+pub(crate) fn prepare_card(
+    vm: &FsVolumeManager,
+    vol: &RawVolume,
+    allocator: ExtAlloc,
+) -> Result<(), FManError<FsBlockDeviceError>> {
+    let root_dir = FileManager::root_dir(vm, vol)?.to_directory(vm);
+    let _ = root_dir.make_dir_in_dir(consts::DB_DIR);
+    let _ = root_dir.make_dir_in_dir(consts::FILES_DIR);
+    let _ = root_dir.make_dir_in_dir(consts::MUSIC_DIR);
+    let _ = root_dir.make_dir_in_dir(consts::TRASH_DIR);
+    let _ = root_dir.make_dir_in_dir(consts::VERSIONS_DIR);
+
+    let db_dir = root_dir.open_dir(consts::DB_DIR)?;
+    let db_dir = db_dir.to_raw_directory();
+    let stuff_dir = DbDirSdmmc::new(db_dir);
+    let mut db = Database::new_init(VM::new(vm), stuff_dir, allocator.clone())?;
+
+    {
+        let name = Column::new("name", ColumnType::Chars).primary();
+        let count = Column::new("count", ColumnType::Int);
+        db.new_table_begin(consts::COUNT_TRACKER_TABLE);
+        db.add_column(name)?;
+        db.add_column(count)?;
+        existing_ok(db.create_table(allocator.clone()))?;
+    }
+
+    {
+        let name = Column::new("path", ColumnType::Chars).primary();
+        let count = Column::new("name", ColumnType::Chars);
+        let size = Column::new("size", ColumnType::Int);
+        let crc32 = Column::new("crc32", ColumnType::Int);
+        let sha256 = Column::new("sha256", ColumnType::Chars);
+        db.new_table_begin(consts::FILES_TABLE);
+        db.add_column(name)?;
+        db.add_column(count)?;
+        db.add_column(size)?;
+        db.add_column(crc32)?;
+        db.add_column(sha256)?;
+        existing_ok(db.create_table(allocator.clone()))?;
+    }
+
+    {
+        let name = Column::new("path", ColumnType::Chars).primary();
+        let count = Column::new("name", ColumnType::Chars);
+        let size = Column::new("size", ColumnType::Int);
+        let crc32 = Column::new("crc32", ColumnType::Int);
+        let sha256 = Column::new("sha256", ColumnType::Chars);
+        db.new_table_begin(consts::MUSIC_TABLE);
+        db.add_column(name)?;
+        db.add_column(count)?;
+        db.add_column(size)?;
+        db.add_column(crc32)?;
+        db.add_column(sha256)?;
+        existing_ok(db.create_table(allocator.clone()))?;
+    }
+
+    {
+        let key = Column::new("key", ColumnType::Chars).primary();
+        let name = Column::new("name", ColumnType::Chars);
+        let length = Column::new("length", ColumnType::Int);
+        let offset = Column::new("offset", ColumnType::Int);
+        db.new_table_begin(consts::UPLOADS_TABLE);
+        db.add_column(key)?;
+        db.add_column(name)?;
+        db.add_column(length)?;
+        db.add_column(offset)?;
+        existing_ok(db.create_table(allocator.clone()))?;
+    }
+
+    {
+        let key = Column::new("key", ColumnType::Chars).primary();
+        let short = Column::new("short", ColumnType::Chars);
+        db.new_table_begin(consts::LONG_NAMES_TABLE);
+        db.add_column(key)?;
+        db.add_column(short)?;
+        existing_ok(db.create_table(allocator.clone()))?;
+    }
+
+    {
+        let key = Column::new("key", ColumnType::Chars).primary();
+        let long = Column::new("long", ColumnType::Chars);
+        db.new_table_begin(consts::SHORT_NAMES_TABLE);
+        db.add_column(key)?;
+        db.add_column(long)?;
+        existing_ok(db.create_table(allocator.clone()))?;
+    }
+
+    {
+        let key = Column::new("key", ColumnType::Chars).primary();
+        let name = Column::new("name", ColumnType::Chars);
+        let size = Column::new("size", ColumnType::Int);
+        let crc32 = Column::new("crc32", ColumnType::Int);
+        let sha256 = Column::new("sha256", ColumnType::Chars);
+        let category = Column::new("category", ColumnType::Chars);
+        let original = Column::new("original", ColumnType::Chars);
+        let deleted = Column::new("deleted", ColumnType::Int);
+        db.new_table_begin(consts::TRASH_TABLE);
+        db.add_column(key)?;
+        db.add_column(name)?;
+        db.add_column(size)?;
+        db.add_column(crc32)?;
+        db.add_column(sha256)?;
+        db.add_column(category)?;
+        db.add_column(original)?;
+        db.add_column(deleted)?;
+        existing_ok(db.create_table(allocator.clone()))?;
+    }
+
+    {
+        let key = Column::new("key", ColumnType::Chars).primary();
+        let name = Column::new("name", ColumnType::Chars);
+        let version = Column::new("version", ColumnType::Int);
+        let size = Column::new("size", ColumnType::Int);
+        let crc32 = Column::new("crc32", ColumnType::Int);
+        let sha256 = Column::new("sha256", ColumnType::Chars);
+        let replaced = Column::new("replaced", ColumnType::Int);
+        db.new_table_begin(consts::VERSIONS_TABLE);
+        db.add_column(key)?;
+        db.add_column(name)?;
+        db.add_column(version)?;
+        db.add_column(size)?;
+        db.add_column(crc32)?;
+        db.add_column(sha256)?;
+        db.add_column(replaced)?;
+        existing_ok(db.create_table(allocator.clone()))?;
+    }
+
+    let count_tracker = db.get_table(consts::COUNT_TRACKER_TABLE, allocator.clone())?;
+
+    {
+        let mut row = Row::new_in(allocator.clone());
+        row.push(Value::Chars(consts::FILES_TABLE.as_bytes()));
+        row.push(Value::Int(1));
+        existing_ok(db.insert_to_table(count_tracker, row, allocator.clone()))?;
+    }
+
+    {
+        let mut row = Row::new_in(allocator.clone());
+        row.push(Value::Chars(consts::MUSIC_TABLE.as_bytes()));
+        row.push(Value::Int(1));
+        existing_ok(db.insert_to_table(count_tracker, row, allocator.clone()))?;
+    }
+
+    {
+        let mut row = Row::new_in(allocator.clone());
+        row.push(Value::Chars(consts::TRASH_TABLE.as_bytes()));
+        row.push(Value::Int(1));
+        existing_ok(db.insert_to_table(count_tracker, row, allocator.clone()))?;
+    }
+
     Ok(())
 }
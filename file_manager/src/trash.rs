@@ -0,0 +1,272 @@
+//! Trash bin for deleted uploads.
+//!
+//! [`FileManager::trash`] moves a file of `FILES` or `MUSIC` into `TRASH`
+//! instead of deleting it. There it is named `<n>.<ext>`, `n` coming from
+//! the `trash` row of `count_tracker` as the ids of the two tables overlap,
+//! and its row moves to the `trash` table together with the category and
+//! short name it had and the time it was deleted. [`FileManager::restore`]
+//! puts both back as they were; the purges delete them for good.
+//!
+//! Files are moved, never copied, and their rows are handled like those of
+//! [`crate::versions`]: dropped before the file leaves, added once it has
+//! arrived.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Query, QueryExecutor, Row, Value};
+use embedded_sdmmc::RawDirectory;
+use crate::checksum::{checksum_file, recorded, Checksums};
+use crate::clock::Clock;
+use crate::ops::{move_entry, register, unregister, FsErr};
+use crate::{consts, lfn, versions, DirGuard, ExtAlloc, FManError, FileManager, FsVolumeManager};
+
+/// A deleted upload waiting in `TRASH`.
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    /// Short name in `TRASH`.
+    pub name: String,
+    /// Name it was uploaded under.
+    pub upload_name: String,
+    pub size: i64,
+    pub checksums: Checksums,
+    /// Table it was registered in, `files` or `music`.
+    pub table: &'static str,
+    /// Short name it had there.
+    pub original: String,
+    /// Unix time of the delete, unless the clock was unset then.
+    pub deleted: Option<u64>,
+}
+
+/// The category table named `table` and the directory of its files.
+fn category(table: &[u8]) -> Option<(&'static str, &'static str)> {
+    [(consts::FILES_TABLE, consts::FILES_DIR), (consts::MUSIC_TABLE, consts::MUSIC_DIR)]
+        .into_iter()
+        .find(|(name, _)| name.as_bytes() == table)
+}
+
+/// Every row of the `trash` table.
+fn entries(vm: &FsVolumeManager, root_dir: RawDirectory) -> Result<Vec<TrashEntry>, FsErr> {
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(consts::TRASH_TABLE, ExtAlloc::default())?;
+
+    let mut entries = Vec::new();
+    let query = Query::<_, &str>::new(table, ExtAlloc::default());
+    // an empty table has no pages to run a query over
+    if let Ok(mut exec) = QueryExecutor::new(
+        query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+        &db.file_handler.page_rw.as_ref().unwrap()
+    ) {
+        while let Ok(row) = exec.next() {
+            let Some((table, _)) = category(row[5].to_chars().unwrap()) else {
+                continue;
+            };
+            let chars = |i: usize| String::from_utf8_lossy(row[i].to_chars().unwrap()).into_owned();
+            entries.push(TrashEntry {
+                name: chars(0),
+                upload_name: chars(1),
+                size: row[2].to_int().unwrap(),
+                checksums: Checksums {
+                    crc32: row[3].to_int().unwrap() as u32,
+                    sha256: Checksums::parse_sha256(row[4].to_chars().unwrap()).unwrap_or([0; 32]),
+                },
+                table,
+                original: chars(6),
+                deleted: Some(row[7].to_int().unwrap() as u64).filter(|secs| *secs != 0),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Deletes `entry` for good, its file and its row.
+fn purge_entry(vm: &FsVolumeManager, root_dir: RawDirectory, entry: &TrashEntry) -> Result<(), FsErr> {
+    let trash_dir = DirGuard::new(vm, vm.open_dir(root_dir, consts::TRASH_DIR)?);
+    match trash_dir.delete_file(&entry.name) {
+        Ok(()) | Err(embedded_sdmmc::Error::NotFound) => (),
+        Err(e) => return Err(FManError::SdErr(e)),
+    }
+    drop(trash_dir);
+
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(consts::TRASH_TABLE, ExtAlloc::default())?;
+    db.delete_from_table(table, Value::Chars(entry.name.as_bytes()), ExtAlloc::default())?;
+    drop(db);
+
+    if entry.table == consts::FILES_TABLE {
+        versions::drop_versions(vm, root_dir, &entry.original)?;
+    }
+    // a long name given to it can be handed out again now
+    let (_, dir) = category(entry.table.as_bytes()).unwrap();
+    lfn::forget(vm, root_dir, dir, &entry.original)
+}
+
+impl FileManager {
+    /// Moves the uploaded file `name` of `table` to the trash. `None` if it
+    /// is not there; a row left behind by a file deleted some other way is
+    /// dropped then.
+    pub async fn trash(&self, table: &str, name: &str) -> Result<Option<TrashEntry>, FsErr> {
+        let Some((table, dir)) = category(table.as_bytes()) else {
+            return Ok(None);
+        };
+        let name = name.to_ascii_uppercase();
+
+        self.with_root_dir(|vm, root_dir| {
+            let files = DirGuard::new(vm, vm.open_dir(root_dir, dir)?);
+            let size = match files.find(&name) {
+                Ok(entry) if entry.attributes.is_directory() => return Err(FManError::IsDir),
+                Ok(entry) => entry.size,
+                Err(embedded_sdmmc::Error::NotFound) => {
+                    unregister(vm, root_dir, dir, &name)?;
+                    return Ok(None);
+                },
+                Err(e) => return Err(FManError::SdErr(e)),
+            };
+            let (upload_name, checksums) = match recorded(vm, root_dir, table, &name)? {
+                Some(recorded) => (recorded.upload_name, recorded.checksums),
+                None => (name.clone(), checksum_file(&files, &name)?.1),
+            };
+            drop(files);
+
+            let id = {
+                let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+                let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+                let count_tracker = db.get_table(consts::COUNT_TRACKER_TABLE, ExtAlloc::default())?;
+                let query = Query::<_, &str>::new(count_tracker, ExtAlloc::default())
+                                             .key(Value::Chars(consts::TRASH_TABLE.as_bytes()));
+                let mut exec = QueryExecutor::new(
+                    query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+                    &db.file_handler.page_rw.as_ref().unwrap()
+                ).map_err(|_| FManError::ServerErr("trash counter missing"))?;
+                exec.next().map_err(|_| FManError::ServerErr("trash counter missing"))?[1].to_int().unwrap()
+            };
+            let trash_name = match name.rsplit_once('.') {
+                Some((_, ext)) => format!("{}.{}", id, ext),
+                None => format!("{}", id),
+            };
+            // left behind by a crash before its row was added
+            match DirGuard::new(vm, vm.open_dir(root_dir, consts::TRASH_DIR)?).delete_file(&trash_name) {
+                Ok(()) | Err(embedded_sdmmc::Error::NotFound) => (),
+                Err(e) => return Err(FManError::SdErr(e)),
+            }
+
+            unregister(vm, root_dir, dir, &name)?;
+            move_entry(vm, root_dir, &format!("{}/{}", dir, name), &format!("{}/{}", consts::TRASH_DIR, trash_name))?;
+
+            let entry = TrashEntry {
+                name: trash_name,
+                upload_name,
+                size: size as i64,
+                checksums,
+                table,
+                original: name,
+                deleted: Clock::unix_secs(),
+            };
+            let sha256 = entry.checksums.sha256_hex();
+            let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+            let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+            let trash_table = db.get_table(consts::TRASH_TABLE, ExtAlloc::default())?;
+            let mut row = Row::new_in(ExtAlloc::default());
+            row.push(Value::Chars(entry.name.as_bytes()));
+            row.push(Value::Chars(entry.upload_name.as_bytes()));
+            row.push(Value::Int(entry.size));
+            row.push(Value::Int(entry.checksums.crc32 as i64));
+            row.push(Value::Chars(sha256.as_bytes()));
+            row.push(Value::Chars(table.as_bytes()));
+            row.push(Value::Chars(entry.original.as_bytes()));
+            row.push(Value::Int(entry.deleted.unwrap_or(0) as i64));
+            db.insert_to_table(trash_table, row, ExtAlloc::default())?;
+
+            let count_tracker = db.get_table(consts::COUNT_TRACKER_TABLE, ExtAlloc::default())?;
+            let mut row = Row::new_in(ExtAlloc::default());
+            row.push(Value::Chars(consts::TRASH_TABLE.as_bytes()));
+            row.push(Value::Int(id + 1));
+            db.update_row(count_tracker, Value::Chars(consts::TRASH_TABLE.as_bytes()), row, ExtAlloc::default())?;
+            Ok(Some(entry))
+        })
+        .await
+    }
+
+    /// Everything in the trash.
+    pub async fn trash_entries(&self) -> Result<Vec<TrashEntry>, FsErr> {
+        self.with_root_dir(entries).await
+    }
+
+    /// Puts the trashed file `name` back where it was deleted from, with
+    /// its row. `None` if the trash has no such file; fails with
+    /// `FileAlreadyExists` if its old name has been taken since.
+    pub async fn restore(&self, name: &str) -> Result<Option<TrashEntry>, FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            let Some(entry) = entries(vm, root_dir)?.into_iter().find(|e| e.name.eq_ignore_ascii_case(name)) else {
+                return Ok(None);
+            };
+            let (_, dir) = category(entry.table.as_bytes()).unwrap();
+
+            let from = format!("{}/{}", consts::TRASH_DIR, entry.name);
+            DirGuard::new(vm, vm.open_dir(root_dir, consts::TRASH_DIR)?).find(&entry.name)?;
+            match DirGuard::new(vm, vm.open_dir(root_dir, dir)?).find(&entry.original) {
+                Ok(_) => return Err(FManError::SdErr(embedded_sdmmc::Error::FileAlreadyExists)),
+                Err(embedded_sdmmc::Error::NotFound) => (),
+                Err(e) => return Err(FManError::SdErr(e)),
+            }
+
+            {
+                let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+                let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+                let table = db.get_table(consts::TRASH_TABLE, ExtAlloc::default())?;
+                db.delete_from_table(table, Value::Chars(entry.name.as_bytes()), ExtAlloc::default())?;
+            }
+            move_entry(vm, root_dir, &from, &format!("{}/{}", dir, entry.original))?;
+            register(vm, root_dir, dir, &entry.original, &entry.upload_name, entry.size as u32, Some(entry.checksums))?;
+            Ok(Some(entry))
+        })
+        .await
+    }
+
+    /// Deletes the trashed file `name` for good. `false` if there is none.
+    pub async fn purge(&self, name: &str) -> Result<bool, FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            match entries(vm, root_dir)?.iter().find(|e| e.name.eq_ignore_ascii_case(name)) {
+                Some(entry) => purge_entry(vm, root_dir, entry).map(|_| true),
+                None => Ok(false),
+            }
+        })
+        .await
+    }
+
+    /// Empties the trash, returning how many files it held.
+    pub async fn purge_all(&self) -> Result<u32, FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            let entries = entries(vm, root_dir)?;
+            for entry in entries.iter() {
+                purge_entry(vm, root_dir, entry)?;
+            }
+            Ok(entries.len() as u32)
+        })
+        .await
+    }
+
+    /// Deletes what has been in the trash for `max_age_secs` or longer,
+    /// returning how many files that was. Nothing goes while the clock is
+    /// unset, nor what was deleted while it was.
+    pub async fn purge_older_than(&self, max_age_secs: u64) -> Result<u32, FsErr> {
+        let Some(now) = Clock::unix_secs() else {
+            return Ok(0);
+        };
+        self.with_root_dir(|vm, root_dir| {
+            let mut purged = 0;
+            for entry in entries(vm, root_dir)? {
+                if entry.deleted.is_some_and(|deleted| now.saturating_sub(deleted) >= max_age_secs) {
+                    purge_entry(vm, root_dir, &entry)?;
+                    purged += 1;
+                }
+            }
+            Ok(purged)
+        })
+        .await
+    }
+}
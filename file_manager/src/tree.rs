@@ -0,0 +1,374 @@
+//! Recursive operations on directory trees.
+//!
+//! [`FsVolumeManager`] allows only [`consts::MAX_OPEN_DIRS`] open
+//! directories, so trees are never walked by recursion: directories are visited one at a time,
+//! from a list of short paths, and reopened from the root each time.
+//! Every operation holds the state lock for its whole duration.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embedded_sdmmc::{DirEntry, Error, Mode, RawDirectory};
+use crate::checksum::recorded;
+use crate::ops::{check_reserved, close_unless, copy_file, move_entry, open_below, register, rename_file, retire, split_path, table_for_dir, FsErr};
+use crate::{consts, lfn, FManError, FileManager, FsVolumeManager};
+
+/// What a tree operation went through.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TreeStats {
+    pub files: u32,
+    pub dirs: u32,
+    pub bytes: u64,
+}
+
+/// The entries of a directory, as short names.
+#[derive(Default)]
+pub(crate) struct Listing {
+    pub(crate) files: Vec<(String, u32)>,
+    pub(crate) dirs: Vec<String>,
+}
+
+pub(crate) fn list(vm: &FsVolumeManager, dir: RawDirectory) -> Result<Listing, FsErr> {
+    let mut listing = Listing::default();
+    vm.iterate_dir(dir, |entry| {
+        if entry.attributes.is_volume() || entry.name.base_name() == b"." || entry.name.base_name() == b".." {
+            return;
+        }
+        let name = format!("{}", entry.name);
+        if entry.attributes.is_directory() {
+            listing.dirs.push(name);
+        } else {
+            listing.files.push((name, entry.size));
+        }
+    })?;
+    Ok(listing)
+}
+
+fn join(dir: &str, name: &str) -> String {
+    match dir.trim_matches('/') {
+        "" => String::from(name),
+        dir => format!("{}/{}", dir, name),
+    }
+}
+
+/// Whether the short path `path` is the directory `dir` or below it.
+fn is_within(dir: &str, path: &str) -> bool {
+    let (dir, path) = (dir.trim_matches('/'), path.trim_matches('/'));
+    path.eq_ignore_ascii_case(dir)
+        || path.len() > dir.len() && path[..dir.len()].eq_ignore_ascii_case(dir) && path.as_bytes()[dir.len()] == b'/'
+}
+
+/// Refuses to touch the directories the server keeps for itself, see
+/// [`check_reserved`], or to remove or move away the directories uploads
+/// go to.
+fn check_protected(short: &str, whole_dir_too: bool) -> Result<(), FsErr> {
+    let short = short.trim_matches('/');
+    check_reserved(short)?;
+    if whole_dir_too && (short.eq_ignore_ascii_case(consts::FILES_DIR) || short.eq_ignore_ascii_case(consts::MUSIC_DIR)) {
+        return Err("FILES and MUSIC cannot be removed".into());
+    }
+    Ok(())
+}
+
+/// The entry at the short path `short`, `None` for the root.
+fn stat(vm: &FsVolumeManager, root_dir: RawDirectory, short: &str) -> Result<Option<DirEntry>, FsErr> {
+    let (parent, name) = split_path(short);
+    if name.is_empty() {
+        return Ok(None);
+    }
+    let dir = open_below(vm, root_dir, parent)?;
+    let entry = vm.find_directory_entry(dir, name);
+    close_unless(vm, dir, root_dir);
+    Ok(Some(entry?))
+}
+
+/// Whether the entry at the short path `short` is a directory.
+fn is_dir(vm: &FsVolumeManager, root_dir: RawDirectory, short: &str) -> Result<bool, FsErr> {
+    Ok(stat(vm, root_dir, short)?.map_or(true, |entry| entry.attributes.is_directory()))
+}
+
+/// Name the file `name` of the directory `dir` gets in the category tables:
+/// its long name if it has one.
+pub(crate) fn display_name(vm: &FsVolumeManager, root_dir: RawDirectory, dir: &str, name: &str) -> Result<String, FsErr> {
+    let long = lfn::long_names(vm, root_dir, dir)?
+        .into_iter()
+        .find(|(short, _)| short.eq_ignore_ascii_case(name))
+        .map(|(_, long)| long);
+    Ok(long.unwrap_or_else(|| String::from(name)))
+}
+
+/// What the directory at the short path `short` holds, itself counted
+/// among the directories.
+pub(crate) fn measure_tree(vm: &FsVolumeManager, root_dir: RawDirectory, short: &str) -> Result<TreeStats, FsErr> {
+    let mut stats = TreeStats::default();
+    let mut pending = alloc::vec![String::from(short.trim_matches('/'))];
+    while let Some(dir) = pending.pop() {
+        let handle = open_below(vm, root_dir, &dir)?;
+        let listing = list(vm, handle);
+        close_unless(vm, handle, root_dir);
+        let listing = listing?;
+        stats.dirs += 1;
+        stats.files += listing.files.len() as u32;
+        stats.bytes += listing.files.iter().map(|(_, size)| *size as u64).sum::<u64>();
+        pending.extend(listing.dirs.iter().map(|sub| join(&dir, sub)));
+    }
+    Ok(stats)
+}
+
+/// Removes the empty directories `(parent, name)`, given by short paths,
+/// in order.
+pub(crate) fn remove_empty_dirs(vm: &FsVolumeManager, root_dir: RawDirectory, dirs: &[(&str, &str)]) -> Result<(), FsErr> {
+    for (parent, name) in dirs {
+        let dir = open_below(vm, root_dir, parent)?;
+        let removed = vm.delete_dir_in_dir(dir, *name);
+        close_unless(vm, dir, root_dir);
+        removed.map_err(|e| match e {
+            Error::DirNotEmpty => FManError::DirNotEmpty,
+            e => FManError::SdErr(e),
+        })?;
+    }
+    Ok(())
+}
+
+/// See [`FileManager::remove_tree`]; `short` is a short path.
+fn remove_tree(vm: &FsVolumeManager, root_dir: RawDirectory, short: &str) -> Result<TreeStats, FsErr> {
+    let mut stats = TreeStats::default();
+    let (parent, name) = split_path(short);
+    if name.is_empty() {
+        return Err("the root directory cannot be removed".into());
+    }
+    check_protected(short, true)?;
+
+    if !is_dir(vm, root_dir, short)? {
+        let dir = open_below(vm, root_dir, parent)?;
+        let removed = vm.find_directory_entry(dir, name)
+            .and_then(|entry| vm.delete_file_in_dir(dir, name).map(|_| entry.size));
+        close_unless(vm, dir, root_dir);
+        let size = removed?;
+        retire(vm, root_dir, parent, name)?;
+        lfn::forget(vm, root_dir, parent, name)?;
+        stats.files = 1;
+        stats.bytes = size as u64;
+        return Ok(stats);
+    }
+
+    // empty every directory of the tree, parents before children
+    let mut dirs = alloc::vec![String::from(short.trim_matches('/'))];
+    let mut next = 0;
+    while next < dirs.len() {
+        let dir = dirs[next].clone();
+        next += 1;
+
+        let handle = open_below(vm, root_dir, &dir)?;
+        let emptied = list(vm, handle).and_then(|listing| {
+            for (file, size) in listing.files.iter() {
+                vm.delete_file_in_dir(handle, file.as_str())?;
+                stats.files += 1;
+                stats.bytes += *size as u64;
+            }
+            Ok(listing)
+        });
+        close_unless(vm, handle, root_dir);
+        let listing = emptied?;
+
+        for (file, _) in listing.files.iter() {
+            retire(vm, root_dir, &dir, file)?;
+        }
+        for (alias, _) in lfn::long_names(vm, root_dir, &dir)? {
+            lfn::forget(vm, root_dir, &dir, &alias)?;
+        }
+        dirs.extend(listing.dirs.iter().map(|sub| join(&dir, sub)));
+    }
+
+    // then remove them, children before parents
+    let victims: Vec<(&str, &str)> = dirs.iter().rev().map(|d| split_path(d)).collect();
+    remove_empty_dirs(vm, root_dir, &victims)?;
+    stats.dirs = dirs.len() as u32;
+    lfn::forget(vm, root_dir, parent, name)?;
+    Ok(stats)
+}
+
+/// See [`FileManager::copy_tree`]; `from` and `to` are short paths and the
+/// parent of `to` exists.
+fn copy_tree(vm: &FsVolumeManager, root_dir: RawDirectory, from: &str, to: &str) -> Result<TreeStats, FsErr> {
+    let mut stats = TreeStats::default();
+    let (from, to) = (from.trim_matches('/'), to.trim_matches('/'));
+    let (src_parent, src_name) = split_path(from);
+    let (dst_parent, dst_name) = split_path(to);
+    if src_name.is_empty() || dst_name.is_empty() {
+        return Err("the root directory cannot be copied".into());
+    }
+    check_protected(from, false)?;
+    check_protected(to, false)?;
+    if is_within(from, to) {
+        return Err("a directory cannot be copied into itself".into());
+    }
+
+    if !is_dir(vm, root_dir, from)? {
+        let src_dir = open_below(vm, root_dir, src_parent)?;
+        let copied = vm.find_directory_entry(src_dir, src_name)
+            .map_err(FManError::SdErr)
+            .and_then(|entry| {
+                let dst_dir = open_below(vm, root_dir, dst_parent)?;
+                let copied = copy_file(vm, src_dir, src_name, dst_dir, dst_name, Mode::ReadWriteCreate);
+                close_unless(vm, dst_dir, root_dir);
+                copied.map(|_| entry.size)
+            });
+        close_unless(vm, src_dir, root_dir);
+        let size = copied?;
+        let upload_name = display_name(vm, root_dir, dst_parent, dst_name)?;
+        // the copy is only as good as the source was when uploaded
+        let checksums = match table_for_dir(src_parent) {
+            Some(table) => recorded(vm, root_dir, table, src_name)?.map(|r| r.checksums),
+            None => None,
+        };
+        register(vm, root_dir, dst_parent, dst_name, &upload_name, size, checksums)?;
+        stats.files = 1;
+        stats.bytes = size as u64;
+        return Ok(stats);
+    }
+
+    let dir = open_below(vm, root_dir, dst_parent)?;
+    let made = vm.make_dir_in_dir(dir, dst_name);
+    close_unless(vm, dir, root_dir);
+    made?;
+    stats.dirs = 1;
+
+    let mut pending = alloc::vec![(String::from(from), String::from(to))];
+    while let Some((src, dst)) = pending.pop() {
+        let src_dir = open_below(vm, root_dir, &src)?;
+        let copied = open_below(vm, root_dir, &dst).and_then(|dst_dir| {
+            let copied = list(vm, src_dir).and_then(|listing| {
+                for (file, size) in listing.files.iter() {
+                    copy_file(vm, src_dir, file, dst_dir, file, Mode::ReadWriteCreate)?;
+                    stats.files += 1;
+                    stats.bytes += *size as u64;
+                }
+                for sub in listing.dirs.iter() {
+                    vm.make_dir_in_dir(dst_dir, sub.as_str())?;
+                    stats.dirs += 1;
+                }
+                Ok(listing)
+            });
+            close_unless(vm, dst_dir, root_dir);
+            copied
+        });
+        close_unless(vm, src_dir, root_dir);
+        let listing = copied?;
+
+        for (alias, long) in lfn::long_names(vm, root_dir, &src)? {
+            lfn::remember(vm, root_dir, &dst, &alias, &long)?;
+        }
+        pending.extend(listing.dirs.iter().map(|sub| (join(&src, sub), join(&dst, sub))));
+    }
+    Ok(stats)
+}
+
+/// See [`FileManager::move_tree`]; `from` is a short path of `entry`, `to`
+/// the long path, which gets its alias only here.
+fn move_tree(vm: &FsVolumeManager, root_dir: RawDirectory, from: &str, to: &str, entry: &DirEntry) -> Result<TreeStats, FsErr> {
+    if !entry.attributes.is_directory() {
+        let to_short = lfn::short_path(vm, root_dir, to, true)?;
+        rename_file(vm, root_dir, from, &to_short, split_path(to).1)?;
+        return Ok(TreeStats { files: 1, dirs: 0, bytes: entry.size as u64 });
+    }
+
+    let stats = measure_tree(vm, root_dir, from)?;
+    let from = from.trim_matches('/');
+    let to_short = lfn::short_path(vm, root_dir, to, true)?;
+    move_entry(vm, root_dir, from, &to_short)?;
+    let (parent, name) = split_path(from);
+    lfn::forget(vm, root_dir, parent, name)?;
+    let to = to_short.trim_matches('/');
+
+    // the long names below are keyed by the short paths of their
+    // directories, which all changed
+    let mut pending = alloc::vec![String::from(to)];
+    while let Some(dir) = pending.pop() {
+        let old = format!("{}{}", from, &dir[to.len()..]);
+        let handle = open_below(vm, root_dir, &dir)?;
+        let listing = list(vm, handle);
+        close_unless(vm, handle, root_dir);
+        for (alias, long) in lfn::long_names(vm, root_dir, &old)? {
+            lfn::forget(vm, root_dir, &old, &alias)?;
+            lfn::remember(vm, root_dir, &dir, &alias, &long)?;
+        }
+        pending.extend(listing?.dirs.iter().map(|sub| join(&dir, sub)));
+    }
+    Ok(stats)
+}
+
+impl FileManager {
+    /// Removes the file or the whole directory tree at the long `path`,
+    /// dropping the long names, category table rows and old versions of what
+    /// it held.
+    ///
+    /// Files go first, directory by directory, then the empty directories,
+    /// children before parents. Should that fail, the emptied directories
+    /// are left in place.
+    pub async fn remove_tree(&self, path: &str) -> Result<TreeStats, FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            let short = lfn::short_path(vm, root_dir, path, false)?;
+            remove_tree(vm, root_dir, &short)
+        }).await
+    }
+
+    /// Copies the file or directory tree at the long `from` to `to`, whose
+    /// parent must exist and which itself must not. Long names are copied
+    /// along; files arriving in `FILES` or `MUSIC` are registered there.
+    /// A failed copy leaves what was copied so far.
+    pub async fn copy_tree(&self, from: &str, to: &str) -> Result<TreeStats, FsErr> {
+        self.with_root_dir(|vm, root_dir| {
+            let from = lfn::short_path(vm, root_dir, from, false)?;
+            if is_taken(vm, root_dir, to)? {
+                return Err(FManError::SdErr(Error::FileAlreadyExists));
+            }
+            let to = lfn::short_path(vm, root_dir, to, true)?;
+            copy_tree(vm, root_dir, &from, &to)
+        }).await
+    }
+
+    /// Moves the file or directory tree at the long `from` to `to`, whose
+    /// parent must exist and which itself must not. Only directory entries
+    /// are rewritten: a file goes like [`FileManager::rename`], a directory
+    /// takes everything below it along, long names included.
+    pub async fn move_tree(&self, from: &str, to: &str) -> Result<TreeStats, FsErr> {
+        let (to_parent, to_name) = split_path(to);
+        self.with_root_dir(|vm, root_dir| {
+            let from_short = lfn::short_path(vm, root_dir, from, false)?;
+            let entry = stat(vm, root_dir, &from_short)?;
+            if is_taken(vm, root_dir, to)? {
+                return Err(FManError::SdErr(Error::FileAlreadyExists));
+            }
+            let to_parent_short = lfn::short_path(vm, root_dir, to_parent, false)?;
+            if !is_dir(vm, root_dir, &to_parent_short)? {
+                return Err(FManError::SdErr(Error::OpenedFileAsDir));
+            }
+            let Some(entry) = entry.filter(|_| !to_name.is_empty()) else {
+                return Err("the root directory cannot be moved".into());
+            };
+            check_protected(&from_short, true)?;
+            // an alias never matches a protected name, an 8.3 name is itself
+            check_protected(&join(&to_parent_short, &to_name.to_ascii_uppercase()), false)?;
+            if entry.attributes.is_directory() && is_within(&from_short, &to_parent_short) {
+                return Err("a directory cannot be moved into itself".into());
+            }
+
+            move_tree(vm, root_dir, &from_short, to, &entry)
+        }).await
+    }
+}
+
+/// Whether anything exists at the long `path`.
+fn is_taken(vm: &FsVolumeManager, root_dir: RawDirectory, path: &str) -> Result<bool, FsErr> {
+    let short = match lfn::short_path(vm, root_dir, path, false) {
+        Ok(short) => short,
+        Err(FManError::SdErr(Error::NotFound)) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    match is_dir(vm, root_dir, &short) {
+        Ok(_) => Ok(true),
+        Err(FManError::SdErr(Error::NotFound)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
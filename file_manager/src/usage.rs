@@ -0,0 +1,66 @@
+//! How full the card is.
+//!
+//! The free clusters come from the count `embedded_sdmmc` keeps: the FAT32
+//! info sector, or else one scan of the FAT after mounting, updated on
+//! every write and delete since.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use embedded_sdmmc::Error;
+use crate::ops::FsErr;
+use crate::tree::{list, measure_tree, TreeStats};
+use crate::{FManError, FileManager, FileManagerState};
+
+/// Space of a volume in bytes, see [`FileManagerState::capacity`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    /// Size of the data area.
+    pub total: u64,
+    /// Size of the free clusters.
+    pub free: u64,
+    pub cluster_size: u32,
+}
+
+/// How full volume 0 is, see [`FileManager::disk_usage`].
+#[derive(Debug, Clone)]
+pub struct DiskUsage {
+    pub capacity: Capacity,
+    /// What each top-level directory holds, by short name.
+    pub dirs: Vec<(String, TreeStats)>,
+}
+
+impl FileManagerState {
+    /// Space of the mounted volume `index`.
+    pub fn capacity(&self, index: usize) -> Result<Capacity, FsErr> {
+        let vm = self.vm()?;
+        let raw = self.raw_volume(index).ok_or(FManError::SdErr(Error::NoSuchVolume))?;
+        let stats = vm.volume_stats(raw)?;
+        let cluster_size = stats.bytes_per_cluster;
+        Ok(Capacity {
+            total: stats.cluster_count as u64 * cluster_size as u64,
+            free: vm.free_clusters(raw)? as u64 * cluster_size as u64,
+            cluster_size,
+        })
+    }
+}
+
+impl FileManager {
+    /// Space of volume 0 and what its top-level directories hold. Walks
+    /// every directory of the volume under the state lock.
+    pub async fn disk_usage(&self) -> Result<DiskUsage, FsErr> {
+        let dirs = self.with_root_dir(|vm, root_dir| {
+            list(vm, root_dir)?
+                .dirs
+                .into_iter()
+                .map(|dir| measure_tree(vm, root_dir, &dir).map(|stats| (dir, stats)))
+                .collect::<Result<Vec<_>, _>>()
+        }).await?;
+        let capacity = self.state.lock().await.capacity(0)?;
+        Ok(DiskUsage { capacity, dirs })
+    }
+
+    /// Bytes free on the mounted volume `index`.
+    pub async fn free_space(&self, index: usize) -> Result<u64, FsErr> {
+        Ok(self.state.lock().await.capacity(index)?.free)
+    }
+}
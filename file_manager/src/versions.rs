@@ -0,0 +1,310 @@
+//! Older versions of uploaded files.
+//!
+//! An upload under a name the `files` table already has becomes the new
+//! version of that file instead of a file of its own, see
+//! [`FileManager::supersede`]. The content it replaces moves to
+//! `VERSIONS/<id>/<n>.<ext>`, `<id>` being the id of the file and `<n>` the
+//! number of that version, and gets a row in the `versions` table. Of the
+//! old versions of a file only the newest [`consts::MAX_VERSIONS`] are kept.
+//! The version in `FILES` is numbered one past the newest old one.
+//!
+//! Versions are moved between `FILES` and `VERSIONS`, never copied. A row
+//! is dropped before its file moves away and added once it has arrived, so
+//! a crash halfway leaves at worst a file without a row, which
+//! [`crate::fsck`] registers in `FILES` and the next archive replaces in
+//! `VERSIONS`, rather than a row for content that is not there.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Query, QueryExecutor, Row, Value};
+use embedded_sdmmc::RawDirectory;
+use crate::checksum::{recorded, Checksums, Recorded};
+use crate::clock::Clock;
+use crate::ops::{move_entry, register, unregister, FsErr};
+use crate::{consts, DirGuard, ExtAlloc, FManError, FileManager, FsVolumeManager};
+
+/// An old version of an uploaded file.
+#[derive(Debug, Clone)]
+pub struct Version {
+    /// Short name of the file in `FILES`.
+    pub name: String,
+    pub version: u32,
+    pub size: i64,
+    pub checksums: Checksums,
+    /// Unix time a newer version replaced it, unless the clock was unset.
+    pub replaced: Option<u64>,
+}
+
+/// The id and extension of the short name `name`.
+fn split_name(name: &str) -> (&str, &str) {
+    name.split_once('.').unwrap_or((name, ""))
+}
+
+/// Short path of the directory holding the old versions of `name`.
+pub fn version_dir(name: &str) -> String {
+    format!("{}/{}", consts::VERSIONS_DIR, split_name(name).0)
+}
+
+/// Short name of `version` of `name` in [`version_dir`].
+pub fn version_file(name: &str, version: u32) -> String {
+    match split_name(name).1 {
+        "" => format!("{}", version),
+        ext => format!("{}.{}", version, ext),
+    }
+}
+
+/// The old versions of `name`, oldest first.
+fn versions_of(vm: &FsVolumeManager, root_dir: RawDirectory, name: &str) -> Result<Vec<Version>, FsErr> {
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(consts::VERSIONS_TABLE, ExtAlloc::default())?;
+
+    let mut versions = Vec::new();
+    let query = Query::<_, &str>::new(table, ExtAlloc::default());
+    // an empty table has no pages to run a query over
+    if let Ok(mut exec) = QueryExecutor::new(
+        query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+        &db.file_handler.page_rw.as_ref().unwrap()
+    ) {
+        while let Ok(row) = exec.next() {
+            if !row[1].to_chars().unwrap().eq_ignore_ascii_case(name.as_bytes()) {
+                continue;
+            }
+            versions.push(Version {
+                name: String::from_utf8_lossy(row[1].to_chars().unwrap()).into_owned(),
+                version: row[2].to_int().unwrap() as u32,
+                size: row[3].to_int().unwrap(),
+                checksums: Checksums {
+                    crc32: row[4].to_int().unwrap() as u32,
+                    sha256: Checksums::parse_sha256(row[5].to_chars().unwrap()).unwrap_or([0; 32]),
+                },
+                replaced: Some(row[6].to_int().unwrap() as u64).filter(|secs| *secs != 0),
+            });
+        }
+    }
+    versions.sort_by_key(|v| v.version);
+    Ok(versions)
+}
+
+/// Number of the version of `name` in `FILES`, given its old `versions`.
+fn current_version(versions: &[Version]) -> u32 {
+    versions.last().map_or(1, |v| v.version + 1)
+}
+
+/// Another file of the `files` table uploaded as `upload_name`, if any.
+fn uploaded_as(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    upload_name: &str,
+    except: &str,
+) -> Result<Option<String>, FsErr> {
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(consts::FILES_TABLE, ExtAlloc::default())?;
+
+    let query = Query::<_, &str>::new(table, ExtAlloc::default());
+    if let Ok(mut exec) = QueryExecutor::new(
+        query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+        &db.file_handler.page_rw.as_ref().unwrap()
+    ) {
+        while let Ok(row) = exec.next() {
+            let key = row[0].to_chars().unwrap();
+            if row[1].to_chars().unwrap() == upload_name.as_bytes() && !key.eq_ignore_ascii_case(except.as_bytes()) {
+                return Ok(Some(String::from_utf8_lossy(key).into_owned()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Short path of `name` in `FILES`.
+fn file_path(name: &str) -> String {
+    format!("{}/{}", consts::FILES_DIR, name)
+}
+
+/// Short path of `version` of `name` in `VERSIONS`.
+fn version_path(name: &str, version: u32) -> String {
+    format!("{}/{}", version_dir(name), version_file(name, version))
+}
+
+/// Moves the content `name` has in `FILES`, as `recorded`, to its old
+/// `version` and records it there. The row of `name` must be gone already.
+fn archive(
+    vm: &FsVolumeManager,
+    root_dir: RawDirectory,
+    name: &str,
+    recorded: &Recorded,
+    version: u32,
+) -> Result<(), FsErr> {
+    {
+        let versions = DirGuard::new(vm, vm.open_dir(root_dir, consts::VERSIONS_DIR)?);
+        match versions.make_dir(split_name(name).0) {
+            Ok(()) | Err(embedded_sdmmc::Error::DirAlreadyExists) => (),
+            Err(e) => return Err(FManError::SdErr(e)),
+        }
+        // left behind by a crash before its row was added
+        match versions.open_dir(split_name(name).0)?.delete_file(&version_file(name, version)) {
+            Ok(()) | Err(embedded_sdmmc::Error::NotFound) => (),
+            Err(e) => return Err(FManError::SdErr(e)),
+        }
+    }
+    move_entry(vm, root_dir, &file_path(name), &version_path(name, version))?;
+
+    let key = format!("{}/{}", name, version);
+    let sha256 = recorded.checksums.sha256_hex();
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(consts::VERSIONS_TABLE, ExtAlloc::default())?;
+    let row = || {
+        let mut row = Row::new_in(ExtAlloc::default());
+        row.push(Value::Chars(key.as_bytes()));
+        row.push(Value::Chars(name.as_bytes()));
+        row.push(Value::Int(version as i64));
+        row.push(Value::Int(recorded.size));
+        row.push(Value::Int(recorded.checksums.crc32 as i64));
+        row.push(Value::Chars(sha256.as_bytes()));
+        row.push(Value::Int(Clock::unix_secs().unwrap_or(0) as i64));
+        row
+    };
+    match db.insert_to_table(table, row(), ExtAlloc::default()) {
+        Err(alpa::db::Error::DuplicateKey) => {
+            db.update_row(table, Value::Chars(key.as_bytes()), row(), ExtAlloc::default())?;
+        },
+        other => other?,
+    }
+    Ok(())
+}
+
+/// Drops the row of the old `version` of `name`.
+fn unrecord(vm: &FsVolumeManager, root_dir: RawDirectory, name: &str, version: u32) -> Result<(), FsErr> {
+    let key = format!("{}/{}", name, version);
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(consts::VERSIONS_TABLE, ExtAlloc::default())?;
+    db.delete_from_table(table, Value::Chars(key.as_bytes()), ExtAlloc::default())?;
+    Ok(())
+}
+
+/// Deletes the old versions of `name` past the newest `keep`.
+fn prune(vm: &FsVolumeManager, root_dir: RawDirectory, name: &str, keep: usize) -> Result<(), FsErr> {
+    let versions = versions_of(vm, root_dir, name)?;
+    let drop_count = versions.len().saturating_sub(keep);
+    if drop_count == 0 {
+        return Ok(());
+    }
+
+    let dir = match vm.open_dir(root_dir, consts::VERSIONS_DIR).and_then(|versions| {
+        let dir = vm.open_dir(versions, split_name(name).0);
+        let _ = vm.close_dir(versions);
+        dir
+    }) {
+        Ok(dir) => Some(DirGuard::new(vm, dir)),
+        Err(embedded_sdmmc::Error::NotFound) => None,
+        Err(e) => return Err(FManError::SdErr(e)),
+    };
+    if let Some(ref dir) = dir {
+        for old in &versions[..drop_count] {
+            match dir.delete_file(&version_file(name, old.version)) {
+                Ok(()) | Err(embedded_sdmmc::Error::NotFound) => (),
+                Err(e) => return Err(FManError::SdErr(e)),
+            }
+        }
+    }
+    drop(dir);
+
+    let db_dir = vm.open_dir(root_dir, consts::DB_DIR)?;
+    let mut db = Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+    let table = db.get_table(consts::VERSIONS_TABLE, ExtAlloc::default())?;
+    for old in &versions[..drop_count] {
+        let key = format!("{}/{}", old.name, old.version);
+        db.delete_from_table(table, Value::Chars(key.as_bytes()), ExtAlloc::default())?;
+    }
+    Ok(())
+}
+
+/// Deletes every old version of `name`, once the file itself is gone for
+/// good.
+pub(crate) fn drop_versions(vm: &FsVolumeManager, root_dir: RawDirectory, name: &str) -> Result<(), FsErr> {
+    prune(vm, root_dir, name, 0)
+}
+
+impl FileManager {
+    /// Makes the just uploaded file `name` of the `files` table the new
+    /// version of the file uploaded under the same name before, if there is
+    /// one, returning the short name and version number it ended up with.
+    /// `None` if `name` is not registered (yet).
+    pub async fn supersede(&self, name: &str) -> Result<Option<(String, u32)>, FsErr> {
+        let name = name.to_ascii_uppercase();
+        self.with_root_dir(|vm, root_dir| {
+            let Some(upload) = recorded(vm, root_dir, consts::FILES_TABLE, &name)? else {
+                return Ok(None);
+            };
+            let Some(previous) = uploaded_as(vm, root_dir, &upload.upload_name, &name)? else {
+                return Ok(Some((name, 1)));
+            };
+            let Some(replaced) = recorded(vm, root_dir, consts::FILES_TABLE, &previous)? else {
+                return Ok(Some((name, 1)));
+            };
+
+            let version = current_version(&versions_of(vm, root_dir, &previous)?);
+            unregister(vm, root_dir, consts::FILES_DIR, &previous)?;
+            archive(vm, root_dir, &previous, &replaced, version)?;
+            unregister(vm, root_dir, consts::FILES_DIR, &name)?;
+            move_entry(vm, root_dir, &file_path(&name), &file_path(&previous))?;
+            register(
+                vm, root_dir, consts::FILES_DIR, &previous,
+                &upload.upload_name, upload.size as u32, Some(upload.checksums),
+            )?;
+            prune(vm, root_dir, &previous, consts::MAX_VERSIONS)?;
+            Ok(Some((previous, version + 1)))
+        })
+        .await
+    }
+
+    /// The old versions of the uploaded file `name`, oldest first, and the
+    /// number of the version in `FILES`. `None` if there is no such upload.
+    pub async fn versions(&self, name: &str) -> Result<Option<(Vec<Version>, u32)>, FsErr> {
+        let name = name.to_ascii_uppercase();
+        self.with_root_dir(|vm, root_dir| {
+            if recorded(vm, root_dir, consts::FILES_TABLE, &name)?.is_none() {
+                return Ok(None);
+            }
+            let versions = versions_of(vm, root_dir, &name)?;
+            let current = current_version(&versions);
+            Ok(Some((versions, current)))
+        })
+        .await
+    }
+
+    /// Brings back `version` of the uploaded file `name` as its newest,
+    /// keeping what it holds now as an old version in its place, and returns
+    /// the number of the new version. `None` if there is no such version.
+    pub async fn rollback(&self, name: &str, version: u32) -> Result<Option<u32>, FsErr> {
+        let name = name.to_ascii_uppercase();
+        self.with_root_dir(|vm, root_dir| {
+            let Some(current) = recorded(vm, root_dir, consts::FILES_TABLE, &name)? else {
+                return Ok(None);
+            };
+            let versions = versions_of(vm, root_dir, &name)?;
+            let Some(target) = versions.iter().find(|v| v.version == version) else {
+                return Ok(None);
+            };
+
+            let current_version = current_version(&versions);
+            unregister(vm, root_dir, consts::FILES_DIR, &name)?;
+            archive(vm, root_dir, &name, &current, current_version)?;
+            unrecord(vm, root_dir, &name, version)?;
+            move_entry(vm, root_dir, &version_path(&name, version), &file_path(&name))?;
+            register(
+                vm, root_dir, consts::FILES_DIR, &name,
+                &current.upload_name, target.size as u32, Some(target.checksums),
+            )?;
+            prune(vm, root_dir, &name, consts::MAX_VERSIONS)?;
+            Ok(Some(current_version + 1))
+        })
+        .await
+    }
+}
@@ -0,0 +1,81 @@
+//! The partitions of the card.
+//!
+//! Volume 0 carries the DB, the long names and everything the server
+//! keeps; it is the `vol` of [`CardState::Active`]. Any further FAT
+//! partition is mounted next to it and reached through a `/vol<n>` prefix,
+//! e.g. `/vol1/BACKUP/NOTES.TXT`, with 8.3 names only.
+
+use alloc::vec::Vec;
+use embedded_sdmmc::fat::FatType;
+use embedded_sdmmc::{Block, RawVolume, VolumeIdx};
+use crate::{CardState, FileManager, FileManagerState, FsVolumeManager};
+
+/// A partition of the card, see [`FileManager::volumes`].
+#[derive(Debug, Clone)]
+pub struct VolumeInfo {
+    /// Position in the partition table.
+    pub index: usize,
+    pub partition_type: u8,
+    /// `None` for partitions that are not FAT16/FAT32.
+    pub fat_type: Option<FatType>,
+    /// Size of the partition in bytes.
+    pub size: u64,
+    /// The mounted volume, if it could be mounted.
+    pub raw: Option<RawVolume>,
+}
+
+/// Lists the partitions of the card behind `vm` and mounts the FAT ones
+/// other than volume 0, which `primary` already is.
+pub(crate) fn open_all(vm: &FsVolumeManager, primary: RawVolume) -> Vec<VolumeInfo> {
+    (0..4)
+        .filter_map(|index| Some((index, vm.partition(VolumeIdx(index)).ok()??)))
+        .map(|(index, partition)| {
+            let raw = match index {
+                0 => Some(primary),
+                _ if partition.is_fat() => vm.open_raw_volume(VolumeIdx(index)).ok(),
+                _ => None,
+            };
+            VolumeInfo {
+                index,
+                partition_type: partition.system_id,
+                fat_type: raw.and_then(|raw| vm.volume_stats(raw).ok()).map(|stats| stats.fat_type),
+                size: partition.num_blocks.0 as u64 * Block::LEN as u64,
+                raw,
+            }
+        })
+        .collect()
+}
+
+impl FileManagerState {
+    /// The mounted volume `index`.
+    pub fn raw_volume(&self, index: usize) -> Option<RawVolume> {
+        match self.card_state {
+            CardState::Active { ref vol, .. } if index == 0 => Some(*vol),
+            CardState::Active { .. } => self.volumes.iter().find(|v| v.index == index).and_then(|v| v.raw),
+            _ => None,
+        }
+    }
+
+    /// Splits the `/vol<n>` prefix of a mounted volume other than volume 0
+    /// off the long `path`. Any other path is on volume 0, so a directory
+    /// named like that stays reachable while no such volume is mounted.
+    pub fn volume_path<'p>(&self, path: &'p str) -> (usize, &'p str) {
+        let trimmed = path.trim_start_matches('/');
+        let (first, rest) = trimmed.split_once('/').unwrap_or((trimmed, ""));
+        let index = first.get(..3)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("vol"))
+            .and_then(|_| first[3..].parse::<usize>().ok());
+
+        match index {
+            Some(i) if i != 0 && self.raw_volume(i).is_some() => (i, rest),
+            _ => (0, path),
+        }
+    }
+}
+
+impl FileManager {
+    /// The partitions of the mounted card; empty while there is none.
+    pub async fn volumes(&self) -> Vec<VolumeInfo> {
+        self.state.lock().await.volumes.clone()
+    }
+}
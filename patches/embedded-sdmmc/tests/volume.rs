@@ -0,0 +1,207 @@
+//! Volume related tests
+
+mod utils;
+
+#[test]
+fn open_all_volumes() {
+    let time_source = utils::make_time_source();
+    let disk = utils::make_block_device(utils::DISK_SOURCE).unwrap();
+    let volume_mgr: embedded_sdmmc::VolumeManager<
+        utils::RamDisk<Vec<u8>>,
+        utils::TestTimeSource,
+        4,
+        4,
+        2,
+    > = embedded_sdmmc::VolumeManager::new_with_limits(disk, time_source, 0x1000_0000);
+
+    // Open Volume 0
+    let fat16_volume = volume_mgr
+        .open_raw_volume(embedded_sdmmc::VolumeIdx(0))
+        .expect("open volume 0");
+
+    // Fail to Open Volume 0 again
+    assert!(matches!(
+        volume_mgr.open_raw_volume(embedded_sdmmc::VolumeIdx(0)),
+        Err(embedded_sdmmc::Error::VolumeAlreadyOpen)
+    ));
+
+    volume_mgr.close_volume(fat16_volume).expect("close fat16");
+
+    // Open Volume 1
+    let fat32_volume = volume_mgr
+        .open_raw_volume(embedded_sdmmc::VolumeIdx(1))
+        .expect("open volume 1");
+
+    // Fail to Volume 1 again
+    assert!(matches!(
+        volume_mgr.open_raw_volume(embedded_sdmmc::VolumeIdx(1)),
+        Err(embedded_sdmmc::Error::VolumeAlreadyOpen)
+    ));
+
+    // Open Volume 0 again
+    let fat16_volume = volume_mgr
+        .open_raw_volume(embedded_sdmmc::VolumeIdx(0))
+        .expect("open volume 0");
+
+    // Open any volume - too many volumes (0 and 1 are open)
+    assert!(matches!(
+        volume_mgr.open_raw_volume(embedded_sdmmc::VolumeIdx(0)),
+        Err(embedded_sdmmc::Error::TooManyOpenVolumes)
+    ));
+
+    volume_mgr.close_volume(fat16_volume).expect("close fat16");
+    volume_mgr.close_volume(fat32_volume).expect("close fat32");
+
+    // This isn't a valid volume
+    assert!(matches!(
+        volume_mgr.open_raw_volume(embedded_sdmmc::VolumeIdx(2)),
+        Err(embedded_sdmmc::Error::FormatError(_e))
+    ));
+
+    // This isn't a valid volume
+    assert!(matches!(
+        volume_mgr.open_raw_volume(embedded_sdmmc::VolumeIdx(3)),
+        Err(embedded_sdmmc::Error::FormatError(_e))
+    ));
+
+    // This isn't a valid volume
+    assert!(matches!(
+        volume_mgr.open_raw_volume(embedded_sdmmc::VolumeIdx(9)),
+        Err(embedded_sdmmc::Error::NoSuchVolume)
+    ));
+
+    let _root_dir = volume_mgr.open_root_dir(fat32_volume).expect("Open dir");
+
+    assert!(matches!(
+        volume_mgr.close_volume(fat32_volume),
+        Err(embedded_sdmmc::Error::VolumeStillInUse)
+    ));
+}
+
+#[test]
+fn close_volume_too_early() {
+    let time_source = utils::make_time_source();
+    let disk = utils::make_block_device(utils::DISK_SOURCE).unwrap();
+    let volume_mgr = embedded_sdmmc::VolumeManager::new(disk, time_source);
+
+    let volume = volume_mgr
+        .open_raw_volume(embedded_sdmmc::VolumeIdx(0))
+        .expect("open volume 0");
+    let root_dir = volume_mgr.open_root_dir(volume).expect("open root dir");
+
+    // Dir open
+    assert!(matches!(
+        volume_mgr.close_volume(volume),
+        Err(embedded_sdmmc::Error::VolumeStillInUse)
+    ));
+
+    let _test_file = volume_mgr
+        .open_file_in_dir(root_dir, "64MB.DAT", embedded_sdmmc::Mode::ReadOnly)
+        .expect("open test file");
+
+    volume_mgr.close_dir(root_dir).unwrap();
+
+    // File open, not dir open
+    assert!(matches!(
+        volume_mgr.close_volume(volume),
+        Err(embedded_sdmmc::Error::VolumeStillInUse)
+    ));
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
+
+#[test]
+fn partitions_and_stats() {
+    let time_source = utils::make_time_source();
+    let disk = utils::make_block_device(utils::DISK_SOURCE).unwrap();
+    let volume_mgr = embedded_sdmmc::VolumeManager::new(disk, time_source);
+
+    let partition = volume_mgr
+        .partition(embedded_sdmmc::VolumeIdx(1))
+        .expect("read partition 1")
+        .expect("partition 1 is used");
+    assert!(partition.is_fat());
+    assert!(partition.num_blocks.0 > 0);
+    assert_eq!(
+        volume_mgr.partition(embedded_sdmmc::VolumeIdx(2)).unwrap(),
+        None
+    );
+    assert!(matches!(
+        volume_mgr.partition(embedded_sdmmc::VolumeIdx(4)),
+        Err(embedded_sdmmc::Error::NoSuchVolume)
+    ));
+
+    let fat32_volume = volume_mgr
+        .open_raw_volume(embedded_sdmmc::VolumeIdx(1))
+        .expect("open volume 1");
+    let stats = volume_mgr.volume_stats(fat32_volume).unwrap();
+    assert_eq!(stats.fat_type, embedded_sdmmc::fat::FatType::Fat32);
+    assert!(stats.bytes_per_cluster >= 512);
+    let free = volume_mgr.free_clusters(fat32_volume).unwrap();
+    assert!(free > 0 && free < stats.cluster_count);
+
+    // Writing a file takes clusters, deleting it gives them back
+    let root_dir = volume_mgr.open_root_dir(fat32_volume).unwrap();
+    let file = volume_mgr
+        .open_file_in_dir(root_dir, "BIG.DAT", embedded_sdmmc::Mode::ReadWriteCreate)
+        .unwrap();
+    let data = vec![0xAA; stats.bytes_per_cluster as usize * 3];
+    volume_mgr.write(file, &data).unwrap();
+    volume_mgr.close_file(file).unwrap();
+    assert_eq!(volume_mgr.free_clusters(fat32_volume).unwrap(), free - 3);
+
+    volume_mgr.close_dir(root_dir).unwrap();
+    volume_mgr.close_volume(fat32_volume).unwrap();
+}
+
+#[test]
+fn free_clusters_kept_up_to_date() {
+    let time_source = utils::make_time_source();
+    let disk = utils::make_block_device(utils::DISK_SOURCE).unwrap();
+    let volume_mgr = embedded_sdmmc::VolumeManager::new(disk, time_source);
+
+    // FAT16 has no info sector, so this counts
+    let fat16_volume = volume_mgr
+        .open_raw_volume(embedded_sdmmc::VolumeIdx(0))
+        .expect("open volume 0");
+    let stats = volume_mgr.volume_stats(fat16_volume).unwrap();
+    let free = volume_mgr.free_clusters(fat16_volume).unwrap();
+
+    let root_dir = volume_mgr.open_root_dir(fat16_volume).unwrap();
+    let cluster = stats.bytes_per_cluster as usize;
+    for (name, clusters) in [("ONE.DAT", 3), ("TWO.DAT", 2)] {
+        let file = volume_mgr
+            .open_file_in_dir(root_dir, name, embedded_sdmmc::Mode::ReadWriteCreate)
+            .unwrap();
+        volume_mgr
+            .write(file, &vec![0x55; cluster * clusters])
+            .unwrap();
+        volume_mgr.close_file(file).unwrap();
+    }
+    assert_eq!(volume_mgr.free_clusters(fat16_volume).unwrap(), free - 5);
+
+    // Deleting gives all of them back, shortening the ones past the end
+    volume_mgr.delete_file_in_dir(root_dir, "ONE.DAT").unwrap();
+    assert_eq!(volume_mgr.free_clusters(fat16_volume).unwrap(), free - 2);
+    let file = volume_mgr
+        .open_file_in_dir(root_dir, "TWO.DAT", embedded_sdmmc::Mode::ReadWriteAppend)
+        .unwrap();
+    volume_mgr.file_seek_from_start(file, 1).unwrap();
+    volume_mgr.truncate_file(file).unwrap();
+    volume_mgr.close_file(file).unwrap();
+    let cached = volume_mgr.free_clusters(fat16_volume).unwrap();
+    assert_eq!(cached, free - 1);
+
+    // and a fresh count agrees
+    volume_mgr.close_dir(root_dir).unwrap();
+    volume_mgr.close_volume(fat16_volume).unwrap();
+    let fat16_volume = volume_mgr
+        .open_raw_volume(embedded_sdmmc::VolumeIdx(0))
+        .expect("open volume 0");
+    assert_eq!(volume_mgr.free_clusters(fat16_volume).unwrap(), cached);
+    volume_mgr.close_volume(fat16_volume).unwrap();
+}
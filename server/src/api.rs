@@ -0,0 +1,432 @@
+use embedded_sdmmc::fat::FatType;
+use embedded_sdmmc::{BlockDevice, DirEntry, Timestamp};
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Query, QueryExecutor};
+use picoserve::response::{IntoResponse, Response, StatusCode};
+use picoserve::response::chunked::{ChunksWritten, ChunkedResponse, ChunkWriter, Chunks};
+use allocator_api2::vec::Vec;
+use file_manager::{
+    get_file_manager,
+    AsyncRootFn,
+    consts,
+    DirGuard,
+    ExtAlloc,
+    FileType,
+    FManError,
+    FsBlockDevice,
+    FsckReport,
+    Issue
+};
+use alloc::format;
+use crate::{fs_path, ConcreteFMan, FileResult, String};
+
+/// Appends `s` to `buf` as a quoted JSON string.
+pub(crate) fn push_json_str<A: allocator_api2::alloc::Allocator>(buf: &mut Vec<u8, A>, s: &[u8]) {
+    buf.push(b'"');
+    for &b in s {
+        match b {
+            b'"' => buf.extend_from_slice(b"\\\""),
+            b'\\' => buf.extend_from_slice(b"\\\\"),
+            b'\n' => buf.extend_from_slice(b"\\n"),
+            b'\r' => buf.extend_from_slice(b"\\r"),
+            b'\t' => buf.extend_from_slice(b"\\t"),
+            b if b < 0x20 => buf.extend_from_slice(format!("\\u{:04x}", b).as_bytes()),
+            b => buf.push(b),
+        }
+    }
+    buf.push(b'"');
+}
+
+pub(crate) fn iso_timestamp(ts: &Timestamp) -> String {
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        1970 + ts.year_since_1970 as u32,
+        ts.zero_indexed_month + 1,
+        ts.zero_indexed_day + 1,
+        ts.hours, ts.minutes, ts.seconds
+    )
+}
+
+/// Serializes a directory entry as a JSON object into `buf`. Its `name` is
+/// the long name found in `names`, else the 8.3 name.
+fn push_entry<A: allocator_api2::alloc::Allocator>(buf: &mut Vec<u8, A>, entry: &DirEntry, names: &[(String, String)]) {
+    let is_dir = entry.attributes.is_directory();
+
+    let short = format!("{}", entry.name);
+    let name = names.iter()
+        .find(|(s, _)| *s == short)
+        .map_or(short.as_str(), |(_, long)| long.as_str());
+
+    buf.extend_from_slice(b"{\"name\":");
+    push_json_str(buf, name.as_bytes());
+    buf.extend_from_slice(b",\"short_name\":");
+    push_json_str(buf, short.as_bytes());
+    buf.extend_from_slice(b",\"extension\":");
+    push_json_str(buf, entry.name.extension());
+    buf.extend_from_slice(format!(
+        ",\"size\":{},\"is_dir\":{},\"attributes\":{{\"read_only\":{},\"hidden\":{},\"system\":{},\"archive\":{}}},\"modified\":\"{}\",\"created\":\"{}\"}}",
+        entry.size,
+        is_dir,
+        entry.attributes.is_read_only(),
+        entry.attributes.is_hidden(),
+        entry.attributes.is_system(),
+        entry.attributes.is_archive(),
+        iso_timestamp(&entry.mtime),
+        iso_timestamp(&entry.ctime),
+    ).as_bytes());
+}
+
+pub struct FsJsonChunks {
+    pub file: FileResult,
+    /// `(short name, long name)` pairs of the entries that have a long name.
+    pub names: alloc::vec::Vec<(String, String)>,
+}
+
+impl Chunks for FsJsonChunks {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut chunk_writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        match self.file {
+            Ok(opened) => {
+                match *opened.entry() {
+                    FileType::Dir(dir) => {
+                        let mut entries: Vec<Vec<u8, ExtAlloc>, ExtAlloc> = Vec::new_in(ExtAlloc::default());
+                        let listed = opened.with_vm(|vm| vm.iterate_dir(dir, |entry| {
+                            if entry.attributes.is_volume() || entry.name.base_name() == b"." || entry.name.base_name() == b".." {
+                                return;
+                            }
+                            let mut buf = Vec::new_in(ExtAlloc::default());
+                            push_entry(&mut buf, entry, &self.names);
+                            entries.push(buf);
+                        })).await.and_then(|listed| listed.map_err(FManError::SdErr));
+
+                        chunk_writer.write_chunk(b"{\"entries\":[").await?;
+                        for (i, e) in entries.iter().enumerate() {
+                            if i > 0 {
+                                chunk_writer.write_chunk(b",").await?;
+                            }
+                            chunk_writer.write_chunk(e).await?;
+                        }
+                        chunk_writer.write_chunk(b"]").await?;
+                        if let Err(e) = listed {
+                            let mut buf = Vec::new_in(ExtAlloc::default());
+                            buf.extend_from_slice(b",\"error\":");
+                            push_json_str(&mut buf, format!("{:?}", e).as_bytes());
+                            chunk_writer.write_chunk(&buf).await?;
+                        }
+                        chunk_writer.write_chunk(b"}").await?;
+                    },
+                    FileType::File(ref entry, _) => {
+                        let mut buf = Vec::new_in(ExtAlloc::default());
+                        buf.extend_from_slice(b"{\"entry\":");
+                        push_entry(&mut buf, entry, &self.names);
+                        buf.push(b'}');
+                        chunk_writer.write_chunk(&buf).await?;
+                    }
+                }
+            },
+            Err(e) => {
+                let mut buf = Vec::new_in(ExtAlloc::default());
+                buf.extend_from_slice(b"{\"error\":");
+                push_json_str(&mut buf, format!("{:?}", e).as_bytes());
+                buf.push(b'}');
+                chunk_writer.write_chunk(&buf).await?;
+            }
+        }
+        chunk_writer.finalize().await
+    }
+}
+
+struct TableJsonAsync<W: picoserve::io::Write> {
+    chunk_writer: ChunkWriter<W>,
+    table: &'static str,
+}
+
+impl<W: picoserve::io::Write> TableJsonAsync<W> {
+    async fn error(mut self, msg: &[u8]) -> Result<ChunksWritten, W::Error> {
+        let mut buf = Vec::new_in(ExtAlloc::default());
+        buf.extend_from_slice(b"{\"error\":");
+        push_json_str(&mut buf, msg);
+        buf.push(b'}');
+        self.chunk_writer.write_chunk(&buf).await?;
+        self.chunk_writer.finalize().await
+    }
+}
+
+impl<W> AsyncRootFn<Result<ChunksWritten, W::Error>> for TableJsonAsync<W>
+where W: picoserve::io::Write,
+{
+    type Fut<'a> = impl core::future::Future<
+        Output = Result<Result<ChunksWritten, W::Error>, FManError<<FsBlockDevice as BlockDevice>::Error>>>
+        + 'a where Self: 'a;
+
+    fn call<'a>(mut self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let allocator = ExtAlloc::default();
+
+            let dir = match root_dir.open_dir(consts::DB_DIR) {
+                Ok(dir) => dir,
+                Err(e) => return Ok(self.error(format!("{:?}", e).as_bytes()).await),
+            };
+            let mut db = match Database::new_init(VM::new(root_dir.vm()), DbDirSdmmc::new(dir.into_raw()), allocator.clone()) {
+                Ok(d) => d,
+                Err(e) => return Ok(self.error(format!("{:?}", e).as_bytes()).await),
+            };
+            let table = match db.get_table(self.table, allocator.clone()) {
+                Ok(t) => t,
+                Err(e) => return Ok(self.error(format!("table not found: {:?}", e).as_bytes()).await),
+            };
+
+            if let Err(e) = self.chunk_writer.write_chunk(b"{\"rows\":[").await {
+                return Ok(Err(e));
+            }
+
+            let query = Query::<_, &str>::new(table, allocator.clone());
+            // an empty table has no pages to run a query over
+            if let Ok(mut exec) = QueryExecutor::new(
+                query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+                &db.file_handler.page_rw.as_ref().unwrap()
+            ) {
+                let mut first = true;
+                while let Ok(row) = exec.next() {
+                    let mut buf = Vec::new_in(allocator.clone());
+                    if !first {
+                        buf.push(b',');
+                    }
+                    first = false;
+                    buf.extend_from_slice(b"{\"path\":");
+                    push_json_str(&mut buf, row[0].to_chars().unwrap());
+                    buf.extend_from_slice(b",\"name\":");
+                    push_json_str(&mut buf, row[1].to_chars().unwrap());
+                    buf.extend_from_slice(format!(
+                        ",\"size\":{},\"crc32\":\"{:08x}\",\"sha256\":",
+                        row[2].to_int().unwrap(), row[3].to_int().unwrap() as u32
+                    ).as_bytes());
+                    push_json_str(&mut buf, row[4].to_chars().unwrap());
+                    buf.push(b'}');
+                    if let Err(e) = self.chunk_writer.write_chunk(&buf).await {
+                        return Ok(Err(e));
+                    }
+                }
+            }
+
+            if let Err(e) = self.chunk_writer.write_chunk(b"]}").await {
+                return Ok(Err(e));
+            }
+            Ok(self.chunk_writer.finalize().await)
+        }
+    }
+}
+
+pub struct TableJsonChunks {
+    pub fman: &'static ConcreteFMan,
+    pub table: &'static str,
+}
+
+impl Chunks for TableJsonChunks {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut chunk_writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        if self.fman.is_card_active().await {
+            match self.fman.with_root_dir_async(TableJsonAsync { chunk_writer, table: self.table }).await {
+                Ok(res) => res,
+                Err(_) => unreachable!()
+            }
+        } else {
+            chunk_writer.write_chunk(b"{\"error\":\"SD Card not active\"}").await?;
+            chunk_writer.finalize().await
+        }
+    }
+}
+
+pub async fn handle_api_fs(path: Result<String, fs_path::PathError>) -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    match path {
+        Ok(path) => {
+            let file = fman.open_path(&path).await;
+            let names = match file {
+                Ok(ref opened) => match *opened.entry() {
+                    FileType::Dir(_) => fman.long_names(&path).await.unwrap_or_default(),
+                    // the path itself carries the long name of a file
+                    FileType::File(ref entry, _) => match path.rsplit_once('/') {
+                        Some((_, long)) if !fs_path::is_valid_83(long.as_bytes()) => {
+                            alloc::vec![(format!("{}", entry.name), String::from(long))]
+                        },
+                        _ => alloc::vec::Vec::new(),
+                    },
+                },
+                Err(_) => alloc::vec::Vec::new(),
+            };
+            Ok(ChunkedResponse::new(FsJsonChunks { file, names }))
+        },
+        Err(e) => Err(Response::new(StatusCode::BAD_REQUEST, e.message())),
+    }
+}
+
+/// Whether a card is mounted, for the UI to poll, e.g.
+/// `{"active":true,"generation":2}`. `generation` changes with every mount.
+pub async fn handle_api_card() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let (active, generation) = fman.card_status().await;
+    Response::new(StatusCode::OK, format!("{{\"active\":{},\"generation\":{}}}", active, generation))
+        .with_header("Content-Type", "application/json")
+}
+
+/// The partitions of the card, e.g.
+/// `[{"index":0,"root":"/","partition_type":12,"type":"FAT32","size":1073741824,"mounted":true}]`.
+/// `root` is where `/fs` serves a mounted volume.
+pub async fn handle_api_volumes() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let mut json = String::from("[");
+    for (i, volume) in fman.volumes().await.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let root = match volume.index {
+            0 => String::from("\"/\""),
+            n => format!("\"/vol{}\"", n),
+        };
+        let fat_type = match volume.fat_type {
+            Some(FatType::Fat16) => "\"FAT16\"",
+            Some(FatType::Fat32) => "\"FAT32\"",
+            None => "null",
+        };
+        json.push_str(&format!(
+            "{{\"index\":{},\"root\":{},\"partition_type\":{},\"type\":{},\"size\":{},\"mounted\":{}}}",
+            volume.index, root, volume.partition_type, fat_type, volume.size, volume.raw.is_some()
+        ));
+    }
+    json.push(']');
+
+    Response::new(StatusCode::OK, json).with_header("Content-Type", "application/json")
+}
+
+/// How full the card is, e.g.
+/// `{"total":1073610752,"free":1073479680,"cluster_size":16384,"dirs":[{"name":"DB","files":6,"dirs":1,"bytes":3072}]}`.
+/// `dirs` are the top-level directories of volume 0, with all they hold.
+pub async fn handle_api_usage() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let usage = match fman.disk_usage().await {
+        Ok(usage) => usage,
+        Err(FManError::CardNotActive) => return Err(Response::new(StatusCode::SERVICE_UNAVAILABLE, String::from("SD Card not active"))),
+        Err(e) => return Err(Response::new(StatusCode::INTERNAL_SERVER_ERROR, format!("error: {:?}", e))),
+    };
+
+    let capacity = usage.capacity;
+    let mut json = format!(
+        "{{\"total\":{},\"free\":{},\"cluster_size\":{},\"dirs\":[",
+        capacity.total, capacity.free, capacity.cluster_size
+    );
+    for (i, (name, stats)) in usage.dirs.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        // short names cannot hold quotes or backslashes
+        json.push_str(&format!(
+            "{{\"name\":\"{}\",\"files\":{},\"dirs\":{},\"bytes\":{}}}",
+            name, stats.files, stats.dirs, stats.bytes
+        ));
+    }
+    json.push_str("]}");
+
+    Ok(Response::new(StatusCode::OK, json).with_header("Content-Type", "application/json"))
+}
+
+/// `GET /api/fsck` checks the upload directories against their tables.
+pub async fn handle_api_fsck() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    fsck_response(fman.fsck(false).await)
+}
+
+/// `POST /api/fsck` checks them and repairs what it finds.
+pub async fn handle_api_fsck_repair() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    fsck_response(fman.fsck(true).await)
+}
+
+fn fsck_response(report: Result<FsckReport, FManError<<FsBlockDevice as BlockDevice>::Error>>) -> impl IntoResponse {
+    let report = match report {
+        Ok(report) => report,
+        Err(FManError::CardNotActive) => return Err(Response::new(StatusCode::SERVICE_UNAVAILABLE, String::from("SD Card not active"))),
+        Err(e) => return Err(Response::new(StatusCode::INTERNAL_SERVER_ERROR, format!("error: {:?}", e))),
+    };
+
+    let mut json = format!(
+        "{{\"repaired\":{},\"files\":{},\"rows\":{},\"findings\":[",
+        report.repaired, report.files, report.rows
+    );
+    for (i, finding) in report.findings.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        // short names cannot hold quotes or backslashes
+        let issue = match finding.issue {
+            Issue::Unregistered { ref name, size } => format!("\"issue\":\"unregistered\",\"name\":\"{}\",\"size\":{}", name, size),
+            Issue::Missing { ref name } => format!("\"issue\":\"missing\",\"name\":\"{}\"", name),
+            Issue::SizeMismatch { ref name, recorded, actual } => format!(
+                "\"issue\":\"size_mismatch\",\"name\":\"{}\",\"recorded\":{},\"actual\":{}",
+                name, recorded, actual
+            ),
+            Issue::StaleCounter { recorded, next_free } => {
+                format!("\"issue\":\"stale_counter\",\"recorded\":{},\"next_free\":{}", recorded, next_free)
+            },
+        };
+        json.push_str(&format!("{{\"table\":\"{}\",{}}}", finding.table, issue));
+    }
+    json.push_str("]}");
+
+    Ok(Response::new(StatusCode::OK, json).with_header("Content-Type", "application/json"))
+}
+
+pub async fn handle_api_files() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    ChunkedResponse::new(TableJsonChunks { fman, table: consts::FILES_TABLE })
+}
+
+pub async fn handle_api_music() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    ChunkedResponse::new(TableJsonChunks { fman, table: consts::MUSIC_TABLE })
+}
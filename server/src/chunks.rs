@@ -3,15 +3,15 @@ use file_manager::{get_file_manager, ExtAlloc, AsyncRootFn, FManError};
 use allocator_api2::boxed::Box;
 use allocator_api2::vec::Vec;
 use file_manager::runtime::{Sender, Receiver, Channel, Signal, Mutex};
-use file_manager::{BlkDev, DummyTimesource, FsBlockDevice};
+use file_manager::{BlkDev, Checksummer, Checksums, Clock, DirGuard, FileGuard, FsBlockDevice, FsVolumeManager, consts};
 use embedded_sdmmc::{RawFile, VolumeManager, BlockDevice, TimeSource, RawDirectory, Mode};
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Value, Row, Query, QueryExecutor};
+use alloc::format;
+use crate::multipart::{MultipartParser, PartSink};
 use crate::String;
 
-#[cfg(feature = "std-mode")]
-pub use tokio::select;
-#[cfg(feature = "embassy-mode")]
-pub use embassy_futures::select;
-
 #[cfg(feature = "std-mode")]
 pub use std::sync::OnceLock;
 #[cfg(feature = "embassy-mode")]
@@ -19,8 +19,16 @@ pub use embassy_sync::once_lock::OnceLock;
 
 const CHUNK_SIZE: usize = 1024;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkKind {
+    Data,
+    EndOfUpload,
+    ReadErr,
+}
+
 #[derive(Debug)]
 pub struct Chunk {
+    pub kind: ChunkKind,
     pub len: usize,
     pub buf: [u8; CHUNK_SIZE]
 }
@@ -44,84 +52,77 @@ pub fn get_ready_sender() -> Sender<Box<Chunk, ExtAlloc>, CHAN_CAP> {
 }
 
 #[derive(Debug)]
-pub struct DangerousVMPtr<D: BlockDevice, T: TimeSource>(pub *const VolumeManager<D, T, 4, 4, 1>);
+pub struct DangerousVMPtr<D: BlockDevice, T: TimeSource>(
+    pub *const VolumeManager<D, T, { consts::MAX_OPEN_DIRS }, { consts::MAX_OPEN_FILES }, { consts::MAX_VOLUMES }>,
+);
 
 unsafe impl <D: BlockDevice, T: TimeSource> Send for DangerousVMPtr<D, T>{}
 
 #[derive(Debug)]
 pub enum UploadEvent<D: BlockDevice, T: TimeSource> {
-    Begin(
-        RawDirectory,
-        String,
-        DangerousVMPtr<D, T>, Vec<u8, ExtAlloc>,
-    ),
-    EndOfUpload,
-    ReadErr
+    /// Sent by the request handler while it holds the file manager lock. The
+    /// body follows on the ready channel, terminated by an `EndOfUpload` or
+    /// `ReadErr` chunk.
+    Begin {
+        db_dir: RawDirectory,
+        files_dir: RawDirectory,
+        table: &'static str,
+        vm: DangerousVMPtr<D, T>,
+        boundary: Vec<u8, ExtAlloc>,
+    },
 }
 
-static EVENT_SIG: OnceLock<Signal<UploadEvent<BlkDev, DummyTimesource>>> = OnceLock::new();
-static RET_SIG: OnceLock<Signal<Result<bool, &'static str>>> = OnceLock::new();
+/// Outcome of a single part of a multipart upload.
+#[derive(Debug, serde::Serialize)]
+pub struct UploadResult {
+    pub name: String,
+    pub path: Option<String>,
+    pub size: i64,
+    /// Checksums of what was written, in hex.
+    pub crc32: Option<String>,
+    pub sha256: Option<String>,
+    /// The same checksums, as they are registered in the DB.
+    #[serde(skip)]
+    pub checksums: Option<Checksums>,
+    /// Version number of the file in the `files` table, see [`crate::versions`].
+    pub version: Option<u32>,
+    pub error: Option<&'static str>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct UploadReport {
+    pub files: alloc::vec::Vec<UploadResult>,
+    pub error: Option<&'static str>,
+}
+
+static EVENT_SIG: OnceLock<Signal<UploadEvent<BlkDev, Clock>>> = OnceLock::new();
+static RET_SIG: OnceLock<Signal<Result<UploadReport, &'static str>>> = OnceLock::new();
 
 pub fn init_signals() {
     EVENT_SIG.set(Signal::new()).unwrap();
     RET_SIG.set(Signal::new()).unwrap();
 }
 
-pub fn get_event_sig() -> &'static Signal<UploadEvent<BlkDev, DummyTimesource>> {
+pub fn get_event_sig() -> &'static Signal<UploadEvent<BlkDev, Clock>> {
     EVENT_SIG.get().unwrap()
 }
 
-pub async fn send_event_sig(msg: UploadEvent<BlkDev, DummyTimesource>) {
+pub async fn send_event_sig(msg: UploadEvent<BlkDev, Clock>) {
     let sig = EVENT_SIG.get().unwrap();
     sig.reset();
     sig.signal(msg).await;
 }
 
-pub fn get_ret_sig() -> &'static Signal<Result<bool, &'static str>> {
+pub fn get_ret_sig() -> &'static Signal<Result<UploadReport, &'static str>> {
     RET_SIG.get().unwrap()
 }
 
-pub async fn send_ret_sig(msg: Result<bool, &'static str>) {
+pub async fn send_ret_sig(msg: Result<UploadReport, &'static str>) {
     let sig = RET_SIG.get().unwrap();
     sig.reset();
     sig.signal(msg).await;
 }
 
-enum Step {
-    FindFilename,
-    ReadFilename,
-    FindDataStart,
-    StreamingBody,
-}
-
-fn find_boundary_across_buffers(buf1: &[u8], buf2: &[u8], pat: &[u8]) -> Option<(usize, usize)> {
-    if let Some(pos) = buf1.windows(pat.len()).position(|w| w == pat) {
-        return Some((1, pos));
-    }
-
-    if let Some(pos) = buf2.windows(pat.len()).position(|w| w == pat) {
-        return Some((2, pos));
-    }
-
-    let len1 = buf1.len();
-    let pat_len = pat.len();
-    
-    for i in 1..pat_len {
-        let suffix_len = pat_len - i;
-        
-        if len1 >= i && buf2.len() >= suffix_len {
-            let part1 = &buf1[len1 - i..];
-            let part2 = &buf2[..suffix_len];
-            
-            if part1 == &pat[..i] && part2 == &pat[i..] {
-                return Some((1, len1 - i));
-            }
-        }
-    }
-
-    None
-}
-
 #[cfg_attr(feature = "embassy-mode", embassy_executor::task(pool_size = 1))]
 pub async fn task_file_uploader() {
     let mut free_chan: Channel<Box<Chunk, ExtAlloc>, CHAN_CAP> = Channel::new();
@@ -135,166 +136,272 @@ pub async fn task_file_uploader() {
     let free_chan = get_free_chan();
 
     for i in 0..CHAN_CAP {
-        let chunk = Box::new_in(Chunk{ len: 0, buf: [0; CHUNK_SIZE] }, ExtAlloc::default());
+        let chunk = Box::new_in(Chunk{ kind: ChunkKind::Data, len: 0, buf: [0; CHUNK_SIZE] }, ExtAlloc::default());
         free_chan.send(chunk).await;
     }
 
     loop {
         let event = get_event_sig().wait().await;
         match event {
-            UploadEvent::Begin(files_dir, actual_name, dangerous_vm_ptr, boundary) => {
-                handle_begin(&ready_receiver, files_dir, actual_name, dangerous_vm_ptr, boundary).await;
+            UploadEvent::Begin { db_dir, files_dir, table, vm, boundary } => {
+                handle_begin(&ready_receiver, db_dir, files_dir, table, vm, boundary).await;
             }
-            _ => ()
         }
     }
 }
 
+/// 8.3 extension for a stored upload, taken from the client side filename.
+pub(crate) fn extension_of(filename: &[u8]) -> String {
+    let ext = match filename.iter().rposition(|&b| b == b'.') {
+        Some(pos) => &filename[pos + 1..],
+        None => &[][..],
+    };
+    let ext: String = ext.iter()
+        .take_while(|b| b.is_ascii_alphanumeric())
+        .take(3)
+        .map(|&b| b.to_ascii_uppercase() as char)
+        .collect();
+
+    if ext.is_empty() { String::from("BIN") } else { ext }
+}
+
+struct OpenPart<'a> {
+    file: FileGuard<'a>,
+    sums: Checksummer,
+    result: UploadResult,
+}
+
+/// Writes every part carrying a filename into its own `<id>.<ext>` file.
+struct MultiFileSink<'a> {
+    files_dir: DirGuard<'a>,
+    next_id: i64,
+    current: Option<OpenPart<'a>>,
+    files: alloc::vec::Vec<UploadResult>,
+}
+
+impl<'a> MultiFileSink<'a> {
+    fn abort_current(&mut self, error: &'static str) {
+        if let Some(mut part) = self.current.take() {
+            drop(part.file);
+            if let Some(path) = part.result.path.take() {
+                let _ = self.files_dir.delete_file(path.as_str());
+            }
+            part.result.error = Some(error);
+            self.files.push(part.result);
+        }
+    }
+}
+
+impl<'a> PartSink for MultiFileSink<'a> {
+    fn part_begin(&mut self, filename: &[u8]) -> Result<(), &'static str> {
+        if filename.is_empty() {
+            return Ok(());
+        }
+
+        let name = String::from_utf8_lossy(filename).into_owned();
+        if self.next_id < 0 || self.next_id >= 99999999 {
+            self.files.push(UploadResult { name, path: None, size: 0, crc32: None, sha256: None, checksums: None, version: None, error: Some("id limit reached") });
+            return Ok(());
+        }
+
+        let path = format!("{}.{}", self.next_id, extension_of(filename));
+        self.next_id += 1;
+
+        match self.files_dir.open_file(path.as_str(), Mode::ReadWriteCreate) {
+            Ok(file) => {
+                self.current = Some(OpenPart {
+                    file,
+                    sums: Checksummer::new(),
+                    result: UploadResult { name, path: Some(path), size: 0, crc32: None, sha256: None, checksums: None, version: None, error: None },
+                });
+            },
+            Err(_) => {
+                self.files.push(UploadResult { name, path: None, size: 0, crc32: None, sha256: None, checksums: None, version: None, error: Some("unable to create file") });
+            }
+        }
+        Ok(())
+    }
+
+    fn part_data(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        if let Some(ref mut part) = self.current {
+            match part.file.write(data) {
+                Ok(()) => {
+                    part.sums.update(data);
+                    part.result.size += data.len() as i64;
+                },
+                Err(_) => self.abort_current("unable to write to file"),
+            }
+        }
+        Ok(())
+    }
+
+    fn part_end(&mut self) -> Result<(), &'static str> {
+        if let Some(OpenPart { file, sums, mut result }) = self.current.take() {
+            if file.close().is_err() {
+                if let Some(path) = result.path.take() {
+                    let _ = self.files_dir.delete_file(path.as_str());
+                }
+                result.error = Some("unable to close file");
+            } else {
+                let sums = sums.finish();
+                result.crc32 = Some(sums.crc32_hex());
+                result.sha256 = Some(sums.sha256_hex());
+                result.checksums = Some(sums);
+            }
+            self.files.push(result);
+        }
+        Ok(())
+    }
+}
+
 async fn handle_begin<D: BlockDevice, T: TimeSource>(
     ready_receiver: &Receiver<Box<Chunk, ExtAlloc>, CHAN_CAP>,
+    db_dir: RawDirectory,
     files_dir: RawDirectory,
-    actual_name: String,
+    table: &'static str,
     vm_ptr: DangerousVMPtr<D, T>,
     boundary: Vec<u8, ExtAlloc>,
 ) {
-    let vm = unsafe { &*(vm_ptr.0 as *const VolumeManager<BlkDev, DummyTimesource, 4, 4, 1>) };
-    let files_dir = files_dir.to_directory(vm);
-    let new_file = match files_dir.open_file_in_dir(str::from_utf8(actual_name.as_bytes()).unwrap(), Mode::ReadWriteCreate) {
-        Ok(f) => f,
-        Err(_) => {
-            send_ret_sig(Err("unable to create file")).await;
-            return;
+    // The request handler keeps the file manager locked until RET_SIG fires,
+    // so nothing else touches the volume manager meanwhile.
+    let vm = unsafe { &*(vm_ptr.0 as *const FsVolumeManager) };
+    // every handle of the upload is closed once it returns, still under that lock
+    let outcome = upload(ready_receiver, vm, db_dir, DirGuard::new(vm, files_dir), table, boundary).await;
+    send_ret_sig(outcome).await;
+}
+
+async fn upload(
+    ready_receiver: &Receiver<Box<Chunk, ExtAlloc>, CHAN_CAP>,
+    vm: &FsVolumeManager,
+    db_dir: RawDirectory,
+    files_dir: DirGuard<'_>,
+    table: &'static str,
+    boundary: Vec<u8, ExtAlloc>,
+) -> Result<UploadReport, &'static str> {
+
+    let mut db = match Database::new_init(VM::new(vm), DbDirSdmmc::new(db_dir), ExtAlloc::default()) {
+        Ok(d) => d,
+        Err(_) => return discard_rest(ready_receiver, Err("db init error")).await,
+    };
+    let count_tracker_table = match db.get_table(consts::COUNT_TRACKER_TABLE, ExtAlloc::default()) {
+        Ok(t) => t,
+        Err(_) => return discard_rest(ready_receiver, Err("unable to get count_tracker table")).await,
+    };
+    let files_table = match db.get_table(table, ExtAlloc::default()) {
+        Ok(t) => t,
+        Err(_) => return discard_rest(ready_receiver, Err("unable to get files table")).await,
+    };
+
+    let cur_file_id: i64 = {
+        let query = Query::<_, &str>::new(count_tracker_table, ExtAlloc::default())
+                                     .key(Value::Chars(table.as_bytes()));
+        match QueryExecutor::new(
+            query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+            &db.file_handler.page_rw.as_ref().unwrap()
+        ) {
+            Ok(mut exec) => match exec.next() {
+                Ok(row) => row[1].to_int().unwrap(),
+                Err(_) => return discard_rest(ready_receiver, Err("bad init")).await,
+            },
+            Err(_) => return discard_rest(ready_receiver, Err("table empty")).await,
         }
     };
-    let mut filename = [0u8; 128];
-    let mut filename_len = 0;
-
-    let mut step = Step::FindFilename;
-    let mut pattern_idx = 0;
-
-    let mut lookback_buf = [0u8; 128];
-    let mut lookback_len = 0;
-    let mut file_size: i64 = 0;
-
-    // 'stream: while let Ok(n) = reader.read(&mut buffer).await {
-    //     if n == 0 { break; }
-
-    //     for (i, &byte) in buffer[..n].iter().enumerate() {
-    //         match step {
-    //             Step::FindFilename => {
-    //                 let file_pat = b"filename=\"";
-    //                 if byte == file_pat[pattern_idx] {
-    //                     pattern_idx += 1;
-    //                     if pattern_idx == file_pat.len() {
-    //                         step = Step::ReadFilename;
-    //                         pattern_idx = 0;
-    //                     }
-    //                 } else {
-    //                     pattern_idx = 0;
-    //                 }
-    //             }
-
-    //             Step::ReadFilename => {
-    //                 if byte == '"' as u8 {
-    //                     step = Step::FindDataStart;
-    //                     pattern_idx = 0;
-    //                 } else {
-    //                     filename[filename_len] = byte;
-    //                     filename_len += 1;
-    //                 }
-    //             }
-
-    //             Step::FindDataStart => {
-    //                 let header_sep = b"\r\n\r\n";
-    //                 if byte == header_sep[pattern_idx] {
-    //                     pattern_idx += 1;
-    //                     if pattern_idx == header_sep.len() {
-    //                         step = Step::StreamingBody;
-    //                     }
-    //                 } else {
-    //                     pattern_idx = 0;
-    //                 }
-    //             }
-
-    //             Step::StreamingBody => {
-    //                 let data_chunk = &buffer[i..n];
-
-    //                 if let Some(pos) = find_boundary_across_buffers(&lookback_buf[..lookback_len], data_chunk, boundary) {
-    //                     match pos {
-    //                         (1, idx) => {
-    //                             let end = idx.saturating_sub(4);
-    //                             let buf = &lookback_buf[0..end];
-    //                             new_file.write(buf).map_err(|_| "unable to write to new_file")?;
-    //                             file_size += buf.len() as i64;
-    //                         },
-    //                         (2, idx) => {
-    //                             if idx >= 4 {
-    //                                 let buf = &lookback_buf[..lookback_len];
-    //                                 new_file.write(buf).map_err(|_| "unable to write to new_file")?;
-    //                                 file_size += buf.len() as i64;
-
-    //                                 let buf = &data_chunk[0..idx-4];
-    //                                 new_file.write(buf).map_err(|_| "unable to write to new_file")?;
-    //                                 file_size += buf.len() as i64;
-    //                             } else {
-    //                                 let lookback_trim = 4 - idx;
-    //                                 let end = lookback_len.saturating_sub(lookback_trim);
-    //                                 let buf = &lookback_buf[..end];
-    //                                 new_file.write(buf).map_err(|_| "unable to write to new_file")?;
-    //                                 file_size += buf.len() as i64;
-    //                             }
-    //                         },
-    //                         _ => unreachable!()
-    //                     }
-    //                     break 'stream;
-    //                 } else {
-    //                     let buf = &lookback_buf[..lookback_len];
-    //                     new_file.write(buf).map_err(|_| "unable to write to new_file")?;
-    //                     file_size += buf.len() as i64;
-
-    //                     if data_chunk.len() >= lookback_buf.len() {
-    //                         let safe_len = data_chunk.len() - lookback_buf.len();
-    //                         let buf = &data_chunk[..safe_len];
-    //                         new_file.write(buf).map_err(|_| "unable to write to new_file")?;
-    //                         file_size += buf.len() as i64;
-
-    //                         let tail = &data_chunk[safe_len..];
-    //                         lookback_buf[..tail.len()].copy_from_slice(tail);
-    //                         lookback_len = tail.len();
-    //                     } else {
-    //                         let move_amt = data_chunk.len();
-    //                         lookback_buf.copy_within(move_amt.., 0);
-
-    //                         let start = lookback_buf.len() - move_amt;
-    //                         lookback_buf[start..].copy_from_slice(data_chunk);
-    //                         lookback_len = lookback_buf.len();
-    //                     }
-    //                 }
-    //                 continue 'stream;
-    //             }
-    //         }
-    //     }
-    // }
+
+    let mut parser = match MultipartParser::new(&boundary) {
+        Ok(p) => p,
+        Err(e) => return discard_rest(ready_receiver, Err(e)).await,
+    };
+
+    let mut sink = MultiFileSink {
+        files_dir,
+        next_id: cur_file_id,
+        current: None,
+        files: alloc::vec::Vec::new(),
+    };
+
+    let mut report = UploadReport::default();
+    if let Err(e) = drain_into(ready_receiver, &mut parser, &mut sink).await {
+        sink.abort_current(e);
+        report.error = Some(e);
+    }
+
+    // Register what made it to the card even if a later part failed.
+    for result in sink.files.iter_mut() {
+        let Some(ref path) = result.path else { continue };
+        let (Some(sums), Some(sha256)) = (result.checksums, &result.sha256) else { continue };
+        let mut row = Row::new_in(ExtAlloc::default());
+        row.push(Value::Chars(path.as_bytes()));
+        row.push(Value::Chars(result.name.as_bytes()));
+        row.push(Value::Int(result.size));
+        row.push(Value::Int(sums.crc32 as i64));
+        row.push(Value::Chars(sha256.as_bytes()));
+        if db.insert_to_table(files_table, row, ExtAlloc::default()).is_err() {
+            let _ = sink.files_dir.delete_file(path.as_str());
+            result.path = None;
+            result.error = Some("unable to insert to table");
+        }
+    }
+
+    if sink.next_id != cur_file_id {
+        let mut row = Row::new_in(ExtAlloc::default());
+        row.push(Value::Chars(table.as_bytes()));
+        row.push(Value::Int(sink.next_id));
+        if db.update_row(count_tracker_table, Value::Chars(table.as_bytes()), row, ExtAlloc::default()).is_err() {
+            report.error = Some("unable to update count_tracker_table to table");
+        }
+    }
+
+    report.files = sink.files;
+    Ok(report)
+}
+
+/// Feeds every chunk of the current upload to `parser`, recycling the chunks
+/// into FREE_CHAN. Once parsing fails the rest of the body is still consumed
+/// so no stale chunks are left behind for the next upload.
+async fn drain_into<S: PartSink>(
+    ready_receiver: &Receiver<Box<Chunk, ExtAlloc>, CHAN_CAP>,
+    parser: &mut MultipartParser,
+    sink: &mut S,
+) -> Result<(), &'static str> {
+    let free_chan = get_free_chan();
+    let mut outcome = Ok(());
+
     loop {
-        select!(
-            chunk = ready_receiver.recv() => {
-                std::println!("chunk = {:?}", chunk);
-            }
-            event = get_event_sig().wait() => {
-                match event {
-                    UploadEvent::ReadErr => {
-                        ()
-                    }
-                    UploadEvent::EndOfUpload => {
-                        ()
-                    }
-                    _ => ()
-                }
-            }
-        );
+        let chunk = ready_receiver.recv().await;
+        let kind = chunk.kind;
+
+        if kind == ChunkKind::Data && outcome.is_ok() && !parser.is_finished() {
+            outcome = parser.feed(&chunk.buf[..chunk.len], sink);
+        }
+        free_chan.send(chunk).await;
+
+        match kind {
+            ChunkKind::Data => (),
+            ChunkKind::EndOfUpload => return outcome.and_then(|_| parser.finish()),
+            ChunkKind::ReadErr => return outcome.and(Err("read error")),
+        }
+    }
+}
+
+/// Recycles the rest of the current upload into FREE_CHAN, passing
+/// `outcome` on.
+async fn discard_rest(
+    ready_receiver: &Receiver<Box<Chunk, ExtAlloc>, CHAN_CAP>,
+    outcome: Result<UploadReport, &'static str>,
+) -> Result<UploadReport, &'static str> {
+    let free_chan = get_free_chan();
+    loop {
+        let chunk = ready_receiver.recv().await;
+        let kind = chunk.kind;
+        free_chan.send(chunk).await;
+        if kind != ChunkKind::Data {
+            break;
+        }
     }
+    outcome
 }
 
 pub async fn init_all() {
     init_signals();
+    crate::verify::init_signals();
 }
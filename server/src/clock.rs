@@ -0,0 +1,77 @@
+//! Setting the wall clock over HTTP.
+//!
+//! `POST /time` takes the milliseconds since the epoch as its body, which
+//! is what the home page sends from `Date.now()` while the clock is unset.
+//! With an empty body the request's own `Date` header is used instead.
+//! Once SNTP keeps the clock it answers `409 Conflict`, see
+//! [`Clock::set_by_client`]. `GET /time` tells what the clock reads.
+
+use picoserve::extract::FromRequest;
+use picoserve::io::Read;
+use picoserve::request::{RequestBody, RequestParts};
+use picoserve::response::{IntoResponse, Response, StatusCode};
+use file_manager::{clock, Clock};
+use alloc::format;
+use crate::conditional::parse_http_date;
+use crate::String;
+
+/// Longest body taken as a time; 13 digits last until the year 2286.
+const MAX_BODY: usize = 20;
+
+/// The time a `POST /time` sets, in milliseconds since the epoch.
+pub struct SetTime(pub Result<u64, &'static str>);
+
+impl<'r, State> FromRequest<'r, State> for SetTime {
+    type Rejection = &'static str;
+
+    async fn from_request<R: Read>(
+        _state: &'r State,
+        parts: RequestParts<'r>,
+        body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        let mut buf = [0u8; MAX_BODY];
+        let mut len = 0;
+        let mut reader = body.reader();
+        loop {
+            match reader.read(&mut buf[len..]).await {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(_) => return Ok(Self(Err("read error"))),
+            }
+            if len == buf.len() {
+                return Ok(Self(Err("body too long")));
+            }
+        }
+
+        let time = match core::str::from_utf8(&buf[..len]).map(str::trim) {
+            Ok("") => parts.headers().get("Date")
+                .and_then(|v| v.as_str().ok())
+                .and_then(parse_http_date)
+                .map(|secs| secs * 1000)
+                .ok_or("neither a body nor a Date header"),
+            Ok(body) => body.parse().map_err(|_| "body is not a number"),
+            Err(_) => Err("body is not a number"),
+        };
+        Ok(Self(time))
+    }
+}
+
+pub async fn handle_time() -> impl IntoResponse {
+    let json = match Clock::unix_secs() {
+        Some(secs) => format!(
+            "{{\"set\":true,\"unix\":{},\"iso\":\"{}Z\"}}",
+            secs,
+            crate::api::iso_timestamp(&clock::timestamp(secs))
+        ),
+        None => String::from("{\"set\":false}"),
+    };
+    Response::new(StatusCode::OK, json).with_header("Content-Type", "application/json")
+}
+
+pub async fn handle_set_time(SetTime(time): SetTime) -> impl IntoResponse {
+    match time {
+        Ok(unix_ms) if Clock::set_by_client(unix_ms) => Response::new(StatusCode::NO_CONTENT, ""),
+        Ok(_) => Response::new(StatusCode::CONFLICT, "the clock is kept by SNTP"),
+        Err(msg) => Response::new(StatusCode::BAD_REQUEST, msg),
+    }
+}
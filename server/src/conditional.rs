@@ -0,0 +1,226 @@
+use embedded_sdmmc::{DirEntry, Timestamp};
+use picoserve::extract::FromRequestParts;
+use picoserve::request::RequestParts;
+use picoserve::response::{Content, IntoResponse, Response, StatusCode};
+use picoserve::io::Write;
+use alloc::format;
+use crate::String;
+
+static WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+static MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Conditional request headers, plus whether the request is a `HEAD`.
+pub struct Conditional {
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    pub head: bool,
+}
+
+impl<'r, State> FromRequestParts<'r, State> for Conditional {
+    type Rejection = &'static str;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        let header = |name| request_parts.headers().get(name)
+            .and_then(|v| v.as_str().ok())
+            .map(String::from);
+
+        Ok(Self {
+            if_none_match: header("If-None-Match"),
+            if_modified_since: header("If-Modified-Since"),
+            head: request_parts.method() == "HEAD",
+        })
+    }
+}
+
+/// Cache validators of a file, taken from its directory entry only.
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: String,
+    pub modified: u64,
+    pub size: u32,
+}
+
+impl Validators {
+    pub fn of(entry: &DirEntry) -> Self {
+        // The first cluster changes whenever a file is recreated, even when
+        // the size and (coarse) timestamp stay the same.
+        let mut hash: u32 = 0x811c9dc5;
+        for b in format!("{:?}{:?}", entry.cluster, entry.mtime).bytes() {
+            hash = (hash ^ b as u32).wrapping_mul(0x01000193);
+        }
+
+        Self {
+            etag: format!("\"{:x}-{:08x}\"", entry.size, hash),
+            last_modified: http_date(&entry.mtime),
+            modified: unix_seconds(&entry.mtime),
+            size: entry.size,
+        }
+    }
+}
+
+impl Conditional {
+    /// Whether the client's cached copy is still current. `If-None-Match`
+    /// takes precedence over `If-Modified-Since` when both are sent.
+    pub fn not_modified(&self, validators: &Validators) -> bool {
+        if let Some(ref tags) = self.if_none_match {
+            return tags.split(',')
+                .map(|t| t.trim())
+                .any(|t| t == "*" || t.strip_prefix("W/").unwrap_or(t) == validators.etag);
+        }
+        match self.if_modified_since.as_deref().and_then(parse_http_date) {
+            Some(since) => validators.modified <= since,
+            None => false,
+        }
+    }
+}
+
+/// A body that is never written, for `HEAD` and `304` responses that still
+/// describe the length and type of the representation.
+pub struct NoBody {
+    pub content_type: &'static str,
+    pub len: usize,
+}
+
+impl Content for NoBody {
+    fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    fn content_length(&self) -> usize {
+        self.len
+    }
+
+    async fn write_content<W: Write>(self, _writer: W) -> Result<(), W::Error> {
+        Ok(())
+    }
+}
+
+/// Headers-only answer to a `HEAD` (`status` 200) or a satisfied
+/// conditional request (`status` 304).
+pub fn bodiless(status: StatusCode, validators: &Validators, content_type: &'static str) -> impl IntoResponse {
+    Response::new(status, NoBody { content_type, len: validators.size as usize })
+        .with_header("ETag", validators.etag.clone())
+        .with_header("Last-Modified", validators.last_modified.clone())
+        .with_header("Accept-Ranges", "bytes")
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn unix_seconds(ts: &Timestamp) -> u64 {
+    let days = days_from_civil(
+        1970 + ts.year_since_1970 as i64,
+        ts.zero_indexed_month as u32 + 1,
+        ts.zero_indexed_day as u32 + 1,
+    );
+    days as u64 * 86400 + ts.hours as u64 * 3600 + ts.minutes as u64 * 60 + ts.seconds as u64
+}
+
+/// Formats `ts` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(ts: &Timestamp) -> String {
+    let days = unix_seconds(ts) / 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        ts.zero_indexed_day + 1,
+        MONTHS[ts.zero_indexed_month as usize % 12],
+        1970 + ts.year_since_1970 as u32,
+        ts.hours, ts.minutes, ts.seconds
+    )
+}
+
+/// Parses an IMF-fixdate into seconds since the Unix epoch. The obsolete
+/// RFC 850 and asctime forms are not accepted.
+pub fn parse_http_date(s: &str) -> Option<u64> {
+    let mut parts = s.split_whitespace().skip(1);
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+
+    let mut hms = parts.next()?.split(':').map(|v| v.parse::<u64>().ok());
+    let (h, m, sec) = (hms.next()??, hms.next()??, hms.next()??);
+    if parts.next()? != "GMT" || year < 1970 {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) as u64 * 86400 + h * 3600 + m * 60 + sec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validators() -> Validators {
+        let mtime = Timestamp::from_calendar(1994, 11, 6, 8, 49, 37).unwrap();
+        Validators {
+            etag: String::from("\"400-0badcafe\""),
+            last_modified: http_date(&mtime),
+            modified: unix_seconds(&mtime),
+            size: 1024,
+        }
+    }
+
+    fn conditional(if_none_match: Option<&str>, if_modified_since: Option<&str>) -> Conditional {
+        Conditional {
+            if_none_match: if_none_match.map(String::from),
+            if_modified_since: if_modified_since.map(String::from),
+            head: false,
+        }
+    }
+
+    /// (`If-None-Match`, `If-Modified-Since`, whether the cached copy is current)
+    const CASES: &[(Option<&str>, Option<&str>, bool)] = &[
+        (None, None, false),
+        (Some("\"400-0badcafe\""), None, true),
+        (Some("W/\"400-0badcafe\""), None, true),
+        (Some("\"1-00000000\", \"400-0badcafe\""), None, true),
+        (Some("*"), None, true),
+        (Some("\"400-0badcaff\""), None, false),
+        (Some("400-0badcafe"), None, false),
+        (Some(""), None, false),
+        (None, Some("Sun, 06 Nov 1994 08:49:37 GMT"), true),
+        (None, Some("Sun, 06 Nov 1994 08:49:38 GMT"), true),
+        (None, Some("Mon, 01 Jan 2024 00:00:00 GMT"), true),
+        (None, Some("Sun, 06 Nov 1994 08:49:36 GMT"), false),
+        (None, Some("Sat, 05 Nov 1994 08:49:37 GMT"), false),
+        (None, Some("Sunday, 06-Nov-94 08:49:37 GMT"), false),
+        (None, Some("Sun Nov  6 08:49:37 1994"), false),
+        (None, Some("garbage"), false),
+        // If-None-Match wins over If-Modified-Since
+        (Some("\"400-0badcaff\""), Some("Mon, 01 Jan 2024 00:00:00 GMT"), false),
+        (Some("\"400-0badcafe\""), Some("Sat, 05 Nov 1994 08:49:37 GMT"), true),
+    ];
+
+    #[test]
+    fn not_modified_table() {
+        let validators = validators();
+        for &(if_none_match, if_modified_since, expected) in CASES {
+            let got = conditional(if_none_match, if_modified_since).not_modified(&validators);
+            assert_eq!(got, expected, "If-None-Match {:?}, If-Modified-Since {:?}", if_none_match, if_modified_since);
+        }
+    }
+
+    #[test]
+    fn http_dates() {
+        let v = validators();
+        assert_eq!(v.last_modified, "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(v.modified, 784111777);
+        assert_eq!(parse_http_date(&v.last_modified), Some(v.modified));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Tue, 29 Feb 2000 23:59:59 GMT"), Some(951868799));
+        assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+    }
+}
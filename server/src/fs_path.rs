@@ -0,0 +1,162 @@
+//! Decoding and canonicalization of request paths into card paths.
+
+use crate::String;
+
+pub use file_manager::lfn::{is_valid_83, is_valid_long};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    BadEncoding,
+    Traversal,
+    InvalidName,
+}
+
+impl PathError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            PathError::BadEncoding => "malformed percent-encoding in path",
+            PathError::Traversal => "path escapes the volume root",
+            PathError::InvalidName => "path segment is not a valid file name",
+        }
+    }
+}
+
+fn hex(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Percent-decodes one path segment. `+` is kept as is, it only means a
+/// space in query strings.
+fn decode_segment(segment: &str) -> Result<alloc::vec::Vec<u8>, PathError> {
+    let bytes = segment.as_bytes();
+    let mut out = alloc::vec::Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let (Some(hi), Some(lo)) = (
+                bytes.get(i + 1).copied().and_then(hex),
+                bytes.get(i + 2).copied().and_then(hex),
+            ) else {
+                return Err(PathError::BadEncoding);
+            };
+            out.push(hi << 4 | lo);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+/// Decodes a percent-encoded request path and canonicalizes it into an
+/// absolute card path such as `/FILES/17.PDF` (`/` for the root). Empty and
+/// `.` segments are dropped and `..` pops a segment, but never above the
+/// root. Valid 8.3 names are upper-cased; any other segment must be a valid
+/// long name and keeps its case, see [`file_manager::lfn`].
+pub fn normalize(encoded: &str) -> Result<String, PathError> {
+    let mut segments: alloc::vec::Vec<String> = alloc::vec::Vec::new();
+
+    for raw in encoded.split('/') {
+        let decoded = decode_segment(raw)?;
+        match decoded.as_slice() {
+            b"" | b"." => (),
+            b".." => {
+                segments.pop().ok_or(PathError::Traversal)?;
+            },
+            name => {
+                let valid_83 = is_valid_83(name);
+                let name = core::str::from_utf8(name).map_err(|_| PathError::InvalidName)?;
+                if valid_83 {
+                    segments.push(name.to_ascii_uppercase());
+                } else if is_valid_long(name) {
+                    segments.push(String::from(name));
+                } else {
+                    return Err(PathError::InvalidName);
+                }
+            }
+        }
+    }
+
+    let mut path = String::with_capacity(encoded.len() + 1);
+    for segment in segments.iter() {
+        path.push('/');
+        path.push_str(segment);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    /// (request path below `/fs` or `/download`, canonical path or error)
+    const CASES: &[(&str, Result<&str, PathError>)] = &[
+        ("", Ok("/")),
+        ("/", Ok("/")),
+        ("//", Ok("/")),
+        ("/FILES", Ok("/FILES")),
+        ("/files/17.pdf", Ok("/FILES/17.PDF")),
+        ("//FILES///17.PDF/", Ok("/FILES/17.PDF")),
+        ("/FILES/./17.PDF", Ok("/FILES/17.PDF")),
+        ("/MUSIC/../FILES/17.PDF", Ok("/FILES/17.PDF")),
+        ("/FILES/%31%37.PDF", Ok("/FILES/17.PDF")),
+        ("/FILES/%2e%2e", Ok("/")),
+        ("/%46ILES/NOTE.TXT", Ok("/FILES/NOTE.TXT")),
+        ("/READ_ME.~1", Ok("/READ_ME.~1")),
+        ("/..", Err(PathError::Traversal)),
+        ("/FILES/../../DB", Err(PathError::Traversal)),
+        ("/%2E%2E/DB", Err(PathError::Traversal)),
+        ("/FILES/%zz.TXT", Err(PathError::BadEncoding)),
+        ("/FILES/A%2", Err(PathError::BadEncoding)),
+        ("/FILES/%", Err(PathError::BadEncoding)),
+        ("/FILES/My%20File.txt", Ok("/FILES/My File.txt")),
+        ("/FILES/A+B.TXT", Ok("/FILES/A+B.TXT")),
+        ("/FILES/LongFileName.txt", Ok("/FILES/LongFileName.txt")),
+        ("/FILES/archive.tar.gz", Ok("/FILES/archive.tar.gz")),
+        ("/Photos%202024/.hidden", Ok("/Photos 2024/.hidden")),
+        ("/FILES/%C3%A9t%C3%A9.txt", Ok("/FILES/été.txt")),
+        ("/FILES/A%2FB.TXT", Err(PathError::InvalidName)),
+        ("/FILES/A%3AB.TXT", Err(PathError::InvalidName)),
+        ("/FILES/A%3F.TXT", Err(PathError::InvalidName)),
+        ("/FILES/TAB%09.TXT", Err(PathError::InvalidName)),
+        ("/FILES/Trailing%20dot.", Err(PathError::InvalidName)),
+        ("/FILES/TRAILING%20", Err(PathError::InvalidName)),
+        ("/FILES/...", Err(PathError::InvalidName)),
+        ("/FILES/%FF.TXT", Err(PathError::InvalidName)),
+    ];
+
+    #[test]
+    fn normalize_table() {
+        for (input, expected) in CASES {
+            let got = normalize(input);
+            assert_eq!(got.as_deref(), expected.as_ref().map(|s| *s), "path {:?}", input);
+        }
+    }
+
+    #[test]
+    fn too_long_names() {
+        let longest = "x".repeat(255);
+        assert_eq!(normalize(&longest), Ok(format!("/{}", longest)));
+        assert_eq!(normalize(&"x".repeat(256)), Err(PathError::InvalidName));
+    }
+
+    #[test]
+    fn valid_83_names() {
+        for name in [&b"A"[..], b"ABCDEFGH.TXT", b"17.PDF", b"NO_EXT", b"A-B.C"] {
+            assert!(is_valid_83(name), "{:?}", name);
+        }
+        for name in [&b""[..], b"ABCDEFGHI", b"A.TEXT", b"A B", b"A.B.C", b".X", b"A[1]"] {
+            assert!(!is_valid_83(name), "{:?}", name);
+        }
+    }
+}
@@ -18,17 +18,32 @@ use alloc::format;
 
 pub mod file_uploader;
 pub mod chunks;
+pub mod clock;
+pub mod multipart;
+pub mod raw_uploader;
+pub mod resumable;
+pub mod range;
+pub mod mime;
+pub mod conditional;
+pub mod api;
+pub mod webdav;
+pub mod template;
+pub mod fs_path;
+pub mod trash;
+pub mod verify;
+pub mod versions;
 
 use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
 use alpa::db::Database;
 use alpa::{Query, QueryExecutor, Value};
-use embedded_sdmmc::{BlockDevice, RawDirectory, TimeSource, VolumeManager};
-use picoserve::routing::{PathDescription};
+use embedded_sdmmc::{BlockDevice, RawFile};
+use picoserve::routing::{PathDescription, RequestHandlerService};
 use picoserve::response::{IntoResponse};
-use picoserve::request::{RequestBody, RequestParts, Path};
+use picoserve::request::{Request, RequestBody, RequestParts, Path};
 use picoserve::extract::{FromRequest};
 use picoserve::io::Read;
-use picoserve::response::Response;
+use picoserve::response::{Body, Connection, HeadersIter, Response, ResponseWriter, StatusCode};
+use picoserve::ResponseSent;
 use allocator_api2::alloc::Allocator;
 use allocator_api2::vec::Vec;
 use picoserve::response::chunked::{ChunksWritten, ChunkedResponse, ChunkWriter, Chunks};
@@ -39,11 +54,12 @@ use file_manager::{
     get_file_manager,
     FManError,
     FileType,
-    CardState,
     consts,
     AsyncRootFn,
-    DummyTimesource,
-    FsBlockDevice
+    DirGuard,
+    FsBlockDevice,
+    Opened,
+    table_for_dir
 };
 
 #[cfg(feature = "embassy-mode")]
@@ -56,11 +72,161 @@ use file_manager::{ConcreteSpi, ConcreteDelay};
 
 pub static HOME_PAGE: &str = include_str!("./html/home.html");
 
+#[cfg(feature = "std-mode")]
+type ConcreteBlkDev = BlkDev;
+#[cfg(feature = "embassy-mode")]
+type ConcreteBlkDev = BlkDev<ConcreteSpi<'static>, ConcreteDelay>;
+
+#[cfg(feature = "std-mode")]
+type ConcreteFMan = FMan;
+#[cfg(feature = "embassy-mode")]
+type ConcreteFMan = FMan<ConcreteSpi<'static>, ConcreteDelay>;
+
+type FileResult = Result<Opened<'static>, FManError<<FsBlockDevice as BlockDevice>::Error>>;
+
+/// Streams the file `f` of `file` (or the inclusive `range` of it) into
+/// `chunk_writer`, HTML-escaped when `escape` is set.
+async fn write_file_chunks<W: picoserve::io::Write, A: Allocator + Clone>(
+    file: &Opened<'_>,
+    f: RawFile,
+    range: Option<(u32, u32)>,
+    escape: bool,
+    allocator: A,
+    chunk_writer: &mut ChunkWriter<W>,
+) -> Result<(), W::Error> {
+    let mut buffer: Vec<u8, A> = Vec::with_capacity_in(1024, allocator.clone());
+    buffer.resize(buffer.capacity(), 0);
+    let mut escaped: Vec<u8, A> = Vec::new_in(allocator);
+
+    let mut remaining = match range {
+        Some((start, end)) => {
+            let sought = file.with_vm(|vm| vm.file_seek_from_start(f, start)).await;
+            if let Err(e) = sought.and_then(|sought| sought.map_err(FManError::SdErr)) {
+                chunk_writer.write_chunk(format!("error: {:?}", e).as_bytes()).await?;
+                return Ok(());
+            }
+            Some((end - start) as usize + 1)
+        },
+        None => None
+    };
+
+    loop {
+        let want = remaining.map_or(buffer.len(), |r| r.min(buffer.len()));
+        if want == 0 {
+            break;
+        }
+        // the state is locked for the read only, not while the chunk is sent
+        let read = file.with_vm(|vm| {
+            let count = vm.read(f, &mut buffer[..want])?;
+            Ok((count, vm.file_eof(f)?))
+        }).await;
+        match read.and_then(|read| read.map_err(FManError::SdErr)) {
+            Ok((count, is_eof)) => {
+                if escape {
+                    escaped.clear();
+                    template::escape_into(&mut escaped, &buffer[0..count]);
+                    chunk_writer.write_chunk(&escaped).await?;
+                } else {
+                    chunk_writer.write_chunk(&buffer[0..count]).await?;
+                }
+                if let Some(ref mut r) = remaining {
+                    *r -= count;
+                }
+                if is_eof {
+                    break;
+                }
+            },
+            Err(e) => {
+                chunk_writer.write_chunk(format!("error: {:?}", e).as_bytes()).await?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The answer to a request for a file or directory on the card. Files are
+/// served with range support, upgraded to `206 Partial Content` when a
+/// satisfiable range of them was asked for.
+enum FileResponse<L, N, H, B> {
+    /// The listing of a directory.
+    Listing(L),
+    Full(Response<H, B>),
+    /// The inclusive byte range `start..=end` of a `len` byte file.
+    Partial { response: Response<H, B>, start: u32, end: u32, len: u32 },
+    /// A satisfied conditional request or a `HEAD`, see [`answer_from_entry`].
+    NotModified(N),
+    Error(FileError),
+}
+
+impl<L, N, H, B> FileResponse<L, N, H, B> {
+    /// `response` for the `partial` range of a `len` byte file, if any.
+    fn ranged(response: Response<H, B>, partial: Option<(u32, u32)>, len: u32) -> Self {
+        match partial {
+            Some((start, end)) => FileResponse::Partial { response, start, end, len },
+            None => FileResponse::Full(response),
+        }
+    }
+}
+
+impl<L: IntoResponse, N: IntoResponse, H: HeadersIter, B: Body> IntoResponse for FileResponse<L, N, H, B> {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match self {
+            FileResponse::Listing(response) => response.write_to(connection, response_writer).await,
+            FileResponse::Full(response) => {
+                response
+                    .with_header("Accept-Ranges", "bytes")
+                    .write_to(connection, response_writer).await
+            },
+            FileResponse::Partial { response, start, end, len } => {
+                response
+                    .with_header("Accept-Ranges", "bytes")
+                    .with_status_code(StatusCode::PARTIAL_CONTENT)
+                    .with_header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                    .write_to(connection, response_writer).await
+            },
+            FileResponse::NotModified(response) => response.write_to(connection, response_writer).await,
+            FileResponse::Error(e) => e.write_to(connection, response_writer).await,
+        }
+    }
+}
+
+/// Why a file or directory on the card could not be served or removed.
+enum FileError {
+    BadPath(fs_path::PathError),
+    /// The range asked for starts past the end of the `len` byte file.
+    Unsatisfiable(u32),
+    /// The path is one the server keeps to itself.
+    Forbidden(&'static str),
+    Fs(FManError<<FsBlockDevice as BlockDevice>::Error>),
+}
+
+impl IntoResponse for FileError {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match self {
+            FileError::BadPath(e) => bad_path(e).write_to(connection, response_writer).await,
+            FileError::Unsatisfiable(len) => range_not_satisfiable(len).write_to(connection, response_writer).await,
+            FileError::Forbidden(e) => Response::new(StatusCode::FORBIDDEN, e).write_to(connection, response_writer).await,
+            FileError::Fs(e) => resolve_error_response(e).write_to(connection, response_writer).await,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct CatchAll;
 
+/// Matches the rest of the path, handing it to the handler decoded and
+/// canonicalized by [`fs_path::normalize`].
 impl<T: Copy + core::fmt::Debug> PathDescription<T> for CatchAll {
-    type NewPathParameters = String;
+    type NewPathParameters = Result<String, fs_path::PathError>;
 
     fn parse_and_validate<'r, U, F: FnOnce(Self::NewPathParameters, Path<'r>) -> Result<U, Self::NewPathParameters>>(
         &self,
@@ -68,7 +234,7 @@ impl<T: Copy + core::fmt::Debug> PathDescription<T> for CatchAll {
         path: Path<'r>,
         validate: F,
     ) -> Result<U, T> {
-        let remaining = String::from(path.encoded());
+        let remaining = fs_path::normalize(path.encoded());
         
         let mut empty = path;
         while let Some(p) = empty.split_first_segment() {
@@ -79,18 +245,52 @@ impl<T: Copy + core::fmt::Debug> PathDescription<T> for CatchAll {
     }
 }
 
+/// Matches a single path segment, handing it over as it is: the key of a
+/// resumable upload, see [`resumable`].
+#[derive(Copy, Clone, Debug)]
+pub struct UploadKey;
+
+impl<T: Copy + core::fmt::Debug> PathDescription<T> for UploadKey {
+    type NewPathParameters = String;
+
+    fn parse_and_validate<'r, U, F: FnOnce(Self::NewPathParameters, Path<'r>) -> Result<U, Self::NewPathParameters>>(
+        &self,
+        current_path_parameters: T,
+        path: Path<'r>,
+        validate: F,
+    ) -> Result<U, T> {
+        let key = path.encoded().trim_start_matches('/');
+        if key.is_empty() || key.contains('/') {
+            return Err(current_path_parameters);
+        }
+
+        let mut empty = path;
+        while let Some(p) = empty.split_first_segment() {
+            empty = p.1;
+        }
+
+        validate(String::from(key), empty).map_err(|_| current_path_parameters)
+    }
+}
+
 pub struct FsIterChunks<D: BlockDevice, A: Allocator + Clone> {
-    pub file: Result<FileType, FManError<D::Error>>,
-    #[cfg(feature = "embassy-mode")]
-    pub fman: &'static FMan<ConcreteSpi<'static>, ConcreteDelay>,
-    #[cfg(feature = "std-mode")]
-    pub fman: &'static FMan,
-    pub allocator: A
+    pub file: Result<Opened<'static>, FManError<D::Error>>,
+    pub allocator: A,
+    pub range: Option<(u32, u32)>,
+    /// `(short name, long name)` pairs shown in place of the short names of
+    /// a directory listing.
+    pub names: alloc::vec::Vec<(String, String)>,
 }
 
 impl <D: BlockDevice, A: Allocator + Clone> Chunks for FsIterChunks<D, A> {
     fn content_type(&self) -> &'static str {
-        "text/html"
+        match (&self.file, self.range) {
+            (Ok(opened), Some(_)) => match opened.entry() {
+                FileType::File(entry, _) => mime::from_extension(entry.name.extension()),
+                FileType::Dir(_) => "text/html"
+            },
+            _ => "text/html"
+        }
     }
 
     async fn write_chunks<W: picoserve::io::Write>(
@@ -98,72 +298,49 @@ impl <D: BlockDevice, A: Allocator + Clone> Chunks for FsIterChunks<D, A> {
         mut chunk_writer: ChunkWriter<W>,
     ) -> Result<ChunksWritten, W::Error> {
         match self.file {
-            Ok(file) => {
-                match file {
+            Ok(opened) => {
+                match *opened.entry() {
                     FileType::Dir(dir) => {
-                        let state = self.fman.state.lock().await;
-                        if let CardState::Active{ ref vm, vol: _ } = state.card_state {
-                            let mut files: Vec<Vec<u8, A>, A> = Vec::new_in(self.allocator.clone());
-                            vm.iterate_dir(dir, |entry| {
-                                if entry.attributes.is_volume() {
-                                    return;
-                                }
-                                let mut buf: Vec<u8, A> = Vec::new_in(self.allocator.clone());
-                                let is_dir = entry.attributes.is_directory();
-                                buf.extend_from_slice(b"<div>");
-                                buf.extend_from_slice(b"<span class=\"size\">");
-                                buf.extend_from_slice(format!("{:?} B", entry.size).as_bytes());
-                                buf.extend_from_slice(b"</span>");
-                                buf.extend_from_slice(b"<a>");
-                                buf.extend_from_slice(entry.name.base_name());
-                                if is_dir {
-                                    buf.push('/' as u8);
-                                } else {
-                                    buf.push('.' as u8);
-                                    buf.extend_from_slice(entry.name.extension());
-                                }
-                                buf.extend_from_slice(b"</a>");
-                                buf.extend_from_slice(b"</div>");
-                                files.push(buf);
-                            }).unwrap();
-                            for f in files.iter() {
-                                chunk_writer.write_chunk(f).await?;
-                                chunk_writer.write_chunk("<br>".as_bytes()).await?;
+                        let mut files: Vec<Vec<u8, A>, A> = Vec::new_in(self.allocator.clone());
+                        let _ = opened.with_vm(|vm| vm.iterate_dir(dir, |entry| {
+                            if entry.attributes.is_volume() {
+                                return;
                             }
-
-                            chunk_writer.write_chunk(include_str!("./html/dir_page.html").as_bytes()).await?;
+                            let mut buf: Vec<u8, A> = Vec::new_in(self.allocator.clone());
+                            let is_dir = entry.attributes.is_directory();
+                            let size = format!("{:?}", entry.size);
+                            let short = format!("{}", entry.name);
+                            let name = self.names.iter()
+                                .find(|(s, _)| *s == short)
+                                .map_or(short.as_str(), |(_, long)| long.as_str());
+                            template::render_into(
+                                &mut buf,
+                                "<div><span class=\"size\">{} B</span><a>{}{}</a></div>",
+                                &[
+                                    template::Arg::Safe(&size),
+                                    template::Arg::Text(name.as_bytes()),
+                                    template::Arg::Safe(if is_dir { "/" } else { "" }),
+                                ]
+                            );
+                            files.push(buf);
+                        })).await;
+                        for f in files.iter() {
+                            chunk_writer.write_chunk(f).await?;
+                            chunk_writer.write_chunk("<br>".as_bytes()).await?;
                         }
+
+                        chunk_writer.write_chunk(include_str!("./html/dir_page.html").as_bytes()).await?;
                     },
                     FileType::File(ref entry, f) => {
-                        let state = self.fman.state.lock().await;
-                        if let CardState::Active{ ref vm, vol: _ } = state.card_state {
+                        if self.range.is_some() {
+                            write_file_chunks(&opened, f, self.range, false, self.allocator.clone(), &mut chunk_writer).await?;
+                        } else {
                             let ext = entry.name.extension();
                             if ext == b"TXT" || ext == b"HTM" {
                                 if ext == b"TXT" {
                                     chunk_writer.write_chunk(b"<pre>").await?;
                                 }
-                                let mut buffer: Vec<u8, A> = Vec::with_capacity_in(1024, self.allocator.clone());
-                                buffer.resize(buffer.capacity(), 0);
-                                buffer.fill(0);
-                                loop {
-                                    match vm.read(f, buffer.as_mut()) {
-                                        Ok(count) => {
-                                            chunk_writer.write_chunk(&buffer[0..count]).await?;
-                                            match vm.file_eof(f) {
-                                                Ok(is_eof) => if is_eof { break },
-                                                Err(e) => {
-                                                    chunk_writer.write_chunk(format!("error: {:?}", e).as_bytes()).await?;
-                                                    break;
-                                                }
-                                            }
-                                        },
-                                        Err(e) => {
-                                            chunk_writer.write_chunk(format!("error: {:?}", e).as_bytes()).await?;
-                                            break;
-                                        }
-                                    }
-                                }
-
+                                write_file_chunks(&opened, f, None, ext == b"TXT", self.allocator.clone(), &mut chunk_writer).await?;
                                 if ext == b"TXT" {
                                     chunk_writer.write_chunk(b"</pre>").await?;
                                 }
@@ -177,7 +354,6 @@ impl <D: BlockDevice, A: Allocator + Clone> Chunks for FsIterChunks<D, A> {
                         }
                     }
                 }
-                self.fman.close_file_type(file).await;
             },
             Err(e) => {
                 chunk_writer.write_chunk(format!("error: {:?}", e).as_bytes()).await?;
@@ -198,15 +374,14 @@ where W: picoserve::io::Write,
         Output = Result<Result<ChunksWritten, W::Error>, FManError<<FsBlockDevice as BlockDevice>::Error>>>
         + 'a where Self: 'a;
 
-    fn call<'a>(mut self, root_dir: RawDirectory, vm: &'a VolumeManager<BlkDev, DummyTimesource, 4, 4, 1>) -> Self::Fut<'a> {
+    fn call<'a>(mut self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
         async move {
-            let root_dir = root_dir.to_directory(vm);
             let allocator = ExtAlloc::default();
 
             match root_dir.open_dir(consts::DB_DIR) {
                 Ok(dir) => {
-                    let db_dir = DbDirSdmmc::new(dir.to_raw_directory());
-                    let vm = VM::new(vm);
+                    let db_dir = DbDirSdmmc::new(dir.into_raw());
+                    let vm = VM::new(root_dir.vm());
                     let mut db = match Database::new_init(vm, db_dir, allocator.clone()) {
                         Ok(d) => d,
                         Err(e) => {
@@ -236,14 +411,15 @@ where W: picoserve::io::Write,
                         ) {
                             Ok(mut exec) => {
                                 while let Ok(row) = exec.next() {
-                                    let actual_name = unsafe { core::str::from_utf8_unchecked(row[0].to_chars().unwrap()) };
-                                    let name = unsafe { core::str::from_utf8_unchecked(row[1].to_chars().unwrap()) };
-                                    if let Err(e) = write!(
-                                        self.chunk_writer,
+                                    let size = format!("{}", row[2].to_int().unwrap());
+                                    if let Err(e) = template::render(
+                                        &mut self.chunk_writer,
                                         "<div><span class=\"size\">{} B</span><a>{};{}</a></div><br>",
-                                        row[2].to_int().unwrap(),
-                                        actual_name,
-                                        name
+                                        &[
+                                            template::Arg::Safe(&size),
+                                            template::Arg::Text(row[0].to_chars().unwrap()),
+                                            template::Arg::Text(row[1].to_chars().unwrap()),
+                                        ]
                                     ).await {
                                         return Ok(Err(e));
                                     }
@@ -301,17 +477,15 @@ impl Chunks for FilesIterChunks {
 }
 
 pub struct DownloadIterChunks<D: BlockDevice, A: Allocator + Clone> {
-    pub file: Result<FileType, FManError<D::Error>>,
-    #[cfg(feature = "embassy-mode")]
-    pub fman: &'static FMan<ConcreteSpi<'static>, ConcreteDelay>,
-    #[cfg(feature = "std-mode")]
-    pub fman: &'static FMan,
-    pub allocator: A
+    pub file: Result<Opened<'static>, FManError<D::Error>>,
+    pub allocator: A,
+    pub range: Option<(u32, u32)>,
+    pub content_type: &'static str
 }
 
 impl <D: BlockDevice, A: Allocator + Clone> Chunks for DownloadIterChunks<D, A> {
     fn content_type(&self) -> &'static str {
-        ""
+        self.content_type
     }
 
     async fn write_chunks<W: picoserve::io::Write>(
@@ -319,38 +493,10 @@ impl <D: BlockDevice, A: Allocator + Clone> Chunks for DownloadIterChunks<D, A>
         mut chunk_writer: ChunkWriter<W>,
     ) -> Result<ChunksWritten, W::Error> {
         match self.file {
-            Ok(file) => {
-                match file {
-                    FileType::Dir(_) => {
-                    },
-                    FileType::File(_, f) => {
-                        let state = self.fman.state.lock().await;
-                        if let CardState::Active{ ref vm, vol: _ } = state.card_state {
-                            let mut buffer: Vec<u8, A> = Vec::with_capacity_in(1024, self.allocator.clone());
-                            buffer.resize(buffer.capacity(), 0);
-                            buffer.fill(0);
-                            loop {
-                                match vm.read(f, buffer.as_mut()) {
-                                    Ok(count) => {
-                                        chunk_writer.write_chunk(&buffer[0..count]).await?;
-                                        match vm.file_eof(f) {
-                                            Ok(is_eof) => if is_eof { break },
-                                            Err(e) => {
-                                                chunk_writer.write_chunk(format!("error: {:?}", e).as_bytes()).await?;
-                                                break;
-                                            }
-                                        }
-                                    },
-                                    Err(e) => {
-                                        chunk_writer.write_chunk(format!("error: {:?}", e).as_bytes()).await?;
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    }
+            Ok(opened) => {
+                if let FileType::File(_, f) = *opened.entry() {
+                    write_file_chunks(&opened, f, self.range, false, self.allocator.clone(), &mut chunk_writer).await?;
                 }
-                self.fman.close_file_type(file).await;
             },
             Err(e) => {
                 chunk_writer.write_chunk(format!("error: {:?}", e).as_bytes()).await?;
@@ -360,7 +506,37 @@ impl <D: BlockDevice, A: Allocator + Clone> Chunks for DownloadIterChunks<D, A>
     }
 }
 
-pub struct FileUploader;
+/// An upload that failed or was turned away, answered with its status.
+#[derive(Debug)]
+pub struct UploadError(pub StatusCode, pub &'static str);
+
+impl From<&'static str> for UploadError {
+    fn from(e: &'static str) -> Self {
+        Self(StatusCode::BAD_REQUEST, e)
+    }
+}
+
+/// Answer to an upload larger than the free space, sent with
+/// `507 Insufficient Storage`.
+pub(crate) const NO_SPACE: &str = "not enough free space on the card";
+
+/// Whether `length` more bytes fit on the volume of the long `path`. When
+/// the free space cannot be read the upload is let through, to fail on its
+/// own should the card fill up.
+pub(crate) async fn fits_on_card(path: &str, length: u64) -> bool {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let (volume, _) = fman.volume_path(path).await;
+    match fman.free_space(volume).await {
+        Ok(free) => length <= free,
+        Err(_) => true,
+    }
+}
+
+pub struct FileUploader(pub Result<chunks::UploadReport, UploadError>);
 
 impl<'r, State> FromRequest<'r, State> for FileUploader {
     type Rejection = &'static str;
@@ -370,11 +546,11 @@ impl<'r, State> FromRequest<'r, State> for FileUploader {
         parts: RequestParts<'r>,
         body: RequestBody<'r, R>,
     ) -> Result<Self, Self::Rejection> {
-        file_uploader::upload_file_to_dir(parts, body, consts::FILES_DIR, consts::FILES_TABLE).await.map(|_| Self)
+        Ok(Self(file_uploader::upload_file_to_dir(parts, body, consts::FILES_DIR, consts::FILES_TABLE).await))
     }
 }
 
-pub struct MusicUploader;
+pub struct MusicUploader(pub Result<chunks::UploadReport, UploadError>);
 
 impl<'r, State> FromRequest<'r, State> for MusicUploader {
     type Rejection = &'static str;
@@ -384,121 +560,459 @@ impl<'r, State> FromRequest<'r, State> for MusicUploader {
         parts: RequestParts<'r>,
         body: RequestBody<'r, R>,
     ) -> Result<Self, Self::Rejection> {
-        file_uploader::upload_file_to_dir(parts, body, consts::MUSIC_DIR, consts::MUSIC_TABLE).await.map(|_| Self)
+        Ok(Self(file_uploader::upload_file_to_dir(parts, body, consts::MUSIC_DIR, consts::MUSIC_TABLE).await))
+    }
+}
+
+pub struct RawUploader(pub Result<usize, UploadError>);
+
+impl<'r, State> FromRequest<'r, State> for RawUploader {
+    type Rejection = &'static str;
+
+    async fn from_request<R: Read>(
+        _state: &'r State,
+        parts: RequestParts<'r>,
+        body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(raw_uploader::upload_raw_to_path(parts, body).await))
+    }
+}
+
+pub struct TusCreateFile(pub Result<String, resumable::TusError>);
+
+impl<'r, State> FromRequest<'r, State> for TusCreateFile {
+    type Rejection = &'static str;
+
+    async fn from_request<R: Read>(
+        _state: &'r State,
+        parts: RequestParts<'r>,
+        _body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(resumable::create_upload(&parts, consts::FILES_TABLE, consts::FILES_DIR).await))
     }
 }
 
-pub async fn handle_file_upload(_: FileUploader) -> impl IntoResponse {
-    "success"
+pub struct TusCreateMusic(pub Result<String, resumable::TusError>);
+
+impl<'r, State> FromRequest<'r, State> for TusCreateMusic {
+    type Rejection = &'static str;
+
+    async fn from_request<R: Read>(
+        _state: &'r State,
+        parts: RequestParts<'r>,
+        _body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(resumable::create_upload(&parts, consts::MUSIC_TABLE, consts::MUSIC_DIR).await))
+    }
 }
 
-pub async fn handle_music_upload(_: MusicUploader) -> impl IntoResponse {
-    "success"
+fn upload_error(UploadError(status, msg): UploadError) -> impl IntoResponse {
+    Response::new(status, msg)
 }
 
-pub async fn handle_fs(path: String) -> impl IntoResponse {
-    #[cfg(feature = "embassy-mode")]
-    let fman = get_file_manager().await;
-    #[cfg(feature = "std-mode")]
-    let fman = get_file_manager();
+pub async fn handle_file_upload(FileUploader(report): FileUploader) -> impl IntoResponse {
+    let report = match report {
+        Ok(mut report) => {
+            versions::supersede_uploads(&mut report).await;
+            Ok(report)
+        },
+        Err(e) => Err(e),
+    };
+    report.map(picoserve::response::json::Json).map_err(upload_error)
+}
 
-    let file = fman.resolve_path_iter(&path).await;
+pub async fn handle_music_upload(MusicUploader(report): MusicUploader) -> impl IntoResponse {
+    report.map(picoserve::response::json::Json).map_err(upload_error)
+}
 
-    #[cfg(feature = "std-mode")] {
-        ChunkedResponse::new(FsIterChunks::<BlkDev, ExtAlloc> { 
-            file, fman, allocator: ExtAlloc::default()
+fn tus_created(created: Result<String, resumable::TusError>) -> impl IntoResponse {
+    created
+        .map(|key| {
+            Response::new(StatusCode::CREATED, "")
+                .with_header("Location", format!("/uploads/{}", key))
+                .with_header("Tus-Resumable", resumable::TUS_VERSION)
         })
+        .map_err(|resumable::TusError(status, msg)| Response::new(status, msg))
+}
+
+pub async fn handle_tus_create_file(TusCreateFile(created): TusCreateFile) -> impl IntoResponse {
+    tus_created(created)
+}
+
+pub async fn handle_tus_create_music(TusCreateMusic(created): TusCreateMusic) -> impl IntoResponse {
+    tus_created(created)
+}
+
+/// `PATCH /uploads/<key>`. A service rather than a handler, as extractors
+/// that read the body never see the routed key.
+pub struct TusPatch;
+
+impl<State> RequestHandlerService<State, String> for TusPatch {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        _state: &State,
+        key: String,
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let patched = resumable::patch_upload(key, &request.parts, request.body_connection.body()).await;
+        let response = patched
+            .map(|offset| {
+                Response::new(StatusCode::NO_CONTENT, "")
+                    .with_header("Upload-Offset", format!("{}", offset))
+                    .with_header("Tus-Resumable", resumable::TUS_VERSION)
+            })
+            .map_err(|resumable::TusError(status, msg)| Response::new(status, msg));
+        response.write_to(request.body_connection.finalize().await?, response_writer).await
     }
+}
 
-    #[cfg(feature = "embassy-mode")] {
-        ChunkedResponse::new(FsIterChunks::<BlkDev<ConcreteSpi<'static>, ConcreteDelay>, ExtAlloc> { 
-            file, fman, allocator: ExtAlloc::default()
+/// Also answers the `HEAD` requests tus clients use to find the resume offset.
+pub async fn handle_tus_status(key: String) -> impl IntoResponse {
+    resumable::upload_status(key).await
+        .map(|session| {
+            Response::new(StatusCode::OK, "")
+                .with_header("Upload-Offset", format!("{}", session.offset))
+                .with_header("Upload-Length", format!("{}", session.length))
+                .with_header("Cache-Control", "no-store")
+                .with_header("Tus-Resumable", resumable::TUS_VERSION)
         })
+        .map_err(|resumable::TusError(status, msg)| Response::new(status, msg))
+}
+
+pub async fn handle_tus_delete(key: String) -> impl IntoResponse {
+    resumable::delete_upload(key).await
+        .map(|_| Response::new(StatusCode::NO_CONTENT, "").with_header("Tus-Resumable", resumable::TUS_VERSION))
+        .map_err(|resumable::TusError(status, msg)| Response::new(status, msg))
+}
+
+/// Byte range to serve for `file` along with its length, or just the length
+/// when the requested range cannot be satisfied.
+fn requested_range(
+    file: &Opened<'_>,
+    range: Option<&str>,
+) -> Result<(Option<(u32, u32)>, u32), u32> {
+    let len = match file.entry() {
+        FileType::File(entry, _) => entry.size,
+        _ => return Ok((None, 0)),
+    };
+
+    match range::resolve(range, len) {
+        range::ByteRange::Full => Ok((None, len)),
+        range::ByteRange::Partial { start, end } => Ok((Some((start, end)), len)),
+        range::ByteRange::Unsatisfiable => Err(len),
     }
 }
 
-pub async fn handle_files() -> impl IntoResponse {
+fn range_not_satisfiable(len: u32) -> impl IntoResponse {
+    Response::new(StatusCode::RANGE_NOT_SATISFIABLE, "")
+        .with_header("Content-Range", format!("bytes */{}", len))
+}
+
+/// Status and message for a path that could not be resolved to a file.
+fn resolve_error_response(e: FManError<<FsBlockDevice as BlockDevice>::Error>) -> impl IntoResponse {
+    let status = match e {
+        FManError::SdErr(embedded_sdmmc::Error::NotFound) => StatusCode::NOT_FOUND,
+        FManError::CardNotActive
+        | FManError::SdErr(embedded_sdmmc::Error::TooManyOpenDirs)
+        | FManError::SdErr(embedded_sdmmc::Error::TooManyOpenFiles) => StatusCode::SERVICE_UNAVAILABLE,
+        FManError::DirNotEmpty
+        | FManError::SdErr(embedded_sdmmc::Error::FileAlreadyOpen)
+        | FManError::SdErr(embedded_sdmmc::Error::VolumeStillInUse) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    Response::new(status, format!("error: {:?}", e))
+}
+
+/// Answers a `HEAD` or a satisfied conditional request from the directory
+/// entry alone, closing the file. Anything else hands the file back.
+fn answer_from_entry(
+    file: Opened<'static>,
+    validators: &conditional::Validators,
+    cond: &conditional::Conditional,
+    content_type: &'static str,
+) -> Result<impl IntoResponse, Opened<'static>> {
+    let status = if cond.not_modified(validators) {
+        StatusCode::NOT_MODIFIED
+    } else if cond.head {
+        StatusCode::OK
+    } else {
+        return Err(file);
+    };
+
+    drop(file);
+    Ok(conditional::bodiless(status, validators, content_type))
+}
+
+fn bad_path(e: fs_path::PathError) -> impl IntoResponse {
+    Response::new(StatusCode::BAD_REQUEST, e.message())
+}
+
+pub async fn handle_fs(
+    path: Result<String, fs_path::PathError>,
+    range: range::RangeHeader,
+    cond: conditional::Conditional,
+) -> impl IntoResponse {
+    match path {
+        Ok(path) => Ok(serve_fs(path, range, cond).await),
+        Err(e) => Err(bad_path(e)),
+    }
+}
+
+async fn serve_fs(
+    path: String,
+    range::RangeHeader(range): range::RangeHeader,
+    cond: conditional::Conditional,
+) -> impl IntoResponse {
     #[cfg(feature = "embassy-mode")]
     let fman = get_file_manager().await;
     #[cfg(feature = "std-mode")]
     let fman = get_file_manager();
 
-    #[cfg(feature = "std-mode")] {
-        ChunkedResponse::new(FilesIterChunks { 
-            fman
-        })
-    }
+    let file = match fman.open_path(&path).await {
+        Ok(file) => file,
+        Err(e) => return FileResponse::Error(FileError::Fs(e)),
+    };
+    let (validators, content_type) = match *file.entry() {
+        FileType::File(ref entry, _) => (
+            conditional::Validators::of(entry),
+            mime::from_extension(entry.name.extension())
+        ),
+        // directory listings are always generated fresh
+        FileType::Dir(_) => {
+            let names = fman.long_names(&path).await.unwrap_or_default();
+            return FileResponse::Listing(ChunkedResponse::new(FsIterChunks::<ConcreteBlkDev, ExtAlloc> {
+                file: Ok(file), allocator: ExtAlloc::default(), range: None, names
+            }))
+        }
+    };
+
+    let file = match answer_from_entry(file, &validators, &cond, content_type) {
+        Ok(response) => return FileResponse::NotModified(response),
+        Err(file) => file,
+    };
+
+    let (partial, len) = match requested_range(&file, range.as_deref()) {
+        Ok(r) => r,
+        Err(len) => return FileResponse::Error(FileError::Unsatisfiable(len)),
+    };
+
+    // HTM files are rendered as they are and raw ranges may be any HTML, so
+    // only the server's own TXT/other file views keep their inline script.
+    let csp = match *file.entry() {
+        FileType::File(ref entry, _) if partial.is_none() && entry.name.extension() != b"HTM" => template::PAGE_CSP,
+        _ => template::USER_CONTENT_CSP,
+    };
+
+    FileResponse::ranged(
+        ChunkedResponse::new(FsIterChunks::<ConcreteBlkDev, ExtAlloc> {
+            file: Ok(file), allocator: ExtAlloc::default(), range: partial, names: alloc::vec::Vec::new()
+        }).into_response()
+            .with_header("ETag", validators.etag)
+            .with_header("Last-Modified", validators.last_modified)
+            .with_header("Content-Security-Policy", csp)
+            .with_header("X-Content-Type-Options", "nosniff"),
+        partial,
+        len
+    )
+}
 
-    #[cfg(feature = "embassy-mode")] {
-        ChunkedResponse::new(FilesIterChunks { 
-            fman
-        })
-    }
+pub async fn handle_fs_put(_path: Result<String, fs_path::PathError>, RawUploader(written): RawUploader) -> impl IntoResponse {
+    written.map(|size| format!("success: {} bytes written", size)).map_err(upload_error)
 }
 
-pub async fn handle_download(path: String) -> impl IntoResponse {
+/// Removes the file or the whole directory tree at `path`.
+pub async fn handle_fs_delete(path: Result<String, fs_path::PathError>) -> impl IntoResponse {
     #[cfg(feature = "embassy-mode")]
     let fman = get_file_manager().await;
     #[cfg(feature = "std-mode")]
     let fman = get_file_manager();
 
-    let file = fman.resolve_path_iter(&path).await;
+    let path = match path {
+        Ok(path) => path,
+        Err(e) => return Err(FileError::BadPath(e)),
+    };
+    match fman.remove_tree(&path).await {
+        Ok(stats) => Ok(format!("success: removed {} files and {} directories, {} bytes", stats.files, stats.dirs, stats.bytes)),
+        Err(FManError::ServerErr(e)) => Err(FileError::Forbidden(e)),
+        Err(e) => Err(FileError::Fs(e)),
+    }
+}
+
+pub async fn handle_files() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
 
     #[cfg(feature = "std-mode")] {
-        ChunkedResponse::new(DownloadIterChunks::<BlkDev, ExtAlloc> { 
-            file, fman, allocator: ExtAlloc::default()
+        ChunkedResponse::new(FilesIterChunks { 
+            fman
         })
     }
 
     #[cfg(feature = "embassy-mode")] {
-        ChunkedResponse::new(DownloadIterChunks::<BlkDev<ConcreteSpi<'static>, ConcreteDelay>, ExtAlloc> { 
-            file, fman, allocator: ExtAlloc::default()
+        ChunkedResponse::new(FilesIterChunks { 
+            fman
         })
     }
 }
 
-
-struct DeleteFileAsync {
-    name: String
+struct OriginalNameAsync {
+    table: &'static str,
+    path: String
 }
 
-impl AsyncRootFn<&'static str> for DeleteFileAsync {
+impl AsyncRootFn<Option<String>> for OriginalNameAsync {
     type Fut<'a> = impl core::future::Future<
-        Output = Result<&'static str, FManError<<FsBlockDevice as BlockDevice>::Error>>> + 'a where Self: 'a;
+        Output = Result<Option<String>, FManError<<FsBlockDevice as BlockDevice>::Error>>> + 'a where Self: 'a;
 
-    fn call<'a>(self, root_dir: RawDirectory, vm: &'a VolumeManager<BlkDev, DummyTimesource, 4, 4, 1>) -> Self::Fut<'a> {
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
         async move {
-            let root_dir = root_dir.to_directory(vm);
             let allocator = ExtAlloc::default();
-            let db_dir = root_dir.open_dir(consts::DB_DIR).map_err(FManError::SdErr)?.to_raw_directory();
-            let files_dir = root_dir.open_dir(consts::FILES_DIR).map_err(FManError::SdErr)?;
+            let db_dir = root_dir.open_dir(consts::DB_DIR).map_err(FManError::SdErr)?;
 
-            let vm = VM::new(vm);
-            let mut db = Database::new_init(vm, DbDirSdmmc::new(db_dir), allocator.clone()).map_err(FManError::DbErr)?;
-        
-            let files_table = db.get_table("files", allocator.clone()).map_err(FManError::DbErr)?;
+            let mut db = Database::new_init(VM::new(root_dir.vm()), DbDirSdmmc::new(db_dir.into_raw()), allocator.clone()).map_err(FManError::DbErr)?;
+            let table = db.get_table(self.table, allocator.clone()).map_err(FManError::DbErr)?;
+
+            let query = Query::<_, &str>::new(table, allocator.clone())
+                                         .key(Value::Chars(self.path.as_bytes()));
+            let name = match QueryExecutor::new(
+                query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+                &db.file_handler.page_rw.as_ref().unwrap()
+            ) {
+                Ok(mut exec) => match exec.next() {
+                    Ok(row) => core::str::from_utf8(row[1].to_chars().unwrap()).ok().map(String::from),
+                    Err(_) => None,
+                },
+                Err(_) => None,
+            };
 
-            match files_dir.delete_file_in_dir(self.name.as_str()) {
-                Err(embedded_sdmmc::Error::NotFound) => (),
-                Err(e) => return Err(FManError::SdErr(e)),
-                Ok(()) => ()
-            }
+            Ok(name)
+        }
+    }
+}
 
-            db.delete_from_table(files_table, Value::Chars(self.name.as_bytes()), allocator.clone()).map_err(FManError::DbErr)?;
+/// Name a download at `path` should be saved as: the name it was uploaded
+/// with when registered in the `files`/`music` tables, else the last
+/// segment of `path`, which is its long name if it has one.
+async fn download_name(path: &str) -> String {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
 
-            Ok("success")
+    let path = path.trim_matches('/');
+    let (dir, file_name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    if let Some(table) = table_for_dir(dir) {
+        let lookup = OriginalNameAsync { table, path: file_name.to_ascii_uppercase() };
+        if let Ok(Some(name)) = fman.with_root_dir_async(lookup).await {
+            return name;
         }
     }
+    String::from(file_name)
 }
 
+/// `Content-Disposition` value for `name`, with a quoted ASCII fallback and
+/// the exact name percent-encoded in `filename*` (RFC 6266).
+fn attachment_disposition(name: &str) -> String {
+    let mut fallback = String::with_capacity(name.len());
+    let mut encoded = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        fallback.push(match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        });
+    }
+    for &b in name.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+pub async fn handle_download(
+    path: Result<String, fs_path::PathError>,
+    range: range::RangeHeader,
+    cond: conditional::Conditional,
+) -> impl IntoResponse {
+    match path {
+        Ok(path) => Ok(serve_download(path, range, cond).await),
+        Err(e) => Err(bad_path(e)),
+    }
+}
+
+/// Serves the file at the canonical card `path` as a download.
+pub(crate) async fn serve_download(
+    path: String,
+    range::RangeHeader(range): range::RangeHeader,
+    cond: conditional::Conditional,
+) -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let name = download_name(&path).await;
+
+    let file = match fman.open_path(&path).await {
+        Ok(file) => file,
+        Err(e) => return FileResponse::Error(FileError::Fs(e)),
+    };
+    let validators = match *file.entry() {
+        FileType::File(ref entry, _) => conditional::Validators::of(entry),
+        FileType::Dir(_) => return FileResponse::Listing(ChunkedResponse::new(DownloadIterChunks::<ConcreteBlkDev, ExtAlloc> {
+            file: Ok(file), allocator: ExtAlloc::default(), range: None, content_type: mime::DEFAULT_MIME
+        }))
+    };
+
+    let content_type = mime::from_filename(&name);
+
+    let file = match answer_from_entry(file, &validators, &cond, content_type) {
+        Ok(response) => return FileResponse::NotModified(response),
+        Err(file) => file,
+    };
+
+    let (partial, len) = match requested_range(&file, range.as_deref()) {
+        Ok(r) => r,
+        Err(len) => return FileResponse::Error(FileError::Unsatisfiable(len)),
+    };
+
+    FileResponse::ranged(
+        ChunkedResponse::new(DownloadIterChunks::<ConcreteBlkDev, ExtAlloc> {
+            file: Ok(file), allocator: ExtAlloc::default(), range: partial, content_type
+        }).into_response()
+            .with_header("Content-Disposition", attachment_disposition(&name))
+            .with_header("Content-Security-Policy", template::USER_CONTENT_CSP)
+            .with_header("X-Content-Type-Options", "nosniff")
+            .with_header("ETag", validators.etag)
+            .with_header("Last-Modified", validators.last_modified),
+        partial,
+        len
+    )
+}
+
+
+/// Moves the upload `name` to the trash, see [`trash`].
 pub async fn handle_delete_file(name: String) -> impl IntoResponse {
     #[cfg(feature = "embassy-mode")]
     let fman = get_file_manager().await;
     #[cfg(feature = "std-mode")]
     let fman = get_file_manager();
 
-    let r = DeleteFileAsync { name };
-    fman.with_root_dir_async(r).await.map_err(|e| picoserve::response::DebugValue(e))
+    match fman.trash(consts::FILES_TABLE, &name).await {
+        Ok(Some(_)) => Ok("success"),
+        Ok(None) => Err(Response::new(StatusCode::NOT_FOUND, String::from("no such file"))),
+        Err(FManError::CardNotActive) => Err(Response::new(StatusCode::SERVICE_UNAVAILABLE, String::from("SD Card not active"))),
+        Err(e) => Err(Response::new(StatusCode::INTERNAL_SERVER_ERROR, format!("error: {:?}", e))),
+    }
 }
 
 struct DeleteDbAsync;
@@ -507,17 +1021,16 @@ impl AsyncRootFn<&'static str> for DeleteDbAsync {
     type Fut<'a> = impl core::future::Future<
         Output = Result<&'static str, FManError<<FsBlockDevice as BlockDevice>::Error>>> + 'a where Self: 'a;
 
-    fn call<'a>(self, root_dir: RawDirectory, vm: &'a VolumeManager<BlkDev, DummyTimesource, 4, 4, 1>) -> Self::Fut<'a> {
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
         async move {
-            let root_dir = root_dir.to_directory(vm);
             let db_dir = root_dir.open_dir(consts::DB_DIR).map_err(FManError::SdErr)?;
-            match db_dir.delete_file_in_dir(alpa::WAL_FILE_NAME) {
+            match db_dir.delete_file(alpa::WAL_FILE_NAME) {
                 Err(embedded_sdmmc::Error::NotFound) => (),
                 Err(e) => return Err(FManError::SdErr(e)),
                 Ok(()) => ()
             }
 
-            match db_dir.delete_file_in_dir(alpa::DB_FILE_NAME) {
+            match db_dir.delete_file(alpa::DB_FILE_NAME) {
                 Err(embedded_sdmmc::Error::NotFound) => (),
                 Err(e) => return Err(FManError::SdErr(e)),
                 Ok(()) => ()
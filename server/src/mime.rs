@@ -0,0 +1,51 @@
+/// Extension to MIME type mapping, extensions in uppercase as FAT stores them.
+pub static MIME_TYPES: &[(&str, &str)] = &[
+    ("TXT", "text/plain; charset=utf-8"),
+    ("HTM", "text/html; charset=utf-8"),
+    ("HTML", "text/html; charset=utf-8"),
+    ("CSS", "text/css"),
+    ("JS", "text/javascript"),
+    ("JSON", "application/json"),
+    ("CSV", "text/csv"),
+    ("XML", "application/xml"),
+    ("PDF", "application/pdf"),
+    ("ZIP", "application/zip"),
+    ("GZ", "application/gzip"),
+    ("TAR", "application/x-tar"),
+    ("PNG", "image/png"),
+    ("JPG", "image/jpeg"),
+    ("JPEG", "image/jpeg"),
+    ("GIF", "image/gif"),
+    ("BMP", "image/bmp"),
+    ("SVG", "image/svg+xml"),
+    ("ICO", "image/x-icon"),
+    ("WEBP", "image/webp"),
+    ("MP3", "audio/mpeg"),
+    ("WAV", "audio/wav"),
+    ("OGG", "audio/ogg"),
+    ("FLAC", "audio/flac"),
+    ("M4A", "audio/mp4"),
+    ("AAC", "audio/aac"),
+    ("MP4", "video/mp4"),
+    ("WEBM", "video/webm"),
+    ("MKV", "video/x-matroska"),
+    ("AVI", "video/x-msvideo"),
+];
+
+pub const DEFAULT_MIME: &str = "application/octet-stream";
+
+/// MIME type for an extension (without the dot), case-insensitive.
+pub fn from_extension(ext: &[u8]) -> &'static str {
+    MIME_TYPES.iter()
+        .find(|(e, _)| e.as_bytes().eq_ignore_ascii_case(ext))
+        .map(|(_, mime)| *mime)
+        .unwrap_or(DEFAULT_MIME)
+}
+
+/// MIME type for a file name, going by whatever follows its last dot.
+pub fn from_filename(name: &str) -> &'static str {
+    match name.rsplit_once('.') {
+        Some((_, ext)) => from_extension(ext.as_bytes()),
+        None => DEFAULT_MIME,
+    }
+}
@@ -0,0 +1,398 @@
+//! Incremental `multipart/form-data` parser.
+//!
+//! The request body reaches the uploader task as a sequence of fixed size
+//! [`Chunk`](crate::chunks::Chunk)s, so a delimiter or a part header can be
+//! split anywhere between two buffers. [`MultipartParser`] keeps only the
+//! state needed to recognise such a split and hands every payload byte to a
+//! [`PartSink`] as soon as it is known not to belong to a delimiter.
+
+/// RFC 2046 limits boundaries to 70 characters.
+pub const MAX_BOUNDARY_LEN: usize = 70;
+pub const MAX_FILENAME_LEN: usize = 128;
+
+const DELIM_CAP: usize = MAX_BOUNDARY_LEN + 4;
+const HEADER_LINE_CAP: usize = 256;
+
+/// Receiver of the parts found in a multipart body.
+///
+/// `part_begin` is called once the headers of a part are complete, with the
+/// `filename` of its `Content-Disposition` (empty for plain form fields).
+pub trait PartSink {
+    fn part_begin(&mut self, filename: &[u8]) -> Result<(), &'static str>;
+    fn part_data(&mut self, data: &[u8]) -> Result<(), &'static str>;
+    fn part_end(&mut self) -> Result<(), &'static str>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Preamble,
+    AfterDelimiter,
+    AfterDelimiterDash,
+    AfterDelimiterCr,
+    Headers,
+    Body,
+    Finished,
+}
+
+pub struct MultipartParser {
+    state: State,
+    delim: [u8; DELIM_CAP],
+    delim_len: usize,
+    fail: [u8; DELIM_CAP],
+    matched: usize,
+    line: [u8; HEADER_LINE_CAP],
+    line_len: usize,
+    filename: [u8; MAX_FILENAME_LEN],
+    filename_len: usize,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &[u8]) -> Result<Self, &'static str> {
+        if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LEN {
+            return Err("invalid multipart boundary");
+        }
+
+        let mut delim = [0u8; DELIM_CAP];
+        delim[..4].copy_from_slice(b"\r\n--");
+        delim[4..4 + boundary.len()].copy_from_slice(boundary);
+        let delim_len = boundary.len() + 4;
+
+        // KMP failure table, so a partial delimiter that turns out to be data
+        // can be released without looking back at previous buffers.
+        let mut fail = [0u8; DELIM_CAP];
+        let mut k = 0;
+        for i in 1..delim_len {
+            while k > 0 && delim[i] != delim[k] {
+                k = fail[k - 1] as usize;
+            }
+            if delim[i] == delim[k] {
+                k += 1;
+            }
+            fail[i] = k as u8;
+        }
+
+        Ok(Self {
+            state: State::Preamble,
+            delim,
+            delim_len,
+            fail,
+            // the first delimiter has no leading CRLF, so pretend it was already seen
+            matched: 2,
+            line: [0; HEADER_LINE_CAP],
+            line_len: 0,
+            filename: [0; MAX_FILENAME_LEN],
+            filename_len: 0,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Finished
+    }
+
+    /// Checks that the closing delimiter was seen once the body is exhausted.
+    pub fn finish(&self) -> Result<(), &'static str> {
+        if self.is_finished() {
+            Ok(())
+        } else {
+            Err("unexpected end of multipart body")
+        }
+    }
+
+    pub fn feed<S: PartSink>(&mut self, data: &[u8], sink: &mut S) -> Result<(), &'static str> {
+        let mut i = 0;
+
+        while i < data.len() {
+            match self.state {
+                State::Preamble | State::Body => {
+                    i += self.scan_body(&data[i..], sink)?;
+                }
+                State::AfterDelimiter => {
+                    match data[i] {
+                        b'-' => self.state = State::AfterDelimiterDash,
+                        b'\r' => self.state = State::AfterDelimiterCr,
+                        b' ' | b'\t' => (),
+                        _ => return Err("malformed multipart delimiter"),
+                    }
+                    i += 1;
+                }
+                State::AfterDelimiterDash => {
+                    if data[i] != b'-' {
+                        return Err("malformed multipart delimiter");
+                    }
+                    self.state = State::Finished;
+                    i += 1;
+                }
+                State::AfterDelimiterCr => {
+                    if data[i] != b'\n' {
+                        return Err("malformed multipart delimiter");
+                    }
+                    self.state = State::Headers;
+                    self.line_len = 0;
+                    self.filename_len = 0;
+                    i += 1;
+                }
+                State::Headers => {
+                    let byte = data[i];
+                    i += 1;
+                    if byte != b'\n' {
+                        if self.line_len < HEADER_LINE_CAP {
+                            self.line[self.line_len] = byte;
+                            self.line_len += 1;
+                        }
+                        continue;
+                    }
+
+                    let mut line_len = self.line_len;
+                    if line_len > 0 && self.line[line_len - 1] == b'\r' {
+                        line_len -= 1;
+                    }
+                    self.line_len = 0;
+
+                    if line_len == 0 {
+                        self.state = State::Body;
+                        sink.part_begin(&self.filename[..self.filename_len])?;
+                    } else {
+                        self.parse_header(line_len);
+                    }
+                }
+                State::Finished => return Ok(()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Streams payload bytes to `sink` until a full delimiter is matched.
+    /// Returns the number of bytes of `data` consumed.
+    fn scan_body<S: PartSink>(&mut self, data: &[u8], sink: &mut S) -> Result<usize, &'static str> {
+        let emit = self.state == State::Body;
+        let mut run_start = 0;
+        let mut i = 0;
+
+        while i < data.len() {
+            let byte = data[i];
+            i += 1;
+
+            if self.matched == 0 {
+                if byte != self.delim[0] {
+                    continue;
+                }
+                if emit && run_start < i - 1 {
+                    sink.part_data(&data[run_start..i - 1])?;
+                }
+                self.matched = 1;
+            } else {
+                loop {
+                    if self.delim[self.matched] == byte {
+                        self.matched += 1;
+                        break;
+                    }
+                    let keep = self.fail[self.matched - 1] as usize;
+                    if emit {
+                        sink.part_data(&self.delim[..self.matched - keep])?;
+                    }
+                    self.matched = keep;
+                    if self.matched == 0 {
+                        break;
+                    }
+                }
+
+                if self.matched == 0 {
+                    if byte == self.delim[0] {
+                        self.matched = 1;
+                    } else {
+                        run_start = i - 1;
+                        continue;
+                    }
+                }
+            }
+            run_start = i;
+
+            if self.matched == self.delim_len {
+                self.matched = 0;
+                if emit {
+                    sink.part_end()?;
+                }
+                self.state = State::AfterDelimiter;
+                return Ok(i);
+            }
+        }
+
+        if emit && self.matched == 0 && run_start < data.len() {
+            sink.part_data(&data[run_start..])?;
+        }
+
+        Ok(data.len())
+    }
+
+    fn parse_header(&mut self, line_len: usize) {
+        let line = &self.line[..line_len];
+        let name = b"content-disposition:";
+        if line.len() < name.len() || !line[..name.len()].eq_ignore_ascii_case(name) {
+            return;
+        }
+
+        let key = b"filename=";
+        let Some(pos) = line.windows(key.len()).position(|w| w == key) else {
+            return;
+        };
+        let value = &line[pos + key.len()..];
+        let value = match value.first() {
+            Some(b'"') => {
+                let value = &value[1..];
+                &value[..value.iter().position(|&b| b == b'"').unwrap_or(value.len())]
+            }
+            _ => &value[..value.iter().position(|&b| b == b';').unwrap_or(value.len())],
+        };
+
+        let len = value.len().min(MAX_FILENAME_LEN);
+        self.filename[..len].copy_from_slice(&value[..len]);
+        self.filename_len = len;
+    }
+}
+
+/// Extracts the `boundary` parameter of a `multipart/form-data` content type.
+pub fn boundary_from_content_type(content_type: &[u8]) -> Option<&[u8]> {
+    let key = b"boundary=";
+    let pos = content_type.windows(key.len()).position(|w| w == key)?;
+    let value = &content_type[pos + key.len()..];
+    let value = match value.first() {
+        Some(b'"') => {
+            let value = &value[1..];
+            &value[..value.iter().position(|&b| b == b'"')?]
+        }
+        _ => &value[..value.iter().position(|&b| b == b';' || b == b' ').unwrap_or(value.len())],
+    };
+
+    if value.is_empty() { None } else { Some(value) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Part {
+        filename: Vec<u8>,
+        data: Vec<u8>,
+        ended: bool,
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        parts: Vec<Part>,
+    }
+
+    impl PartSink for Recorder {
+        fn part_begin(&mut self, filename: &[u8]) -> Result<(), &'static str> {
+            self.parts.push(Part { filename: filename.to_vec(), ..Default::default() });
+            Ok(())
+        }
+
+        fn part_data(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            let part = self.parts.last_mut().ok_or("data outside part")?;
+            assert!(!part.ended);
+            part.data.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn part_end(&mut self) -> Result<(), &'static str> {
+            self.parts.last_mut().ok_or("end outside part")?.ended = true;
+            Ok(())
+        }
+    }
+
+    const BOUNDARY: &[u8] = b"----WebKitFormBoundaryx7Z";
+
+    fn body(parts: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (filename, data) in parts {
+            out.extend_from_slice(b"--");
+            out.extend_from_slice(BOUNDARY);
+            out.extend_from_slice(b"\r\nContent-Disposition: form-data; name=\"file\"; filename=\"");
+            out.extend_from_slice(filename);
+            out.extend_from_slice(b"\"\r\nContent-Type: application/octet-stream\r\n\r\n");
+            out.extend_from_slice(data);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"--");
+        out.extend_from_slice(BOUNDARY);
+        out.extend_from_slice(b"--\r\n");
+        out
+    }
+
+    fn parse_in_chunks(input: &[u8], sizes: &mut dyn Iterator<Item = usize>) -> Recorder {
+        let mut parser = MultipartParser::new(BOUNDARY).unwrap();
+        let mut sink = Recorder::default();
+        let mut rest = input;
+        while !rest.is_empty() {
+            let n = sizes.next().unwrap_or(rest.len()).clamp(1, rest.len());
+            parser.feed(&rest[..n], &mut sink).unwrap();
+            rest = &rest[n..];
+        }
+        parser.finish().unwrap();
+        sink
+    }
+
+    fn expected(parts: &[(&[u8], &[u8])]) -> Vec<Part> {
+        parts.iter().map(|(filename, data)| Part {
+            filename: filename.to_vec(),
+            data: data.to_vec(),
+            ended: true,
+        }).collect()
+    }
+
+    #[test]
+    fn single_part_in_one_buffer() {
+        let parts: &[(&[u8], &[u8])] = &[(b"notes.txt", b"hello world")];
+        let sink = parse_in_chunks(&body(parts), &mut core::iter::empty());
+        assert_eq!(sink.parts, expected(parts));
+    }
+
+    #[test]
+    fn split_at_every_offset() {
+        let parts: &[(&[u8], &[u8])] = &[(b"a.bin", b"\r\n--not-the-boundary\r\n-"), (b"b.mp3", b"xyz")];
+        let input = body(parts);
+        for split in 1..input.len() {
+            let sink = parse_in_chunks(&input, &mut [split].into_iter());
+            assert_eq!(sink.parts, expected(parts), "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn one_byte_at_a_time() {
+        let parts: &[(&[u8], &[u8])] = &[(b"song.mp3", b"\r\r\n\r\n-------WebKitFormBoundary")];
+        let sink = parse_in_chunks(&body(parts), &mut core::iter::repeat(1));
+        assert_eq!(sink.parts, expected(parts));
+    }
+
+    #[test]
+    fn partial_delimiter_at_end_of_payload() {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"payload\r\n--");
+        data.extend_from_slice(&BOUNDARY[..BOUNDARY.len() - 1]);
+        let parts: &[(&[u8], &[u8])] = &[(b"tricky.dat", &data)];
+        let input = body(parts);
+        for size in [1, 3, 7, 64] {
+            let sink = parse_in_chunks(&input, &mut core::iter::repeat(size));
+            assert_eq!(sink.parts, expected(parts), "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let input = body(&[(b"a.txt", b"data")]);
+        let mut parser = MultipartParser::new(BOUNDARY).unwrap();
+        let mut sink = Recorder::default();
+        parser.feed(&input[..input.len() - 10], &mut sink).unwrap();
+        assert!(parser.finish().is_err());
+    }
+
+    #[test]
+    fn boundary_from_header() {
+        assert_eq!(boundary_from_content_type(b"multipart/form-data; boundary=abc"), Some(&b"abc"[..]));
+        assert_eq!(boundary_from_content_type(b"multipart/form-data; boundary=\"a b\"; x=y"), Some(&b"a b"[..]));
+        assert_eq!(boundary_from_content_type(b"multipart/form-data"), None);
+    }
+}
@@ -0,0 +1,109 @@
+use picoserve::extract::FromRequestParts;
+use picoserve::request::RequestParts;
+use crate::String;
+
+/// Raw value of the `Range` request header, if any.
+pub struct RangeHeader(pub Option<String>);
+
+impl<'r, State> FromRequestParts<'r, State> for RangeHeader {
+    type Rejection = &'static str;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(
+            request_parts.headers().get("Range")
+                .and_then(|v| v.as_str().ok())
+                .map(String::from)
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    Full,
+    /// Inclusive on both ends, as in `Content-Range`.
+    Partial { start: u32, end: u32 },
+    Unsatisfiable,
+}
+
+/// Resolves a `Range` header against a file of `len` bytes. Only a single
+/// `bytes` range is honoured; anything else falls back to the full file.
+pub fn resolve(header: Option<&str>, len: u32) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let (start, end) = if start.is_empty() {
+        let Ok(suffix) = end.parse::<u32>() else { return ByteRange::Full };
+        if suffix == 0 || len == 0 {
+            return ByteRange::Unsatisfiable;
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let Ok(start) = start.parse::<u32>() else { return ByteRange::Full };
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            let Ok(end) = end.parse::<u32>() else { return ByteRange::Full };
+            if end < start {
+                return ByteRange::Full;
+            }
+            end.min(len.saturating_sub(1))
+        };
+        if start >= len {
+            return ByteRange::Unsatisfiable;
+        }
+        (start, end)
+    };
+
+    ByteRange::Partial { start, end }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (`Range` header, file length, resolved range)
+    const CASES: &[(Option<&str>, u32, ByteRange)] = &[
+        (None, 100, ByteRange::Full),
+        (Some("bytes=0-9"), 100, ByteRange::Partial { start: 0, end: 9 }),
+        (Some(" bytes=10-19 "), 100, ByteRange::Partial { start: 10, end: 19 }),
+        (Some("bytes=90-"), 100, ByteRange::Partial { start: 90, end: 99 }),
+        (Some("bytes=90-500"), 100, ByteRange::Partial { start: 90, end: 99 }),
+        (Some("bytes=99-99"), 100, ByteRange::Partial { start: 99, end: 99 }),
+        (Some("bytes=-10"), 100, ByteRange::Partial { start: 90, end: 99 }),
+        (Some("bytes=-500"), 100, ByteRange::Partial { start: 0, end: 99 }),
+        (Some("bytes=-0"), 100, ByteRange::Unsatisfiable),
+        (Some("bytes=100-"), 100, ByteRange::Unsatisfiable),
+        (Some("bytes=100-200"), 100, ByteRange::Unsatisfiable),
+        (Some("bytes=500-600"), 100, ByteRange::Unsatisfiable),
+        (Some("bytes=20-10"), 100, ByteRange::Full),
+        (Some("bytes=0-0,5-9"), 100, ByteRange::Full),
+        (Some("bytes=-5, 10-"), 100, ByteRange::Full),
+        (Some("items=0-9"), 100, ByteRange::Full),
+        (Some("bytes=a-9"), 100, ByteRange::Full),
+        (Some("bytes=0-b"), 100, ByteRange::Full),
+        (Some("bytes=-x"), 100, ByteRange::Full),
+        (Some("bytes=10"), 100, ByteRange::Full),
+        (Some("bytes=0-"), 0, ByteRange::Unsatisfiable),
+        (Some("bytes=0-9"), 0, ByteRange::Unsatisfiable),
+        (Some("bytes=-10"), 0, ByteRange::Unsatisfiable),
+        (None, 0, ByteRange::Full),
+    ];
+
+    #[test]
+    fn resolve_table() {
+        for &(header, len, expected) in CASES {
+            assert_eq!(resolve(header, len), expected, "{header:?} of {len} bytes");
+        }
+    }
+}
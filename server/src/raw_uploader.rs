@@ -0,0 +1,138 @@
+use embedded_sdmmc::{Mode, BlockDevice};
+use picoserve::request::{RequestBody, RequestParts};
+use picoserve::io::Read;
+use picoserve::response::StatusCode;
+use file_manager::{check_reserved, get_file_manager, lfn, ExtAlloc, AsyncRootFn, DirGuard, FManError, FsBlockDevice};
+use allocator_api2::vec::Vec;
+use crate::{fs_path, String, UploadError};
+
+/// Mount point of the raw file routes, stripped from the request path.
+pub const FS_ROUTE: &str = "/fs";
+
+struct RawUploaderAsync<'r, R: Read> {
+    path: String,
+    body: RequestBody<'r, R>,
+    content_length: Option<usize>,
+}
+
+/// Opens (creating as needed) every directory of `parents` below `root_dir`.
+pub(crate) fn open_dir_all<'a>(
+    root_dir: DirGuard<'a>,
+    parents: &str,
+) -> Result<DirGuard<'a>, FManError<<FsBlockDevice as BlockDevice>::Error>> {
+    let mut dir = root_dir;
+
+    for name in parents.split('/').filter(|s| !s.is_empty()) {
+        if name == "." || name == ".." {
+            return Err("relative path segments are not allowed".into());
+        }
+
+        match dir.make_dir(name) {
+            Ok(()) | Err(embedded_sdmmc::Error::DirAlreadyExists) => (),
+            Err(e) => return Err(FManError::SdErr(e)),
+        }
+
+        dir = dir.open_dir(name)?;
+    }
+
+    Ok(dir)
+}
+
+impl<'r, R> AsyncRootFn<usize> for RawUploaderAsync<'r, R>
+where R: Read {
+    type Fut<'a> = impl core::future::Future<Output = Result<usize, FManError<<FsBlockDevice as BlockDevice>::Error>>> + 'a where Self: 'a;
+
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let short = lfn::short_path(root_dir.vm(), root_dir.raw(), &self.path, true)?;
+            let path = short.trim_matches('/');
+            let (parents, name) = path.rsplit_once('/').unwrap_or(("", path));
+            if name.is_empty() || name == "." || name == ".." {
+                return Err("missing file name".into());
+            }
+
+            let dir = open_dir_all(root_dir, parents)?;
+            let file = dir.open_file(name, Mode::ReadWriteCreateOrTruncate)?;
+
+            let mut buffer: Vec<u8, ExtAlloc> = Vec::with_capacity_in(1024, ExtAlloc::default());
+            buffer.resize(buffer.capacity(), 0);
+
+            let mut reader = self.body.reader();
+            let mut written = 0;
+            let streamed: Result<(), FManError<<FsBlockDevice as BlockDevice>::Error>> = loop {
+                match reader.read(buffer.as_mut()).await {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => {
+                        if let Err(e) = file.write(&buffer[..n]) {
+                            break Err(FManError::SdErr(e));
+                        }
+                        written += n;
+                    },
+                    Err(_) => break Err("read error".into()),
+                }
+            };
+
+            let closed = file.close().map_err(FManError::SdErr);
+            let result = streamed.and(closed).and_then(|_| {
+                if self.content_length.is_none_or(|len| written == len) {
+                    Ok(written)
+                } else {
+                    Err("body shorter than Content-Length".into())
+                }
+            });
+
+            if result.is_err() {
+                let _ = dir.delete_file(name);
+            }
+            result
+        }
+    }
+}
+
+/// Streams a raw request body into the file at the long `path` (relative to
+/// the card root, or to another volume's with a `/vol<n>` prefix), creating
+/// parent directories and replacing an existing file. Names that are not
+/// 8.3 are given a short alias on the way. When `content_length` is known a
+/// shorter body is treated as a failed upload.
+pub async fn upload_raw<'r, R: Read>(
+    path: String,
+    body: RequestBody<'r, R>,
+    content_length: Option<usize>,
+) -> Result<usize, &'static str> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let (volume, path) = fman.volume_path(&path).await;
+    let uploader_async = RawUploaderAsync { path, body, content_length };
+    fman.with_volume_root_dir_async(volume, uploader_async).await.map_err(|e| match e {
+        FManError::ServerErr(e) => e,
+        FManError::CardNotActive => "SD Card not active",
+        _ => "error while upload_raw_to_path"
+    })
+}
+
+/// Streams a raw request body into the file at the request path (below
+/// [`FS_ROUTE`]), creating parent directories and replacing an existing file.
+/// Fails with `403 Forbidden` inside the directories the server keeps for
+/// itself, and with `507 Insufficient Storage` when the announced body does
+/// not fit on the card.
+pub async fn upload_raw_to_path<'r, R: Read>(
+    parts: RequestParts<'r>,
+    body: RequestBody<'r, R>,
+) -> Result<usize, UploadError> {
+    if parts.headers().get("Content-Length").is_none() {
+        return Err("Content-Length required".into());
+    }
+
+    let path = parts.path().encoded();
+    let path = fs_path::normalize(path.strip_prefix(FS_ROUTE).unwrap_or(path)).map_err(|e| e.message())?;
+    check_reserved(&path).map_err(|e| UploadError(StatusCode::FORBIDDEN, e))?;
+    let content_length = body.content_length();
+    if !crate::fits_on_card(&path, content_length as u64).await {
+        return Err(UploadError(StatusCode::INSUFFICIENT_STORAGE, crate::NO_SPACE));
+    }
+
+    Ok(upload_raw(path, body, Some(content_length)).await?)
+}
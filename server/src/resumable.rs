@@ -0,0 +1,450 @@
+//! tus-style resumable uploads.
+//!
+//! `POST /uploads/file` (or `/uploads/music`) with an `Upload-Length` header
+//! reserves an id from `count_tracker`, creates the empty `<id>.<ext>` file
+//! and records a session row in the `uploads` table. The client then sends
+//! `PATCH /uploads/<key>` requests carrying `Upload-Offset`; the offset is
+//! persisted after every flush so that a dropped connection or a reboot can
+//! resume from the last durable byte (`HEAD /uploads/<key>`). The PATCH that
+//! reaches `Upload-Length` registers the file in its category table and
+//! removes the session.
+
+use alpa::embedded_sdmmc_fs::{DbDirSdmmc, VM};
+use alpa::db::Database;
+use alpa::{Value, Row, Query, QueryExecutor};
+use embedded_sdmmc::{Mode, BlockDevice};
+use picoserve::request::{RequestBody, RequestParts};
+use picoserve::response::StatusCode;
+use picoserve::io::Read;
+use file_manager::{get_file_manager, ExtAlloc, AsyncRootFn, DirGuard, FManError, FsBlockDevice, consts};
+use allocator_api2::vec::Vec;
+use alloc::format;
+use file_manager::checksum::checksum_file;
+use crate::chunks::extension_of;
+use crate::String;
+
+#[cfg(feature = "embassy-mode")]
+use esp_println::println;
+#[cfg(feature = "std-mode")]
+use std::println;
+
+pub const TUS_VERSION: &str = "1.0.0";
+
+/// Offsets are persisted at least this often while a PATCH is streaming.
+const PERSIST_EVERY: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct TusError(pub StatusCode, pub &'static str);
+
+type FsErr = FManError<<FsBlockDevice as BlockDevice>::Error>;
+
+/// Session keys are `<table>-<id>.<ext>` since ids are only unique per table.
+fn split_key(key: &str) -> Option<(&'static str, &'static str, &str)> {
+    let (table, path) = key.split_once('-')?;
+    match table {
+        consts::FILES_TABLE => Some((consts::FILES_TABLE, consts::FILES_DIR, path)),
+        consts::MUSIC_TABLE => Some((consts::MUSIC_TABLE, consts::MUSIC_DIR, path)),
+        _ => None,
+    }
+}
+
+fn header_u64(parts: &RequestParts<'_>, name: &str) -> Result<Option<u64>, TusError> {
+    match parts.headers().get(name) {
+        Some(v) => v.as_str().ok()
+            .and_then(|v| v.trim().parse().ok())
+            .map(Some)
+            .ok_or(TusError(StatusCode::BAD_REQUEST, "invalid numeric header")),
+        None => Ok(None),
+    }
+}
+
+fn decode_base64(input: &[u8]) -> Option<alloc::vec::Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = input.strip_suffix(b"==").or(input.strip_suffix(b"=")).unwrap_or(input);
+    let mut out = alloc::vec::Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &c in input {
+        acc = (acc << 6) | value(c)?;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Original filename from `Upload-Metadata: filename <base64>, ...`.
+fn filename_from_metadata(parts: &RequestParts<'_>) -> alloc::vec::Vec<u8> {
+    parts.headers().get("Upload-Metadata")
+        .and_then(|v| {
+            v.as_raw().split(|&b| b == b',').find_map(|pair| {
+                let pair = pair.trim_ascii();
+                let value = pair.strip_prefix(b"filename ")?;
+                decode_base64(value.trim_ascii())
+            })
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| b"upload".to_vec())
+}
+
+pub struct Session {
+    pub name: alloc::vec::Vec<u8>,
+    pub length: i64,
+    pub offset: i64,
+}
+
+struct TusCreateAsync {
+    table: &'static str,
+    dir: &'static str,
+    name: alloc::vec::Vec<u8>,
+    length: u64,
+}
+
+impl AsyncRootFn<Result<String, TusError>> for TusCreateAsync {
+    type Fut<'a> = impl core::future::Future<Output = Result<Result<String, TusError>, FsErr>> + 'a where Self: 'a;
+
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let files_dir = root_dir.open_dir(self.dir)?;
+            let db_dir = root_dir.open_dir(consts::DB_DIR)?.into_raw();
+            let mut db = Database::new_init(VM::new(root_dir.vm()), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+            let count_tracker_table = db.get_table(consts::COUNT_TRACKER_TABLE, ExtAlloc::default())?;
+            let uploads_table = db.get_table(consts::UPLOADS_TABLE, ExtAlloc::default())?;
+
+            let cur_file_id: i64 = {
+                let query = Query::<_, &str>::new(count_tracker_table, ExtAlloc::default())
+                                             .key(Value::Chars(self.table.as_bytes()));
+                match QueryExecutor::new(
+                    query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+                    &db.file_handler.page_rw.as_ref().unwrap()
+                ) {
+                    Ok(mut exec) => match exec.next() {
+                        Ok(row) => row[1].to_int().unwrap(),
+                        Err(_) => return Err("bad init".into()),
+                    },
+                    Err(_) => return Err("table empty".into()),
+                }
+            };
+
+            if cur_file_id < 0 || cur_file_id >= 99999999 {
+                return Ok(Err(TusError(StatusCode::INSUFFICIENT_STORAGE, "id limit reached")));
+            }
+
+            let path = format!("{}.{}", cur_file_id, extension_of(&self.name));
+            let key = format!("{}-{}", self.table, path);
+
+            files_dir.open_file(path.as_str(), Mode::ReadWriteCreate)?.close()?;
+
+            {
+                let mut row = Row::new_in(ExtAlloc::default());
+                row.push(Value::Chars(self.table.as_bytes()));
+                row.push(Value::Int(cur_file_id + 1));
+                db.update_row(count_tracker_table, Value::Chars(self.table.as_bytes()), row, ExtAlloc::default())?;
+            }
+
+            {
+                let mut row = Row::new_in(ExtAlloc::default());
+                row.push(Value::Chars(key.as_bytes()));
+                row.push(Value::Chars(&self.name));
+                row.push(Value::Int(self.length as i64));
+                row.push(Value::Int(0));
+                if let Err(e) = db.insert_to_table(uploads_table, row, ExtAlloc::default()) {
+                    let _ = files_dir.delete_file(path.as_str());
+                    return Err(e.into());
+                }
+            }
+
+            Ok(Ok(key))
+        }
+    }
+}
+
+/// Clamps a session's offset to what actually reached the card.
+fn durable_session(
+    entry: Result<embedded_sdmmc::DirEntry, embedded_sdmmc::Error<<FsBlockDevice as BlockDevice>::Error>>,
+    session: Option<Session>,
+) -> Result<Session, TusError> {
+    let mut session = session.ok_or(TusError(StatusCode::NOT_FOUND, "upload not found"))?;
+    let entry = entry.map_err(|_| TusError(StatusCode::GONE, "upload file missing"))?;
+    session.offset = session.offset.min(entry.size as i64);
+    Ok(session)
+}
+
+/// The DB kept in [`consts::DB_DIR`].
+type SessionsDb<'a> = Database<VM<'a>, DbDirSdmmc, ExtAlloc>;
+
+/// The session stored under `key` in `table`, if there is one.
+fn find_session(db: &mut SessionsDb<'_>, table: &str, key: &str) -> Option<Session> {
+    let table = db.get_table(table, ExtAlloc::default()).ok()?;
+    let query = Query::<_, &str>::new(table, ExtAlloc::default())
+                                 .key(Value::Chars(key.as_bytes()));
+    let mut exec = QueryExecutor::new(
+        query, &mut db.table_buf, &mut db.buf1, &mut db.buf2,
+        &db.file_handler.page_rw.as_ref().unwrap()
+    ).ok()?;
+    let row = exec.next().ok()?;
+    Some(Session {
+        name: row[1].to_chars().unwrap().to_vec(),
+        length: row[2].to_int().unwrap(),
+        offset: row[3].to_int().unwrap(),
+    })
+}
+
+struct TusStatusAsync {
+    key: String,
+}
+
+impl AsyncRootFn<Result<Session, TusError>> for TusStatusAsync {
+    type Fut<'a> = impl core::future::Future<Output = Result<Result<Session, TusError>, FsErr>> + 'a where Self: 'a;
+
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let Some((_, dir, path)) = split_key(&self.key) else {
+                return Ok(Err(TusError(StatusCode::NOT_FOUND, "upload not found")));
+            };
+            let files_dir = root_dir.open_dir(dir)?;
+            let db_dir = root_dir.open_dir(consts::DB_DIR)?.into_raw();
+            let mut db = Database::new_init(VM::new(root_dir.vm()), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+            let session = find_session(&mut db, consts::UPLOADS_TABLE, &self.key);
+            Ok(durable_session(files_dir.find(path), session))
+        }
+    }
+}
+
+struct TusDeleteAsync {
+    key: String,
+}
+
+impl AsyncRootFn<Result<(), TusError>> for TusDeleteAsync {
+    type Fut<'a> = impl core::future::Future<Output = Result<Result<(), TusError>, FsErr>> + 'a where Self: 'a;
+
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let Some((_, dir, path)) = split_key(&self.key) else {
+                return Ok(Err(TusError(StatusCode::NOT_FOUND, "upload not found")));
+            };
+            let files_dir = root_dir.open_dir(dir)?;
+            let db_dir = root_dir.open_dir(consts::DB_DIR)?.into_raw();
+            let mut db = Database::new_init(VM::new(root_dir.vm()), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+            let uploads_table = db.get_table(consts::UPLOADS_TABLE, ExtAlloc::default())?;
+
+            if find_session(&mut db, consts::UPLOADS_TABLE, &self.key).is_none() {
+                return Ok(Err(TusError(StatusCode::NOT_FOUND, "upload not found")));
+            }
+
+            match files_dir.delete_file(path) {
+                Err(embedded_sdmmc::Error::NotFound) => (),
+                Err(e) => return Err(FManError::SdErr(e)),
+                Ok(()) => ()
+            }
+            db.delete_from_table(uploads_table, Value::Chars(self.key.as_bytes()), ExtAlloc::default())?;
+
+            Ok(Ok(()))
+        }
+    }
+}
+
+struct TusPatchAsync<'r, R: Read> {
+    key: String,
+    offset: u64,
+    body: RequestBody<'r, R>,
+}
+
+/// Where a PATCH left its session.
+struct Patched {
+    offset: u64,
+    /// Whether it reached `Upload-Length`, registering the file.
+    complete: bool,
+}
+
+impl<'r, R> AsyncRootFn<Result<Patched, TusError>> for TusPatchAsync<'r, R>
+where R: Read {
+    type Fut<'a> = impl core::future::Future<Output = Result<Result<Patched, TusError>, FsErr>> + 'a where Self: 'a;
+
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let Some((table, dir, path)) = split_key(&self.key) else {
+                return Ok(Err(TusError(StatusCode::NOT_FOUND, "upload not found")));
+            };
+            let files_dir = root_dir.open_dir(dir)?;
+            let db_dir = root_dir.open_dir(consts::DB_DIR)?.into_raw();
+            let mut db = Database::new_init(VM::new(root_dir.vm()), DbDirSdmmc::new(db_dir), ExtAlloc::default())?;
+            let uploads_table = db.get_table(consts::UPLOADS_TABLE, ExtAlloc::default())?;
+            let category_table = db.get_table(table, ExtAlloc::default())?;
+
+            let session = find_session(&mut db, consts::UPLOADS_TABLE, &self.key);
+            let session = match durable_session(files_dir.find(path), session) {
+                Ok(s) => s,
+                Err(e) => return Ok(Err(e)),
+            };
+            if session.offset as u64 != self.offset {
+                return Ok(Err(TusError(StatusCode::CONFLICT, "Upload-Offset does not match")));
+            }
+
+            let file = files_dir.open_file(path, Mode::ReadWriteAppend)?;
+            file.seek_from_start(self.offset as u32)?;
+
+            let mut buffer: Vec<u8, ExtAlloc> = Vec::with_capacity_in(1024, ExtAlloc::default());
+            buffer.resize(buffer.capacity(), 0);
+
+            let mut reader = self.body.reader();
+            let mut offset = session.offset;
+            let mut unpersisted = 0;
+            let mut outcome: Result<(), TusError> = Ok(());
+
+            loop {
+                let n = match reader.read(buffer.as_mut()).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(_) => {
+                        outcome = Err(TusError(StatusCode::BAD_REQUEST, "read error"));
+                        break;
+                    }
+                };
+                if offset + n as i64 > session.length {
+                    outcome = Err(TusError(StatusCode::PAYLOAD_TOO_LARGE, "body exceeds Upload-Length"));
+                    break;
+                }
+                if file.write(&buffer[..n]).is_err() {
+                    outcome = Err(TusError(StatusCode::INTERNAL_SERVER_ERROR, "unable to write to file"));
+                    break;
+                }
+                offset += n as i64;
+                unpersisted += n;
+
+                if unpersisted >= PERSIST_EVERY {
+                    unpersisted = 0;
+                    if file.flush().is_ok() {
+                        let mut row = Row::new_in(ExtAlloc::default());
+                        row.push(Value::Chars(self.key.as_bytes()));
+                        row.push(Value::Chars(&session.name));
+                        row.push(Value::Int(session.length));
+                        row.push(Value::Int(offset));
+                        db.update_row(uploads_table, Value::Chars(self.key.as_bytes()), row, ExtAlloc::default())?;
+                    }
+                }
+            }
+
+            // Whatever made it to the card before an error is still durable.
+            file.close()?;
+            {
+                let mut row = Row::new_in(ExtAlloc::default());
+                row.push(Value::Chars(self.key.as_bytes()));
+                row.push(Value::Chars(&session.name));
+                row.push(Value::Int(session.length));
+                row.push(Value::Int(offset));
+                db.update_row(uploads_table, Value::Chars(self.key.as_bytes()), row, ExtAlloc::default())?;
+            }
+
+            let complete = outcome.is_ok() && offset == session.length;
+            if complete {
+                // the upload may have spanned reboots, so read the whole file back
+                let (_, sums) = checksum_file(&files_dir, path)?;
+                let mut row = Row::new_in(ExtAlloc::default());
+                row.push(Value::Chars(path.as_bytes()));
+                row.push(Value::Chars(&session.name));
+                row.push(Value::Int(session.length));
+                row.push(Value::Int(sums.crc32 as i64));
+                row.push(Value::Chars(sums.sha256_hex().as_bytes()));
+                db.insert_to_table(category_table, row, ExtAlloc::default())?;
+                db.delete_from_table(uploads_table, Value::Chars(self.key.as_bytes()), ExtAlloc::default())?;
+            }
+
+            Ok(outcome.map(|_| Patched { offset: offset as u64, complete }))
+        }
+    }
+}
+
+fn fs_error(e: FsErr) -> TusError {
+    match e {
+        FManError::CardNotActive => TusError(StatusCode::SERVICE_UNAVAILABLE, "SD Card not active"),
+        FManError::ServerErr(e) => TusError(StatusCode::BAD_REQUEST, e),
+        _ => TusError(StatusCode::INTERNAL_SERVER_ERROR, "storage error"),
+    }
+}
+
+pub async fn create_upload(
+    parts: &RequestParts<'_>,
+    table: &'static str,
+    dir: &'static str,
+) -> Result<String, TusError> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let length = header_u64(parts, "Upload-Length")?
+        .ok_or(TusError(StatusCode::BAD_REQUEST, "Upload-Length required"))?;
+    if length > u32::MAX as u64 {
+        return Err(TusError(StatusCode::PAYLOAD_TOO_LARGE, "file too large for FAT"));
+    }
+    if !crate::fits_on_card(dir, length).await {
+        return Err(TusError(StatusCode::INSUFFICIENT_STORAGE, crate::NO_SPACE));
+    }
+
+    let create = TusCreateAsync { table, dir, name: filename_from_metadata(parts), length };
+    fman.with_root_dir_async(create).await.map_err(fs_error)?
+}
+
+pub async fn upload_status(key: String) -> Result<Session, TusError> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    fman.with_root_dir_async(TusStatusAsync { key }).await.map_err(fs_error)?
+}
+
+pub async fn delete_upload(key: String) -> Result<(), TusError> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    fman.with_root_dir_async(TusDeleteAsync { key }).await.map_err(fs_error)?
+}
+
+/// Appends a PATCH body to the session `key`.
+pub async fn patch_upload<'r, R: Read>(
+    key: String,
+    parts: &RequestParts<'_>,
+    body: RequestBody<'r, R>,
+) -> Result<u64, TusError> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let content_type = parts.headers().get("Content-Type").map(|v| v.as_raw());
+    if content_type != Some(b"application/offset+octet-stream".as_slice()) {
+        return Err(TusError(StatusCode::UNSUPPORTED_MEDIA_TYPE, "expected application/offset+octet-stream"));
+    }
+    let offset = header_u64(parts, "Upload-Offset")?
+        .ok_or(TusError(StatusCode::BAD_REQUEST, "Upload-Offset required"))?;
+    let path = match split_key(&key) {
+        Some((consts::FILES_TABLE, _, path)) => Some(String::from(path)),
+        _ => None,
+    };
+    let patched = fman.with_root_dir_async(TusPatchAsync { key, offset, body }).await.map_err(fs_error)??;
+    // the PATCH that finishes an upload under a name uploaded before makes
+    // it the new version; the data is stored either way, so that does not
+    // fail the PATCH, but it is logged
+    if let Some(path) = path.filter(|_| patched.complete) {
+        if let Err(e) = fman.supersede(&path).await {
+            println!("unable to add upload {} as a new version: {:?}", path, e);
+        }
+    }
+    Ok(patched.offset)
+}
@@ -0,0 +1,66 @@
+//! Minimal HTML templating for the listing pages. Anything that comes from
+//! the card (file names, DB `name` values, file contents) must reach a page
+//! through [`Arg::Text`] or [`escape_into`].
+
+use allocator_api2::alloc::Allocator;
+use allocator_api2::vec::Vec;
+use picoserve::response::chunked::ChunkWriter;
+use file_manager::ExtAlloc;
+
+/// `Content-Security-Policy` for user-supplied HTML: a sandboxed, opaque
+/// origin without scripts, so that a page cannot reach the admin routes.
+pub const USER_CONTENT_CSP: &str = "sandbox; default-src 'none'; img-src 'self' data:; style-src 'self' 'unsafe-inline'; media-src 'self'";
+
+/// `Content-Security-Policy` for the pages generated by the server itself.
+pub const PAGE_CSP: &str = "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'";
+
+pub enum Arg<'a> {
+    /// Untrusted text, HTML-escaped on output.
+    Text(&'a [u8]),
+    /// Markup or values produced by the server, written as is.
+    Safe(&'a str),
+}
+
+/// Appends `s` to `buf` with the HTML special characters escaped.
+pub fn escape_into<A: Allocator>(buf: &mut Vec<u8, A>, s: &[u8]) {
+    for &b in s {
+        match b {
+            b'&' => buf.extend_from_slice(b"&amp;"),
+            b'<' => buf.extend_from_slice(b"&lt;"),
+            b'>' => buf.extend_from_slice(b"&gt;"),
+            b'"' => buf.extend_from_slice(b"&quot;"),
+            b'\'' => buf.extend_from_slice(b"&#39;"),
+            b => buf.push(b),
+        }
+    }
+}
+
+/// Appends `template` to `buf`, replacing each `{}` with the next of `args`.
+/// Placeholders without an argument are dropped.
+pub fn render_into<A: Allocator>(buf: &mut Vec<u8, A>, template: &str, args: &[Arg<'_>]) {
+    let mut args = args.iter();
+    let mut pieces = template.split("{}");
+
+    if let Some(first) = pieces.next() {
+        buf.extend_from_slice(first.as_bytes());
+    }
+    for piece in pieces {
+        match args.next() {
+            Some(Arg::Text(text)) => escape_into(buf, text),
+            Some(Arg::Safe(markup)) => buf.extend_from_slice(markup.as_bytes()),
+            None => (),
+        }
+        buf.extend_from_slice(piece.as_bytes());
+    }
+}
+
+/// Renders `template` with `args` as one chunk.
+pub async fn render<W: picoserve::io::Write>(
+    chunk_writer: &mut ChunkWriter<W>,
+    template: &str,
+    args: &[Arg<'_>],
+) -> Result<(), W::Error> {
+    let mut buf = Vec::new_in(ExtAlloc::default());
+    render_into(&mut buf, template, args);
+    chunk_writer.write_chunk(&buf).await
+}
@@ -0,0 +1,146 @@
+//! The trash bin over HTTP.
+//!
+//! `DELETE /files/delete/<name>` moves an upload to the trash, see
+//! [`file_manager::trash`]. `GET /trash` lists what is in it,
+//! `POST /trash/restore/<name>` puts a file back, `DELETE /trash/<name>`
+//! deletes one for good and `DELETE /trash` all of them.
+//! [`task_trash_purger`] deletes what has been there long enough.
+
+use picoserve::response::{IntoResponse, Response, StatusCode};
+use allocator_api2::vec::Vec;
+use embedded_sdmmc::BlockDevice;
+use file_manager::runtime::sleep_ms;
+use file_manager::{clock, get_file_manager, ExtAlloc, FManError, FsBlockDevice, consts};
+use alloc::format;
+use crate::api::{iso_timestamp, push_json_str};
+use crate::String;
+
+type FsErr = FManError<<FsBlockDevice as BlockDevice>::Error>;
+
+fn error_status(e: FsErr) -> (StatusCode, String) {
+    match e {
+        FManError::CardNotActive => (StatusCode::SERVICE_UNAVAILABLE, String::from("SD Card not active")),
+        FManError::SdErr(embedded_sdmmc::Error::FileAlreadyExists) => {
+            (StatusCode::CONFLICT, String::from("a file took its place meanwhile"))
+        },
+        e => (StatusCode::INTERNAL_SERVER_ERROR, format!("error: {:?}", e)),
+    }
+}
+
+/// Deletes what has been in the trash for `max_age_secs`, checking every
+/// [`consts::TRASH_PURGE_EVERY_MS`]; 0 keeps everything.
+#[cfg_attr(feature = "embassy-mode", embassy_executor::task(pool_size = 1))]
+pub async fn task_trash_purger(max_age_secs: u64) {
+    if max_age_secs == 0 {
+        return;
+    }
+    loop {
+        sleep_ms(consts::TRASH_PURGE_EVERY_MS).await;
+
+        #[cfg(feature = "embassy-mode")]
+        let fman = get_file_manager().await;
+        #[cfg(feature = "std-mode")]
+        let fman = get_file_manager();
+
+        // without a card there is nothing to purge, and the next round retries
+        let _ = fman.purge_older_than(max_age_secs).await;
+    }
+}
+
+/// `GET /trash`, e.g.
+/// `{"entries":[{"name":"1.TXT","upload_name":"a.txt","table":"files","original":"7.TXT","size":3,"deleted":"2026-10-17T12:00:00Z"}]}`;
+/// `deleted` is null for what was deleted before the clock was set.
+pub async fn handle_trash_list() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let entries = match fman.trash_entries().await {
+        Ok(entries) => entries,
+        Err(e) => {
+            let (status, msg) = error_status(e);
+            return Err(Response::new(status, msg));
+        },
+    };
+
+    let mut buf = Vec::new_in(ExtAlloc::default());
+    buf.extend_from_slice(b"{\"entries\":[");
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',');
+        }
+        // short names cannot hold quotes or backslashes, upload names can
+        buf.extend_from_slice(format!("{{\"name\":\"{}\",\"upload_name\":", entry.name).as_bytes());
+        push_json_str(&mut buf, entry.upload_name.as_bytes());
+        buf.extend_from_slice(format!(
+            ",\"table\":\"{}\",\"original\":\"{}\",\"size\":{},\"deleted\":",
+            entry.table, entry.original, entry.size
+        ).as_bytes());
+        match entry.deleted {
+            Some(secs) => buf.extend_from_slice(format!("\"{}Z\"}}", iso_timestamp(&clock::timestamp(secs))).as_bytes()),
+            None => buf.extend_from_slice(b"null}"),
+        }
+    }
+    buf.extend_from_slice(b"]}");
+
+    Ok(Response::new(StatusCode::OK, String::from_utf8_lossy(&buf).into_owned())
+        .with_header("Content-Type", "application/json"))
+}
+
+/// `POST /trash/restore/<name>` puts a trashed file back, answering with
+/// where it went: `{"table":"files","name":"7.TXT"}`.
+pub async fn handle_trash_restore(name: String) -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    match fman.restore(&name).await {
+        Ok(Some(entry)) => Ok(
+            Response::new(StatusCode::OK, format!("{{\"table\":\"{}\",\"name\":\"{}\"}}", entry.table, entry.original))
+                .with_header("Content-Type", "application/json")
+        ),
+        Ok(None) => Err(Response::new(StatusCode::NOT_FOUND, String::from("not in the trash"))),
+        Err(e) => {
+            let (status, msg) = error_status(e);
+            Err(Response::new(status, msg))
+        },
+    }
+}
+
+/// `DELETE /trash/<name>` deletes a trashed file for good.
+pub async fn handle_trash_purge(name: String) -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    match fman.purge(&name).await {
+        Ok(true) => Ok(Response::new(StatusCode::NO_CONTENT, String::new())),
+        Ok(false) => Err(Response::new(StatusCode::NOT_FOUND, String::from("not in the trash"))),
+        Err(e) => {
+            let (status, msg) = error_status(e);
+            Err(Response::new(status, msg))
+        },
+    }
+}
+
+/// `DELETE /trash` empties the trash: `{"purged":2}`.
+pub async fn handle_trash_purge_all() -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    fman.purge_all()
+        .await
+        .map(|purged| {
+            Response::new(StatusCode::OK, format!("{{\"purged\":{}}}", purged))
+                .with_header("Content-Type", "application/json")
+        })
+        .map_err(|e| {
+            let (status, msg) = error_status(e);
+            Response::new(status, msg)
+        })
+}
@@ -0,0 +1,169 @@
+//! Re-reading uploaded files against the checksums taken at upload time.
+//!
+//! `GET /files/verify/<name>` checks one file of the files table there and
+//! then. `POST /files/verify` wakes [`task_verifier`], which goes through
+//! every file of both tables one at a time, so that other requests get the
+//! card in between; `GET /files/verify` tells how far it got and which
+//! files did not match.
+
+use picoserve::response::{IntoResponse, Response, StatusCode};
+use file_manager::checksum::Verification;
+use file_manager::runtime::{Mutex, Signal};
+use file_manager::{get_file_manager, FManError, consts};
+use alloc::format;
+use alloc::vec::Vec;
+use crate::chunks::OnceLock;
+use crate::String;
+
+/// A file the last run found changed or gone.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub table: &'static str,
+    pub name: String,
+    pub missing: bool,
+}
+
+/// Progress of the last `verify all` run.
+#[derive(Debug, Default, Clone)]
+pub struct VerifyJob {
+    pub running: bool,
+    pub checked: u32,
+    pub total: u32,
+    pub mismatches: Vec<Mismatch>,
+    pub error: Option<String>,
+}
+
+static START_SIG: OnceLock<Signal<()>> = OnceLock::new();
+static JOB: OnceLock<Mutex<VerifyJob>> = OnceLock::new();
+
+pub fn init_signals() {
+    START_SIG.set(Signal::new()).unwrap();
+    JOB.set(Mutex::new(VerifyJob::default())).unwrap();
+}
+
+fn job() -> &'static Mutex<VerifyJob> {
+    JOB.get().unwrap()
+}
+
+/// Verifies every registered file whenever `POST /files/verify` asks for it.
+#[cfg_attr(feature = "embassy-mode", embassy_executor::task(pool_size = 1))]
+pub async fn task_verifier() {
+    loop {
+        START_SIG.get().unwrap().wait().await;
+
+        #[cfg(feature = "embassy-mode")]
+        let fman = get_file_manager().await;
+        #[cfg(feature = "std-mode")]
+        let fman = get_file_manager();
+
+        let mut files = Vec::new();
+        let mut error = None;
+        for table in [consts::FILES_TABLE, consts::MUSIC_TABLE] {
+            match fman.registered(table).await {
+                Ok(names) => files.extend(names.into_iter().map(|name| (table, name))),
+                Err(e) => error = Some(format!("{:?}", e)),
+            }
+        }
+        job().lock().await.total = files.len() as u32;
+
+        if error.is_none() {
+            for (table, name) in files {
+                // the card is only held for one file at a time
+                let outcome = fman.verify(table, &name).await;
+                let mut job = job().lock().await;
+                job.checked += 1;
+                match outcome {
+                    Ok(Some(verification)) if !verification.is_ok() => job.mismatches.push(Mismatch {
+                        table,
+                        name,
+                        missing: verification.actual.is_none(),
+                    }),
+                    // fine, or deleted since it was listed
+                    Ok(_) => (),
+                    Err(e) => {
+                        error = Some(format!("{:?}", e));
+                        break;
+                    },
+                }
+            }
+        }
+
+        let mut job = job().lock().await;
+        job.error = error;
+        job.running = false;
+    }
+}
+
+/// `POST /files/verify` starts verifying every uploaded file.
+pub async fn handle_verify_all() -> impl IntoResponse {
+    let mut job = job().lock().await;
+    if job.running {
+        return Response::new(StatusCode::CONFLICT, "verification already running");
+    }
+    *job = VerifyJob { running: true, ..VerifyJob::default() };
+    drop(job);
+
+    START_SIG.get().unwrap().signal(()).await;
+    Response::new(StatusCode::ACCEPTED, "")
+}
+
+/// `GET /files/verify` reports on the last run, e.g.
+/// `{"running":false,"checked":2,"total":2,"mismatches":[{"table":"files","name":"1.TXT","missing":false}],"error":null}`.
+pub async fn handle_verify_status() -> impl IntoResponse {
+    let job = job().lock().await.clone();
+
+    let mut json = format!(
+        "{{\"running\":{},\"checked\":{},\"total\":{},\"mismatches\":[",
+        job.running, job.checked, job.total
+    );
+    for (i, mismatch) in job.mismatches.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        // short names cannot hold quotes or backslashes
+        json.push_str(&format!(
+            "{{\"table\":\"{}\",\"name\":\"{}\",\"missing\":{}}}",
+            mismatch.table, mismatch.name, mismatch.missing
+        ));
+    }
+    match job.error {
+        Some(ref e) => json.push_str(&format!("],\"error\":\"{}\"}}", e.replace(['"', '\\'], "'"))),
+        None => json.push_str("],\"error\":null}"),
+    }
+
+    Response::new(StatusCode::OK, json).with_header("Content-Type", "application/json")
+}
+
+/// `GET /files/verify/<name>` re-reads one uploaded file, e.g.
+/// `{"name":"1.TXT","ok":true,"recorded":{"size":3,"crc32":"352441c2","sha256":"ba78..."},"actual":{...}}`;
+/// `actual` is null when the file is gone.
+pub async fn handle_verify_file(name: String) -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let verification = match fman.verify(consts::FILES_TABLE, &name).await {
+        Ok(Some(verification)) => verification,
+        Ok(None) => return Err(Response::new(StatusCode::NOT_FOUND, String::from("no such upload"))),
+        Err(FManError::CardNotActive) => return Err(Response::new(StatusCode::SERVICE_UNAVAILABLE, String::from("SD Card not active"))),
+        Err(e) => return Err(Response::new(StatusCode::INTERNAL_SERVER_ERROR, format!("error: {:?}", e))),
+    };
+    Ok(Response::new(StatusCode::OK, verification_json(&verification)).with_header("Content-Type", "application/json"))
+}
+
+fn verification_json(verification: &Verification) -> String {
+    let recorded = &verification.recorded;
+    let actual = match verification.actual {
+        Some((size, sums)) => format!(
+            "{{\"size\":{},\"crc32\":\"{}\",\"sha256\":\"{}\"}}",
+            size, sums.crc32_hex(), sums.sha256_hex()
+        ),
+        None => String::from("null"),
+    };
+    format!(
+        "{{\"name\":\"{}\",\"ok\":{},\"recorded\":{{\"size\":{},\"crc32\":\"{}\",\"sha256\":\"{}\"}},\"actual\":{}}}",
+        verification.name, verification.is_ok(), recorded.size,
+        recorded.checksums.crc32_hex(), recorded.checksums.sha256_hex(), actual
+    )
+}
@@ -0,0 +1,108 @@
+//! Versions of uploaded files over HTTP.
+//!
+//! Uploading to `/upload/file` under a name uploaded before adds a version
+//! to that file, see [`file_manager::versions`]; the upload report tells the
+//! short name and version each file ended up with.
+//! `GET /files/versions/<name>` lists the old versions of a file,
+//! `GET /files/versions/<name>/<n>` downloads one and
+//! `POST /files/rollback/<name>/<n>` makes one the newest again.
+
+use picoserve::response::{IntoResponse, Response, StatusCode};
+use file_manager::versions::{version_dir, version_file};
+use file_manager::{clock, get_file_manager, lfn, FManError};
+use alloc::format;
+use crate::chunks::UploadReport;
+use crate::{conditional, range, String};
+
+/// Turns each file of a finished upload to `/upload/file` into the new
+/// version of the file uploaded under its name before, if any.
+pub(crate) async fn supersede_uploads(report: &mut UploadReport) {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    for result in report.files.iter_mut() {
+        let Some(ref path) = result.path else { continue };
+        match fman.supersede(path).await {
+            Ok(Some((path, version))) => {
+                result.path = Some(path);
+                result.version = Some(version);
+            },
+            Ok(None) => (),
+            // still stored, just as a file of its own
+            Err(_) => result.error = Some("unable to add as a new version"),
+        }
+    }
+}
+
+/// `GET /files/versions/<name>`, e.g.
+/// `{"name":"1.TXT","current":3,"versions":[{"version":1,"size":3,"crc32":"352441c2","sha256":"ba78...","replaced":"2026-10-17T12:00:00Z"}]}`;
+/// `replaced` is null for what was replaced before the clock was set.
+pub async fn handle_versions(name: String) -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    let (versions, current) = match fman.versions(&name).await {
+        Ok(Some(versions)) => versions,
+        Ok(None) => return Err(Response::new(StatusCode::NOT_FOUND, String::from("no such upload"))),
+        Err(FManError::CardNotActive) => return Err(Response::new(StatusCode::SERVICE_UNAVAILABLE, String::from("SD Card not active"))),
+        Err(e) => return Err(Response::new(StatusCode::INTERNAL_SERVER_ERROR, format!("error: {:?}", e))),
+    };
+
+    // short names cannot hold quotes or backslashes
+    let mut json = format!("{{\"name\":\"{}\",\"current\":{},\"versions\":[", name.to_ascii_uppercase(), current);
+    for (i, version) in versions.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let replaced = match version.replaced {
+            Some(secs) => format!("\"{}Z\"", crate::api::iso_timestamp(&clock::timestamp(secs))),
+            None => String::from("null"),
+        };
+        json.push_str(&format!(
+            "{{\"version\":{},\"size\":{},\"crc32\":\"{}\",\"sha256\":\"{}\",\"replaced\":{}}}",
+            version.version, version.size, version.checksums.crc32_hex(), version.checksums.sha256_hex(), replaced
+        ));
+    }
+    json.push_str("]}");
+
+    Ok(Response::new(StatusCode::OK, json).with_header("Content-Type", "application/json"))
+}
+
+/// `GET /files/versions/<name>/<n>` downloads an old version, with the
+/// ranges and conditions of `/download`.
+pub async fn handle_version_download(
+    (name, version): (String, u32),
+    range: range::RangeHeader,
+    cond: conditional::Conditional,
+) -> impl IntoResponse {
+    let name = name.to_ascii_uppercase();
+    // the name becomes part of a path, so it has to be a short name alone
+    if !lfn::is_valid_83(name.as_bytes()) {
+        return Err(Response::new(StatusCode::BAD_REQUEST, String::from("not a valid 8.3 file name")));
+    }
+    let path = format!("/{}/{}", version_dir(&name), version_file(&name, version));
+    Ok(crate::serve_download(path, range, cond).await)
+}
+
+/// `POST /files/rollback/<name>/<n>` brings back an old version as the
+/// newest, answering with its new number: `{"name":"1.TXT","version":4}`.
+pub async fn handle_rollback((name, version): (String, u32)) -> impl IntoResponse {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    match fman.rollback(&name, version).await {
+        Ok(Some(current)) => Ok(Response::new(
+            StatusCode::OK,
+            format!("{{\"name\":\"{}\",\"version\":{}}}", name.to_ascii_uppercase(), current),
+        ).with_header("Content-Type", "application/json")),
+        Ok(None) => Err(Response::new(StatusCode::NOT_FOUND, String::from("no such version"))),
+        Err(FManError::CardNotActive) => Err(Response::new(StatusCode::SERVICE_UNAVAILABLE, String::from("SD Card not active"))),
+        Err(e) => Err(Response::new(StatusCode::INTERNAL_SERVER_ERROR, format!("error: {:?}", e))),
+    }
+}
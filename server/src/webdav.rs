@@ -0,0 +1,469 @@
+//! WebDAV (class 1) access to the card below `/dav`, so that it can be
+//! mounted from desktop file managers.
+//!
+//! Entries are listed and addressed by their 8.3 names; long names (see
+//! `file_manager::lfn`) are resolved by GET, DELETE, COPY and MOVE only.
+//! Collections are removed, copied and moved with the tree operations of
+//! `FileManager`. `Depth: infinity` on PROPFIND is answered as `Depth: 1`.
+//! Nothing inside the DB, trash or versions directories can be changed,
+//! see `file_manager::check_reserved`.
+
+use embedded_sdmmc::{BlockDevice, DirEntry};
+use picoserve::extract::FromRequestParts;
+use picoserve::io::{Read, Write};
+use picoserve::request::{Request, RequestParts};
+use picoserve::response::{Content, IntoResponse, Response, ResponseWriter, StatusCode};
+use picoserve::routing::RequestHandlerService;
+use picoserve::ResponseSent;
+use file_manager::{check_reserved, get_file_manager, AsyncRootFn, DirGuard, FManError, FsBlockDevice};
+use alloc::format;
+use crate::{conditional, fs_path, mime, range, raw_uploader, String};
+
+/// Mount point of the WebDAV tree.
+pub const DAV_ROUTE: &str = "/dav";
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE";
+
+#[derive(Debug)]
+pub struct DavError(pub StatusCode, pub &'static str);
+
+type FsErr = FManError<<FsBlockDevice as BlockDevice>::Error>;
+
+fn fs_error(e: FsErr) -> DavError {
+    match e {
+        FManError::CardNotActive => DavError(StatusCode::SERVICE_UNAVAILABLE, "SD Card not active"),
+        FManError::SdErr(embedded_sdmmc::Error::NotFound) => DavError(StatusCode::NOT_FOUND, "not found"),
+        FManError::SdErr(embedded_sdmmc::Error::FilenameError(_)) => DavError(StatusCode::BAD_REQUEST, "not a valid 8.3 file name"),
+        FManError::SdErr(embedded_sdmmc::Error::FileAlreadyOpen) => DavError(StatusCode::CONFLICT, "file is in use"),
+        FManError::SdErr(embedded_sdmmc::Error::TooManyOpenDirs)
+        | FManError::SdErr(embedded_sdmmc::Error::TooManyOpenFiles) => {
+            DavError(StatusCode::SERVICE_UNAVAILABLE, "too many open files, try again")
+        },
+        FManError::ServerErr(e) => DavError(StatusCode::BAD_REQUEST, e),
+        _ => DavError(StatusCode::INTERNAL_SERVER_ERROR, "storage error"),
+    }
+}
+
+/// Like [`fs_error`], for the tree operations: what they refuse is
+/// forbidden rather than malformed.
+fn tree_error(e: FsErr) -> DavError {
+    match e {
+        FManError::ServerErr(e) => DavError(StatusCode::FORBIDDEN, e),
+        FManError::SdErr(embedded_sdmmc::Error::FileAlreadyOpen)
+        | FManError::SdErr(embedded_sdmmc::Error::VolumeStillInUse) => DavError(StatusCode::CONFLICT, "resource is in use"),
+        e => fs_error(e),
+    }
+}
+
+/// Splits `path` into its parent directories and final name.
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_matches('/');
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Opens the directory `parents` below `base` without creating anything.
+/// `base` is itself returned for an empty `parents`.
+fn open_below<'a>(base: DirGuard<'a>, parents: &str) -> Result<DirGuard<'a>, FsErr> {
+    let mut dir = base;
+    for name in parents.split('/').filter(|s| !s.is_empty()) {
+        if name == "." || name == ".." {
+            return Err("relative path segments are not allowed".into());
+        }
+        dir = dir.open_dir(name)?;
+    }
+    Ok(dir)
+}
+
+fn push_escaped(xml: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => xml.push_str("&amp;"),
+            '<' => xml.push_str("&lt;"),
+            '>' => xml.push_str("&gt;"),
+            '"' => xml.push_str("&quot;"),
+            c => xml.push(c),
+        }
+    }
+}
+
+/// Appends the `<D:response>` of one resource; `entry` is `None` for the root.
+fn push_response(xml: &mut String, href: &str, entry: Option<&DirEntry>) {
+    let is_dir = entry.is_none_or(|e| e.attributes.is_directory());
+
+    xml.push_str("<D:response><D:href>");
+    push_escaped(xml, href);
+    xml.push_str("</D:href><D:propstat><D:prop>");
+
+    if let Some(entry) = entry {
+        let validators = conditional::Validators::of(entry);
+        xml.push_str("<D:displayname>");
+        push_escaped(xml, &format!("{}", entry.name));
+        xml.push_str("</D:displayname>");
+        xml.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified><D:creationdate>{}Z</D:creationdate>",
+            validators.last_modified,
+            crate::api::iso_timestamp(&entry.ctime)
+        ));
+        if !is_dir {
+            xml.push_str(&format!(
+                "<D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>{}</D:getcontenttype><D:getetag>",
+                entry.size,
+                mime::from_extension(entry.name.extension())
+            ));
+            push_escaped(xml, &validators.etag);
+            xml.push_str("</D:getetag>");
+        }
+    }
+
+    if is_dir {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        xml.push_str("<D:resourcetype/>");
+    }
+    xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>");
+}
+
+/// Appends a response for every entry of `dir`, below the collection `href`.
+fn push_children(xml: &mut String, href: &str, dir: &DirGuard<'_>) -> Result<(), FsErr> {
+    dir.iterate(|entry| {
+        let name = format!("{}", entry.name);
+        if entry.attributes.is_volume() || name == "." || name == ".." {
+            return;
+        }
+        let slash = if entry.attributes.is_directory() { "/" } else { "" };
+        push_response(xml, &format!("{}/{}{}", href.trim_end_matches('/'), name, slash), Some(entry));
+    })?;
+    Ok(())
+}
+
+struct PropfindAsync {
+    path: String,
+    children: bool,
+}
+
+impl AsyncRootFn<Result<String, DavError>> for PropfindAsync {
+    type Fut<'a> = impl core::future::Future<Output = Result<Result<String, DavError>, FsErr>> + 'a where Self: 'a;
+
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let (parents, name) = split_path(&self.path);
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">");
+
+            let listed = if name.is_empty() {
+                let href = format!("{}/", DAV_ROUTE);
+                push_response(&mut xml, &href, None);
+                if self.children {
+                    push_children(&mut xml, &href, &root_dir)
+                } else {
+                    Ok(())
+                }
+            } else {
+                let parent = match open_below(root_dir, parents) {
+                    Ok(d) => d,
+                    Err(_) => return Ok(Err(DavError(StatusCode::NOT_FOUND, "not found"))),
+                };
+                match parent.find(name) {
+                    Ok(entry) if entry.attributes.is_directory() => {
+                        let href = format!("{}/{}/", DAV_ROUTE, self.path.trim_matches('/'));
+                        push_response(&mut xml, &href, Some(&entry));
+                        if self.children {
+                            parent.open_dir(name)
+                                .map_err(FManError::SdErr)
+                                .and_then(|dir| push_children(&mut xml, &href, &dir))
+                        } else {
+                            Ok(())
+                        }
+                    },
+                    Ok(entry) => {
+                        push_response(&mut xml, &format!("{}/{}", DAV_ROUTE, self.path.trim_matches('/')), Some(&entry));
+                        Ok(())
+                    },
+                    Err(e) => Err(FManError::SdErr(e)),
+                }
+            };
+
+            xml.push_str("</D:multistatus>");
+            Ok(listed.map(|_| xml).map_err(fs_error))
+        }
+    }
+}
+
+struct MkcolAsync {
+    path: String,
+}
+
+impl AsyncRootFn<Result<StatusCode, DavError>> for MkcolAsync {
+    type Fut<'a> = impl core::future::Future<Output = Result<Result<StatusCode, DavError>, FsErr>> + 'a where Self: 'a;
+
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let (parents, name) = split_path(&self.path);
+            let outcome = if name.is_empty() {
+                Err(DavError(StatusCode::METHOD_NOT_ALLOWED, "collection already exists"))
+            } else if let Err(e) = check_reserved(&self.path) {
+                Err(DavError(StatusCode::FORBIDDEN, e))
+            } else {
+                match open_below(root_dir, parents) {
+                    Ok(parent) => match parent.make_dir(name) {
+                        Ok(()) => Ok(StatusCode::CREATED),
+                        Err(embedded_sdmmc::Error::DirAlreadyExists)
+                        | Err(embedded_sdmmc::Error::FileAlreadyExists) => {
+                            Err(DavError(StatusCode::METHOD_NOT_ALLOWED, "resource already exists"))
+                        },
+                        Err(e) => Err(fs_error(FManError::SdErr(e))),
+                    },
+                    Err(_) => Err(DavError(StatusCode::CONFLICT, "parent collection not found")),
+                }
+            };
+            Ok(outcome)
+        }
+    }
+}
+
+struct PutTargetAsync {
+    path: String,
+}
+
+/// Checks where a PUT would land: `Ok(true)` when it replaces a file.
+impl AsyncRootFn<Result<bool, DavError>> for PutTargetAsync {
+    type Fut<'a> = impl core::future::Future<Output = Result<Result<bool, DavError>, FsErr>> + 'a where Self: 'a;
+
+    fn call<'a>(self, root_dir: DirGuard<'a>) -> Self::Fut<'a> {
+        async move {
+            let (parents, name) = split_path(&self.path);
+            let outcome = if name.is_empty() {
+                Err(DavError(StatusCode::METHOD_NOT_ALLOWED, "cannot PUT to a collection"))
+            } else if let Err(e) = check_reserved(&self.path) {
+                Err(DavError(StatusCode::FORBIDDEN, e))
+            } else {
+                match open_below(root_dir, parents) {
+                    Ok(parent) => match parent.find(name) {
+                        Ok(entry) if entry.attributes.is_directory() => {
+                            Err(DavError(StatusCode::METHOD_NOT_ALLOWED, "cannot PUT to a collection"))
+                        },
+                        Ok(_) => Ok(true),
+                        Err(embedded_sdmmc::Error::NotFound) => Ok(false),
+                        Err(e) => Err(fs_error(FManError::SdErr(e))),
+                    },
+                    Err(_) => Err(DavError(StatusCode::CONFLICT, "parent collection not found")),
+                }
+            };
+            Ok(outcome)
+        }
+    }
+}
+
+async fn delete(path: String) -> Result<StatusCode, DavError> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    if split_path(&path).1.is_empty() {
+        return Err(DavError(StatusCode::FORBIDDEN, "the root collection cannot be deleted"));
+    }
+    fman.remove_tree(&path).await.map_err(tree_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Whether anything is at `path`.
+async fn exists(path: &str) -> Result<bool, DavError> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    match fman.open_path(path).await {
+        Ok(_) => Ok(true),
+        Err(FManError::SdErr(embedded_sdmmc::Error::NotFound)) => Ok(false),
+        Err(e) => Err(fs_error(e)),
+    }
+}
+
+/// COPY (or MOVE, with `remove_source`) of `src` to `dst`. A replaced
+/// destination is removed first; `shallow` copies a collection without its
+/// members (`Depth: 0`).
+async fn copy(src: String, dst: String, overwrite: bool, shallow: bool, remove_source: bool) -> Result<StatusCode, DavError> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    if split_path(&src).1.is_empty() || split_path(&dst).1.is_empty() {
+        return Err(DavError(StatusCode::FORBIDDEN, "the root collection cannot be copied or replaced"));
+    }
+    if src.trim_matches('/').eq_ignore_ascii_case(dst.trim_matches('/')) {
+        return Err(DavError(StatusCode::FORBIDDEN, "source and destination are the same"));
+    }
+
+    let src_is_dir = fman.open_path(&src).await.map_err(fs_error)?.is_dir();
+    if !fman.open_path(split_path(&dst).0).await.is_ok_and(|found| found.is_dir()) {
+        return Err(DavError(StatusCode::CONFLICT, "destination collection not found"));
+    }
+
+    let existed = exists(&dst).await?;
+    if existed {
+        if !overwrite {
+            return Err(DavError(StatusCode::PRECONDITION_FAILED, "destination exists"));
+        }
+        fman.remove_tree(&dst).await.map_err(tree_error)?;
+    }
+
+    if remove_source {
+        fman.move_tree(&src, &dst).await.map_err(tree_error)?;
+    } else if shallow && src_is_dir {
+        fman.make_dir(&dst).await.map_err(tree_error)?;
+    } else {
+        fman.copy_tree(&src, &dst).await.map_err(tree_error)?;
+    }
+    Ok(if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED })
+}
+
+/// Path below [`DAV_ROUTE`] named by the `Destination` header.
+fn destination_path(parts: &RequestParts<'_>) -> Result<String, DavError> {
+    let dest = parts.headers().get("Destination")
+        .and_then(|v| v.as_str().ok())
+        .ok_or(DavError(StatusCode::BAD_REQUEST, "Destination required"))?;
+
+    let path = match dest.find("://") {
+        Some(i) => {
+            let rest = &dest[i + 3..];
+            &rest[rest.find('/').unwrap_or(rest.len())..]
+        },
+        None => dest,
+    };
+
+    let path = path.strip_prefix(DAV_ROUTE)
+        .filter(|p| p.is_empty() || p.starts_with('/'))
+        .ok_or(DavError(StatusCode::BAD_GATEWAY, "destination outside of the WebDAV tree"))?;
+    fs_path::normalize(path).map_err(|e| DavError(StatusCode::BAD_REQUEST, e.message()))
+}
+
+async fn run<O, F: AsyncRootFn<Result<O, DavError>>>(f: F) -> Result<O, DavError> {
+    #[cfg(feature = "embassy-mode")]
+    let fman = get_file_manager().await;
+    #[cfg(feature = "std-mode")]
+    let fman = get_file_manager();
+
+    fman.with_root_dir_async(f).await.map_err(fs_error)?
+}
+
+fn outcome_response(outcome: Result<StatusCode, DavError>) -> impl IntoResponse {
+    let (status, msg) = match outcome {
+        Ok(status) => (status, ""),
+        Err(DavError(status, msg)) => (status, msg),
+    };
+    Response::new(status, msg)
+}
+
+/// A multistatus document.
+struct Xml(String);
+
+impl Content for Xml {
+    fn content_type(&self) -> &'static str {
+        "application/xml; charset=utf-8"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: Write>(self, mut writer: W) -> Result<(), W::Error> {
+        writer.write_all(self.0.as_bytes()).await
+    }
+}
+
+/// Serves every WebDAV method below [`DAV_ROUTE`], given the rest of the path.
+pub struct WebDav;
+
+impl<State> RequestHandlerService<State, Result<String, fs_path::PathError>> for WebDav {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        state: &State,
+        path: Result<String, fs_path::PathError>,
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        macro_rules! respond {
+            ($response:expr) => {{
+                let response = $response;
+                response.write_to(request.body_connection.finalize().await?, response_writer).await
+            }};
+        }
+
+        let path = match path {
+            Ok(path) => path,
+            Err(e) => return respond!(outcome_response(Err(DavError(StatusCode::BAD_REQUEST, e.message())))),
+        };
+
+        match request.parts.method() {
+            "OPTIONS" => respond!(
+                Response::new(StatusCode::OK, "")
+                    .with_header("DAV", "1")
+                    .with_header("Allow", ALLOW)
+                    .with_header("MS-Author-Via", "DAV")
+            ),
+            "GET" | "HEAD" => {
+                let range = range::RangeHeader::from_request_parts(state, &request.parts).await;
+                let cond = conditional::Conditional::from_request_parts(state, &request.parts).await;
+                match (range, cond) {
+                    (Ok(range), Ok(cond)) => respond!(crate::serve_download(path, range, cond).await),
+                    (Err(e), _) | (_, Err(e)) => respond!(outcome_response(Err(DavError(StatusCode::BAD_REQUEST, e)))),
+                }
+            },
+            "PROPFIND" => {
+                let depth = request.parts.headers().get("Depth").and_then(|v| v.as_str().ok());
+                let propfind = PropfindAsync { path, children: depth != Some("0") };
+                match run(propfind).await {
+                    Ok(xml) => respond!(Response::new(StatusCode::new(207), Xml(xml))),
+                    Err(e) => respond!(outcome_response(Err(e))),
+                }
+            },
+            "MKCOL" => {
+                if request.body_connection.content_length() > 0 {
+                    respond!(outcome_response(Err(DavError(StatusCode::UNSUPPORTED_MEDIA_TYPE, "MKCOL bodies are not supported"))))
+                } else {
+                    respond!(outcome_response(run(MkcolAsync { path }).await))
+                }
+            },
+            "PUT" => {
+                let outcome = match run(PutTargetAsync { path: path.clone() }).await {
+                    Ok(existed) => {
+                        let content_length = request.parts.headers().get("Content-Length")
+                            .map(|_| request.body_connection.content_length());
+                        let fits = match content_length {
+                            Some(len) => crate::fits_on_card(&path, len as u64).await,
+                            None => true,
+                        };
+                        if !fits {
+                            Err(DavError(StatusCode::INSUFFICIENT_STORAGE, crate::NO_SPACE))
+                        } else {
+                            raw_uploader::upload_raw(path, request.body_connection.body(), content_length).await
+                                .map(|_| if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED })
+                                .map_err(|e| DavError(StatusCode::INTERNAL_SERVER_ERROR, e))
+                        }
+                    },
+                    Err(e) => Err(e),
+                };
+                respond!(outcome_response(outcome))
+            },
+            "DELETE" => respond!(outcome_response(delete(path).await)),
+            method @ ("COPY" | "MOVE") => {
+                let outcome = match destination_path(&request.parts) {
+                    Ok(dst) => {
+                        let overwrite = request.parts.headers().get("Overwrite")
+                            .map(|v| v.as_raw() != b"F")
+                            .unwrap_or(true);
+                        let shallow = request.parts.headers().get("Depth").is_some_and(|v| v.as_raw() == b"0");
+                        copy(path, dst, overwrite, shallow, method == "MOVE").await
+                    },
+                    Err(e) => Err(e),
+                };
+                respond!(outcome_response(outcome))
+            },
+            _ => respond!(
+                Response::new(StatusCode::METHOD_NOT_ALLOWED, "").with_header("Allow", ALLOW)
+            ),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Checksums taken at upload time and verifying files against them.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::{request, server_port, upload};
+use file_manager::get_file_manager;
+
+const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+fn verify(name: &str) -> String {
+    let reply = request("GET", &format!("/files/verify/{}", name), &[], b"");
+    assert_eq!(reply.status, 200, "{}", reply.text());
+    assert_eq!(reply.header("Content-Type"), Some("application/json"));
+    reply.text()
+}
+
+/// Runs in one test, as every step depends on the files the last one left.
+#[test]
+fn records_and_verifies_checksums() {
+    server_port();
+
+    let json = upload(&[("a.txt", b"abc"), ("b.txt", b"123456789")]);
+    assert!(json.contains(&format!("\"path\":\"1.TXT\",\"size\":3,\"crc32\":\"352441c2\",\"sha256\":\"{}\"", ABC_SHA256)), "{}", json);
+    assert!(json.contains("\"path\":\"2.TXT\",\"size\":9,\"crc32\":\"cbf43926\""), "{}", json);
+
+    let listing = request("GET", "/api/files", &[], b"").text();
+    assert!(listing.contains(&format!("\"size\":3,\"crc32\":\"352441c2\",\"sha256\":\"{}\"", ABC_SHA256)), "{}", listing);
+
+    let json = verify("1.TXT");
+    assert!(json.starts_with("{\"name\":\"1.TXT\",\"ok\":true,"), "{}", json);
+    assert!(json.ends_with(&format!("\"actual\":{{\"size\":3,\"crc32\":\"352441c2\",\"sha256\":\"{}\"}}}}", ABC_SHA256)), "{}", json);
+    assert_eq!(request("GET", "/files/verify/99.TXT", &[], b"").status, 404);
+
+    // One file changed behind the table's back without changing its size,
+    // the other deleted behind it.
+    assert_eq!(request("PUT", "/dav/FILES/1.TXT", &[], b"abd").status, 204);
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let state = get_file_manager().state.lock().await;
+        let files = state.open_root(0).unwrap().open_dir("FILES").unwrap();
+        files.delete_file("2.TXT").unwrap();
+    });
+    let json = verify("1.TXT");
+    assert!(json.contains("\"ok\":false,"), "{}", json);
+    assert!(json.contains(&format!("\"recorded\":{{\"size\":3,\"crc32\":\"352441c2\",\"sha256\":\"{}\"}}", ABC_SHA256)), "{}", json);
+    assert!(verify("2.TXT").ends_with("\"ok\":false,\"recorded\":{\"size\":9,\"crc32\":\"cbf43926\",\"sha256\":\"15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225\"},\"actual\":null}"));
+
+    assert_eq!(request("POST", "/files/verify", &[], b"").status, 202);
+    let mut json = String::new();
+    for _ in 0..100 {
+        json = request("GET", "/files/verify", &[], b"").text();
+        if json.starts_with("{\"running\":false,") {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(
+        json,
+        "{\"running\":false,\"checked\":2,\"total\":2,\"mismatches\":[\
+         {\"table\":\"files\",\"name\":\"1.TXT\",\"missing\":false},\
+         {\"table\":\"files\",\"name\":\"2.TXT\",\"missing\":true}],\"error\":null}"
+    );
+}
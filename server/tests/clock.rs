@@ -0,0 +1,32 @@
+//! Setting the wall clock, and the dates it puts on files.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::request;
+
+/// Runs in one test, as there is a single clock.
+#[test]
+fn set_clock_dates_files() {
+    // the tokio build starts from the host clock
+    assert!(request("GET", "/time", &[], b"").text().starts_with("{\"set\":true,"));
+
+    // 2001-01-01T00:00:00Z, as `Date.now()` would send it
+    assert_eq!(request("POST", "/time", &[], b"978307200000").status, 204);
+    let json = request("GET", "/time", &[], b"").text();
+    assert!(json.contains("\"iso\":\"2001-01-01T00:00:0"), "{}", json);
+
+    assert_eq!(request("PUT", "/fs/CLOCK.TXT", &[], b"tick").status, 200);
+    let reply = request("GET", "/fs/CLOCK.TXT", &[], b"");
+    let modified = reply.header("Last-Modified").unwrap();
+    assert!(modified.starts_with("Mon, 01 Jan 2001 00:00:0"), "{}", modified);
+
+    // with an empty body the Date header counts
+    assert_eq!(request("POST", "/time", &[("Date", "Sat, 01 Jun 2002 12:00:00 GMT")], b"").status, 204);
+    let json = request("GET", "/time", &[], b"").text();
+    assert!(json.contains("\"iso\":\"2002-06-01T12:00:0"), "{}", json);
+
+    assert_eq!(request("POST", "/time", &[], b"soon").status, 400);
+    assert_eq!(request("POST", "/time", &[], b"").status, 400);
+    assert_eq!(request("POST", "/time", &[], b"123456789012345678901234").status, 400);
+}
@@ -0,0 +1,182 @@
+//! Test server on the simulated block device, shared by the integration
+//! tests, and a minimal HTTP/1.1 client to talk to it.
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpStream};
+use std::sync::OnceLock;
+
+use alpa::embedded_sdmmc_ram_device::allocators;
+use file_manager::{init_file_manager, init_file_system, BlkDev, Clock, ExtAlloc};
+use picoserve::routing::{delete, get, parse_path_segment, post, PathRouter, Router};
+use picoserve::time::Duration;
+use server::CatchAll;
+
+fn router() -> Router<impl PathRouter> {
+    Router::new()
+        .route("/api/fsck", get(server::api::handle_api_fsck).post(server::api::handle_api_fsck_repair))
+        .route("/upload/file", post(server::handle_file_upload))
+        .route("/uploads/file", post(server::handle_tus_create_file))
+        .route(
+            ("/uploads", server::UploadKey),
+            get(server::handle_tus_status)
+                .patch_service(server::TusPatch)
+                .delete(server::handle_tus_delete)
+        )
+        .route("/files/list", get(server::handle_files))
+        .route(("/files/delete", parse_path_segment::<String>()), delete(server::handle_delete_file))
+        .route("/trash", get(server::trash::handle_trash_list).delete(server::trash::handle_trash_purge_all))
+        .route(("/trash/restore", parse_path_segment::<String>()), post(server::trash::handle_trash_restore))
+        .route(("/trash", parse_path_segment::<String>()), delete(server::trash::handle_trash_purge))
+        .route("/files/verify", get(server::verify::handle_verify_status).post(server::verify::handle_verify_all))
+        .route(("/files/verify", parse_path_segment::<String>()), get(server::verify::handle_verify_file))
+        .route(("/files/versions", parse_path_segment::<String>()), get(server::versions::handle_versions))
+        .route(
+            ("/files/versions", parse_path_segment::<String>(), parse_path_segment::<u32>()),
+            get(server::versions::handle_version_download)
+        )
+        .route(
+            ("/files/rollback", parse_path_segment::<String>(), parse_path_segment::<u32>()),
+            post(server::versions::handle_rollback)
+        )
+        .route("/api/files", get(server::api::handle_api_files))
+        .route("/api/usage", get(server::api::handle_api_usage))
+        .route("/time", get(server::clock::handle_time).post(server::clock::handle_set_time))
+        .route(("/download", CatchAll), get(server::handle_download))
+        .route(("/fs", CatchAll), get(server::handle_fs).put(server::handle_fs_put).delete(server::handle_fs_delete))
+        .route_service(("/dav", CatchAll), server::webdav::WebDav)
+}
+
+/// Starts the server once on an ephemeral port and returns that port.
+pub fn server_port() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+
+    *PORT.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            allocators::init_simulated_hardware();
+            let image = std::env::temp_dir().join(format!("server-tests-{}.img", std::process::id()));
+            let _ = std::fs::remove_file(&image);
+            init_file_manager(BlkDev::new(image.to_str().unwrap()).unwrap(), Clock);
+
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            tokio::task::LocalSet::new().block_on(&runtime, async move {
+                init_file_system(ExtAlloc::default()).await.unwrap();
+                server::chunks::init_all().await;
+                tokio::task::spawn_local(server::chunks::task_file_uploader());
+                tokio::task::spawn_local(server::verify::task_verifier());
+
+                let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+                tx.send(listener.local_addr().unwrap().port()).unwrap();
+
+                let app = std::rc::Rc::new(router());
+                let config = picoserve::Config::new(picoserve::Timeouts {
+                    start_read_request: Some(Duration::from_secs(5)),
+                    persistent_start_read_request: None,
+                    read_request: Some(Duration::from_secs(1)),
+                    write: Some(Duration::from_secs(1)),
+                });
+
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let config = config.clone();
+                    let app = app.clone();
+
+                    tokio::task::spawn_local(async move {
+                        let mut buffer = [0u8; 2048];
+                        let _ = picoserve::Server::new_tokio(&app, &config, &mut buffer).serve(stream).await;
+                    });
+                }
+            });
+        });
+
+        rx.recv().unwrap()
+    })
+}
+
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+fn dechunk(mut raw: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = raw.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = std::str::from_utf8(&raw[..line_end]).unwrap().split(';').next().unwrap();
+        let size = usize::from_str_radix(size.trim(), 16).unwrap();
+        raw = &raw[line_end + 2..];
+        if size == 0 {
+            return body;
+        }
+        body.extend_from_slice(&raw[..size]);
+        raw = &raw[size + 2..];
+    }
+}
+
+pub fn request(method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> Reply {
+    let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, server_port())).unwrap();
+
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", method, path);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    let has_length = headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"));
+    if (!body.is_empty() || method == "PUT") && !has_length {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).unwrap();
+
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").expect("incomplete response");
+    let head = String::from_utf8_lossy(&raw[..split]).into_owned();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let mut reply = Reply { status, headers, body: raw[split + 4..].to_vec() };
+    if reply.header("Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+        reply.body = dechunk(&reply.body);
+    }
+    reply
+}
+
+/// Uploads `parts` as `(filename, data)` to `/upload/file` in one multipart
+/// request and returns the JSON report.
+pub fn upload(parts: &[(&str, &[u8])]) -> String {
+    let mut body = Vec::new();
+    for (filename, data) in parts {
+        body.extend_from_slice(format!(
+            "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n",
+            filename
+        ).as_bytes());
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(b"--XyZ--\r\n");
+
+    let reply = request("POST", "/upload/file", &[("Content-Type", "multipart/form-data; boundary=XyZ")], &body);
+    assert_eq!(reply.status, 200, "{}", reply.text());
+    reply.text()
+}
@@ -0,0 +1,82 @@
+//! Conditional requests and `HEAD` on `/download` and `/fs` file views.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::request;
+
+/// Creates `/CONDS/NOTE.TXT` once for the cases below.
+fn fixture() {
+    static ONCE: std::sync::Once = std::sync::Once::new();
+    ONCE.call_once(|| {
+        assert_eq!(request("MKCOL", "/dav/CONDS", &[], b"").status, 201);
+        assert_eq!(request("PUT", "/dav/CONDS/NOTE.TXT", &[], b"cached note").status, 201);
+    });
+}
+
+#[test]
+fn matching_etag_is_not_modified() {
+    fixture();
+    for path in ["/download/CONDS/NOTE.TXT", "/fs/CONDS/NOTE.TXT"] {
+        let full = request("GET", path, &[], b"");
+        assert_eq!(full.status, 200, "{}", path);
+        let etag = full.header("ETag").expect("ETag").to_string();
+
+        let cached = request("GET", path, &[("If-None-Match", &etag)], b"");
+        assert_eq!(cached.status, 304, "{}", path);
+        assert!(cached.body.is_empty(), "{}", path);
+        assert_eq!(cached.header("ETag"), Some(etag.as_str()), "{}", path);
+
+        let weak = format!("W/{}", etag);
+        assert_eq!(request("GET", path, &[("If-None-Match", &weak)], b"").status, 304, "{}", path);
+        assert_eq!(request("GET", path, &[("If-None-Match", "\"other\", *")], b"").status, 304, "{}", path);
+
+        let stale = request("GET", path, &[("If-None-Match", "\"0-00000000\"")], b"");
+        assert_eq!(stale.status, 200, "{}", path);
+        assert_eq!(stale.body, b"cached note", "{}", path);
+    }
+}
+
+#[test]
+fn last_modified_is_not_modified() {
+    fixture();
+    let full = request("GET", "/download/CONDS/NOTE.TXT", &[], b"");
+    let last_modified = full.header("Last-Modified").expect("Last-Modified").to_string();
+
+    let cached = request("GET", "/download/CONDS/NOTE.TXT", &[("If-Modified-Since", &last_modified)], b"");
+    assert_eq!(cached.status, 304);
+    assert!(cached.body.is_empty());
+
+    let old = request("GET", "/download/CONDS/NOTE.TXT", &[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")], b"");
+    assert_eq!(old.status, 200);
+
+    // If-None-Match wins when both are sent
+    let both = request(
+        "GET", "/download/CONDS/NOTE.TXT",
+        &[("If-None-Match", "\"0-00000000\""), ("If-Modified-Since", &last_modified)],
+        b""
+    );
+    assert_eq!(both.status, 200);
+}
+
+#[test]
+fn rewritten_file_gets_a_new_etag() {
+    fixture();
+    assert_eq!(request("PUT", "/dav/CONDS/EDIT.TXT", &[], b"first").status, 201);
+    let etag = request("GET", "/download/CONDS/EDIT.TXT", &[], b"").header("ETag").unwrap().to_string();
+
+    assert_eq!(request("PUT", "/dav/CONDS/EDIT.TXT", &[], b"second!").status, 204);
+    let reply = request("GET", "/download/CONDS/EDIT.TXT", &[("If-None-Match", &etag)], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, b"second!");
+}
+
+#[test]
+fn head_has_headers_only() {
+    fixture();
+    let reply = request("HEAD", "/download/CONDS/NOTE.TXT", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert!(reply.body.is_empty());
+    assert_eq!(reply.header("Content-Length"), Some("11"));
+    assert!(reply.header("ETag").is_some());
+}
@@ -0,0 +1,51 @@
+//! Names and contents from the card reach the listing pages HTML-escaped,
+//! and user HTML is served sandboxed.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::{request, server_port, upload};
+use server::template::{PAGE_CSP, USER_CONTENT_CSP};
+
+const PAYLOAD: &str = "<img src=x onerror=alert(1)>";
+const ESCAPED: &str = "&lt;img src=x onerror=alert(1)&gt;";
+
+/// Runs in one test, as the upload below is the first and only one.
+#[test]
+fn markup_in_names_and_contents_is_escaped() {
+    server_port();
+    let name = format!("{}.txt", PAYLOAD);
+    upload(&[(name.as_str(), PAYLOAD.as_bytes())]);
+
+    let files = request("GET", "/files/list", &[], b"");
+    assert_eq!(files.status, 200);
+    assert!(files.text().contains(&format!("{}.txt", ESCAPED)), "{}", files.text());
+    assert!(!files.text().contains(PAYLOAD), "{}", files.text());
+
+    let listing = request("GET", "/fs/FILES", &[], b"");
+    assert_eq!(listing.status, 200);
+    assert!(listing.text().contains("1.TXT"), "{}", listing.text());
+    assert!(!listing.text().contains(PAYLOAD), "{}", listing.text());
+
+    let view = request("GET", "/fs/FILES/1.TXT", &[], b"");
+    assert_eq!(view.status, 200);
+    assert!(view.text().contains(&format!("<pre>{}</pre>", ESCAPED)), "{}", view.text());
+    assert!(!view.text().contains(PAYLOAD), "{}", view.text());
+    assert_eq!(view.header("Content-Security-Policy"), Some(PAGE_CSP));
+    assert_eq!(view.header("X-Content-Type-Options"), Some("nosniff"));
+}
+
+#[test]
+fn user_html_is_sandboxed() {
+    server_port();
+    assert_eq!(request("MKCOL", "/dav/ESCAPING", &[], b"").status, 201);
+    let page = format!("<html><body>{}<script>alert(2)</script></body></html>", PAYLOAD);
+    assert_eq!(request("PUT", "/dav/ESCAPING/PAGE.HTM", &[], page.as_bytes()).status, 201);
+
+    for path in ["/fs/ESCAPING/PAGE.HTM", "/download/ESCAPING/PAGE.HTM"] {
+        let reply = request("GET", path, &[], b"");
+        assert_eq!(reply.status, 200, "{}", path);
+        assert_eq!(reply.header("Content-Security-Policy"), Some(USER_CONTENT_CSP), "{}", path);
+        assert_eq!(reply.header("X-Content-Type-Options"), Some("nosniff"), "{}", path);
+    }
+}
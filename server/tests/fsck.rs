@@ -0,0 +1,64 @@
+//! Checking and repairing the upload directories against their tables.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::{request, server_port};
+use file_manager::get_file_manager;
+
+fn check() -> String {
+    let reply = request("GET", "/api/fsck", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Type"), Some("application/json"));
+    reply.text()
+}
+
+fn repair() -> String {
+    let reply = request("POST", "/api/fsck", &[], b"");
+    assert_eq!(reply.status, 200);
+    reply.text()
+}
+
+/// Runs in one test, as every step depends on the tables the last one left.
+#[test]
+fn finds_and_repairs_drift() {
+    server_port();
+
+    // Files put through WebDAV get no row, and their ids are past the counter.
+    assert_eq!(request("PUT", "/dav/FILES/500.TXT", &[], b"hello").status, 201);
+    assert_eq!(request("PUT", "/dav/FILES/501.TXT", &[], b"x").status, 201);
+    let json = check();
+    assert!(json.starts_with("{\"repaired\":false,"), "{}", json);
+    for finding in [
+        "{\"table\":\"files\",\"issue\":\"unregistered\",\"name\":\"500.TXT\",\"size\":5}",
+        "{\"table\":\"files\",\"issue\":\"unregistered\",\"name\":\"501.TXT\",\"size\":1}",
+        "{\"table\":\"files\",\"issue\":\"stale_counter\",\"recorded\":1,\"next_free\":502}",
+    ] {
+        assert!(json.contains(finding), "{}", json);
+    }
+
+    let json = repair();
+    assert!(json.starts_with("{\"repaired\":true,"), "{}", json);
+    assert!(check().ends_with("\"findings\":[]}"), "{}", check());
+
+    // A file grown behind the table's back, and one deleted behind it.
+    assert_eq!(request("PUT", "/dav/FILES/500.TXT", &[], b"hello world").status, 204);
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let state = get_file_manager().state.lock().await;
+        let files = state.open_root(0).unwrap().open_dir("FILES").unwrap();
+        files.delete_file("501.TXT").unwrap();
+    });
+    let json = check();
+    for finding in [
+        "{\"table\":\"files\",\"issue\":\"size_mismatch\",\"name\":\"500.TXT\",\"recorded\":5,\"actual\":11}",
+        "{\"table\":\"files\",\"issue\":\"missing\",\"name\":\"501.TXT\"}",
+    ] {
+        assert!(json.contains(finding), "{}", json);
+    }
+    assert!(!json.contains("stale_counter"), "{}", json);
+
+    repair();
+    let json = check();
+    assert!(json.ends_with("\"findings\":[]}"), "{}", json);
+    assert!(json.contains("\"rows\":1,"), "{}", json);
+}
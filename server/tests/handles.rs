@@ -0,0 +1,93 @@
+//! Directory and file handles are closed on every path out of a handler.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::{request, server_port};
+use embedded_sdmmc::RawDirectory;
+use file_manager::{consts, get_file_manager, DirGuard};
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+}
+
+/// Runs in one test, as leaking handles on purpose would fail any request
+/// made next to it.
+#[test]
+fn handles_are_closed() {
+    server_port();
+    assert_eq!(request("MKCOL", "/dav/HANDLES", &[], b"").status, 201);
+    assert_eq!(request("PUT", "/dav/HANDLES/NOTE.TXT", &[], b"note").status, 201);
+
+    // Failing halfway down a path must not leave the directories above open,
+    // so more failures than there are handles still leave room for a listing.
+    for path in ["/fs/HANDLES/MISSING/NOTE.TXT", "/download/HANDLES/NOTE.TXT/X", "/dav/HANDLES/MISSING/"] {
+        for _ in 0..8 {
+            assert_ne!(request("GET", path, &[], b"").status, 200, "{}", path);
+        }
+    }
+    for _ in 0..8 {
+        assert_eq!(request("PROPFIND", "/dav/HANDLES/MISSING/X", &[("Depth", "1")], b"").status, 404);
+        assert_eq!(request("MKCOL", "/dav/HANDLES/MISSING/X", &[], b"").status, 409);
+        assert_eq!(request("GET", "/fs/HANDLES/NOTE.TXT", &[], b"").status, 200);
+    }
+    assert_eq!(request("GET", "/fs/HANDLES", &[], b"").status, 200);
+
+    // Directories given up with `into_raw` stay open until adopted again.
+    // A request waits for one of them for a while, then gives up.
+    let leaked = leak_dirs();
+    let started = std::time::Instant::now();
+    assert_eq!(request("GET", "/fs/HANDLES", &[], b"").status, 503);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(consts::HANDLE_WAIT_MS));
+    adopt(leaked);
+    assert_eq!(request("GET", "/fs/HANDLES", &[], b"").status, 200);
+
+    // Closing one while it waits lets it through.
+    let leaked = leak_dirs();
+    let closer = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(consts::HANDLE_WAIT_MS / 4));
+        adopt(leaked);
+    });
+    assert_eq!(request("GET", "/fs/HANDLES/NOTE.TXT", &[], b"").text(), "note");
+    closer.join().unwrap();
+
+    // An opened file leaves the state unlocked, as a download being
+    // streamed does; if dropped while the state is locked it is closed by
+    // whoever locks it next.
+    block_on(async {
+        let fman = get_file_manager();
+        let opened = fman.open_path("/HANDLES/NOTE.TXT").await.unwrap();
+        assert_eq!(request("GET", "/fs/HANDLES", &[], b"").status, 200);
+        assert_eq!(request("DELETE", "/dav/HANDLES/NOTE.TXT", &[], b"").status, 409);
+
+        let state = fman.state.lock().await;
+        drop(opened);
+        drop(state);
+    });
+    assert_eq!(request("DELETE", "/dav/HANDLES/NOTE.TXT", &[], b"").status, 204);
+}
+
+/// Opens directories until there are no handles left, keeping them open.
+fn leak_dirs() -> Vec<RawDirectory> {
+    block_on(async {
+        let state = get_file_manager().state.lock().await;
+        let root = state.open_root(0).unwrap();
+        let mut leaked = Vec::new();
+        while let Ok(dir) = root.open_dir("HANDLES") {
+            leaked.push(dir.into_raw());
+        }
+        leaked.push(root.into_raw());
+        assert!(state.open_root(0).is_err(), "no directory handle should be left");
+        leaked
+    })
+}
+
+/// Closes what [`leak_dirs`] left open.
+fn adopt(leaked: Vec<RawDirectory>) {
+    block_on(async {
+        let state = get_file_manager().state.lock().await;
+        for raw in leaked {
+            drop(DirGuard::new(state.vm().unwrap(), raw));
+        }
+    })
+}
@@ -0,0 +1,111 @@
+//! Path decoding and canonicalization as seen through `/fs` and `/download`.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::request;
+
+/// Creates `/PATHS/NOTE.TXT` once for the cases below.
+fn fixture() {
+    static ONCE: std::sync::Once = std::sync::Once::new();
+    ONCE.call_once(|| {
+        assert_eq!(request("MKCOL", "/dav/PATHS", &[], b"").status, 201);
+        assert_eq!(request("PUT", "/dav/PATHS/NOTE.TXT", &[], b"note").status, 201);
+    });
+}
+
+/// (method, request path, expected status)
+const CASES: &[(&str, &str, u16)] = &[
+    ("GET", "/fs/", 200),
+    ("GET", "/fs//PATHS", 200),
+    ("GET", "/fs/PATHS/NOTE.TXT", 200),
+    ("GET", "/fs/paths/note.txt", 200),
+    ("GET", "/fs//PATHS///NOTE.TXT", 200),
+    ("GET", "/fs/PATHS/./NOTE.TXT", 200),
+    ("GET", "/fs/FILES/../PATHS/NOTE.TXT", 200),
+    ("GET", "/fs/PATHS/%4EOTE.TXT", 200),
+    ("GET", "/fs/PATHS/%4eote.txt", 200),
+    ("GET", "/fs/..", 400),
+    ("GET", "/fs/PATHS/../../DB", 400),
+    ("GET", "/fs/%2e%2e/DB", 400),
+    ("GET", "/fs/PATHS/%zz", 400),
+    ("GET", "/fs/PATHS/NOTE%2", 400),
+    ("GET", "/fs/PATHS/MY%20NOTE.TXT", 404),
+    ("GET", "/fs/PATHS/MY+NOTE.TXT", 404),
+    ("GET", "/fs/PATHS/A%2FB.TXT", 400),
+    ("GET", "/fs/PATHS/LONGFILENAME.TXT", 404),
+    ("GET", "/fs/PATHS/NOTE.HTML", 404),
+    ("GET", "/fs/PATHS/A%3AB.TXT", 400),
+    ("GET", "/fs/PATHS/NOTE.TXT.", 400),
+    ("GET", "/fs/PATHS/MISSING.TXT", 404),
+    ("PUT", "/fs/PATHS/../../ESCAPE.TXT", 400),
+    ("GET", "/download/PATHS/NOTE.TXT", 200),
+    ("GET", "/download//paths//note.txt", 200),
+    ("GET", "/download/PATHS/%4EOTE.TXT", 200),
+    ("GET", "/download/FILES/../PATHS/NOTE.TXT", 200),
+    ("GET", "/download/../PATHS/NOTE.TXT", 400),
+    ("GET", "/download/PATHS/%", 400),
+    ("GET", "/download/PATHS/NOTE%20.TXT", 404),
+    ("GET", "/download/PATHS/TOOLONGNAME.TXT", 404),
+    ("GET", "/download/PATHS/NOTE%3F.TXT", 400),
+    ("GET", "/download/PATHS/MISSING.TXT", 404),
+];
+
+#[test]
+fn route_paths() {
+    fixture();
+    for (method, path, status) in CASES {
+        let body: &[u8] = if *method == "PUT" { b"x" } else { b"" };
+        assert_eq!(request(method, path, &[], body).status, *status, "{} {}", method, path);
+    }
+}
+
+#[test]
+fn download_bodies_match_canonical_file() {
+    fixture();
+    for path in ["/download/PATHS/NOTE.TXT", "/download/paths/%6eote.txt", "/download/FILES/../PATHS/NOTE.TXT"] {
+        assert_eq!(request("GET", path, &[], b"").body, b"note", "{}", path);
+    }
+}
+
+#[test]
+fn long_names_round_trip() {
+    fixture();
+    let put = request("PUT", "/fs/PATHS/Long%20Trip/%C3%89t%C3%A9%202024.markdown", &[], b"summer");
+    assert_eq!(put.status, 200);
+
+    for path in ["/download/PATHS/Long%20Trip/%C3%89t%C3%A9%202024.markdown", "/download/paths/long%20trip/%C3%89t%C3%A9%202024.MARKDOWN"] {
+        let reply = request("GET", path, &[], b"");
+        assert_eq!(reply.status, 200, "{}", path);
+        assert_eq!(reply.body, b"summer", "{}", path);
+    }
+
+    let parent = request("GET", "/fs/PATHS", &[], b"").text();
+    assert!(parent.contains("<a>Long Trip/</a>"), "{}", parent);
+    let listing = request("GET", "/fs/PATHS/Long%20Trip", &[], b"").text();
+    assert!(listing.contains("<a>Été 2024.markdown</a>"), "{}", listing);
+
+    // the alias is stable: writing again replaces the same file
+    assert_eq!(request("PUT", "/fs/PATHS/Long%20Trip/%C3%89t%C3%A9%202024.markdown", &[], b"autumn").status, 200);
+    assert_eq!(request("GET", "/download/PATHS/Long%20Trip/%C3%89t%C3%A9%202024.markdown", &[], b"").body, b"autumn");
+    let listing = request("GET", "/fs/PATHS/Long%20Trip", &[], b"").text();
+    assert_eq!(listing.matches("markdown").count(), 1, "{}", listing);
+}
+
+#[test]
+fn recursive_delete_forgets_long_names() {
+    fixture();
+    assert_eq!(request("PUT", "/fs/PATHS/Gone%20Soon/Inner%20Dir/first%20note.txt", &[], b"1").status, 200);
+    assert_eq!(request("PUT", "/fs/PATHS/Gone%20Soon/SECOND.TXT", &[], b"22").status, 200);
+
+    let reply = request("DELETE", "/fs/PATHS/Gone%20Soon", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.text(), "success: removed 2 files and 2 directories, 3 bytes");
+
+    assert_eq!(request("GET", "/fs/PATHS/Gone%20Soon", &[], b"").status, 404);
+    let parent = request("GET", "/fs/PATHS", &[], b"").text();
+    assert!(!parent.contains("Gone Soon"), "{}", parent);
+
+    assert_eq!(request("DELETE", "/fs/DB", &[], b"").status, 403);
+    assert_eq!(request("DELETE", "/fs/PATHS/NOSUCH", &[], b"").status, 404);
+}
@@ -0,0 +1,67 @@
+//! Range requests on `/download` and `/fs` file views.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::request;
+
+/// Creates `/RANGES/DIGITS.TXT` once for the cases below.
+fn fixture() {
+    static ONCE: std::sync::Once = std::sync::Once::new();
+    ONCE.call_once(|| {
+        assert_eq!(request("MKCOL", "/dav/RANGES", &[], b"").status, 201);
+        assert_eq!(request("PUT", "/dav/RANGES/DIGITS.TXT", &[], b"0123456789").status, 201);
+    });
+}
+
+/// (`Range` header, expected status, `Content-Range`, body)
+const CASES: &[(&str, u16, Option<&str>, &[u8])] = &[
+    ("bytes=2-5", 206, Some("bytes 2-5/10"), b"2345"),
+    ("bytes=7-", 206, Some("bytes 7-9/10"), b"789"),
+    ("bytes=-3", 206, Some("bytes 7-9/10"), b"789"),
+    ("bytes=8-100", 206, Some("bytes 8-9/10"), b"89"),
+    ("bytes=0-0", 206, Some("bytes 0-0/10"), b"0"),
+    ("bytes=10-", 416, Some("bytes */10"), b""),
+    ("bytes=-0", 416, Some("bytes */10"), b""),
+    ("bytes=5-2", 200, None, b"0123456789"),
+    ("bytes=0-1,4-5", 200, None, b"0123456789"),
+    ("lines=1-2", 200, None, b"0123456789"),
+];
+
+#[test]
+fn download_ranges() {
+    fixture();
+    for (range, status, content_range, body) in CASES {
+        let reply = request("GET", "/download/RANGES/DIGITS.TXT", &[("Range", range)], b"");
+        assert_eq!(reply.status, *status, "{}", range);
+        assert_eq!(reply.header("Content-Range"), *content_range, "{}", range);
+        assert_eq!(reply.body, *body, "{}", range);
+        if *status != 416 {
+            assert_eq!(reply.header("Accept-Ranges"), Some("bytes"), "{}", range);
+        }
+    }
+}
+
+#[test]
+fn file_view_ranges() {
+    fixture();
+    let reply = request("GET", "/fs/RANGES/DIGITS.TXT", &[("Range", "bytes=3-4")], b"");
+    assert_eq!(reply.status, 206);
+    assert_eq!(reply.header("Content-Range"), Some("bytes 3-4/10"));
+    assert_eq!(reply.body, b"34");
+
+    let reply = request("GET", "/fs/RANGES/DIGITS.TXT", &[("Range", "bytes=20-30")], b"");
+    assert_eq!(reply.status, 416);
+    assert_eq!(reply.header("Content-Range"), Some("bytes */10"));
+}
+
+#[test]
+fn ranges_of_an_empty_file() {
+    fixture();
+    assert_eq!(request("PUT", "/dav/RANGES/EMPTY.TXT", &[], b"").status, 201);
+
+    let reply = request("GET", "/download/RANGES/EMPTY.TXT", &[("Range", "bytes=0-")], b"");
+    assert_eq!(reply.status, 416);
+    assert_eq!(reply.header("Content-Range"), Some("bytes */0"));
+    assert_eq!(request("GET", "/download/RANGES/EMPTY.TXT", &[], b"").status, 200);
+}
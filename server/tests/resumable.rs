@@ -0,0 +1,71 @@
+//! tus uploads that are cut off and resumed at the offset the server kept.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpStream};
+
+use common::{request, server_port};
+
+const OCTETS: (&str, &str) = ("Content-Type", "application/offset+octet-stream");
+const TUS: (&str, &str) = ("Tus-Resumable", "1.0.0");
+
+/// Sends a PATCH announcing all of `data` but only the first `sent` bytes
+/// of it, then hangs up, as a client losing its connection would.
+fn interrupted_patch(location: &str, data: &[u8], sent: usize) {
+    let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, server_port())).unwrap();
+    let head = format!(
+        "PATCH {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nTus-Resumable: 1.0.0\r\nUpload-Offset: 0\r\nContent-Length: {}\r\n\r\n",
+        location, OCTETS.1, data.len()
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(&data[..sent]).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    // whatever the answer, the server is done with the PATCH once it closes
+    let mut ignored = Vec::new();
+    let _ = stream.read_to_end(&mut ignored);
+}
+
+fn upload_offset(location: &str) -> usize {
+    let reply = request("HEAD", location, &[TUS], b"");
+    assert_eq!(reply.status, 200, "{}", reply.text());
+    reply.header("Upload-Offset").unwrap().parse().unwrap()
+}
+
+#[test]
+fn interrupted_upload_resumes() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+    let created = request(
+        "POST", "/uploads/file",
+        &[TUS, ("Upload-Length", "100000"), ("Upload-Metadata", "filename cmVzdW1lLmJpbg==")],
+        b""
+    );
+    assert_eq!(created.status, 201, "{}", created.text());
+    let location = created.header("Location").unwrap().to_string();
+    assert_eq!(upload_offset(&location), 0);
+
+    // more than one persisting interval gets through before the cut
+    interrupted_patch(&location, &data, 70_000);
+    let offset = upload_offset(&location);
+    assert!(offset >= 64 * 1024 && offset <= 70_000, "resumes at {}", offset);
+
+    // a PATCH from anywhere else is refused
+    let wrong = request("PATCH", &location, &[TUS, OCTETS, ("Upload-Offset", "0")], &data);
+    assert_eq!(wrong.status, 409);
+    assert_eq!(upload_offset(&location), offset);
+
+    let rest = request("PATCH", &location, &[TUS, OCTETS, ("Upload-Offset", &offset.to_string())], &data[offset..]);
+    assert_eq!(rest.status, 204, "{}", rest.text());
+    assert_eq!(rest.header("Upload-Offset"), Some("100000"));
+
+    // the finished session is gone and the file is whole
+    assert_eq!(request("HEAD", &location, &[TUS], b"").status, 404);
+    let (_, path) = location.rsplit_once('/').unwrap().1.split_once('-').unwrap();
+    let download = request("GET", &format!("/download/FILES/{}", path), &[], b"");
+    assert_eq!(download.status, 200);
+    assert!(download.body == data, "resumed upload differs");
+    assert!(request("GET", "/api/files", &[], b"").text().contains("resume.bin"));
+}
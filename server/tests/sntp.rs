@@ -0,0 +1,119 @@
+//! The SNTP client against a stand-in server on the loopback interface.
+#![cfg(feature = "std-mode")]
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use file_manager::sntp::{self, SntpConfig, SntpError};
+use file_manager::Clock;
+
+/// 2003-03-03T00:00:00Z, the stand-in's time when it starts.
+const BASE_MS: u64 = 1_046_649_600_000;
+/// How far the stand-in jumps ahead from its fourth request on.
+const JUMP_MS: u64 = 500;
+
+fn ntp_timestamp(unix_ms: u64) -> [u8; 8] {
+    let secs = (unix_ms / 1000 + 2_208_988_800) as u32;
+    let frac = (((unix_ms % 1000) << 32) / 1000) as u32;
+    let mut ts = [0u8; 8];
+    ts[..4].copy_from_slice(&secs.to_be_bytes());
+    ts[4..].copy_from_slice(&frac.to_be_bytes());
+    ts
+}
+
+fn reply(request: &[u8], stratum: u8, unix_ms: u64) -> [u8; 48] {
+    let mut reply = [0u8; 48];
+    // no leap indicator, version 4, mode 4 (server)
+    reply[0] = 0b00_100_100;
+    reply[1] = stratum;
+    reply[24..32].copy_from_slice(&request[40..48]);
+    reply[32..40].copy_from_slice(&ntp_timestamp(unix_ms));
+    reply[40..48].copy_from_slice(&ntp_timestamp(unix_ms));
+    reply
+}
+
+/// Ignores the first request, sends a kiss-o'-death to the second and a
+/// stray reply ahead of each proper one after that.
+fn stand_in() -> std::net::SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let started = Instant::now();
+        let mut buf = [0u8; 64];
+        for n in 0.. {
+            let (len, client) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(len, 48);
+            assert_eq!(buf[0] & 0b111, 3, "not a client request");
+            let now = BASE_MS + started.elapsed().as_millis() as u64 + if n >= 3 { JUMP_MS } else { 0 };
+            match n {
+                0 => (),
+                1 => {
+                    socket.send_to(&reply(&buf, 0, now), client).unwrap();
+                },
+                _ => {
+                    let mut stray = reply(&buf, 2, now + 60_000);
+                    stray[24] ^= 0xff;
+                    socket.send_to(&stray, client).unwrap();
+                    socket.send_to(&reply(&buf, 2, now), client).unwrap();
+                },
+            }
+        }
+    });
+    addr
+}
+
+#[test]
+fn syncs_clock_with_backoff() {
+    let server = stand_in();
+    let config = SntpConfig { poll_ms: 50, min_retry_ms: 20, max_retry_ms: 40, timeout_ms: 200 };
+    let started = Instant::now();
+    assert!(!Clock::is_set());
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let mut socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        let synced_twice = async {
+            while sntp::status().syncs < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::select! {
+            _ = sntp::run(&mut socket, config) => unreachable!(),
+            _ = tokio::time::timeout(Duration::from_secs(5), synced_twice) => (),
+        }
+    });
+
+    let status = sntp::status();
+    assert_eq!(status.syncs, 2, "{:?}", status);
+    assert_eq!(status.failures, 2, "{:?}", status);
+    assert_eq!(status.last_error, None);
+    assert!(status.delay_ms < 100, "{:?}", status);
+    // the second sync made up for the jump, which the drift is made of
+    assert!((JUMP_MS as i32 - 100..=JUMP_MS as i32 + 100).contains(&status.offset_ms), "{:?}", status);
+    assert!(status.drift_ppm > 0, "{:?}", status);
+
+    let expected = BASE_MS + JUMP_MS + started.elapsed().as_millis() as u64;
+    let now = Clock::unix_ms().unwrap();
+    assert!(now.abs_diff(expected) < 200, "{} vs {}", now, expected);
+    assert_eq!(status.last_sync.map(|secs| secs as u64 / 60), Some(BASE_MS / 60_000));
+
+    // a browser can no longer turn it back
+    assert!(!Clock::set_by_client(BASE_MS - 86_400_000));
+    assert!(Clock::unix_ms().unwrap().abs_diff(expected) < 200);
+}
+
+#[test]
+fn rejects_bad_replies() {
+    let request = sntp::request(BASE_MS);
+    assert_eq!(sntp::parse_reply(&reply(&request, 2, BASE_MS)[..40], BASE_MS, BASE_MS), Err(SntpError::Malformed));
+    assert_eq!(sntp::parse_reply(&request, BASE_MS, BASE_MS), Err(SntpError::Malformed));
+    assert_eq!(sntp::parse_reply(&reply(&request, 2, BASE_MS), BASE_MS + 1, BASE_MS), Err(SntpError::Malformed));
+    assert_eq!(sntp::parse_reply(&reply(&request, 16, BASE_MS), BASE_MS, BASE_MS), Err(SntpError::Unsynchronized));
+
+    let mut unsynchronized = reply(&request, 2, BASE_MS);
+    unsynchronized[0] |= 0b11 << 6;
+    assert_eq!(sntp::parse_reply(&unsynchronized, BASE_MS, BASE_MS), Err(SntpError::Unsynchronized));
+
+    let sample = sntp::parse_reply(&reply(&request, 2, BASE_MS + 1500), BASE_MS, BASE_MS + 20).unwrap();
+    assert_eq!((sample.offset_ms, sample.delay_ms), (1490, 20));
+}
@@ -0,0 +1,79 @@
+//! Deleting uploads into the trash, restoring and purging them.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::{request, server_port, upload};
+use file_manager::get_file_manager;
+
+fn trash_listing() -> String {
+    let reply = request("GET", "/trash", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Type"), Some("application/json"));
+    reply.text()
+}
+
+fn purge_older_than(secs: u64) -> u32 {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        get_file_manager().purge_older_than(secs).await.unwrap()
+    })
+}
+
+/// Runs in one test, as every step depends on the files the last one left.
+#[test]
+fn trashes_restores_and_purges() {
+    server_port();
+    upload(&[("a.txt", b"abc")]);
+    upload(&[("b.txt", b"hello")]);
+
+    let reply = request("DELETE", "/files/delete/1.TXT", &[], b"");
+    assert_eq!((reply.status, reply.text().as_str()), (200, "success"));
+    assert_eq!(request("DELETE", "/files/delete/1.TXT", &[], b"").status, 404);
+    assert_eq!(request("GET", "/dav/FILES/1.TXT", &[], b"").status, 404);
+    assert!(!request("GET", "/api/files", &[], b"").text().contains("\"a.txt\""));
+    let json = trash_listing();
+    assert!(json.starts_with(
+        "{\"entries\":[{\"name\":\"1.TXT\",\"upload_name\":\"a.txt\",\"table\":\"files\",\"original\":\"1.TXT\",\"size\":3,\"deleted\":\"20"
+    ), "{}", json);
+
+    // The trash is only changed through /trash.
+    let dest = format!("http://localhost:{}/dav/FILES/1.TXT", server_port());
+    assert_eq!(request("MOVE", "/dav/TRASH/1.TXT", &[("Destination", &dest)], b"").status, 403);
+    assert_eq!(request("DELETE", "/dav/TRASH/1.TXT", &[], b"").status, 403);
+    assert_eq!(request("PUT", "/dav/TRASH/4.TXT", &[], b"x").status, 403);
+    assert_eq!(request("PUT", "/fs/TRASH/4.TXT", &[], b"x").status, 403);
+    assert_eq!(request("DELETE", "/fs/TRASH", &[], b"").status, 403);
+    assert_eq!(request("GET", "/dav/TRASH/1.TXT", &[], b"").body, b"abc");
+
+    // Restoring waits for the old name to be free again.
+    assert_eq!(request("PUT", "/dav/FILES/1.TXT", &[], b"other").status, 201);
+    assert_eq!(request("POST", "/trash/restore/1.TXT", &[], b"").status, 409);
+    assert_eq!(request("DELETE", "/dav/FILES/1.TXT", &[], b"").status, 204);
+    let reply = request("POST", "/trash/restore/1.TXT", &[], b"");
+    assert_eq!((reply.status, reply.text().as_str()), (200, "{\"table\":\"files\",\"name\":\"1.TXT\"}"));
+    assert_eq!(request("POST", "/trash/restore/1.TXT", &[], b"").status, 404);
+    assert_eq!(request("GET", "/dav/FILES/1.TXT", &[], b"").body, b"abc");
+    assert!(request("GET", "/files/verify/1.TXT", &[], b"").text().contains("\"ok\":true"));
+    assert!(request("GET", "/api/files", &[], b"").text().contains("\"a.txt\""));
+    assert_eq!(trash_listing(), "{\"entries\":[]}");
+
+    // Trash names keep counting, so nothing in there is overwritten.
+    assert_eq!(request("DELETE", "/files/delete/1.TXT", &[], b"").status, 200);
+    assert_eq!(request("DELETE", "/files/delete/2.TXT", &[], b"").status, 200);
+    let json = trash_listing();
+    assert!(json.contains("{\"name\":\"2.TXT\",\"upload_name\":\"a.txt\",\"table\":\"files\",\"original\":\"1.TXT\""), "{}", json);
+    assert!(json.contains("{\"name\":\"3.TXT\",\"upload_name\":\"b.txt\",\"table\":\"files\",\"original\":\"2.TXT\""), "{}", json);
+
+    assert_eq!(purge_older_than(3600), 0);
+    assert_eq!(request("DELETE", "/trash/2.TXT", &[], b"").status, 204);
+    assert_eq!(request("DELETE", "/trash/2.TXT", &[], b"").status, 404);
+    assert_eq!(request("GET", "/dav/TRASH/2.TXT", &[], b"").status, 404);
+    assert_eq!(purge_older_than(0), 1);
+    assert_eq!(request("GET", "/dav/TRASH/3.TXT", &[], b"").status, 404);
+
+    upload(&[("c.txt", b"c")]);
+    assert_eq!(request("DELETE", "/files/delete/3.TXT", &[], b"").status, 200);
+    let reply = request("DELETE", "/trash", &[], b"");
+    assert_eq!((reply.status, reply.text().as_str()), (200, "{\"purged\":1}"));
+    assert_eq!(trash_listing(), "{\"entries\":[]}");
+}
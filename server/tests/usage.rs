@@ -0,0 +1,44 @@
+//! Disk usage reporting and uploads larger than the free space.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::request;
+
+/// The number following `"name":` in `json`.
+fn number(json: &str, name: &str) -> u64 {
+    let key = format!("\"{}\":", name);
+    let start = json.find(&key).unwrap_or_else(|| panic!("no {} in {}", name, json)) + key.len();
+    let digits: String = json[start..].chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().unwrap()
+}
+
+#[test]
+fn usage_per_top_level_directory() {
+    assert_eq!(request("PUT", "/fs/USAGE/A.BIN", &[], &[0u8; 1000]).status, 200);
+    assert_eq!(request("PUT", "/fs/USAGE/SUB/B.BIN", &[], &[0u8; 24]).status, 200);
+
+    let reply = request("GET", "/api/usage", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Type"), Some("application/json"));
+    let json = reply.text();
+    assert!(json.contains("{\"name\":\"USAGE\",\"files\":2,\"dirs\":2,\"bytes\":1024}"), "{}", json);
+    for dir in ["DB", "FILES", "MUSIC"] {
+        assert!(json.contains(&format!("{{\"name\":\"{}\",", dir)), "{}", json);
+    }
+
+    let (total, free) = (number(&json, "total"), number(&json, "free"));
+    assert!(free > 0 && free < total, "{}", json);
+    assert_eq!(total % number(&json, "cluster_size"), 0);
+}
+
+#[test]
+fn uploads_larger_than_the_free_space() {
+    let free = number(&request("GET", "/api/usage", &[], b"").text(), "free");
+    let too_much = (free + 1).to_string();
+
+    assert_eq!(request("PUT", "/fs/HUGE.BIN", &[("Content-Length", &too_much)], b"").status, 507);
+    assert_eq!(request("PUT", "/dav/HUGE.BIN", &[("Content-Length", &too_much)], b"").status, 507);
+    assert_eq!(request("GET", "/fs/HUGE.BIN", &[], b"").status, 404);
+    assert_eq!(request("PUT", "/fs/SMALL.BIN", &[], b"fits").status, 200);
+}
@@ -0,0 +1,77 @@
+//! Uploads under a name uploaded before becoming versions of that file.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::{request, server_port, upload};
+
+fn versions(name: &str) -> String {
+    let reply = request("GET", &format!("/files/versions/{}", name), &[], b"");
+    assert_eq!(reply.status, 200, "{}", reply.text());
+    assert_eq!(reply.header("Content-Type"), Some("application/json"));
+    reply.text()
+}
+
+/// Runs in one test, as every step depends on the files the last one left.
+#[test]
+fn keeps_rolls_back_and_prunes_versions() {
+    server_port();
+
+    let json = upload(&[("notes.txt", b"v1")]);
+    assert!(json.contains("\"path\":\"1.TXT\",\"size\":2,"), "{}", json);
+    assert!(json.contains("\"version\":1,\"error\":null"), "{}", json);
+    assert_eq!(versions("1.TXT"), "{\"name\":\"1.TXT\",\"current\":1,\"versions\":[]}");
+
+    // The same name again replaces the content but keeps the old one.
+    let json = upload(&[("notes.txt", b"v2!")]);
+    assert!(json.contains("\"path\":\"1.TXT\",\"size\":3,"), "{}", json);
+    assert!(json.contains("\"version\":2,\"error\":null"), "{}", json);
+    assert_eq!(request("GET", "/dav/FILES/1.TXT", &[], b"").body, b"v2!");
+    assert_eq!(request("GET", "/dav/FILES/2.TXT", &[], b"").status, 404);
+    let listing = request("GET", "/api/files", &[], b"").text();
+    assert_eq!(listing.matches("\"notes.txt\"").count(), 1, "{}", listing);
+
+    let json = versions("1.TXT");
+    assert!(json.starts_with("{\"name\":\"1.TXT\",\"current\":2,\"versions\":[{\"version\":1,\"size\":2,\"crc32\":\""), "{}", json);
+    assert!(json.contains("\"replaced\":\"20"), "{}", json);
+    assert_eq!(request("GET", "/files/versions/1.TXT/1", &[], b"").body, b"v1");
+    assert_eq!(request("DELETE", "/dav/VERSIONS", &[], b"").status, 403);
+    assert_eq!(request("PUT", "/fs/VERSIONS/1/9.TXT", &[], b"x").status, 403);
+    assert_eq!(request("MKCOL", "/dav/VERSIONS/9", &[], b"").status, 403);
+    assert_eq!(request("GET", "/files/versions/1.TXT/2", &[], b"").status, 404);
+    assert_eq!(request("GET", "/files/versions/9.TXT", &[], b"").status, 404);
+    assert_eq!(request("GET", "/files/versions/..%2F..%2FDB%2FFILES/1", &[], b"").status, 400);
+
+    // Rolling back keeps what it replaces as a version in place of the one
+    // brought back.
+    let reply = request("POST", "/files/rollback/1.TXT/1", &[], b"");
+    assert_eq!((reply.status, reply.text().as_str()), (200, "{\"name\":\"1.TXT\",\"version\":3}"));
+    assert_eq!(request("GET", "/dav/FILES/1.TXT", &[], b"").body, b"v1");
+    assert_eq!(request("GET", "/files/versions/1.TXT/2", &[], b"").body, b"v2!");
+    assert_eq!(request("GET", "/files/versions/1.TXT/1", &[], b"").status, 404);
+    assert!(request("GET", "/files/verify/1.TXT", &[], b"").text().contains("\"ok\":true"));
+    assert_eq!(request("POST", "/files/rollback/1.TXT/7", &[], b"").status, 404);
+
+    // Only the newest old versions are kept.
+    for n in 4..=9 {
+        let json = upload(&[("notes.txt", format!("v{}", n).as_bytes())]);
+        assert!(json.contains(&format!("\"path\":\"1.TXT\",\"size\":2,")), "{}", json);
+        assert!(json.contains(&format!("\"version\":{},", n)), "{}", json);
+    }
+    let json = versions("1.TXT");
+    assert!(json.starts_with("{\"name\":\"1.TXT\",\"current\":9,\"versions\":[{\"version\":4,"), "{}", json);
+    assert_eq!(json.matches("\"version\":").count(), 5, "{}", json);
+    assert_eq!(request("GET", "/files/versions/1.TXT/3", &[], b"").status, 404);
+    assert_eq!(request("GET", "/files/versions/1.TXT/4", &[], b"").body, b"v4");
+    assert_eq!(request("GET", "/dav/FILES/1.TXT", &[], b"").body, b"v9");
+
+    // Other names are files of their own.
+    let json = upload(&[("other.txt", b"x")]);
+    assert!(json.contains("\"size\":1,"), "{}", json);
+    assert!(json.contains("\"version\":1,"), "{}", json);
+
+    // Deleting a file for good takes its old versions along.
+    assert_eq!(request("DELETE", "/dav/FILES/1.TXT", &[], b"").status, 204);
+    assert_eq!(request("GET", "/files/versions/1.TXT/4", &[], b"").status, 404);
+    assert_eq!(request("GET", "/dav/VERSIONS/1/4.TXT", &[], b"").status, 404);
+}
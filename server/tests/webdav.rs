@@ -0,0 +1,223 @@
+//! litmus-style WebDAV checks against the simulated block device.
+//!
+//! Run with `cargo test -p server --features std-mode --test webdav`. Every
+//! test works inside its own collection so that they can share one server.
+#![cfg(feature = "std-mode")]
+
+mod common;
+
+use common::{request, server_port};
+
+fn mkcol(path: &str) {
+    assert_eq!(request("MKCOL", path, &[], b"").status, 201, "MKCOL {}", path);
+}
+
+fn put(path: &str, body: &[u8]) -> u16 {
+    request("PUT", path, &[], body).status
+}
+
+fn destination(path: &str) -> String {
+    format!("http://localhost:{}{}", server_port(), path)
+}
+
+#[test]
+fn basic_options() {
+    let reply = request("OPTIONS", "/dav/", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert!(reply.header("DAV").is_some_and(|v| v.split(',').any(|c| c.trim() == "1")));
+}
+
+#[test]
+fn basic_put_get() {
+    mkcol("/dav/BASIC");
+    assert_eq!(put("/dav/BASIC/RES.TXT", b"This is\na test file.\n"), 201);
+
+    let reply = request("GET", "/dav/BASIC/RES.TXT", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, b"This is\na test file.\n");
+
+    assert_eq!(put("/dav/BASIC/RES.TXT", b"replaced"), 204);
+    assert_eq!(request("GET", "/dav/BASIC/RES.TXT", &[], b"").body, b"replaced");
+}
+
+#[test]
+fn basic_put_no_parent() {
+    assert_eq!(put("/dav/NOPARENT/RES.TXT", b"orphan"), 409);
+}
+
+#[test]
+fn basic_mkcol_over_existing() {
+    mkcol("/dav/AGAIN");
+    assert_eq!(request("MKCOL", "/dav/AGAIN", &[], b"").status, 405);
+}
+
+#[test]
+fn basic_mkcol_no_parent() {
+    assert_eq!(request("MKCOL", "/dav/NOCOLL/SUB", &[], b"").status, 409);
+}
+
+#[test]
+fn basic_mkcol_with_body() {
+    assert_eq!(request("MKCOL", "/dav/BODY", &[("Content-Type", "text/plain")], b"junk").status, 415);
+}
+
+#[test]
+fn basic_delete() {
+    mkcol("/dav/DELETE");
+    assert_eq!(put("/dav/DELETE/RES.TXT", b"gone soon"), 201);
+    assert_eq!(request("DELETE", "/dav/DELETE/RES.TXT", &[], b"").status, 204);
+    assert_eq!(request("GET", "/dav/DELETE/RES.TXT", &[], b"").status, 404);
+}
+
+#[test]
+fn basic_delete_null() {
+    assert_eq!(request("DELETE", "/dav/NOSUCH.TXT", &[], b"").status, 404);
+}
+
+#[test]
+fn copymove_copy_simple() {
+    mkcol("/dav/COPY");
+    assert_eq!(put("/dav/COPY/SRC.TXT", b"copy me"), 201);
+
+    let dest = destination("/dav/COPY/DST.TXT");
+    let reply = request("COPY", "/dav/COPY/SRC.TXT", &[("Destination", &dest)], b"");
+    assert_eq!(reply.status, 201);
+    assert_eq!(request("GET", "/dav/COPY/DST.TXT", &[], b"").body, b"copy me");
+    assert_eq!(request("GET", "/dav/COPY/SRC.TXT", &[], b"").body, b"copy me");
+}
+
+#[test]
+fn copymove_copy_overwrite() {
+    mkcol("/dav/OVERWR");
+    assert_eq!(put("/dav/OVERWR/SRC.TXT", b"new"), 201);
+    assert_eq!(put("/dav/OVERWR/DST.TXT", b"old"), 201);
+
+    let dest = destination("/dav/OVERWR/DST.TXT");
+    let refused = request("COPY", "/dav/OVERWR/SRC.TXT", &[("Destination", &dest), ("Overwrite", "F")], b"");
+    assert_eq!(refused.status, 412);
+    assert_eq!(request("GET", "/dav/OVERWR/DST.TXT", &[], b"").body, b"old");
+
+    let replaced = request("COPY", "/dav/OVERWR/SRC.TXT", &[("Destination", &dest), ("Overwrite", "T")], b"");
+    assert_eq!(replaced.status, 204);
+    assert_eq!(request("GET", "/dav/OVERWR/DST.TXT", &[], b"").body, b"new");
+}
+
+#[test]
+fn copymove_copy_no_dest_coll() {
+    mkcol("/dav/NODEST");
+    assert_eq!(put("/dav/NODEST/SRC.TXT", b"x"), 201);
+
+    let dest = destination("/dav/NODEST/MISSING/DST.TXT");
+    assert_eq!(request("COPY", "/dav/NODEST/SRC.TXT", &[("Destination", &dest)], b"").status, 409);
+}
+
+#[test]
+fn copymove_move() {
+    mkcol("/dav/MOVE");
+    mkcol("/dav/MOVE/SUB");
+    assert_eq!(put("/dav/MOVE/SRC.TXT", b"moving"), 201);
+
+    let dest = destination("/dav/MOVE/SUB/DST.TXT");
+    assert_eq!(request("MOVE", "/dav/MOVE/SRC.TXT", &[("Destination", &dest)], b"").status, 201);
+    assert_eq!(request("GET", "/dav/MOVE/SRC.TXT", &[], b"").status, 404);
+    assert_eq!(request("GET", "/dav/MOVE/SUB/DST.TXT", &[], b"").body, b"moving");
+}
+
+#[test]
+fn delete_coll() {
+    mkcol("/dav/DELCOLL");
+    mkcol("/dav/DELCOLL/SUB");
+    assert_eq!(put("/dav/DELCOLL/ONE.TXT", b"one"), 201);
+    assert_eq!(put("/dav/DELCOLL/SUB/TWO.TXT", b"two"), 201);
+
+    assert_eq!(request("DELETE", "/dav/DELCOLL", &[], b"").status, 204);
+    assert_eq!(request("PROPFIND", "/dav/DELCOLL/", &[("Depth", "0")], b"").status, 404);
+    // the name is free again
+    mkcol("/dav/DELCOLL");
+}
+
+#[test]
+fn copymove_copy_coll() {
+    mkcol("/dav/CPCOLL");
+    mkcol("/dav/CPCOLL/SRC");
+    mkcol("/dav/CPCOLL/SRC/SUB");
+    assert_eq!(put("/dav/CPCOLL/SRC/ONE.TXT", b"one"), 201);
+    assert_eq!(put("/dav/CPCOLL/SRC/SUB/TWO.TXT", b"two"), 201);
+
+    let dest = destination("/dav/CPCOLL/DST");
+    assert_eq!(request("COPY", "/dav/CPCOLL/SRC", &[("Destination", &dest)], b"").status, 201);
+    assert_eq!(request("GET", "/dav/CPCOLL/DST/ONE.TXT", &[], b"").body, b"one");
+    assert_eq!(request("GET", "/dav/CPCOLL/DST/SUB/TWO.TXT", &[], b"").body, b"two");
+    assert_eq!(request("GET", "/dav/CPCOLL/SRC/SUB/TWO.TXT", &[], b"").body, b"two");
+
+    let shallow = destination("/dav/CPCOLL/EMPTY");
+    assert_eq!(request("COPY", "/dav/CPCOLL/SRC", &[("Destination", &shallow), ("Depth", "0")], b"").status, 201);
+    let listing = request("PROPFIND", "/dav/CPCOLL/EMPTY/", &[("Depth", "1")], b"");
+    assert_eq!(String::from_utf8_lossy(&listing.body).matches("<D:response>").count(), 1);
+
+    let inside = destination("/dav/CPCOLL/SRC/SUB/SRC");
+    assert_eq!(request("COPY", "/dav/CPCOLL/SRC", &[("Destination", &inside)], b"").status, 403);
+}
+
+#[test]
+fn copymove_move_coll() {
+    mkcol("/dav/MVCOLL");
+    mkcol("/dav/MVCOLL/SRC");
+    mkcol("/dav/MVCOLL/SRC/SUB");
+    assert_eq!(put("/dav/MVCOLL/SRC/SUB/DEEP.TXT", b"deep"), 201);
+    mkcol("/dav/MVCOLL/OLD");
+
+    let dest = destination("/dav/MVCOLL/OLD");
+    let refused = request("MOVE", "/dav/MVCOLL/SRC", &[("Destination", &dest), ("Overwrite", "F")], b"");
+    assert_eq!(refused.status, 412);
+
+    assert_eq!(request("MOVE", "/dav/MVCOLL/SRC", &[("Destination", &dest)], b"").status, 204);
+    assert_eq!(request("GET", "/dav/MVCOLL/OLD/SUB/DEEP.TXT", &[], b"").body, b"deep");
+    assert_eq!(request("PROPFIND", "/dav/MVCOLL/SRC/", &[("Depth", "0")], b"").status, 404);
+}
+
+#[test]
+fn copymove_move_long_names() {
+    mkcol("/dav/MVLONG");
+    assert_eq!(request("PUT", "/fs/MVLONG/Moving%20Out/Inner%20Dir/third%20note.txt", &[], b"3").status, 200);
+
+    let inside = destination("/dav/MVLONG/Moving%20Out/Inner%20Dir/Again");
+    assert_eq!(request("MOVE", "/dav/MVLONG/Moving%20Out", &[("Destination", &inside)], b"").status, 403);
+    let protected = destination("/dav/DB/Moving%20Out");
+    assert_eq!(request("MOVE", "/dav/MVLONG/Moving%20Out", &[("Destination", &protected)], b"").status, 403);
+
+    let dest = destination("/dav/MVLONG/Moved%20In");
+    assert_eq!(request("MOVE", "/dav/MVLONG/Moving%20Out", &[("Destination", &dest)], b"").status, 201);
+    let moved = request("GET", "/download/MVLONG/Moved%20In/Inner%20Dir/third%20note.txt", &[], b"");
+    assert_eq!((moved.status, moved.body.as_slice()), (200, &b"3"[..]));
+    assert_eq!(request("GET", "/download/MVLONG/Moving%20Out/Inner%20Dir/third%20note.txt", &[], b"").status, 404);
+
+    let listing = request("GET", "/fs/MVLONG", &[], b"").text();
+    assert!(listing.contains("<a>Moved In/</a>") && !listing.contains("Moving Out"), "{}", listing);
+}
+
+#[test]
+fn props_propfind_depth() {
+    mkcol("/dav/PROPS");
+    mkcol("/dav/PROPS/SUB");
+    assert_eq!(put("/dav/PROPS/RES.TXT", b"12345"), 201);
+
+    let shallow = request("PROPFIND", "/dav/PROPS/", &[("Depth", "0")], b"");
+    assert_eq!(shallow.status, 207);
+    let text = shallow.text();
+    assert_eq!(text.matches("<D:response>").count(), 1);
+    assert!(text.contains("<D:collection/>"));
+
+    let deep = request("PROPFIND", "/dav/PROPS/", &[("Depth", "1")], b"");
+    assert_eq!(deep.status, 207);
+    let text = deep.text();
+    assert_eq!(text.matches("<D:response>").count(), 3);
+    assert!(text.contains("<D:href>/dav/PROPS/RES.TXT</D:href>"));
+    assert!(text.contains("<D:href>/dav/PROPS/SUB/</D:href>"));
+    assert!(text.contains("<D:getcontentlength>5</D:getcontentlength>"));
+}
+
+#[test]
+fn props_propfind_missing() {
+    assert_eq!(request("PROPFIND", "/dav/NOWHERE/", &[("Depth", "0")], b"").status, 404);
+}